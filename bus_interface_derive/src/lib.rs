@@ -0,0 +1,367 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: lib.rs
+ * Desc: #[derive(Sensor)], builds a sensor's channel table from its fields
+ *       so the format/name strings and the index dispatch in `read_data`
+ *       can't get out of step.
+ *
+ *       #[derive(Sensor)]
+ *       #[sensor(name = "Aht20", refresh = sample)]
+ *       struct Aht20 {
+ *           #[channel(name = "Temp", unit = "Cel", scale = 0.01)]
+ *           temp: i16,
+ *           #[channel(name = "Humid", unit = "%", scale = 0.01)]
+ *           humid: u16,
+ *           reading: SensorData,
+ *       }
+ *
+ *       Channels are numbered in field order. The one field of type
+ *       `SensorData` holds the bytes `read_data` hands back, arrays(`[i16; 3]`)
+ *       are sent element by element.
+ */
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::parse_macro_input;
+use syn::Data;
+use syn::DeriveInput;
+use syn::Error;
+use syn::Expr;
+use syn::Fields;
+use syn::Ident;
+use syn::LitStr;
+use syn::Meta;
+use syn::Type;
+
+
+// One #[channel] field.
+struct Channel {
+    field: Ident,
+    format: String,     //"u16", "i16[3]"..
+    size: usize,        //Bytes in a reading.
+    array: bool,
+    name: String,
+    unit: Option<LitStr>,
+    scale: Option<Expr>,
+    offset: Option<Expr>,
+    min: Option<Expr>,
+    max: Option<Expr>,
+    resolution: Option<Expr>,
+}
+
+impl Channel {
+    fn has_meta(&self) -> bool {
+        self.unit.is_some() || self.scale.is_some() || self.offset.is_some() ||
+            self.min.is_some() || self.max.is_some() || self.resolution.is_some()
+    }
+}
+
+
+#[proc_macro_derive(Sensor, attributes(sensor, channel))]
+pub fn derive_sensor(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(ts) => ts.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+
+fn expand(input: &DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let ident = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(named) => &named.named,
+            _ => return Err(Error::new_spanned(ident, "Sensor needs a struct with named fields")),
+        },
+        _ => return Err(Error::new_spanned(ident, "Sensor can only be derived for structs")),
+    };
+
+    // #[sensor(name = "..", refresh = method)]
+    let mut sensor_name = ident.to_string();
+    let mut refresh: Option<Ident> = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("sensor")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                sensor_name = meta.value()?.parse::<LitStr>()?.value();
+            } else if meta.path.is_ident("refresh") {
+                refresh = Some(meta.value()?.parse::<Ident>()?);
+            } else {
+                return Err(meta.error("expected `name` or `refresh`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut channels: Vec<Channel> = vec![];
+    let mut reading: Option<(Ident, Type)> = None;
+    for field in fields.iter() {
+        let field_ident = match &field.ident {
+            Some(i) => i.clone(),
+            None => continue,
+        };
+
+        let attr = match field.attrs.iter().find(|a| a.path().is_ident("channel")) {
+            Some(a) => a,
+            None => {
+                if type_name(&field.ty).as_deref() == Some("SensorData") {
+                    if reading.is_some() {
+                        return Err(Error::new_spanned(field, "only one SensorData field is allowed"));
+                    }
+                    reading = Some((field_ident, field.ty.clone()));
+                }
+                continue;
+            }
+        };
+
+        let (format, size, array) = match channel_type(&field.ty) {
+            Some(t) => t,
+            None => {
+                return Err(Error::new_spanned(&field.ty,
+                    "channels must be u8, i8, u16, i16, u32, i32, f32, u64, i64, f64 or an array of one"));
+            }
+        };
+
+        let mut ch = Channel {
+            name: field_ident.to_string(),
+            field: field_ident,
+            format,
+            size,
+            array,
+            unit: None,
+            scale: None,
+            offset: None,
+            min: None,
+            max: None,
+            resolution: None,
+        };
+        // A bare #[channel] takes all the defaults.
+        if matches!(attr.meta, Meta::Path(_)) {
+            channels.push(ch);
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            let key = match meta.path.get_ident() {
+                Some(k) => k.to_string(),
+                None => return Err(meta.error("expected a channel option")),
+            };
+            match key.as_str() {
+                "name" => ch.name = meta.value()?.parse::<LitStr>()?.value(),
+                "unit" => ch.unit = Some(meta.value()?.parse()?),
+                "scale" => ch.scale = Some(meta.value()?.parse()?),
+                "offset" => ch.offset = Some(meta.value()?.parse()?),
+                "min" => ch.min = Some(meta.value()?.parse()?),
+                "max" => ch.max = Some(meta.value()?.parse()?),
+                "resolution" => ch.resolution = Some(meta.value()?.parse()?),
+                _ => return Err(meta.error("expected name, unit, scale, offset, min, max or resolution")),
+            }
+            Ok(())
+        })?;
+        channels.push(ch);
+    }
+
+    if channels.is_empty() {
+        return Err(Error::new_spanned(ident, "Sensor needs at least one #[channel] field"));
+    }
+    if channels.len() > u8::MAX as usize {
+        return Err(Error::new_spanned(ident, "too many channels"));
+    }
+    let (reading, reading_ty) = match reading {
+        Some(r) => r,
+        None => return Err(Error::new_spanned(ident, "Sensor needs a SensorData field for the readings")),
+    };
+
+    // The old strings are split on spaces, so spaces in names become '_'
+    // there. The descriptor keeps the real name.
+    let format = channels.iter().map(|c| c.format.as_str()).collect::<Vec<_>>().join(" ");
+    let data_names = channels.iter().map(|c| c.name.replace(' ', "_")).collect::<Vec<_>>().join(" ");
+    let count = channels.len() as u8;
+
+    let idx: Vec<u8> = (0..count).collect();
+    let reads: Vec<proc_macro2::TokenStream> = channels.iter().map(|c| read_tokens(c, &reading)).collect();
+    let largest = channels.iter().map(|c| c.size).max().unwrap_or(0);
+    let names: Vec<LitStr> = channels.iter().map(|c| LitStr::new(&c.name, Span::call_site())).collect();
+    let metas: Vec<proc_macro2::TokenStream> = channels.iter().map(meta_tokens).collect();
+
+    let refresh = match refresh {
+        Some(method) => quote! { self.#method(idx); },
+        None => quote! {},
+    };
+
+    // Only a plain `SensorData` can be handed out by `read_sensor`.
+    let sensor_data = if is_plain(&reading_ty) {
+        quote! {
+            ::bus_interface::channels::SensorChannels::read_channel(self, idx);
+            &self.#reading
+        }
+    } else {
+        quote! { &::bus_interface::NO_READING }
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut expanded = quote! {
+        impl #impl_generics ::bus_interface::channels::SensorChannels for #ident #ty_generics #where_clause {
+            const NAME: &'static str = #sensor_name;
+            const FORMAT: &'static str = #format;
+            const DATA_NAMES: &'static str = #data_names;
+            const COUNT: u8 = #count;
+
+            fn channel_name(idx: u8) -> ::core::option::Option<&'static str> {
+                match idx {
+                    #( #idx => ::core::option::Option::Some(#names), )*
+                    _ => ::core::option::Option::None,
+                }
+            }
+
+            fn channel_meta(idx: u8) -> ::core::option::Option<::bus_interface::units::ChannelMeta> {
+                match idx {
+                    #( #idx => #metas, )*
+                    _ => ::core::option::Option::None,
+                }
+            }
+
+            fn read_channel(&mut self, idx: u8) -> &[u8] {
+                #refresh
+                match idx {
+                    #( #idx => #reads, )*
+                    _ => self.#reading.set(&[]),
+                }
+                self.#reading.as_bytes()
+            }
+
+            fn read_sensor_data(&mut self, idx: u8) -> &::bus_interface::SensorData {
+                #sensor_data
+            }
+        }
+    };
+
+    // Catch a SensorData field too small for the biggest channel at compile
+    // time, the generic case is left to the sensor.
+    if input.generics.params.is_empty() {
+        expanded.extend(quote! {
+            const _: () = ::core::assert!(
+                #largest <= <#reading_ty>::CAPACITY,
+                "the SensorData field is too small for the biggest channel"
+            );
+        });
+    }
+    Ok(expanded)
+}
+
+
+// Copies a channel's field into the reading, big endian. Arrays element by
+// element.
+fn read_tokens(ch: &Channel, reading: &Ident) -> proc_macro2::TokenStream {
+    let field = &ch.field;
+    if !ch.array {
+        return quote! { self.#reading.set(&self.#field.to_be_bytes()) };
+    }
+
+    let size = ch.size;
+    quote! {
+        {
+            let mut buf = [0u8; #size];
+            let mut at = 0;
+            for v in self.#field.iter() {
+                let b = v.to_be_bytes();
+                buf[at..at + b.len()].copy_from_slice(&b);
+                at += b.len();
+            }
+            self.#reading.set(&buf)
+        }
+    }
+}
+
+
+fn meta_tokens(ch: &Channel) -> proc_macro2::TokenStream {
+    if !ch.has_meta() {
+        return quote! { ::core::option::Option::None };
+    }
+
+    let unit = match &ch.unit {
+        Some(u) => quote! { #u },
+        None => quote! { "1" },
+    };
+    let f = |e: &Option<Expr>, default: proc_macro2::TokenStream| match e {
+        Some(e) => quote! { (#e) as f32 },
+        None => default,
+    };
+    let scale = f(&ch.scale, quote! { 1.0 });
+    let offset = f(&ch.offset, quote! { 0.0 });
+    let min = f(&ch.min, quote! { f32::MIN });
+    let max = f(&ch.max, quote! { f32::MAX });
+    let resolution = f(&ch.resolution, quote! { 1.0 });
+
+    quote! {
+        ::core::option::Option::Some(::bus_interface::units::ChannelMeta {
+            unit: #unit,
+            scale: #scale,
+            offset: #offset,
+            min: #min,
+            max: #max,
+            resolution: #resolution,
+        })
+    }
+}
+
+
+// A path type without generic arguments, `SensorData` but not
+// `SensorData<96>`.
+fn is_plain(ty: &Type) -> bool {
+    match ty {
+        Type::Path(p) => p.path.segments.last().is_some_and(|s| s.arguments.is_empty()),
+        _ => false,
+    }
+}
+
+
+// Last segment of a plain path type, `bus_interface::SensorData` -> "SensorData".
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(p) => p.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    }
+}
+
+
+// Element format and size in bytes.
+fn format_of(ty: &str) -> Option<(&'static str, usize)> {
+    match ty {
+        "u8" => Some(("u8", 1)),
+        "i8" => Some(("i8", 1)),
+        "u16" => Some(("u16", 2)),
+        "i16" => Some(("i16", 2)),
+        "u32" => Some(("u32", 4)),
+        "i32" => Some(("i32", 4)),
+        "f32" => Some(("f32", 4)),
+        "u64" => Some(("u64", 8)),
+        "i64" => Some(("i64", 8)),
+        "f64" => Some(("f64", 8)),
+        _ => None,
+    }
+}
+
+
+// A channel field's format("i16", "i16[3]"), size in bytes and whether it's
+// an array. Array lengths have to be plain numbers.
+fn channel_type(ty: &Type) -> Option<(String, usize, bool)> {
+    match ty {
+        Type::Array(arr) => {
+            let (elem, size) = format_of(&type_name(&arr.elem)?)?;
+            let len = match &arr.len {
+                Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(n), .. }) => n.base10_parse::<u16>().ok()?,
+                _ => return None,
+            };
+            if len == 0 {
+                return None;
+            }
+            Some((format!("{}[{}]", elem, len), size * len as usize, true))
+        }
+        _ => {
+            let (elem, size) = format_of(&type_name(ty)?)?;
+            Some((elem.to_string(), size, false))
+        }
+    }
+}
@@ -0,0 +1,358 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: acquisition.rs
+ * Desc: Synchronized acquisition. Modules are armed with a trigger number
+ *       ahead of time, then one broadcast AcqTriggerRequest with that number
+ *       starts all of them at the same instant:
+ *
+ *           arm(trigger) -> [broadcast] trigger(trigger) -> .. -> stop
+ *
+ *       Every command replies [status, AcqMode, trigger, started(8)] apart
+ *       from the broadcast trigger, which isn't answered so the modules
+ *       don't all talk over each other right as they start sampling.
+ *       `started` is the module's synchronized time(see `timesync`) when
+ *       it started, 0 if it isn't running or has no clock.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::BusError;
+
+// status, mode, trigger, started(8)
+pub const STATE_LEN: usize = 11;
+
+
+// First byte of every acquisition reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AcqStatus {
+    Ok = 0,
+    NotSupported,   //The module can't be triggered.
+    BadState,       //Trigger while not armed, arm while running..
+    Failed,         //The sensor's hook reported an error.
+}
+
+impl From<u8> for AcqStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => AcqStatus::Ok,
+            1 => AcqStatus::NotSupported,
+            2 => AcqStatus::BadState,
+            _ => AcqStatus::Failed,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum AcqMode {
+    #[default]
+    Idle = 0,
+    Armed,      //Waiting for its trigger.
+    Running,
+}
+
+impl From<u8> for AcqMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => AcqMode::Armed,
+            2 => AcqMode::Running,
+            _ => AcqMode::Idle,
+        }
+    }
+}
+
+
+// Where the acquisition is at, kept by the `Acquisition` so the handler
+// doesn't need any state of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AcqState {
+    pub mode: AcqMode,
+    pub trigger: u8,        //What it was armed with.
+    pub started_us: u64,    //0 if not running or no clock.
+}
+
+impl AcqState {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(AcqStatus::Ok as u8);
+        buf.push(self.mode as u8);
+        buf.push(self.trigger);
+        buf.extend_from_slice(&self.started_us.to_be_bytes());
+    }
+
+    pub fn decode(b: &[u8]) -> Result<AcqState, BusError> {
+        if b.len() < STATE_LEN || AcqStatus::from(b[0]) != AcqStatus::Ok {
+            return Err(BusError::BadParameter);
+        }
+        Ok(AcqState {
+            mode: b[1].into(),
+            trigger: b[2],
+            started_us: u64::from_be_bytes([b[3], b[4], b[5], b[6], b[7], b[8], b[9], b[10]]),
+        })
+    }
+}
+
+
+// The sensor's side of it. The hooks get the hardware ready, start and
+// stop it, the handler keeps track of the mode.
+pub trait Acquisition {
+
+    // Gets ready to start with as little delay as possible, e.g. powers up
+    // and configures the ADC.
+    fn arm(&mut self) -> Result<(), BusError>;
+
+    // Called as soon as the trigger arrives, keep it short.
+    fn start(&mut self) -> Result<(), BusError>;
+
+    fn stop(&mut self) -> Result<(), BusError>;
+
+    fn acq_state(&mut self) -> &mut AcqState;
+}
+
+
+fn reply(acq: &mut dyn Acquisition, status: AcqStatus, buf: &mut Vec<u8>) {
+    if status != AcqStatus::Ok {
+        return buf.push(status as u8);
+    }
+    acq.acq_state().encode(buf);
+}
+
+fn trigger_arg(args: &[u8]) -> u8 {
+    args.first().copied().unwrap_or(0)
+}
+
+
+pub fn handle_arm(acq: &mut dyn Acquisition, args: &[u8], buf: &mut Vec<u8>) {
+    if acq.acq_state().mode == AcqMode::Running {
+        return reply(acq, AcqStatus::BadState, buf);
+    }
+    if acq.arm().is_err() {
+        *acq.acq_state() = AcqState::default();
+        return reply(acq, AcqStatus::Failed, buf);
+    }
+    *acq.acq_state() = AcqState {
+        mode: AcqMode::Armed,
+        trigger: trigger_arg(args),
+        started_us: 0,
+    };
+    reply(acq, AcqStatus::Ok, buf);
+}
+
+
+// Starts if armed with the same trigger. Modules armed with some other
+// trigger(or not at all) stay as they are. `now` is the module's time,
+// taken as the trigger came in, 0 without a clock.
+pub fn handle_trigger(acq: &mut dyn Acquisition, now: u64, args: &[u8], buf: &mut Vec<u8>) {
+    let state = *acq.acq_state();
+    if state.mode != AcqMode::Armed || state.trigger != trigger_arg(args) {
+        return reply(acq, AcqStatus::BadState, buf);
+    }
+
+    if acq.start().is_err() {
+        *acq.acq_state() = AcqState::default();
+        return reply(acq, AcqStatus::Failed, buf);
+    }
+    let state = acq.acq_state();
+    state.mode = AcqMode::Running;
+    state.started_us = now;
+    reply(acq, AcqStatus::Ok, buf);
+}
+
+
+pub fn handle_stop(acq: &mut dyn Acquisition, buf: &mut Vec<u8>) {
+    if acq.acq_state().mode != AcqMode::Idle && acq.stop().is_err() {
+        return reply(acq, AcqStatus::Failed, buf);
+    }
+    *acq.acq_state() = AcqState::default();
+    reply(acq, AcqStatus::Ok, buf);
+}
+
+
+pub fn handle_state(acq: &mut dyn Acquisition, buf: &mut Vec<u8>) {
+    reply(acq, AcqStatus::Ok, buf);
+}
+
+
+#[cfg(test)]
+mod acquisition_tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Adc {
+        state: AcqState,
+        starts: usize,
+        broken: bool,
+    }
+
+    impl Acquisition for Adc {
+        fn arm(&mut self) -> Result<(), BusError> {
+            if self.broken {
+                return Err(BusError::BusError);
+            }
+            Ok(())
+        }
+
+        fn start(&mut self) -> Result<(), BusError> {
+            self.starts += 1;
+            Ok(())
+        }
+
+        fn stop(&mut self) -> Result<(), BusError> {
+            Ok(())
+        }
+
+        fn acq_state(&mut self) -> &mut AcqState {
+            &mut self.state
+        }
+    }
+
+    #[test]
+    fn arm_trigger_stop() {
+        let mut adc = Adc::default();
+        let mut buf: Vec<u8> = vec![];
+
+        handle_arm(&mut adc, &[7], &mut buf);
+        assert_eq!(AcqState::decode(&buf).unwrap().mode, AcqMode::Armed);
+
+        // Someone else's trigger.
+        buf.clear();
+        handle_trigger(&mut adc, 5_000, &[3], &mut buf);
+        assert_eq!(buf, vec![AcqStatus::BadState as u8]);
+        assert_eq!(adc.starts, 0);
+
+        buf.clear();
+        handle_trigger(&mut adc, 5_000, &[7], &mut buf);
+        let state = AcqState::decode(&buf).unwrap();
+        assert_eq!((state.mode, state.trigger, state.started_us), (AcqMode::Running, 7, 5_000));
+
+        buf.clear();
+        handle_arm(&mut adc, &[7], &mut buf);
+        assert_eq!(buf, vec![AcqStatus::BadState as u8]);
+
+        buf.clear();
+        handle_stop(&mut adc, &mut buf);
+        assert_eq!(AcqState::decode(&buf).unwrap(), AcqState::default());
+    }
+
+    #[test]
+    fn arm_fails() {
+        let mut adc = Adc { broken: true, ..Adc::default() };
+        let mut buf: Vec<u8> = vec![];
+        handle_arm(&mut adc, &[1], &mut buf);
+        assert_eq!(buf, vec![AcqStatus::Failed as u8]);
+        assert_eq!(adc.state.mode, AcqMode::Idle);
+        assert!(AcqState::decode(&buf).is_err());
+    }
+
+    struct Logger {
+        adc: Adc,
+        clock: crate::fake_clock::FakeClock,
+        reading: crate::SensorData,
+    }
+
+    impl crate::SensorInterface for Logger {
+        fn get_name(&self) -> &'static str {
+            "Logger"
+        }
+
+        fn get_format(&self) -> &'static str {
+            "i16"
+        }
+
+        fn get_data_names(&self) -> &'static str {
+            "Strain"
+        }
+
+        fn read_sensor(&mut self, _idx: u8) -> &crate::SensorData {
+            &self.reading
+        }
+
+        fn get_status(&self) -> crate::SensorStatus {
+            crate::SensorStatus::Ready
+        }
+
+        fn soft_reset(&mut self) -> crate::SensorStatus {
+            crate::SensorStatus::Ready
+        }
+
+        fn clock(&mut self) -> Option<&mut dyn crate::timesync::ModuleClock> {
+            Some(&mut self.clock)
+        }
+
+        fn acquisition(&mut self) -> Option<&mut dyn Acquisition> {
+            Some(&mut self.adc)
+        }
+    }
+
+    fn logger(local: u64) -> Logger {
+        Logger {
+            adc: Adc::default(),
+            clock: crate::fake_clock::FakeClock::new(local),
+            reading: crate::SensorData::new(),
+        }
+    }
+
+    // Three modules, two armed with the same trigger. One broadcast starts
+    // both and nobody answers it.
+    #[test]
+    fn broadcast_trigger() {
+        use crate::BusStatus;
+        use crate::controller::Controller;
+        use crate::Bus;
+        use crate::fake_bus::SharedBus;
+
+        let mut ctrl = Controller::new();
+        let mut bus: SharedBus<Logger> = SharedBus::new();
+        bus.add(1, logger(1_000));
+        bus.add(2, logger(1_000));
+        bus.add(3, logger(1_000));
+
+        assert_eq!(ctrl.arm_acquisition(&mut bus, 1, 4).unwrap().mode, AcqMode::Armed);
+        assert_eq!(ctrl.arm_acquisition(&mut bus, 2, 4).unwrap().mode, AcqMode::Armed);
+        assert_eq!(ctrl.arm_acquisition(&mut bus, 3, 9).unwrap().trigger, 9);
+
+        assert!(ctrl.trigger_acquisition(&mut bus, 4).is_ok());
+        assert!(bus.receive_message().is_err());
+
+        let one = ctrl.acquisition_state(&mut bus, 1).unwrap();
+        let two = ctrl.acquisition_state(&mut bus, 2).unwrap();
+        assert_eq!((one.mode, two.mode), (AcqMode::Running, AcqMode::Running));
+        assert_eq!(one.started_us, 1_000);
+        assert_eq!(one.started_us, two.started_us);
+        assert_eq!(ctrl.acquisition_state(&mut bus, 3).unwrap().mode, AcqMode::Armed);
+
+        // Not armed with it, a direct trigger is refused.
+        assert!(matches!(ctrl.trigger_node(&mut bus, 3, 4), Err(BusStatus::Rejected)));
+        assert_eq!(ctrl.trigger_node(&mut bus, 3, 9).unwrap().mode, AcqMode::Running);
+
+        assert_eq!(ctrl.stop_acquisition(&mut bus, 1).unwrap().mode, AcqMode::Idle);
+        assert_eq!(bus.modules[0].1.adc.starts, 1);
+    }
+
+    // One module with three loggers as sub-devices, two armed. The
+    // broadcast starts both, not just the first sensor.
+    #[test]
+    fn broadcast_trigger_sub_devices() {
+        use crate::ControllerCommand;
+        use crate::controller::Controller;
+        use crate::fake_bus::MultiBus;
+
+        let mut ctrl = Controller::new();
+        let mut bus = MultiBus::new(1, vec![Box::new(logger(500)), Box::new(logger(500)), Box::new(logger(500))]);
+        for sub in [0, 2] {
+            let ret = ctrl.send_sub_command(&mut bus, 1, sub, &ControllerCommand::AcqArmRequest, &[4]).unwrap();
+            assert_eq!(AcqState::decode(&ret.raw_bytes).unwrap().mode, AcqMode::Armed);
+        }
+
+        assert!(ctrl.trigger_acquisition(&mut bus, 4).is_ok());
+
+        let modes: Vec<AcqMode> = (0..3).map(|sub| {
+            let ret = ctrl.send_sub_command(&mut bus, 1, sub, &ControllerCommand::AcqStateRequest, &[]).unwrap();
+            AcqState::decode(&ret.raw_bytes).unwrap().mode
+        }).collect();
+        assert_eq!(modes, vec![AcqMode::Running, AcqMode::Idle, AcqMode::Running]);
+    }
+}
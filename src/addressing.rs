@@ -0,0 +1,238 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: addressing.rs
+ * Desc: Dynamic node addresses, so modules don't need a fixed `slv_id`.
+ *
+ *       A module without an address sits on `UNASSIGNED_NODE` and every so
+ *       often sends an announce frame with its hardware serial. The
+ *       controller picks a free address and sends an AddressAssignRequest
+ *       carrying that serial, only the module with the matching serial
+ *       takes it. The module saves the address and answers on it from then
+ *       on. IdentifyRequest lets the controller spot two modules answering
+ *       on the same address.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::Bus;
+use crate::BusError;
+use crate::SensorInterface;
+use crate::can_id::CanId;
+use crate::can_id::MessageType;
+use crate::can_id::CONTROLLER_NODE;
+use crate::can_id::DEFAULT_PRIORITY;
+use crate::can_id::UNASSIGNED_NODE;
+use crate::config_store::ConfigEntry;
+use crate::config_store::ConfigStore;
+use crate::config_store::StoreStatus;
+use crate::config_store::load_record;
+use crate::config_store::param_key;
+use crate::config_store::save_record;
+use crate::params::ParamValue;
+
+pub const SERIAL_LEN: usize = 8;
+// serial(8) + the new address.
+pub const ASSIGN_ARGS_LEN: usize = SERIAL_LEN + 1;
+
+// Key of the address in the module's address store.
+const ADDRESS_NAME: &str = "NodeAddress";
+
+
+// First byte of the IdentifyRequest and AddressAssignRequest replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AddrStatus {
+    Ok = 0,
+    NoSerial,       //The module has no serial, it needs a fixed address.
+    BadAddress,     //Controller, unassigned and broadcast can't be handed out.
+    NotSaved,       //Took the address but it won't survive a reboot.
+}
+
+impl From<u8> for AddrStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => AddrStatus::Ok,
+            1 => AddrStatus::NoSerial,
+            2 => AddrStatus::BadAddress,
+            _ => AddrStatus::NotSaved,
+        }
+    }
+}
+
+
+// The address the module saved last time, or `UNASSIGNED_NODE`. Call at
+// start up and hand the result to `handle_bus_command_dynamic`.
+pub fn stored_address(sens: &mut dyn SensorInterface) -> u8 {
+    let entries = match sens.address_store() {
+        Some(store) => load_record(store),
+        None => return UNASSIGNED_NODE,
+    };
+
+    let key = param_key(ADDRESS_NAME);
+    if let Ok(entries) = entries {
+        for entry in entries.iter().filter(|e| e.key == key) {
+            if let ParamValue::U8(node) = entry.value {
+                if CanId::is_assignable(node) {
+                    return node;
+                }
+            }
+        }
+    }
+    UNASSIGNED_NODE
+}
+
+
+pub fn save_address(store: &mut dyn ConfigStore, node: u8) -> StoreStatus {
+    let entry = ConfigEntry {
+        key: param_key(ADDRESS_NAME),
+        value: ParamValue::U8(node),
+    };
+    save_record(store, &[entry])
+}
+
+
+// Sends the announce frame, [serial(8)] from `UNASSIGNED_NODE`. The module
+// calls this now and then(with some jitter) until it has an address.
+pub fn announce(bus: &mut dyn Bus, sens: &dyn SensorInterface) -> Result<(), BusError> {
+    let serial = match sens.serial_number() {
+        Some(s) => s,
+        None => return Err(BusError::BadParameter),
+    };
+
+    let id = CanId::new(DEFAULT_PRIORITY, MessageType::Announce, CONTROLLER_NODE, UNASSIGNED_NODE)
+        .to_raw()?;
+    bus.send_message(id, &serial.to_be_bytes().to_vec())
+}
+
+
+pub fn decode_serial(b: &[u8]) -> Result<u64, BusError> {
+    if b.len() < SERIAL_LEN {
+        return Err(BusError::BadParameter);
+    }
+    let mut serial: [u8; SERIAL_LEN] = [0; SERIAL_LEN];
+    serial.copy_from_slice(&b[..SERIAL_LEN]);
+    Ok(u64::from_be_bytes(serial))
+}
+
+
+// [status, serial(8)]
+pub fn handle_identify(sens: &dyn SensorInterface, buf: &mut Vec<u8>) {
+    match sens.serial_number() {
+        Some(serial) => {
+            buf.push(AddrStatus::Ok as u8);
+            buf.extend_from_slice(&serial.to_be_bytes());
+        }
+        None => {
+            buf.push(AddrStatus::NoSerial as u8);
+            buf.extend_from_slice(&[0; SERIAL_LEN]);
+        }
+    }
+}
+
+
+// Returns the address to answer on from now on. Nothing is written to
+// `buf` when the serial isn't ours, the request was meant for some other
+// module sharing the address and we stay quiet.
+pub fn handle_assign(sens: &mut dyn SensorInterface, args: &[u8], buf: &mut Vec<u8>) -> Option<u8> {
+    if args.len() < ASSIGN_ARGS_LEN {
+        return None;
+    }
+    let serial = decode_serial(args).ok()?;
+    if sens.serial_number() != Some(serial) {
+        return None;
+    }
+
+    let node = args[SERIAL_LEN];
+    if !CanId::is_assignable(node) {
+        buf.push(AddrStatus::BadAddress as u8);
+        return None;
+    }
+
+    let saved = match sens.address_store() {
+        Some(store) => save_address(store, node),
+        None => StoreStatus::NoStore,
+    };
+    let status = match saved {
+        StoreStatus::Ok => AddrStatus::Ok,
+        _ => AddrStatus::NotSaved,
+    };
+    buf.push(status as u8);
+    Some(node)
+}
+
+
+#[cfg(test)]
+mod addressing_tests {
+    use super::*;
+    use crate::fake_bus::FakeBus;
+    use crate::fake_sensor::*;
+    use crate::file_store::FileStore;
+    use crate::file_store::file_store_tests::temp_path;
+
+    const SERIAL: u64 = 0x0011_2233_4455_6677;
+
+    fn sensor(serial: Option<u64>) -> ExampleSensor {
+        ExampleSensor {
+            serial,
+            ..ExampleSensor::new([0; 4])
+        }
+    }
+
+    fn assign_args(serial: u64, node: u8) -> Vec<u8> {
+        let mut args = serial.to_be_bytes().to_vec();
+        args.push(node);
+        args
+    }
+
+    #[test]
+    fn announce_frame() {
+        let mut bus = FakeBus::new();
+        assert!(announce(&mut bus, &sensor(Some(SERIAL))).is_ok());
+
+        let id = CanId::from_raw(bus.spy_id()).unwrap();
+        assert_eq!(id.msg_type, MessageType::Announce);
+        assert_eq!(id.source, UNASSIGNED_NODE);
+        assert_eq!(decode_serial(&bus.spy_data()).unwrap(), SERIAL);
+
+        // Nothing to announce without a serial.
+        assert!(announce(&mut bus, &sensor(None)).is_err());
+    }
+
+    #[test]
+    fn assign_only_matching_serial() {
+        let mut sens = sensor(Some(SERIAL));
+        let mut buf: Vec<u8> = vec![];
+
+        assert_eq!(handle_assign(&mut sens, &assign_args(SERIAL + 1, 5), &mut buf), None);
+        assert!(buf.is_empty());
+
+        assert_eq!(handle_assign(&mut sens, &assign_args(SERIAL, 0xFF), &mut buf), None);
+        assert_eq!(buf, vec![AddrStatus::BadAddress as u8]);
+
+        // No store, it still takes the address for now.
+        buf.clear();
+        assert_eq!(handle_assign(&mut sens, &assign_args(SERIAL, 5), &mut buf), Some(5));
+        assert_eq!(buf, vec![AddrStatus::NotSaved as u8]);
+    }
+
+    #[test]
+    fn address_survives_reboot() {
+        let path = temp_path();
+        {
+            let mut sens = sensor(Some(SERIAL));
+            sens.addr_store = Some(FileStore::open(&path, 64).unwrap());
+            assert_eq!(stored_address(&mut sens), UNASSIGNED_NODE);
+
+            let mut buf: Vec<u8> = vec![];
+            assert_eq!(handle_assign(&mut sens, &assign_args(SERIAL, 9), &mut buf), Some(9));
+            assert_eq!(buf, vec![AddrStatus::Ok as u8]);
+        }
+
+        let mut sens = sensor(Some(SERIAL));
+        sens.addr_store = Some(FileStore::open(&path, 64).unwrap());
+        assert_eq!(stored_address(&mut sens), 9);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: alarm.rs
+ * Desc: Threshold alarms checked on the module, so the controller doesn't
+ *       have to poll for them. Each rule watches one channel's raw value
+ *       against a high and/or low limit:
+ *
+ *           high: goes off above `high`, clears below `high - hysteresis`
+ *           low:  goes off below `low`, clears above `low + hysteresis`
+ *
+ *       Every change(normal -> high, high -> normal..) is sent on its own
+ *       as an Alarm frame, see `handler::check_alarms`(`check_alarms_multi`
+ *       for modules with several sensors, the frame says which one).
+ *       `alarm_monitor` is the controller side.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::Bus;
+use crate::BusError;
+use crate::can_id::CanId;
+use crate::can_id::MessageType;
+use crate::can_id::CONTROLLER_NODE;
+
+// channel, high(4), low(4), hysteresis(4)
+pub const RULE_LEN: usize = 13;
+// sub-device, channel, level, value(4)
+pub const ALARM_LEN: usize = 7;
+
+// Rules a table holds by default.
+pub const DEFAULT_MAX_RULES: usize = 8;
+
+// Alarms get ahead of the usual traffic.
+pub const ALARM_PRIORITY: u8 = 1;
+
+
+// First byte of the alarm command replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AlarmStatus {
+    Ok = 0,
+    NotSupported,   //The module doesn't check alarms.
+    BadIndex,       //No such channel, or no rule on it.
+    BadRule,        //Neither limit set, high below low, or a negative hysteresis.
+    Full,           //No room for another rule.
+}
+
+impl From<u8> for AlarmStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => AlarmStatus::Ok,
+            1 => AlarmStatus::NotSupported,
+            2 => AlarmStatus::BadIndex,
+            3 => AlarmStatus::BadRule,
+            _ => AlarmStatus::Full,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum AlarmLevel {
+    #[default]
+    Normal = 0,
+    High,
+    Low,
+}
+
+impl From<u8> for AlarmLevel {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => AlarmLevel::High,
+            2 => AlarmLevel::Low,
+            _ => AlarmLevel::Normal,
+        }
+    }
+}
+
+
+// Limits are in the channel's raw units(before `ChannelMeta` scaling).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlarmRule {
+    pub channel: u8,
+    pub high: Option<f32>,
+    pub low: Option<f32>,
+    pub hysteresis: f32,
+}
+
+impl AlarmRule {
+    pub fn is_valid(&self) -> bool {
+        let crossed = match (self.high, self.low) {
+            (Some(high), Some(low)) => high < low,
+            _ => false,
+        };
+        (self.high.is_some() || self.low.is_some()) && !crossed && self.hysteresis >= 0.0
+    }
+
+    // Where `level` goes with a new reading.
+    pub fn evaluate(&self, level: AlarmLevel, value: f32) -> AlarmLevel {
+        match level {
+            AlarmLevel::High => {
+                if self.high.is_some_and(|high| value >= high - self.hysteresis) {
+                    return AlarmLevel::High;
+                }
+            }
+            AlarmLevel::Low => {
+                if self.low.is_some_and(|low| value <= low + self.hysteresis) {
+                    return AlarmLevel::Low;
+                }
+            }
+            AlarmLevel::Normal => {}
+        }
+
+        if self.high.is_some_and(|high| value > high) {
+            return AlarmLevel::High;
+        }
+        if self.low.is_some_and(|low| value < low) {
+            return AlarmLevel::Low;
+        }
+        AlarmLevel::Normal
+    }
+
+    // A limit that isn't set goes as NaN.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.channel);
+        buf.extend_from_slice(&self.high.unwrap_or(f32::NAN).to_be_bytes());
+        buf.extend_from_slice(&self.low.unwrap_or(f32::NAN).to_be_bytes());
+        buf.extend_from_slice(&self.hysteresis.to_be_bytes());
+    }
+
+    pub fn decode(b: &[u8]) -> Result<AlarmRule, BusError> {
+        if b.len() < RULE_LEN {
+            return Err(BusError::BadParameter);
+        }
+        let limit = |at: usize| {
+            let v = be_f32(&b[at..at + 4]);
+            if v.is_nan() { None } else { Some(v) }
+        };
+        Ok(AlarmRule {
+            channel: b[0],
+            high: limit(1),
+            low: limit(5),
+            hysteresis: be_f32(&b[9..13]),
+        })
+    }
+}
+
+fn be_f32(b: &[u8]) -> f32 {
+    f32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+
+// The frame a module sends when a channel's level changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alarm {
+    pub sub: u8,        //Which of the module's sensors, 0 for single sensor modules.
+    pub channel: u8,
+    pub level: AlarmLevel,
+    pub value: f32,     //The reading that changed it.
+}
+
+impl Alarm {
+    // [sub, channel, level, value(4, BE)]
+    pub fn to_bytes(&self) -> [u8; ALARM_LEN] {
+        let v = self.value.to_be_bytes();
+        [self.sub, self.channel, self.level as u8, v[0], v[1], v[2], v[3]]
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Result<Alarm, BusError> {
+        if b.len() < ALARM_LEN {
+            return Err(BusError::BadParameter);
+        }
+        Ok(Alarm {
+            sub: b[0],
+            channel: b[1],
+            level: b[2].into(),
+            value: be_f32(&b[3..7]),
+        })
+    }
+}
+
+
+pub fn send_alarm(bus: &mut dyn Bus, node: u8, alarm: &Alarm) -> Result<(), BusError> {
+    let id = CanId::new(ALARM_PRIORITY, MessageType::Alarm, CONTROLLER_NODE, node).to_raw()?;
+    bus.send_message(id, &alarm.to_bytes().to_vec())
+}
+
+
+// A module's rules and where each one is at. One rule per channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlarmTable {
+    rules: Vec<(AlarmRule, AlarmLevel)>,
+    max_rules: usize,
+}
+
+impl Default for AlarmTable {
+    fn default() -> Self {
+        AlarmTable::new(DEFAULT_MAX_RULES)
+    }
+}
+
+impl AlarmTable {
+    pub fn new(max_rules: usize) -> AlarmTable {
+        AlarmTable {
+            rules: Vec::new(),
+            max_rules,
+        }
+    }
+
+    // Replaces the channel's rule if it has one, the level starts over.
+    pub fn set(&mut self, rule: AlarmRule) -> AlarmStatus {
+        if !rule.is_valid() {
+            return AlarmStatus::BadRule;
+        }
+        if let Some(entry) = self.rules.iter_mut().find(|(r, _)| r.channel == rule.channel) {
+            *entry = (rule, AlarmLevel::Normal);
+            return AlarmStatus::Ok;
+        }
+        if self.rules.len() >= self.max_rules {
+            return AlarmStatus::Full;
+        }
+        self.rules.push((rule, AlarmLevel::Normal));
+        AlarmStatus::Ok
+    }
+
+    pub fn get(&self, channel: u8) -> Option<(AlarmRule, AlarmLevel)> {
+        self.rules.iter().find(|(r, _)| r.channel == channel).copied()
+    }
+
+    pub fn clear(&mut self, channel: u8) -> bool {
+        let before = self.rules.len();
+        self.rules.retain(|(r, _)| r.channel != channel);
+        self.rules.len() != before
+    }
+
+    // The channels with a rule, in the order they were set.
+    pub fn channels(&self) -> Vec<u8> {
+        self.rules.iter().map(|(r, _)| r.channel).collect()
+    }
+
+    // Checks a new reading, gives the alarm to send if the level changed.
+    // The table doesn't know its sub-device, the alarm is for sub 0.
+    pub fn update(&mut self, channel: u8, value: f32) -> Option<Alarm> {
+        let (rule, level) = self.rules.iter_mut().find(|(r, _)| r.channel == channel)?;
+        let new = rule.evaluate(*level, value);
+        if new == *level {
+            return None;
+        }
+        *level = new;
+        Some(Alarm { sub: 0, channel, level: new, value })
+    }
+}
+
+
+// `channels` is how many the sensor has, rules on others are refused.
+pub fn handle_set(table: &mut AlarmTable, channels: u8, args: &[u8], buf: &mut Vec<u8>) {
+    let status = match AlarmRule::decode(args) {
+        Ok(rule) if rule.channel < channels => table.set(rule),
+        Ok(_) => AlarmStatus::BadIndex,
+        Err(_e) => AlarmStatus::BadRule,
+    };
+    buf.push(status as u8);
+}
+
+
+// [status, rule, level]
+pub fn handle_get(table: &mut AlarmTable, args: &[u8], buf: &mut Vec<u8>) {
+    match args.first().and_then(|c| table.get(*c)) {
+        Some((rule, level)) => {
+            buf.push(AlarmStatus::Ok as u8);
+            rule.encode(buf);
+            buf.push(level as u8);
+        }
+        None => buf.push(AlarmStatus::BadIndex as u8),
+    }
+}
+
+
+pub fn handle_clear(table: &mut AlarmTable, args: &[u8], buf: &mut Vec<u8>) {
+    match args.first() {
+        Some(c) if table.clear(*c) => buf.push(AlarmStatus::Ok as u8),
+        _ => buf.push(AlarmStatus::BadIndex as u8),
+    }
+}
+
+
+#[cfg(test)]
+mod alarm_tests {
+    use super::*;
+
+    fn rule() -> AlarmRule {
+        AlarmRule {
+            channel: 1,
+            high: Some(100.0),
+            low: Some(10.0),
+            hysteresis: 5.0,
+        }
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut table = AlarmTable::default();
+        assert_eq!(table.set(rule()), AlarmStatus::Ok);
+
+        assert_eq!(table.update(1, 50.0), None);
+        assert_eq!(table.update(1, 101.0).unwrap().level, AlarmLevel::High);
+        // Still within the hysteresis.
+        assert_eq!(table.update(1, 96.0), None);
+        let cleared = table.update(1, 94.0).unwrap();
+        assert_eq!((cleared.level, cleared.value), (AlarmLevel::Normal, 94.0));
+
+        assert_eq!(table.update(1, 9.0).unwrap().level, AlarmLevel::Low);
+        assert_eq!(table.update(1, 14.0), None);
+        // Straight from low to high.
+        assert_eq!(table.update(1, 120.0).unwrap().level, AlarmLevel::High);
+        // No rule on that channel.
+        assert_eq!(table.update(2, 120.0), None);
+    }
+
+    #[test]
+    fn rules() {
+        let mut table = AlarmTable::new(1);
+        let bad = AlarmRule { high: None, low: None, ..rule() };
+        assert_eq!(table.set(bad), AlarmStatus::BadRule);
+        let crossed = AlarmRule { high: Some(5.0), low: Some(10.0), ..rule() };
+        assert_eq!(table.set(crossed), AlarmStatus::BadRule);
+        assert_eq!(table.set(rule()), AlarmStatus::Ok);
+        assert_eq!(table.set(AlarmRule { channel: 2, ..rule() }), AlarmStatus::Full);
+        // Same channel replaces it.
+        assert_eq!(table.set(AlarmRule { low: None, ..rule() }), AlarmStatus::Ok);
+        assert_eq!(table.get(1).unwrap().0.low, None);
+
+        let mut buf: Vec<u8> = vec![];
+        handle_get(&mut table, &[1], &mut buf);
+        assert_eq!(buf[0], AlarmStatus::Ok as u8);
+        assert_eq!(AlarmRule::decode(&buf[1..]).unwrap(), AlarmRule { low: None, ..rule() });
+        assert_eq!(buf[1 + RULE_LEN], AlarmLevel::Normal as u8);
+
+        buf.clear();
+        handle_clear(&mut table, &[1], &mut buf);
+        assert_eq!(buf, vec![AlarmStatus::Ok as u8]);
+        assert!(table.channels().is_empty());
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let alarm = Alarm { sub: 2, channel: 3, level: AlarmLevel::Low, value: -2.5 };
+        assert_eq!(Alarm::from_bytes(&alarm.to_bytes()).unwrap(), alarm);
+        assert!(Alarm::from_bytes(&[0, 1]).is_err());
+    }
+}
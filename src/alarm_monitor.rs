@@ -0,0 +1,253 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: alarm_monitor.rs
+ * Desc: Controller side of the module alarms(see `alarm`). Keeps the level
+ *       of every channel that has reported one and tells whoever
+ *       subscribed each time one changes.
+ */
+
+use crate::Bus;
+use crate::alarm::Alarm;
+use crate::alarm::AlarmLevel;
+use crate::controller::Controller;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlarmEvent {
+    pub node: u8,
+    pub alarm: Alarm,
+}
+
+
+pub type AlarmSubscriber<'a> = Box<dyn FnMut(&AlarmEvent) + 'a>;
+
+
+pub struct AlarmMonitor<'a> {
+    nodes: Vec<(u8, Vec<Alarm>)>,   //The last alarm from each sub-device's channel.
+    subscribers: Vec<AlarmSubscriber<'a>>,
+}
+
+impl Default for AlarmMonitor<'_> {
+    fn default() -> Self {
+        AlarmMonitor::new()
+    }
+}
+
+impl<'a> AlarmMonitor<'a> {
+    pub fn new() -> AlarmMonitor<'a> {
+        AlarmMonitor {
+            nodes: vec![],
+            subscribers: vec![],
+        }
+    }
+
+    // Every alarm goes to every subscriber, in the order they subscribed.
+    pub fn subscribe(&mut self, f: impl FnMut(&AlarmEvent) + 'a) {
+        self.subscribers.push(Box::new(f));
+    }
+
+    // Picks up the alarms waiting on the bus(or already read by the
+    // controller).
+    pub fn poll(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus) {
+        ctrl.poll_bus(bus);
+        for (node, alarm) in ctrl.take_alarms() {
+            self.alarm(node, alarm);
+        }
+    }
+
+    pub fn alarm(&mut self, node: u8, alarm: Alarm) {
+        let i = match self.nodes.iter().position(|(n, _)| *n == node) {
+            Some(i) => i,
+            None => {
+                self.nodes.push((node, vec![]));
+                self.nodes.len() - 1
+            }
+        };
+
+        let channels = &mut self.nodes[i].1;
+        match channels.iter_mut().find(|a| a.sub == alarm.sub && a.channel == alarm.channel) {
+            Some(last) => *last = alarm,
+            None => channels.push(alarm),
+        }
+
+        let event = AlarmEvent { node, alarm };
+        for f in self.subscribers.iter_mut() {
+            f(&event);
+        }
+    }
+
+    // Where a sub-device's channel is at, Normal if it never reported. Sub
+    // is 0 on single sensor modules.
+    pub fn level(&self, node: u8, sub: u8, channel: u8) -> AlarmLevel {
+        self.active(node).iter()
+            .find(|a| a.sub == sub && a.channel == channel)
+            .map(|a| a.level)
+            .unwrap_or(AlarmLevel::Normal)
+    }
+
+    // The node's channels that are in alarm right now.
+    pub fn active(&self, node: u8) -> Vec<Alarm> {
+        for (n, channels) in self.nodes.iter() {
+            if *n == node {
+                return channels.iter().filter(|a| a.level != AlarmLevel::Normal).copied().collect();
+            }
+        }
+        vec![]
+    }
+
+    // Nodes with at least one channel in alarm.
+    pub fn nodes_in_alarm(&self) -> Vec<u8> {
+        self.nodes.iter()
+            .filter(|(_, channels)| channels.iter().any(|a| a.level != AlarmLevel::Normal))
+            .map(|(n, _)| *n)
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod alarm_monitor_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::BusStatus;
+    use crate::SensorData;
+    use crate::SensorInterface;
+    use crate::SensorStatus;
+    use crate::alarm::AlarmRule;
+    use crate::alarm::AlarmTable;
+    use crate::fake_bus::LoopbackBus;
+
+    const NODE: u8 = 0x01;
+
+    // A temperature sensor in centi-degrees, checking its own alarms.
+    struct Thermostat {
+        temp: i16,
+        reading: SensorData,
+        alarms: AlarmTable,
+    }
+
+    impl SensorInterface for Thermostat {
+        fn get_name(&self) -> &'static str {
+            "Thermostat"
+        }
+
+        fn get_format(&self) -> &'static str {
+            "i16"
+        }
+
+        fn get_data_names(&self) -> &'static str {
+            "Temp"
+        }
+
+        fn read_sensor(&mut self, _idx: u8) -> &SensorData {
+            self.reading.set(&self.temp.to_be_bytes());
+            &self.reading
+        }
+
+        fn get_status(&self) -> SensorStatus {
+            SensorStatus::Ready
+        }
+
+        fn soft_reset(&mut self) -> SensorStatus {
+            SensorStatus::Ready
+        }
+
+        fn alarms(&mut self) -> Option<&mut AlarmTable> {
+            Some(&mut self.alarms)
+        }
+    }
+
+    fn thermostat() -> Thermostat {
+        Thermostat {
+            temp: 2000,
+            reading: SensorData::new(),
+            alarms: AlarmTable::default(),
+        }
+    }
+
+    fn over_heat() -> AlarmRule {
+        AlarmRule {
+            channel: 0,
+            high: Some(5000.0),
+            low: None,
+            hysteresis: 200.0,
+        }
+    }
+
+    #[test]
+    fn alarms_from_module() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, thermostat());
+        assert!(ctrl.set_alarm(&mut bus, NODE, &over_heat()).is_ok());
+
+        let events: RefCell<Vec<AlarmEvent>> = RefCell::new(vec![]);
+        let mut mon = AlarmMonitor::new();
+        mon.subscribe(|e| events.borrow_mut().push(*e));
+
+        assert_eq!(bus.check_alarms(), 0);
+        bus.sens.temp = 5100;
+        assert_eq!(bus.check_alarms(), 1);
+        // Already high, nothing more.
+        assert_eq!(bus.check_alarms(), 0);
+        mon.poll(&mut ctrl, &mut bus);
+
+        assert_eq!(mon.level(NODE, 0, 0), AlarmLevel::High);
+        assert_eq!(mon.nodes_in_alarm(), vec![NODE]);
+        assert_eq!(ctrl.get_alarm(&mut bus, NODE, 0).unwrap(), (over_heat(), AlarmLevel::High));
+
+        // Inside the hysteresis, then back to normal.
+        bus.sens.temp = 4900;
+        assert_eq!(bus.check_alarms(), 0);
+        bus.sens.temp = 4700;
+        assert_eq!(bus.check_alarms(), 1);
+        mon.poll(&mut ctrl, &mut bus);
+        assert!(mon.active(NODE).is_empty());
+        drop(mon);
+
+        let events = events.borrow();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].alarm.level, AlarmLevel::High);
+        assert_eq!(events[0].alarm.value, 5100.0);
+        assert_eq!(events[1], AlarmEvent {
+            node: NODE,
+            alarm: Alarm { sub: 0, channel: 0, level: AlarmLevel::Normal, value: 4700.0 },
+        });
+    }
+
+    // Two thermostats behind one node, only the second has a rule. The
+    // alarm says which one it was.
+    #[test]
+    fn alarms_from_sub_device() {
+        use crate::ControllerCommand;
+        use crate::fake_bus::MultiBus;
+
+        let mut ctrl = Controller::new();
+        let mut bus = MultiBus::new(NODE, vec![Box::new(thermostat()), Box::new(thermostat())]);
+        let mut rule: Vec<u8> = vec![];
+        AlarmRule { high: Some(1000.0), ..over_heat() }.encode(&mut rule);
+        assert!(ctrl.send_sub_command(&mut bus, NODE, 1, &ControllerCommand::AlarmSetRequest, &rule).is_ok());
+
+        let mut mon = AlarmMonitor::new();
+        assert_eq!(bus.check_alarms(), 1);
+        mon.poll(&mut ctrl, &mut bus);
+
+        assert_eq!(mon.level(NODE, 1, 0), AlarmLevel::High);
+        assert_eq!(mon.level(NODE, 0, 0), AlarmLevel::Normal);
+        assert_eq!(mon.active(NODE)[0].sub, 1);
+    }
+
+    #[test]
+    fn refused_rules() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, thermostat());
+
+        let bad_channel = AlarmRule { channel: 4, ..over_heat() };
+        assert!(matches!(ctrl.set_alarm(&mut bus, NODE, &bad_channel), Err(BusStatus::Rejected)));
+        assert!(matches!(ctrl.get_alarm(&mut bus, NODE, 0), Err(BusStatus::Rejected)));
+
+        assert!(ctrl.set_alarm(&mut bus, NODE, &over_heat()).is_ok());
+        assert!(ctrl.clear_alarm(&mut bus, NODE, 0).is_ok());
+        assert!(matches!(ctrl.clear_alarm(&mut bus, NODE, 0), Err(BusStatus::Rejected)));
+    }
+}
@@ -0,0 +1,298 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: block.rs
+ * Desc: Block transfer, for pulling a capture of thousands of samples
+ *       (vibration, audio..) off a module. The module side.
+ *
+ *       prepare(channel) -> info -> read(offset, len).. -> ack
+ *
+ *       Prepare captures the block and works out a crc32 over all of it,
+ *       the controller checks the pieces it put back together against
+ *       that. The block stays put until it's acked, so a lost read can
+ *       just be asked for again. Samples are big endian.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::BusError;
+use crate::channels::ChannelType;
+use crate::crc::crc32_begin;
+use crate::crc::crc32_finish;
+use crate::crc::crc32_update;
+
+// channel
+pub const PREPARE_ARGS_LEN: usize = 1;
+// offset(4), len(2)
+pub const READ_ARGS_LEN: usize = 6;
+// status, offset(4), then the data.
+pub const READ_HEADER_LEN: usize = 5;
+// status, channel, type, size(4), crc(4)
+pub const INFO_LEN: usize = 11;
+
+// How much is read at a time when working out the crc.
+const CRC_BLOCK: usize = 64;
+
+
+// First byte of every block reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BlockStatus {
+    Ok = 0,
+    NoSource,       //The module doesn't do block transfers.
+    BadChannel,     //Nothing to capture on that channel.
+    NotReady,       //No block prepared(or it was acked already).
+    BadOffset,      //Read past the end of the block.
+    Failed,         //The capture or a read of it went wrong.
+}
+
+impl From<u8> for BlockStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => BlockStatus::Ok,
+            1 => BlockStatus::NoSource,
+            2 => BlockStatus::BadChannel,
+            3 => BlockStatus::NotReady,
+            4 => BlockStatus::BadOffset,
+            _ => BlockStatus::Failed,
+        }
+    }
+}
+
+
+// The block that's been prepared, kept by the `BlockSource`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub channel: u8,
+    pub channel_type: ChannelType,  //Of each sample.
+    pub size: u32,                  //In bytes.
+    pub crc: u32,                   //crc32 of the whole block.
+}
+
+impl BlockInfo {
+    pub fn samples(&self) -> usize {
+        match self.channel_type.size() {
+            0 => 0,
+            size => self.size as usize / size,
+        }
+    }
+
+    // [status, channel, type, size, crc]
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(BlockStatus::Ok as u8);
+        buf.push(self.channel);
+        buf.push(self.channel_type as u8);
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.crc.to_be_bytes());
+    }
+
+    pub fn decode(b: &[u8]) -> Result<BlockInfo, BusError> {
+        if b.len() < INFO_LEN || BlockStatus::from(b[0]) != BlockStatus::Ok {
+            return Err(BusError::BadParameter);
+        }
+        Ok(BlockInfo {
+            channel: b[1],
+            channel_type: b[2].into(),
+            size: be_u32(&b[3..7]),
+            crc: be_u32(&b[7..11]),
+        })
+    }
+}
+
+
+// Where the module keeps its captures.
+pub trait BlockSource {
+
+    // Captures a block on `channel`, gives the sample type and the size
+    // in bytes. Whatever was there before is gone.
+    fn capture(&mut self, channel: u8) -> Result<(ChannelType, u32), BlockStatus>;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), BusError>;
+
+    // The controller has the block, the memory can be used again.
+    fn release(&mut self);
+
+    fn block_info(&mut self) -> &mut Option<BlockInfo>;
+}
+
+
+fn be_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+
+pub fn handle_prepare(source: &mut dyn BlockSource, args: &[u8], buf: &mut Vec<u8>) {
+    *source.block_info() = None;
+    if args.len() < PREPARE_ARGS_LEN {
+        return buf.push(BlockStatus::BadChannel as u8);
+    }
+    let channel = args[0];
+
+    let (channel_type, size) = match source.capture(channel) {
+        Ok(c) => c,
+        Err(status) => return buf.push(status as u8),
+    };
+
+    let mut crc = crc32_begin();
+    let mut piece: [u8; CRC_BLOCK] = [0; CRC_BLOCK];
+    let mut offset: u32 = 0;
+    while offset < size {
+        let len = CRC_BLOCK.min((size - offset) as usize);
+        if source.read(offset, &mut piece[..len]).is_err() {
+            return buf.push(BlockStatus::Failed as u8);
+        }
+        crc = crc32_update(crc, &piece[..len]);
+        offset += len as u32;
+    }
+
+    let info = BlockInfo {
+        channel,
+        channel_type,
+        size,
+        crc: crc32_finish(crc),
+    };
+    *source.block_info() = Some(info);
+    info.encode(buf);
+}
+
+
+pub fn handle_info(source: &mut dyn BlockSource, buf: &mut Vec<u8>) {
+    match *source.block_info() {
+        Some(info) => info.encode(buf),
+        None => buf.push(BlockStatus::NotReady as u8),
+    }
+}
+
+
+// `room` is how much data fits in the reply frame, longer reads are cut
+// short. The controller carries on from the offset it got back.
+pub fn handle_read(source: &mut dyn BlockSource, args: &[u8], room: usize, buf: &mut Vec<u8>) {
+    let info = match *source.block_info() {
+        Some(info) => info,
+        None => return buf.push(BlockStatus::NotReady as u8),
+    };
+    if args.len() < READ_ARGS_LEN {
+        return buf.push(BlockStatus::BadOffset as u8);
+    }
+    let offset = be_u32(&args[0..4]);
+    let wanted = u16::from_be_bytes([args[4], args[5]]) as usize;
+    if offset > info.size {
+        return buf.push(BlockStatus::BadOffset as u8);
+    }
+
+    let len = wanted.min(room).min((info.size - offset) as usize);
+    let at = buf.len();
+    buf.push(BlockStatus::Ok as u8);
+    buf.extend_from_slice(&offset.to_be_bytes());
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    if source.read(offset, &mut buf[start..]).is_err() {
+        buf.truncate(at);
+        buf.push(BlockStatus::Failed as u8);
+    }
+}
+
+
+pub fn handle_ack(source: &mut dyn BlockSource, buf: &mut Vec<u8>) {
+    if source.block_info().is_none() {
+        return buf.push(BlockStatus::NotReady as u8);
+    }
+    source.release();
+    *source.block_info() = None;
+    buf.push(BlockStatus::Ok as u8);
+}
+
+
+#[cfg(test)]
+mod block_tests {
+    use super::*;
+    use crate::crc::crc32;
+
+    struct Capture {
+        mem: Vec<u8>,
+        info: Option<BlockInfo>,
+        released: bool,
+    }
+
+    impl BlockSource for Capture {
+        fn capture(&mut self, channel: u8) -> Result<(ChannelType, u32), BlockStatus> {
+            if channel != 0 {
+                return Err(BlockStatus::BadChannel);
+            }
+            Ok((ChannelType::I16, self.mem.len() as u32))
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), BusError> {
+            let start = offset as usize;
+            buf.copy_from_slice(&self.mem[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn release(&mut self) {
+            self.released = true;
+        }
+
+        fn block_info(&mut self) -> &mut Option<BlockInfo> {
+            &mut self.info
+        }
+    }
+
+    fn capture() -> Capture {
+        Capture {
+            mem: (0..200u32).map(|i| (i * 3) as u8).collect(),
+            info: None,
+            released: false,
+        }
+    }
+
+    fn read_args(offset: u32, len: u16) -> Vec<u8> {
+        let mut args = offset.to_be_bytes().to_vec();
+        args.extend_from_slice(&len.to_be_bytes());
+        args
+    }
+
+    #[test]
+    fn transfer() {
+        let mut src = capture();
+        let mut buf: Vec<u8> = vec![];
+        handle_prepare(&mut src, &[0], &mut buf);
+        let info = BlockInfo::decode(&buf).unwrap();
+        assert_eq!(info.size, 200);
+        assert_eq!(info.samples(), 100);
+        assert_eq!(info.crc, crc32(&src.mem));
+
+        buf.clear();
+        handle_info(&mut src, &mut buf);
+        assert_eq!(BlockInfo::decode(&buf).unwrap(), info);
+
+        // Asked for more than fits, cut to the room left.
+        buf.clear();
+        handle_read(&mut src, &read_args(190, 50), 40, &mut buf);
+        assert_eq!(buf[..READ_HEADER_LEN], [0, 0, 0, 0, 190]);
+        assert_eq!(buf[READ_HEADER_LEN..], src.mem[190..]);
+
+        buf.clear();
+        handle_read(&mut src, &read_args(201, 1), 40, &mut buf);
+        assert_eq!(buf, vec![BlockStatus::BadOffset as u8]);
+
+        buf.clear();
+        handle_ack(&mut src, &mut buf);
+        assert_eq!(buf, vec![BlockStatus::Ok as u8]);
+        assert!(src.released);
+
+        buf.clear();
+        handle_read(&mut src, &read_args(0, 1), 40, &mut buf);
+        assert_eq!(buf, vec![BlockStatus::NotReady as u8]);
+    }
+
+    #[test]
+    fn bad_channel() {
+        let mut src = capture();
+        let mut buf: Vec<u8> = vec![];
+        handle_prepare(&mut src, &[3], &mut buf);
+        assert_eq!(buf, vec![BlockStatus::BadChannel as u8]);
+        assert!(src.info.is_none());
+        assert!(BlockInfo::decode(&buf).is_err());
+    }
+}
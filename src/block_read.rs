@@ -0,0 +1,383 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: block_read.rs
+ * Desc: Controller side of the block transfer, see block.rs for the
+ *       module side.
+ */
+
+use crate::Bus;
+use crate::BusStatus;
+use crate::ControllerCommand;
+use crate::block::BlockInfo;
+use crate::block::BlockStatus;
+use crate::block::READ_HEADER_LEN;
+use crate::channels::ChannelType;
+use crate::controller::Controller;
+use crate::crc::crc32;
+
+// How many lost reads in a row before giving up.
+const MAX_RETRIES: usize = 3;
+
+
+// A sample type a block can be read out as.
+pub trait Sample: Sized {
+    const TYPE: ChannelType;
+
+    // `b` is exactly one sample, big endian.
+    fn from_be(b: &[u8]) -> Self;
+}
+
+macro_rules! impl_sample {
+    ($t:ty, $ct:ident) => {
+        impl Sample for $t {
+            const TYPE: ChannelType = ChannelType::$ct;
+
+            fn from_be(b: &[u8]) -> Self {
+                let mut raw = [0u8; core::mem::size_of::<$t>()];
+                raw.copy_from_slice(b);
+                <$t>::from_be_bytes(raw)
+            }
+        }
+    };
+}
+
+impl_sample!(u8, U8);
+impl_sample!(i8, I8);
+impl_sample!(u16, U16);
+impl_sample!(i16, I16);
+impl_sample!(u32, U32);
+impl_sample!(i32, I32);
+impl_sample!(f32, F32);
+impl_sample!(u64, U64);
+impl_sample!(i64, I64);
+impl_sample!(f64, F64);
+
+
+// A block as it came off the module, checked against its crc.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub info: BlockInfo,
+    pub data: Vec<u8>,
+}
+
+impl Block {
+    // The samples as the type the module captured them as, Rejected if
+    // `T` is some other type.
+    pub fn samples<T: Sample>(&self) -> Result<Vec<T>, BusStatus> {
+        if T::TYPE != self.info.channel_type {
+            return Err(BusStatus::Rejected);
+        }
+        let size = T::TYPE.size();
+        Ok(self.data.chunks_exact(size).map(T::from_be).collect())
+    }
+
+    // Every sample as a number, whatever its type.
+    pub fn values(&self) -> Vec<f64> {
+        let channel_type = self.info.channel_type;
+        match channel_type.size() {
+            0 => vec![],
+            size => self.data.chunks_exact(size).filter_map(|b| channel_type.decode(b)).collect(),
+        }
+    }
+}
+
+
+// Reads a block of samples off a module:
+//
+//     let block = BlockReader::new(channel).read(&mut ctrl, &mut bus, node)?;
+//     let samples: Vec<i16> = block.samples()?;
+pub struct BlockReader<'a> {
+    channel: u8,
+    chunk_size: Option<usize>,
+    progress: Option<Box<dyn FnMut(usize, usize) + 'a>>,
+}
+
+impl<'a> BlockReader<'a> {
+    pub fn new(channel: u8) -> BlockReader<'a> {
+        BlockReader {
+            channel,
+            chunk_size: None,
+            progress: None,
+        }
+    }
+
+    // Caps how much each read asks for, by default it's as much as the
+    // module can fit in a reply.
+    pub fn chunk_size(mut self, size: usize) -> BlockReader<'a> {
+        self.chunk_size = Some(size);
+        self
+    }
+
+    // Called with (bytes read, block size) after every read.
+    pub fn on_progress(mut self, f: impl FnMut(usize, usize) + 'a) -> BlockReader<'a> {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    // Captures a block, reads it and acks it once the crc checks out. A
+    // block that doesn't check out isn't acked, `read_again` can have
+    // another go at it.
+    pub fn read(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus, node: u8) -> Result<Block, BusStatus> {
+        let ret = ctrl.send_command_with_args(bus, node, &ControllerCommand::BlockPrepareRequest, &[self.channel])?;
+        let info = decode_info(&ret.raw_bytes)?;
+        self.fetch(ctrl, bus, node, info)
+    }
+
+    // Reads the block the module already has without capturing a new one.
+    pub fn read_again(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus, node: u8) -> Result<Block, BusStatus> {
+        let ret = ctrl.send_command_with_args(bus, node, &ControllerCommand::BlockInfoRequest, &[])?;
+        let info = decode_info(&ret.raw_bytes)?;
+        if info.channel != self.channel {
+            return Err(BusStatus::Rejected);
+        }
+        self.fetch(ctrl, bus, node, info)
+    }
+
+    fn fetch(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus, node: u8, info: BlockInfo) -> Result<Block, BusStatus> {
+        let size = info.size as usize;
+        let chunk = self.chunk_size.unwrap_or(u16::MAX as usize).min(u16::MAX as usize);
+        if chunk == 0 {
+            return Err(BusStatus::Rejected);
+        }
+
+        let mut data: Vec<u8> = Vec::with_capacity(size);
+        let mut retries: usize = 0;
+        while data.len() < size {
+            let offset = data.len();
+            let len = chunk.min(size - offset) as u16;
+            let mut args: Vec<u8> = (offset as u32).to_be_bytes().to_vec();
+            args.extend_from_slice(&len.to_be_bytes());
+
+            let ret = match ctrl.send_command_with_args(bus, node, &ControllerCommand::BlockReadRequest, &args) {
+                Ok(ret) => ret,
+                Err(BusStatus::Error) if retries < MAX_RETRIES => {
+                    // Lost the request or the reply, the block is still
+                    // there so just ask again.
+                    retries += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            retries = 0;
+
+            let b = &ret.raw_bytes;
+            if b.is_empty() {
+                return Err(BusStatus::DataErr);
+            }
+            let status = BlockStatus::from(b[0]);
+            if status != BlockStatus::Ok {
+                return Err(status_to_bus(status));
+            }
+            if b.len() <= READ_HEADER_LEN {
+                return Err(BusStatus::DataErr);
+            }
+            if u32::from_be_bytes([b[1], b[2], b[3], b[4]]) as usize != offset {
+                return Err(BusStatus::DataErr);
+            }
+            let piece = &b[READ_HEADER_LEN..];
+            data.extend_from_slice(&piece[..piece.len().min(size - offset)]);
+
+            if let Some(f) = self.progress.as_mut() {
+                f(data.len(), size);
+            }
+        }
+
+        if crc32(&data) != info.crc {
+            return Err(BusStatus::DataErr);
+        }
+
+        let ret = ctrl.send_command_with_args(bus, node, &ControllerCommand::BlockAckRequest, &[])?;
+        match ret.raw_bytes.first().map(|s| BlockStatus::from(*s)) {
+            Some(BlockStatus::Ok) => {}
+            Some(status) => return Err(status_to_bus(status)),
+            None => return Err(BusStatus::DataErr),
+        }
+        Ok(Block { info, data })
+    }
+}
+
+
+fn decode_info(b: &[u8]) -> Result<BlockInfo, BusStatus> {
+    match b.first().map(|s| BlockStatus::from(*s)) {
+        Some(BlockStatus::Ok) => {}
+        Some(status) => return Err(status_to_bus(status)),
+        None => return Err(BusStatus::DataErr),
+    }
+    match BlockInfo::decode(b) {
+        Ok(info) => Ok(info),
+        Err(_e) => Err(BusStatus::DataErr),
+    }
+}
+
+fn status_to_bus(status: BlockStatus) -> BusStatus {
+    match status {
+        BlockStatus::Ok => BusStatus::Good,
+        BlockStatus::NoSource => BusStatus::Unsupported,
+        BlockStatus::BadChannel => BusStatus::Rejected,
+        BlockStatus::NotReady | BlockStatus::BadOffset | BlockStatus::Failed => BusStatus::Error,
+    }
+}
+
+
+#[cfg(test)]
+mod block_read_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::BusError;
+    use crate::SensorData;
+    use crate::SensorInterface;
+    use crate::SensorStatus;
+    use crate::block::BlockSource;
+    use crate::fake_bus::LoopbackBus;
+
+    const NODE: u8 = 0x01;
+
+    // A vibration sensor with a capture buffer of i16 samples.
+    struct Vibration {
+        mem: Vec<u8>,
+        info: Option<BlockInfo>,
+        captures: usize,
+        has_source: bool,
+        reading: SensorData,
+    }
+
+    impl BlockSource for Vibration {
+        fn capture(&mut self, channel: u8) -> Result<(ChannelType, u32), BlockStatus> {
+            if channel != 0 {
+                return Err(BlockStatus::BadChannel);
+            }
+            self.captures += 1;
+            self.mem = (0..1000i32).flat_map(|i| ((i - 500) as i16).to_be_bytes()).collect();
+            Ok((ChannelType::I16, self.mem.len() as u32))
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), BusError> {
+            let start = offset as usize;
+            buf.copy_from_slice(&self.mem[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn release(&mut self) {
+            self.mem.clear();
+        }
+
+        fn block_info(&mut self) -> &mut Option<BlockInfo> {
+            &mut self.info
+        }
+    }
+
+    impl SensorInterface for Vibration {
+        fn get_name(&self) -> &'static str {
+            "Vibration"
+        }
+
+        fn get_format(&self) -> &'static str {
+            "i16"
+        }
+
+        fn get_data_names(&self) -> &'static str {
+            "Accel"
+        }
+
+        fn read_sensor(&mut self, _idx: u8) -> &SensorData {
+            &self.reading
+        }
+
+        fn get_status(&self) -> SensorStatus {
+            SensorStatus::Ready
+        }
+
+        fn soft_reset(&mut self) -> SensorStatus {
+            SensorStatus::Ready
+        }
+
+        fn block_source(&mut self) -> Option<&mut dyn BlockSource> {
+            if !self.has_source {
+                return None;
+            }
+            Some(self)
+        }
+    }
+
+    fn vibration() -> Vibration {
+        Vibration {
+            mem: vec![],
+            info: None,
+            captures: 0,
+            has_source: true,
+            reading: SensorData::new(),
+        }
+    }
+
+    #[test]
+    fn read_block() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, vibration());
+
+        let seen: RefCell<Vec<usize>> = RefCell::new(vec![]);
+        let mut reader = BlockReader::new(0)
+            .chunk_size(500)
+            .on_progress(|done, total| {
+                assert_eq!(total, 2000);
+                seen.borrow_mut().push(done);
+            });
+        let block = reader.read(&mut ctrl, &mut bus, NODE).unwrap();
+        drop(reader);
+
+        let samples: Vec<i16> = block.samples().unwrap();
+        assert_eq!(samples.len(), 1000);
+        assert_eq!((samples[0], samples[999]), (-500, 499));
+        assert_eq!(block.values()[500], 0.0);
+        assert!(block.samples::<u16>().is_err());
+
+        // Each read is cut to what fits in a 64 byte reply.
+        assert!(seen.borrow().iter().all(|done| done % 58 == 0 || *done == 2000));
+        // Acked, the module let go of it.
+        assert!(bus.sens.info.is_none());
+        assert!(bus.sens.mem.is_empty());
+    }
+
+    #[test]
+    fn lost_reply() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, vibration());
+        bus.drop_reply_at = Some(4);
+
+        let block = BlockReader::new(0).read(&mut ctrl, &mut bus, NODE).unwrap();
+        assert_eq!(block.samples::<i16>().unwrap().len(), 1000);
+        assert_eq!(bus.sens.captures, 1);
+    }
+
+    #[test]
+    fn bad_crc_kept() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, vibration());
+        let ret = ctrl.send_command_with_args(&mut bus, NODE, &ControllerCommand::BlockPrepareRequest, &[0]);
+        assert!(ret.is_ok());
+
+        // Goes bad after the crc was worked out.
+        bus.sens.mem[10] ^= 0xFF;
+        let mut reader = BlockReader::new(0);
+        assert!(matches!(reader.read_again(&mut ctrl, &mut bus, NODE), Err(BusStatus::DataErr)));
+
+        // Not acked, put it right and read it again without a new capture.
+        bus.sens.mem[10] ^= 0xFF;
+        let block = reader.read_again(&mut ctrl, &mut bus, NODE).unwrap();
+        assert_eq!(block.samples::<i16>().unwrap()[5], -495);
+        assert_eq!(bus.sens.captures, 1);
+        assert!(matches!(reader.read_again(&mut ctrl, &mut bus, NODE), Err(BusStatus::Error)));
+    }
+
+    #[test]
+    fn no_source() {
+        let mut ctrl = Controller::new();
+        let mut sens = vibration();
+        sens.has_source = false;
+        let mut bus = LoopbackBus::new(NODE, sens);
+        assert!(matches!(BlockReader::new(0).read(&mut ctrl, &mut bus, NODE), Err(BusStatus::Unsupported)));
+
+        let mut bus = LoopbackBus::new(NODE, vibration());
+        assert!(matches!(BlockReader::new(2).read(&mut ctrl, &mut bus, NODE), Err(BusStatus::Rejected)));
+    }
+}
@@ -0,0 +1,228 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: can_id.rs
+ * Desc: Structured extended(29-bit) CAN identifier shared by the
+ *       controller and the sensor modules.
+ */
+
+use crate::BusError;
+use crate::crc::CrcMode;
+
+/*
+ * Layout of the 29 bit identifier, MSB first:
+ *
+ *  28..26  priority        (0 is the highest priority, same as CAN arbitration)
+ *  25..24  crc trailer     (see `crc::CrcMode`)
+ *  23..16  message type
+ *  15..8   destination node
+ *   7..0   source node
+ */
+pub const EXT_ID_MASK: u32 = 0x1FFF_FFFF;
+
+const PRIORITY_SHIFT: u32 = 26;
+const PRIORITY_MASK: u32 = 0x07;
+const CRC_SHIFT: u32 = 24;
+const CRC_MASK: u32 = 0x03;
+const MSG_TYPE_SHIFT: u32 = 16;
+const NODE_MASK: u32 = 0xFF;
+const DEST_SHIFT: u32 = 8;
+const SOURCE_SHIFT: u32 = 0;
+
+pub const MAX_PRIORITY: u8 = 7;
+pub const DEFAULT_PRIORITY: u8 = 4;
+
+// Node 0 is always the bus controller, 0xFF addresses every module.
+pub const CONTROLLER_NODE: u8 = 0;
+pub const BROADCAST_NODE: u8 = 0xFF;
+// Modules that haven't been given an address yet use this one.
+pub const UNASSIGNED_NODE: u8 = 0xFE;
+
+
+// What kind of frame is being carried, lives in the id so that the
+// hardware filters can sort frames without looking at the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    Request = 0,    //Controller asking a module for something.
+    Response,       //Module answering a request.
+    Broadcast,      //Controller talking to every module at once.
+    Announce,       //Unaddressed module asking for a node address.
+    Heartbeat,      //Module saying it's still alive, see `heartbeat`.
+    Alarm,          //A module's alarm changed level, see `alarm`.
+    Unknown = 0xFF,
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => MessageType::Request,
+            1 => MessageType::Response,
+            2 => MessageType::Broadcast,
+            3 => MessageType::Announce,
+            4 => MessageType::Heartbeat,
+            5 => MessageType::Alarm,
+            _ => MessageType::Unknown,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanId {
+    pub priority: u8,
+    pub msg_type: MessageType,
+    pub destination: u8,
+    pub source: u8,
+    pub crc: CrcMode,
+}
+
+impl CanId {
+    pub fn new(priority: u8, msg_type: MessageType, destination: u8, source: u8) -> CanId {
+        CanId {
+            priority,
+            msg_type,
+            destination,
+            source,
+            crc: CrcMode::None,
+        }
+    }
+
+    // Marks the payload as carrying a crc trailer.
+    pub fn with_crc(mut self, crc: CrcMode) -> CanId {
+        self.crc = crc;
+        self
+    }
+
+    // Convenience for the controller, requests use the default priority.
+    pub fn request(destination: u8) -> CanId {
+        CanId::new(DEFAULT_PRIORITY, MessageType::Request, destination, CONTROLLER_NODE)
+    }
+
+    // Convenience for the modules, the response goes back to whoever asked
+    // using the same crc trailer the request came with.
+    pub fn response_to(&self) -> CanId {
+        CanId::new(self.priority, MessageType::Response, self.source, self.destination)
+            .with_crc(self.crc)
+    }
+
+    // True if a module with the given node address should act on this id.
+    pub fn is_for(&self, node: u8) -> bool {
+        self.destination == node || self.destination == BROADCAST_NODE
+    }
+
+    // Addresses the controller can hand out, everything but the controller,
+    // unassigned and broadcast.
+    pub fn is_assignable(node: u8) -> bool {
+        node != CONTROLLER_NODE && node != UNASSIGNED_NODE && node != BROADCAST_NODE
+    }
+
+    // Packs the fields into the raw value handed to the `Bus`.
+    pub fn to_raw(&self) -> Result<u32, BusError> {
+        if self.priority > MAX_PRIORITY ||
+            self.msg_type == MessageType::Unknown ||
+            self.crc == CrcMode::Unknown {
+            return Err(BusError::BadParameter);
+        }
+
+        let raw: u32 =
+            ((self.priority as u32) << PRIORITY_SHIFT) |
+            ((self.crc as u32) << CRC_SHIFT) |
+            ((self.msg_type as u32) << MSG_TYPE_SHIFT) |
+            ((self.destination as u32) << DEST_SHIFT) |
+            ((self.source as u32) << SOURCE_SHIFT);
+        Ok(raw)
+    }
+
+    // Unpacks a raw id received from the `Bus`.
+    pub fn from_raw(raw: u32) -> Result<CanId, BusError> {
+        if raw & !EXT_ID_MASK != 0 {
+            return Err(BusError::BadParameter);
+        }
+
+        let msg_type: MessageType = (((raw >> MSG_TYPE_SHIFT) & NODE_MASK) as u8).into();
+        if msg_type == MessageType::Unknown {
+            return Err(BusError::BadParameter);
+        }
+
+        let crc: CrcMode = (((raw >> CRC_SHIFT) & CRC_MASK) as u8).into();
+        if crc == CrcMode::Unknown {
+            return Err(BusError::BadParameter);
+        }
+
+        Ok(CanId {
+            priority: ((raw >> PRIORITY_SHIFT) & PRIORITY_MASK) as u8,
+            msg_type,
+            destination: ((raw >> DEST_SHIFT) & NODE_MASK) as u8,
+            source: ((raw >> SOURCE_SHIFT) & NODE_MASK) as u8,
+            crc,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod can_id_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let id = CanId::new(3, MessageType::Response, 0x12, 0x34);
+        let raw = id.to_raw().unwrap();
+        assert!(raw <= EXT_ID_MASK);
+        assert_eq!(CanId::from_raw(raw).unwrap(), id);
+    }
+
+    #[test]
+    fn crc_round_trip() {
+        let id = CanId::request(9).with_crc(CrcMode::Crc32);
+        let raw = id.to_raw().unwrap();
+        assert_eq!((raw >> 24) & 0x03, CrcMode::Crc32 as u32);
+        assert_eq!(CanId::from_raw(raw).unwrap().crc, CrcMode::Crc32);
+        assert_eq!(id.response_to().crc, CrcMode::Crc32);
+
+        // The spare crc value isn't valid.
+        assert!(CanId::from_raw(0x0300_0000).is_err());
+    }
+
+    #[test]
+    fn field_layout() {
+        let id = CanId::new(MAX_PRIORITY, MessageType::Broadcast, BROADCAST_NODE, 0x01);
+        assert_eq!(id.to_raw().unwrap(), (7 << 26) | (2 << 16) | (0xFF << 8) | 0x01);
+    }
+
+    #[test]
+    fn lower_value_wins_arbitration() {
+        let urgent = CanId::new(0, MessageType::Request, 1, CONTROLLER_NODE);
+        let normal = CanId::request(1);
+        assert!(urgent.to_raw().unwrap() < normal.to_raw().unwrap());
+    }
+
+    #[test]
+    fn bad_values_rejected() {
+        assert!(CanId::new(8, MessageType::Request, 1, 0).to_raw().is_err());
+        assert!(CanId::from_raw(0x2000_0000).is_err());
+        assert!(CanId::from_raw(0x00FE_0000).is_err());
+    }
+
+    #[test]
+    fn assignable_nodes() {
+        assert!(CanId::is_assignable(1));
+        assert!(CanId::is_assignable(0xFD));
+        assert!(!CanId::is_assignable(CONTROLLER_NODE));
+        assert!(!CanId::is_assignable(UNASSIGNED_NODE));
+        assert!(!CanId::is_assignable(BROADCAST_NODE));
+    }
+
+    #[test]
+    fn response_swaps_nodes() {
+        let req = CanId::request(5);
+        let resp = req.response_to();
+        assert_eq!(resp.msg_type, MessageType::Response);
+        assert_eq!(resp.source, 5);
+        assert_eq!(resp.destination, CONTROLLER_NODE);
+        assert!(req.is_for(5));
+        assert!(!req.is_for(6));
+        assert!(CanId::new(0, MessageType::Broadcast, BROADCAST_NODE, 0).is_for(6));
+    }
+}
@@ -0,0 +1,166 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: capabilities.rs
+ * Desc: Protocol version and the capabilities a module reports, so the
+ *       controller and the modules can be upgraded independently.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::BusError;
+use crate::ControllerCommand;
+use crate::COMMAND_COUNT;
+
+// Bumped on breaking changes to the wire format.
+pub const PROTOCOL_VERSION_MAJOR: u8 = 1;
+// Bumped when commands or features are added.
+pub const PROTOCOL_VERSION_MINOR: u8 = 1;
+
+// Biggest payload(including headers and trailers) the module can take,
+// CAN FD sized by default.
+pub const DEFAULT_MAX_PAYLOAD: u16 = 64;
+
+// Optional features, bit flags in `Capabilities::features`.
+pub const FEATURE_STREAMING: u8 = 1 << 0;
+pub const FEATURE_CRC: u8 = 1 << 1;
+pub const FEATURE_CONFIG_WRITE: u8 = 1 << 2;
+pub const FEATURE_HEARTBEAT: u8 = 1 << 3;
+
+// major, minor, commands(8), max payload(2), endianness, features
+const CAPABILITIES_LEN: usize = 14;
+
+
+// Byte order the module uses for multi-byte sensor data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Endianness {
+    Big = 0,
+    Little,
+}
+
+impl From<u8> for Endianness {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Endianness::Little,
+            _ => Endianness::Big,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version_major: u8,
+    pub version_minor: u8,
+    pub commands: u64,      //bit n set if `ControllerCommand` n is handled.
+    pub max_payload: u16,
+    pub endianness: Endianness,
+    pub features: u8,
+}
+
+impl Default for Capabilities {
+    // Everything the stock handler in this crate knows how to answer.
+    fn default() -> Self {
+        let mut commands: u64 = 0;
+        for cmd in 0..COMMAND_COUNT {
+            commands |= 1 << cmd;
+        }
+
+        Capabilities {
+            version_major: PROTOCOL_VERSION_MAJOR,
+            version_minor: PROTOCOL_VERSION_MINOR,
+            commands,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            endianness: Endianness::Big,
+            features: FEATURE_CRC,
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn supports(&self, cmd: ControllerCommand) -> bool {
+        let bit = cmd as u8 as u32;
+        if bit >= u64::BITS {
+            return false;
+        }
+        self.commands & (1 << bit) != 0
+    }
+
+    pub fn has_feature(&self, feature: u8) -> bool {
+        self.features & feature == feature
+    }
+
+    // Same major version means the wire format is compatible.
+    pub fn is_compatible(&self) -> bool {
+        self.version_major == PROTOCOL_VERSION_MAJOR
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(CAPABILITIES_LEN);
+        buf.push(self.version_major);
+        buf.push(self.version_minor);
+        buf.extend_from_slice(&self.commands.to_be_bytes());
+        buf.extend_from_slice(&self.max_payload.to_be_bytes());
+        buf.push(self.endianness as u8);
+        buf.push(self.features);
+        buf
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Result<Capabilities, BusError> {
+        if b.len() < CAPABILITIES_LEN {
+            return Err(BusError::BadParameter);
+        }
+
+        let mut commands: [u8; 8] = [0; 8];
+        commands.copy_from_slice(&b[2..10]);
+
+        Ok(Capabilities {
+            version_major: b[0],
+            version_minor: b[1],
+            commands: u64::from_be_bytes(commands),
+            max_payload: u16::from_be_bytes([b[10], b[11]]),
+            endianness: b[12].into(),
+            features: b[13],
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod capabilities_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let caps = Capabilities {
+            version_major: 1,
+            version_minor: 3,
+            commands: 0x8000_0000_0000_0021,
+            max_payload: 512,
+            endianness: Endianness::Little,
+            features: FEATURE_STREAMING | FEATURE_CRC,
+        };
+
+        let bytes = caps.to_bytes();
+        assert_eq!(bytes.len(), CAPABILITIES_LEN);
+        assert_eq!(Capabilities::from_bytes(&bytes).unwrap(), caps);
+    }
+
+    #[test]
+    fn default_supports_everything() {
+        let caps = Capabilities::default();
+        for cmd in 0..COMMAND_COUNT {
+            assert!(caps.supports(cmd.into()));
+        }
+        assert!(caps.has_feature(FEATURE_CRC));
+        assert!(!caps.has_feature(FEATURE_CONFIG_WRITE));
+        assert!(caps.is_compatible());
+    }
+
+    #[test]
+    fn short_reply() {
+        assert!(Capabilities::from_bytes(&[1, 0, 0]).is_err());
+    }
+}
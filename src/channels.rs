@@ -0,0 +1,613 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: channels.rs
+ * Desc: Binary description of a module's channels, replaces splitting the
+ *       FormattingRequest/DnamesRequest strings on spaces.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::string::String;
+
+use core::fmt::Write;
+
+use crate::BusError;
+use crate::units::ChannelMeta;
+
+/*
+ * The ChannelDescRequest reply is [status] then a list of TLVs:
+ *   tag(1) len(1) value(len)
+ *
+ * Tags we don't know are skipped, so new ones can be added without
+ * breaking older controllers.
+ */
+pub const TAG_COUNT: u8 = 1;    //u8, channels the module has.
+pub const TAG_INDEX: u8 = 2;    //u8
+pub const TAG_TYPE: u8 = 3;     //u8, a `ChannelType`.
+pub const TAG_NAME: u8 = 4;     //utf8, may have spaces.
+pub const TAG_FLAGS: u8 = 5;    //u8, the CHANNEL_* flags.
+pub const TAG_LEN: u8 = 6;      //u16, elements in an array channel, 1 if left out.
+
+// Channel flags.
+pub const CHANNEL_HAS_META: u8 = 1 << 0;    //ChannelMetaRequest describes it.
+pub const CHANNEL_DIAGNOSTIC: u8 = 1 << 1;  //Module housekeeping, not a measurement.
+
+
+// First byte of the ChannelDescRequest reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DescStatus {
+    Ok = 0,
+    BadIndex,
+}
+
+impl From<u8> for DescStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => DescStatus::Ok,
+            _ => DescStatus::BadIndex,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ChannelType {
+    U8 = 0,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    U64,
+    I64,
+    F64,
+    Unknown = 0xFF,
+}
+
+impl From<u8> for ChannelType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => ChannelType::U8,
+            1 => ChannelType::I8,
+            2 => ChannelType::U16,
+            3 => ChannelType::I16,
+            4 => ChannelType::U32,
+            5 => ChannelType::I32,
+            6 => ChannelType::F32,
+            7 => ChannelType::U64,
+            8 => ChannelType::I64,
+            9 => ChannelType::F64,
+            _ => ChannelType::Unknown,
+        }
+    }
+}
+
+impl ChannelType {
+    // From the element names used in `get_format`, see `parse_format` for
+    // arrays.
+    pub fn from_format(format: &str) -> ChannelType {
+        match format {
+            "u8" => ChannelType::U8,
+            "i8" => ChannelType::I8,
+            "u16" => ChannelType::U16,
+            "i16" => ChannelType::I16,
+            "u32" => ChannelType::U32,
+            "i32" => ChannelType::I32,
+            "f32" => ChannelType::F32,
+            "u64" => ChannelType::U64,
+            "i64" => ChannelType::I64,
+            "f64" => ChannelType::F64,
+            _ => ChannelType::Unknown,
+        }
+    }
+
+    pub fn format(&self) -> &'static str {
+        match self {
+            ChannelType::U8 => "u8",
+            ChannelType::I8 => "i8",
+            ChannelType::U16 => "u16",
+            ChannelType::I16 => "i16",
+            ChannelType::U32 => "u32",
+            ChannelType::I32 => "i32",
+            ChannelType::F32 => "f32",
+            ChannelType::U64 => "u64",
+            ChannelType::I64 => "i64",
+            ChannelType::F64 => "f64",
+            ChannelType::Unknown => "?",
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            ChannelType::U8 | ChannelType::I8 => 1,
+            ChannelType::U16 | ChannelType::I16 => 2,
+            ChannelType::U32 | ChannelType::I32 | ChannelType::F32 => 4,
+            ChannelType::U64 | ChannelType::I64 | ChannelType::F64 => 8,
+            ChannelType::Unknown => 0,
+        }
+    }
+
+    // Reads one element(big endian) as a number.
+    pub fn decode(&self, b: &[u8]) -> Option<f64> {
+        let size = self.size();
+        if size == 0 || b.len() < size {
+            return None;
+        }
+        let mut raw: [u8; 8] = [0; 8];
+        raw[..size].copy_from_slice(&b[..size]);
+
+        let value = match self {
+            ChannelType::U8 => raw[0] as f64,
+            ChannelType::I8 => raw[0] as i8 as f64,
+            ChannelType::U16 => u16::from_be_bytes([raw[0], raw[1]]) as f64,
+            ChannelType::I16 => i16::from_be_bytes([raw[0], raw[1]]) as f64,
+            ChannelType::U32 => u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+            ChannelType::I32 => i32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+            ChannelType::F32 => f32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+            ChannelType::U64 => u64::from_be_bytes(raw) as f64,
+            ChannelType::I64 => i64::from_be_bytes(raw) as f64,
+            ChannelType::F64 => f64::from_be_bytes(raw),
+            ChannelType::Unknown => return None,
+        };
+        Some(value)
+    }
+}
+
+
+// One entry of `get_format`, a type with an optional array length:
+// "u16", "i16[3]", "f32[128]". Gives the element type and how many.
+pub fn parse_format(format: &str) -> Option<(ChannelType, u16)> {
+    let (elem, len) = match format.split_once('[') {
+        Some((elem, rest)) => {
+            let len = rest.strip_suffix(']')?.parse::<u16>().ok()?;
+            (elem, len)
+        }
+        None => (format, 1),
+    };
+    match ChannelType::from_format(elem) {
+        ChannelType::Unknown => None,
+        t if len > 0 => Some((t, len)),
+        _ => None,
+    }
+}
+
+
+// One channel, the same type on the module and the controller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelDescriptor {
+    pub index: u8,
+    pub channel_type: ChannelType,     //Of each element for arrays.
+    pub len: u16,                      //Elements, 1 for plain channels.
+    pub name: String,
+    pub flags: u8,
+}
+
+impl ChannelDescriptor {
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    // The `get_format` entry for it, "u16" or "i16[3]".
+    pub fn format(&self) -> String {
+        let mut f = String::from(self.channel_type.format());
+        if self.len != 1 {
+            let _ = write!(f, "[{}]", self.len);
+        }
+        f
+    }
+
+    // Bytes in a reading of it.
+    pub fn size(&self) -> usize {
+        self.channel_type.size() * self.len as usize
+    }
+
+    // The elements of a reading as numbers, None if the reading is short.
+    pub fn decode_values(&self, b: &[u8]) -> Option<Vec<f64>> {
+        let size = self.channel_type.size();
+        if size == 0 || b.len() < self.size() {
+            return None;
+        }
+        b.chunks(size).take(self.len as usize).map(|e| self.channel_type.decode(e)).collect()
+    }
+
+    // [status, TLVs..], `count` is how many channels the module has.
+    pub fn encode(&self, count: u8, buf: &mut Vec<u8>) {
+        buf.push(DescStatus::Ok as u8);
+        push_tlv(buf, TAG_COUNT, &[count]);
+        push_tlv(buf, TAG_INDEX, &[self.index]);
+        push_tlv(buf, TAG_TYPE, &[self.channel_type as u8]);
+        push_tlv(buf, TAG_FLAGS, &[self.flags]);
+        if self.len != 1 {
+            push_tlv(buf, TAG_LEN, &self.len.to_be_bytes());
+        }
+        // The length is a byte, names are cut to fit between characters.
+        let mut end = self.name.len().min(u8::MAX as usize);
+        while !self.name.is_char_boundary(end) {
+            end -= 1;
+        }
+        push_tlv(buf, TAG_NAME, &self.name.as_bytes()[..end]);
+    }
+
+    // Returns the descriptor and the module's channel count.
+    pub fn decode(b: &[u8]) -> Result<(ChannelDescriptor, u8), BusError> {
+        if b.is_empty() || DescStatus::from(b[0]) != DescStatus::Ok {
+            return Err(BusError::BadParameter);
+        }
+
+        let mut desc = ChannelDescriptor {
+            index: 0,
+            channel_type: ChannelType::Unknown,
+            len: 1,
+            name: String::new(),
+            flags: 0,
+        };
+        let mut count: Option<u8> = None;
+        let mut index: Option<u8> = None;
+
+        let mut at = 1;
+        while at < b.len() {
+            if at + 2 > b.len() {
+                return Err(BusError::BadParameter);
+            }
+            let (tag, len) = (b[at], b[at + 1] as usize);
+            let value = match b.get(at + 2..at + 2 + len) {
+                Some(v) => v,
+                None => return Err(BusError::BadParameter),
+            };
+
+            match (tag, value.first()) {
+                (TAG_COUNT, Some(v)) => count = Some(*v),
+                (TAG_INDEX, Some(v)) => index = Some(*v),
+                (TAG_TYPE, Some(v)) => desc.channel_type = (*v).into(),
+                (TAG_FLAGS, Some(v)) => desc.flags = *v,
+                (TAG_LEN, _) if len == 2 => desc.len = u16::from_be_bytes([value[0], value[1]]),
+                (TAG_NAME, _) => {
+                    desc.name = match core::str::from_utf8(value) {
+                        Ok(n) => n.into(),
+                        Err(_e) => return Err(BusError::BadParameter),
+                    };
+                }
+                _ => {}
+            }
+            at += 2 + len;
+        }
+
+        // Those two have to be there, everything else has a default.
+        match (index, count) {
+            (Some(i), Some(c)) => {
+                desc.index = i;
+                Ok((desc, c))
+            }
+            _ => Err(BusError::BadParameter),
+        }
+    }
+}
+
+
+fn push_tlv(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buf.push(tag);
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value);
+}
+
+
+// Builds a descriptor from the old space separated format/name strings,
+// for sensors that don't give their own.
+pub fn from_strings(formats: &str, names: &str, idx: u8) -> Option<ChannelDescriptor> {
+    let format = formats.split(' ').nth(idx as usize)?;
+    let name = names.split(' ').nth(idx as usize).unwrap_or("");
+    let (channel_type, len) = parse_format(format).unwrap_or((ChannelType::Unknown, 1));
+    Some(ChannelDescriptor {
+        index: idx,
+        channel_type,
+        len,
+        name: name.into(),
+        flags: 0,
+    })
+}
+
+
+// The channel table of a sensor, generated by `#[derive(Sensor)]` from
+// the struct's #[channel] fields. `sensor_channels!` fills in the matching
+// `SensorInterface` methods from it.
+pub trait SensorChannels {
+    const NAME: &'static str;
+    const FORMAT: &'static str;
+    const DATA_NAMES: &'static str;
+    const COUNT: u8;
+
+    fn channel_name(idx: u8) -> Option<&'static str>;
+
+    fn channel_meta(idx: u8) -> Option<ChannelMeta>;
+
+    // Refreshes the sensor if it has a refresh method then copies the
+    // channel's field into the SensorData field and gives its bytes.
+    fn read_channel(&mut self, idx: u8) -> &[u8];
+
+    // `read_channel` as a plain `SensorData`. Sensors with a bigger reading
+    // give `NO_READING`, their channels may not fit.
+    fn read_sensor_data(&mut self, idx: u8) -> &crate::SensorData;
+
+    fn descriptor(idx: u8) -> Option<ChannelDescriptor> {
+        let mut desc = from_strings(Self::FORMAT, Self::DATA_NAMES, idx)?;
+        desc.name = Self::channel_name(idx)?.into();
+        if Self::channel_meta(idx).is_some() {
+            desc.flags |= CHANNEL_HAS_META;
+        }
+        Some(desc)
+    }
+}
+
+
+// Use inside `impl SensorInterface for ..` of a `#[derive(Sensor)]` type,
+// it gives the name, format, data name, read and channel methods. The rest
+// (status, reset, params..) are written as usual.
+#[macro_export]
+macro_rules! sensor_channels {
+    () => {
+        fn get_name(&self) -> &'static str {
+            <Self as $crate::channels::SensorChannels>::NAME
+        }
+
+        fn get_format(&self) -> &'static str {
+            <Self as $crate::channels::SensorChannels>::FORMAT
+        }
+
+        fn get_data_names(&self) -> &'static str {
+            <Self as $crate::channels::SensorChannels>::DATA_NAMES
+        }
+
+        fn read_sensor(&mut self, idx: u8) -> &$crate::SensorData {
+            $crate::channels::SensorChannels::read_sensor_data(self, idx)
+        }
+
+        fn read_data(&mut self, idx: u8) -> &[u8] {
+            $crate::channels::SensorChannels::read_channel(self, idx)
+        }
+
+        fn channel_count(&self) -> u8 {
+            <Self as $crate::channels::SensorChannels>::COUNT
+        }
+
+        fn channel_descriptor(&self, idx: u8) -> Option<$crate::channels::ChannelDescriptor> {
+            <Self as $crate::channels::SensorChannels>::descriptor(idx)
+        }
+
+        fn channel_meta(&self, idx: u8) -> Option<$crate::units::ChannelMeta> {
+            <Self as $crate::channels::SensorChannels>::channel_meta(idx)
+        }
+    };
+}
+
+
+#[cfg(test)]
+mod channels_tests {
+    use super::*;
+    use crate::SensorData;
+
+    fn desc() -> ChannelDescriptor {
+        ChannelDescriptor {
+            index: 2,
+            channel_type: ChannelType::I16,
+            len: 1,
+            name: String::from("Rel humidity"),
+            flags: CHANNEL_HAS_META,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf: Vec<u8> = vec![];
+        desc().encode(3, &mut buf);
+        assert_eq!(ChannelDescriptor::decode(&buf).unwrap(), (desc(), 3));
+    }
+
+    #[test]
+    fn unknown_tags_skipped() {
+        let mut buf: Vec<u8> = vec![];
+        desc().encode(3, &mut buf);
+        push_tlv(&mut buf, 0x77, &[1, 2, 3]);
+        assert_eq!(ChannelDescriptor::decode(&buf).unwrap().0, desc());
+    }
+
+    #[test]
+    fn long_names() {
+        // 254 bytes then a 2 byte character across the limit, it's left out
+        // rather than split.
+        let d = ChannelDescriptor {
+            name: "a".repeat(254) + "°C",
+            ..desc()
+        };
+        let mut buf: Vec<u8> = vec![];
+        d.encode(3, &mut buf);
+        let (got, _) = ChannelDescriptor::decode(&buf).unwrap();
+        assert_eq!(got.name, "a".repeat(254));
+    }
+
+    #[test]
+    fn bad_records() {
+        let mut buf: Vec<u8> = vec![];
+        desc().encode(3, &mut buf);
+
+        // Cut off part way through a value.
+        assert!(ChannelDescriptor::decode(&buf[..buf.len() - 1]).is_err());
+        // No index.
+        assert!(ChannelDescriptor::decode(&[0, TAG_COUNT, 1, 3]).is_err());
+        assert!(ChannelDescriptor::decode(&[DescStatus::BadIndex as u8]).is_err());
+    }
+
+    #[test]
+    fn old_strings() {
+        let d = from_strings("u8 u16 u16", "Status Temp Humid", 1).unwrap();
+        assert_eq!(d.channel_type, ChannelType::U16);
+        assert_eq!(d.name, "Temp");
+        assert!(from_strings("u8", "Status", 1).is_none());
+    }
+
+    #[test]
+    fn array_formats() {
+        assert_eq!(parse_format("i16[3]"), Some((ChannelType::I16, 3)));
+        assert_eq!(parse_format("f64"), Some((ChannelType::F64, 1)));
+        assert_eq!(parse_format("i16[0]"), None);
+        assert_eq!(parse_format("i16[3"), None);
+
+        let d = from_strings("u8 i16[3]", "Status Accel", 1).unwrap();
+        assert_eq!((d.channel_type, d.len, d.size()), (ChannelType::I16, 3, 6));
+        assert_eq!(d.format(), "i16[3]");
+
+        let mut buf: Vec<u8> = vec![];
+        d.encode(2, &mut buf);
+        assert_eq!(ChannelDescriptor::decode(&buf).unwrap(), (d.clone(), 2));
+
+        let values = d.decode_values(&[0x00, 0x01, 0xFF, 0xFF, 0x01, 0x00]).unwrap();
+        assert_eq!(values, vec![1.0, -1.0, 256.0]);
+        assert!(d.decode_values(&[0x00, 0x01]).is_none());
+    }
+
+    #[derive(crate::Sensor)]
+    #[sensor(name = "Derived", refresh = sample)]
+    struct DerivedSensor {
+        samples: u8,
+        #[channel(name = "Pressure", unit = "Pa", scale = 10, offset = -5, min = 0)]
+        pressure: u16,
+        #[channel(name = "Dew point")]
+        dew: i8,
+        #[channel]
+        ratio: f32,
+        reading: SensorData,
+    }
+
+    impl DerivedSensor {
+        fn sample(&mut self, _idx: u8) {
+            self.samples += 1;
+        }
+    }
+
+    impl crate::SensorInterface for DerivedSensor {
+        crate::sensor_channels!();
+
+        fn get_status(&self) -> crate::SensorStatus {
+            crate::SensorStatus::Ready
+        }
+
+        fn soft_reset(&mut self) -> crate::SensorStatus {
+            crate::SensorStatus::Ready
+        }
+    }
+
+    fn derived() -> DerivedSensor {
+        DerivedSensor {
+            samples: 0,
+            pressure: 0x1234,
+            dew: -3,
+            ratio: 0.5,
+            reading: SensorData::new(),
+        }
+    }
+
+    #[test]
+    fn derived_strings() {
+        use crate::SensorInterface;
+        let sens = derived();
+        assert_eq!(sens.get_name(), "Derived");
+        assert_eq!(sens.get_format(), "u16 i8 f32");
+        assert_eq!(sens.get_data_names(), "Pressure Dew_point ratio");
+        assert_eq!(sens.channel_count(), 3);
+
+        let desc = sens.channel_descriptor(1).unwrap();
+        assert_eq!(desc.name, "Dew point");
+        assert_eq!(desc.channel_type, ChannelType::I8);
+        assert!(!desc.has_flag(CHANNEL_HAS_META));
+
+        let meta = sens.channel_meta(0).unwrap();
+        assert_eq!(meta.unit, "Pa");
+        assert_eq!((meta.scale, meta.offset, meta.min, meta.max), (10.0, -5.0, 0.0, f32::MAX));
+        assert!(sens.channel_meta(2).is_none());
+        assert!(sens.channel_descriptor(3).is_none());
+    }
+
+    #[test]
+    fn derived_reads() {
+        use crate::SensorInterface;
+        let mut sens = derived();
+        assert_eq!(sens.read_data(0), &[0x12, 0x34]);
+        assert_eq!(sens.read_data(1), &[0xFD]);
+        assert_eq!(sens.read_data(2), &0.5f32.to_be_bytes());
+        assert!(sens.read_data(3).is_empty());
+        assert_eq!(sens.read_sensor(0).as_bytes(), &[0x12, 0x34]);
+        assert_eq!(sens.samples, 5);
+    }
+
+    #[derive(crate::Sensor)]
+    struct ArraySensor {
+        #[channel(name = "Accel")]
+        accel: [i16; 3],
+        #[channel(name = "Spectrum")]
+        spectrum: [f64; 12],
+        #[channel(name = "Count")]
+        count: u64,
+        reading: SensorData<96>,
+    }
+
+    impl crate::SensorInterface for ArraySensor {
+        crate::sensor_channels!();
+
+        fn get_status(&self) -> crate::SensorStatus {
+            crate::SensorStatus::Ready
+        }
+
+        fn soft_reset(&mut self) -> crate::SensorStatus {
+            crate::SensorStatus::Ready
+        }
+    }
+
+    fn arrays() -> ArraySensor {
+        let mut spectrum = [0.0; 12];
+        for (i, v) in spectrum.iter_mut().enumerate() {
+            *v = i as f64 / 4.0;
+        }
+        ArraySensor {
+            accel: [1, -2, 300],
+            spectrum,
+            count: 1 << 40,
+            reading: SensorData::new(),
+        }
+    }
+
+    #[test]
+    fn derived_arrays() {
+        use crate::SensorInterface;
+        let mut sens = arrays();
+        assert_eq!(sens.get_format(), "i16[3] f64[12] u64");
+        assert_eq!(sens.read_data(0), &[0x00, 0x01, 0xFF, 0xFE, 0x01, 0x2C]);
+        assert_eq!(sens.read_data(1).len(), 96);
+        // Too big for a plain SensorData, only read_data has it.
+        assert!(sens.read_sensor(1).as_bytes().is_empty());
+
+        let desc = sens.channel_descriptor(1).unwrap();
+        assert_eq!((desc.channel_type, desc.len), (ChannelType::F64, 12));
+    }
+
+    // 96 bytes don't fit one frame, LargeDataRequest sends them segmented.
+    #[test]
+    fn large_read() {
+        use crate::controller::Controller;
+        use crate::fake_bus::LoopbackBus;
+
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(3, arrays());
+
+        assert_eq!(ctrl.read_values(&mut bus, 3, 0).unwrap(), vec![1.0, -2.0, 300.0]);
+        let spectrum = ctrl.read_values(&mut bus, 3, 1).unwrap();
+        assert_eq!(spectrum.len(), 12);
+        assert_eq!(spectrum[11], 2.75);
+        assert_eq!(ctrl.read_values(&mut bus, 3, 2).unwrap(), vec![(1u64 << 40) as f64]);
+        assert!(ctrl.read_values(&mut bus, 3, 3).is_err());
+    }
+}
@@ -28,8 +28,17 @@ pub struct CmdReturn {
     pub format: Vec<String>,
     pub data_names: Vec<String>,
     pub raw_bytes: Vec<u8>,
+    #[cfg(any(test, feature = "bus_master"))]
+    pub health: Option<crate::status::SensorHealth>,    //StatusRequest/ResetRequest only.
 }
 
+impl Default for CmdReturn {
+    fn default() -> Self {
+        CmdReturn::new()
+    }
+}
+
+#[allow(clippy::needless_return, clippy::let_and_return)]
 impl CmdReturn {
     pub fn new() -> CmdReturn {
         let ret = CmdReturn{
@@ -37,6 +46,8 @@ impl CmdReturn {
             format: vec![],
             data_names: vec![], 
             raw_bytes: vec![],
+            #[cfg(any(test, feature = "bus_master"))]
+            health: None,
         };
         ret
     }
@@ -125,7 +136,7 @@ impl CmdReturn {
 
 }
 
-fn bytes_to_u16(b: &Vec<u8>, start: usize) -> u16 {
+fn bytes_to_u16(b: &[u8], start: usize) -> u16 {
         let tmp: u16 =
             ((b[start] as u16 )<< 8) | 
             (b[start + 1] as u16);
@@ -133,7 +144,7 @@ fn bytes_to_u16(b: &Vec<u8>, start: usize) -> u16 {
 }
 
 
-fn bytes_to_i16(b: &Vec<u8>, start: usize) -> i16 {
+fn bytes_to_i16(b: &[u8], start: usize) -> i16 {
         let tmp: i16 =
             ((b[start] as i16 )<< 8) | 
             (b[start + 1] as i16);
@@ -141,7 +152,7 @@ fn bytes_to_i16(b: &Vec<u8>, start: usize) -> i16 {
 }
 
 
-fn bytes_to_u32(b: &Vec<u8>, start: usize) -> u32 {
+fn bytes_to_u32(b: &[u8], start: usize) -> u32 {
     let tmp: u32 = 
         ((b[start] as u32 )<< 24) | 
         ((b[start + 1] as u32 )<< 16) | 
@@ -151,7 +162,7 @@ fn bytes_to_u32(b: &Vec<u8>, start: usize) -> u32 {
 }
 
 
-fn bytes_to_i32(b: &Vec<u8>, start: usize) -> i32 {
+fn bytes_to_i32(b: &[u8], start: usize) -> i32 {
     let tmp: i32 = 
         ((b[start] as i32 )<< 24) | 
         ((b[start + 1] as i32 )<< 16) | 
@@ -181,6 +192,7 @@ mod test_cmdreturn {
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn self_test() {
         assert!(true);
     }
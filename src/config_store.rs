@@ -0,0 +1,449 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: config_store.rs
+ * Desc: Keeps a module's configuration parameters across reboots.
+ *       The layout is written with flash in mind, see below.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec;
+
+use crate::BusError;
+use crate::crc::crc16;
+use crate::crc::crc32;
+use crate::params::ParamValue;
+use crate::params::PARAM_VALUE_LEN;
+
+/*
+ * The store is treated like a block of flash: erasing sets every byte to
+ * 0xFF and writes only ever go to erased bytes.
+ *
+ * The region is split into two banks(halves) that are erased separately.
+ * Records are appended one after the other in a bank, the newest valid one
+ * in either bank wins. When the bank with the newest record is full the
+ * other one is erased and the new record goes at its start, so each byte
+ * gets erased once per "fill" instead of once per save.
+ *
+ * The bank holding the newest record is never erased or written over by a
+ * save. If the power goes during a save the record from before it is still
+ * there, and a half written record fails its crc and is skipped.
+ *
+ * Record:
+ *   magic(1) version(1) generation(4, BE) count(1)
+ *   count * [key(2, BE) type(1) value(4, BE)]
+ *   crc32(4, BE) over everything before it
+ *
+ * Keys are a crc16 of the parameter name so reordering parameters in a
+ * firmware update doesn't mix up the saved values. Records from another
+ * layout version are ignored.
+ */
+pub const RECORD_MAGIC: u8 = 0xC5;
+pub const LAYOUT_VERSION: u8 = 1;
+pub const ERASED: u8 = 0xFF;
+
+const HEADER_LEN: usize = 7;
+const ENTRY_LEN: usize = 2 + PARAM_VALUE_LEN;
+const TRAILER_LEN: usize = 4;
+
+pub const BANKS: usize = 2;
+
+
+// Result of a save/load/factory reset, sent back as the first byte of
+// the reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StoreStatus {
+    Ok = 0,
+    NoStore,    //The module doesn't have anywhere to save.
+    Empty,      //Nothing(valid) has been saved yet.
+    TooBig,     //The record doesn't fit even in an erased store.
+    Failed,     //The storage itself reported an error.
+}
+
+impl From<u8> for StoreStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => StoreStatus::Ok,
+            1 => StoreStatus::NoStore,
+            2 => StoreStatus::Empty,
+            3 => StoreStatus::TooBig,
+            _ => StoreStatus::Failed,
+        }
+    }
+}
+
+
+// The storage a module provides(a flash page, eeprom, a file on a host).
+pub trait ConfigStore {
+
+    // Size of the region in bytes.
+    fn capacity(&self) -> usize;
+
+    // Records start on a multiple of this, for flash that has to be
+    // written a word at a time.
+    fn write_size(&self) -> usize {
+        1
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), BusError>;
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), BusError>;
+
+    // Sets one bank back to `ERASED`, the bytes from
+    // `bank * bank_size(store)` for `bank_size(store)`. Banks have to be
+    // separately erasable(their own flash pages/sectors).
+    fn erase(&mut self, bank: usize) -> Result<(), BusError>;
+}
+
+
+// Half the region, rounded down to the write size.
+pub fn bank_size(store: &dyn ConfigStore) -> usize {
+    let ws = store.write_size().max(1);
+    (store.capacity() / BANKS) / ws * ws
+}
+
+
+// Forgets everything saved, for a factory reset.
+pub fn erase_all(store: &mut dyn ConfigStore) -> Result<(), BusError> {
+    for bank in 0..BANKS {
+        store.erase(bank)?;
+    }
+    Ok(())
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfigEntry {
+    pub key: u16,
+    pub value: ParamValue,
+}
+
+pub fn param_key(name: &str) -> u16 {
+    crc16(name.as_bytes())
+}
+
+
+// What a scan of the store found.
+struct Scan {
+    latest: Option<(u32, usize)>,   //generation and offset of the newest record.
+    end: [usize; BANKS],            //first erased byte after each bank's records.
+}
+
+
+fn align(offset: usize, write_size: usize) -> usize {
+    let ws = if write_size == 0 { 1 } else { write_size };
+    offset.div_ceil(ws) * ws
+}
+
+
+// Walks each bank's records from its start until it hits erased space.
+fn scan(store: &mut dyn ConfigStore) -> Result<Scan, BusError> {
+    let mut found = Scan { latest: None, end: [0; BANKS] };
+    let size = bank_size(store);
+    for bank in 0..BANKS {
+        found.end[bank] = scan_bank(store, bank * size, (bank + 1) * size, &mut found.latest)?;
+    }
+    Ok(found)
+}
+
+
+// Gives the first erased byte after the bank's records, `limit` if it's
+// full.
+fn scan_bank(store: &mut dyn ConfigStore, start: usize, limit: usize, latest: &mut Option<(u32, usize)>) -> Result<usize, BusError> {
+    let mut offset = start;
+
+    while offset + HEADER_LEN <= limit {
+        let mut header: [u8; HEADER_LEN] = [0; HEADER_LEN];
+        store.read(offset, &mut header)?;
+
+        if header[0] != RECORD_MAGIC {
+            // Erased space is the end, anything else is junk we can't
+            // walk past so it's treated as full.
+            if header[0] != ERASED {
+                return Ok(limit);
+            }
+            break;
+        }
+
+        let len = HEADER_LEN + header[6] as usize * ENTRY_LEN + TRAILER_LEN;
+        if offset + len > limit {
+            return Ok(limit);
+        }
+
+        let mut record = vec![0; len];
+        store.read(offset, &mut record)?;
+        let generation = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+
+        // A torn write or an old layout gets skipped over.
+        if header[1] == LAYOUT_VERSION && record_crc_ok(&record) {
+            let newer = match latest {
+                Some((gen, _)) => generation > *gen,
+                None => true,
+            };
+            if newer {
+                *latest = Some((generation, offset));
+            }
+        }
+
+        offset = align(offset + len, store.write_size());
+    }
+
+    Ok(offset.min(limit))
+}
+
+
+fn record_crc_ok(record: &[u8]) -> bool {
+    let split = record.len() - TRAILER_LEN;
+    let (body, trailer) = record.split_at(split);
+    crc32(body).to_be_bytes() == trailer
+}
+
+
+// Appends a new record holding the entries. If it won't fit in the bank
+// with the newest record the other bank is erased and it goes there.
+pub fn save_record(store: &mut dyn ConfigStore, entries: &[ConfigEntry]) -> StoreStatus {
+    if entries.len() > u8::MAX as usize {
+        return StoreStatus::TooBig;
+    }
+
+    let found = match scan(store) {
+        Ok(f) => f,
+        Err(_e) => return StoreStatus::Failed,
+    };
+    let generation = match found.latest {
+        Some((gen, _)) => gen.wrapping_add(1),
+        None => 0,
+    };
+
+    let mut record: Vec<u8> = vec![RECORD_MAGIC, LAYOUT_VERSION];
+    record.extend_from_slice(&generation.to_be_bytes());
+    record.push(entries.len() as u8);
+    for entry in entries.iter() {
+        record.extend_from_slice(&entry.key.to_be_bytes());
+        entry.value.encode(&mut record);
+    }
+    let crc = crc32(&record);
+    record.extend_from_slice(&crc.to_be_bytes());
+
+    let size = bank_size(store);
+    if record.len() > size {
+        return StoreStatus::TooBig;
+    }
+
+    let bank = match found.latest {
+        Some((_, offset)) => offset / size,
+        None => 0,
+    };
+    let mut offset = found.end[bank];
+    if offset + record.len() > (bank + 1) * size {
+        let other = (bank + 1) % BANKS;
+        if store.erase(other).is_err() {
+            return StoreStatus::Failed;
+        }
+        offset = other * size;
+    }
+
+    match store.write(offset, &record) {
+        Ok(()) => StoreStatus::Ok,
+        Err(_e) => StoreStatus::Failed,
+    }
+}
+
+
+// Reads back the newest valid record.
+pub fn load_record(store: &mut dyn ConfigStore) -> Result<Vec<ConfigEntry>, StoreStatus> {
+    let found = match scan(store) {
+        Ok(f) => f,
+        Err(_e) => return Err(StoreStatus::Failed),
+    };
+    let offset = match found.latest {
+        Some((_, offset)) => offset,
+        None => return Err(StoreStatus::Empty),
+    };
+
+    let mut count: [u8; 1] = [0];
+    if store.read(offset + HEADER_LEN - 1, &mut count).is_err() {
+        return Err(StoreStatus::Failed);
+    }
+
+    let mut body = vec![0; count[0] as usize * ENTRY_LEN];
+    if store.read(offset + HEADER_LEN, &mut body).is_err() {
+        return Err(StoreStatus::Failed);
+    }
+
+    let mut entries: Vec<ConfigEntry> = vec![];
+    for chunk in body.chunks(ENTRY_LEN) {
+        let key = u16::from_be_bytes([chunk[0], chunk[1]]);
+        // Types this firmware doesn't know are skipped, not fatal.
+        if let Ok(value) = ParamValue::decode(&chunk[2..]) {
+            entries.push(ConfigEntry { key, value });
+        }
+    }
+    Ok(entries)
+}
+
+
+#[cfg(test)]
+mod config_store_tests {
+    use super::*;
+
+    // Plain ram standing in for flash, checks we only write erased bytes.
+    // `torn` loses power halfway through the next write.
+    struct RamStore {
+        mem: Vec<u8>,
+        erases: usize,
+        torn: bool,
+    }
+
+    impl ConfigStore for RamStore {
+        fn capacity(&self) -> usize {
+            self.mem.len()
+        }
+
+        fn write_size(&self) -> usize {
+            4
+        }
+
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), BusError> {
+            buf.copy_from_slice(&self.mem[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), BusError> {
+            assert_eq!(offset % 4, 0);
+            let len = if self.torn { data.len() / 2 } else { data.len() };
+            for (i, byte) in data[..len].iter().enumerate() {
+                assert_eq!(self.mem[offset + i], ERASED, "write to unerased flash");
+                self.mem[offset + i] = *byte;
+            }
+            if self.torn {
+                return Err(BusError::BusError);
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self, bank: usize) -> Result<(), BusError> {
+            self.erases += 1;
+            let size = bank_size(self);
+            self.mem[bank * size..(bank + 1) * size].iter_mut().for_each(|b| *b = ERASED);
+            Ok(())
+        }
+    }
+
+    fn ram_store(size: usize) -> RamStore {
+        RamStore { mem: vec![ERASED; size], erases: 0, torn: false }
+    }
+
+    fn entries(rate: u16) -> Vec<ConfigEntry> {
+        vec![
+            ConfigEntry { key: param_key("SampleRate"), value: ParamValue::U16(rate) },
+            ConfigEntry { key: param_key("TempLimit"), value: ParamValue::F32(60.0) },
+        ]
+    }
+
+    #[test]
+    fn empty_store() {
+        let mut store = ram_store(64);
+        assert_eq!(load_record(&mut store), Err(StoreStatus::Empty));
+    }
+
+    #[test]
+    fn save_then_load() {
+        let mut store = ram_store(64);
+        assert_eq!(save_record(&mut store, &entries(100)), StoreStatus::Ok);
+        assert_eq!(load_record(&mut store).unwrap(), entries(100));
+    }
+
+    #[test]
+    fn newest_record_wins() {
+        let mut store = ram_store(256);
+        for rate in 1..4 {
+            assert_eq!(save_record(&mut store, &entries(rate)), StoreStatus::Ok);
+        }
+        assert_eq!(load_record(&mut store).unwrap(), entries(3));
+        assert_eq!(store.erases, 0);
+    }
+
+    #[test]
+    fn erases_only_when_full() {
+        // 7 + 2 * 7 + 4 = 25 bytes, 28 aligned, so two records fit a bank.
+        let mut store = ram_store(128);
+        assert_eq!(save_record(&mut store, &entries(1)), StoreStatus::Ok);
+        assert_eq!(save_record(&mut store, &entries(2)), StoreStatus::Ok);
+        assert_eq!(store.erases, 0);
+
+        // Into the second bank, the first is left alone.
+        assert_eq!(save_record(&mut store, &entries(3)), StoreStatus::Ok);
+        assert_eq!(store.erases, 1);
+        assert_eq!(store.mem[0], RECORD_MAGIC);
+        assert_eq!(store.mem[64], RECORD_MAGIC);
+        assert_eq!(load_record(&mut store).unwrap(), entries(3));
+
+        // Then back to the first once the second is full.
+        for rate in 4..6 {
+            assert_eq!(save_record(&mut store, &entries(rate)), StoreStatus::Ok);
+        }
+        assert_eq!(store.erases, 2);
+        assert_eq!(load_record(&mut store).unwrap(), entries(5));
+    }
+
+    #[test]
+    fn power_loss_during_save() {
+        let mut store = ram_store(128);
+        assert_eq!(save_record(&mut store, &entries(1)), StoreStatus::Ok);
+        assert_eq!(save_record(&mut store, &entries(2)), StoreStatus::Ok);
+
+        // The bank is full, the power goes halfway through writing the
+        // next record into the other one.
+        store.torn = true;
+        assert_eq!(save_record(&mut store, &entries(3)), StoreStatus::Failed);
+        store.torn = false;
+        assert_eq!(load_record(&mut store).unwrap(), entries(2));
+
+        // And the next save still works.
+        assert_eq!(save_record(&mut store, &entries(4)), StoreStatus::Ok);
+        assert_eq!(load_record(&mut store).unwrap(), entries(4));
+    }
+
+    #[test]
+    fn factory_erase() {
+        let mut store = ram_store(128);
+        assert_eq!(save_record(&mut store, &entries(1)), StoreStatus::Ok);
+        assert!(erase_all(&mut store).is_ok());
+        assert_eq!(load_record(&mut store), Err(StoreStatus::Empty));
+    }
+
+    #[test]
+    fn torn_record_skipped() {
+        let mut store = ram_store(128);
+        assert_eq!(save_record(&mut store, &entries(1)), StoreStatus::Ok);
+        assert_eq!(save_record(&mut store, &entries(2)), StoreStatus::Ok);
+
+        // Flip a bit in the second record, the first should be used.
+        store.mem[28 + HEADER_LEN + 3] ^= 0x01;
+        assert_eq!(load_record(&mut store).unwrap(), entries(1));
+    }
+
+    #[test]
+    fn other_version_ignored() {
+        let mut store = ram_store(64);
+        assert_eq!(save_record(&mut store, &entries(1)), StoreStatus::Ok);
+
+        // Rewrite it as a future layout with a valid crc.
+        store.mem[1] = LAYOUT_VERSION + 1;
+        let crc = crc32(&store.mem[0..21]);
+        store.mem[21..25].copy_from_slice(&crc.to_be_bytes());
+        assert_eq!(load_record(&mut store), Err(StoreStatus::Empty));
+    }
+
+    #[test]
+    fn too_big() {
+        // A record has to fit in one bank.
+        let mut store = ram_store(48);
+        assert_eq!(save_record(&mut store, &entries(1)), StoreStatus::TooBig);
+    }
+}
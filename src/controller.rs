@@ -9,59 +9,1113 @@
 use crate::Bus;
 use crate::BusStatus;
 use crate::ControllerCommand;
-use crate::CRONTROLLER_ID;
+use crate::can_id::CanId;
+use crate::can_id::MessageType;
 use crate::cmd_return::CmdReturn;
+use crate::crc::CrcMode;
+use crate::capabilities::Capabilities;
+use crate::capabilities::FEATURE_CRC;
+use crate::capabilities::FEATURE_CONFIG_WRITE;
+use crate::params::ParamInfo;
+use crate::params::ParamType;
+use crate::params::ParamStatus;
+use crate::params::ParamValue;
+use crate::config_store::StoreStatus;
+use crate::addressing;
+use crate::addressing::AddrStatus;
+use crate::can_id::UNASSIGNED_NODE;
+use crate::can_id::BROADCAST_NODE;
+use crate::can_id::CONTROLLER_NODE;
+use crate::can_id::DEFAULT_PRIORITY;
+use crate::acquisition::AcqState;
+use crate::acquisition::AcqStatus;
+use crate::alarm;
+use crate::alarm::Alarm;
+use crate::alarm::AlarmLevel;
+use crate::alarm::AlarmRule;
+use crate::alarm::AlarmStatus;
+use crate::heartbeat::Heartbeat;
+use crate::status::SensorHealth;
+use crate::timesync;
+use crate::timesync::ModuleClock;
+use crate::timesync::SyncResult;
+use crate::timesync::TimeStatus;
+use crate::timesync::TimedSample;
+use crate::units;
+use crate::units::ChannelInfo;
+use crate::units::Measurement;
+use crate::units::MetaStatus;
+use crate::capabilities::Endianness;
+use crate::channels;
+use crate::channels::ChannelDescriptor;
+use crate::subdevice;
+use crate::subdevice::SubDevice;
+use crate::subdevice::SubStatus;
+use crate::vendor;
+use crate::segment;
+use crate::selftest::SelfTestReport;
+use crate::selftest::SelfTestStatus;
+use crate::vendor::VendorStatus;
 
-// Used by the BUS Master/Controller
-#[allow(dead_code)]
-pub fn send_bus_command(
-    bus: &mut dyn Bus,
-    cmd: &ControllerCommand,
-    dname: String) -> Result<CmdReturn,BusStatus>
-{
-    
-    let mut ret = CmdReturn::new();
-    let mut data: Vec<u8> = vec![]; // Vec::with_capacity(SEND_BUFFER_BYTES);
+// How many frames we'll look through for our reply before giving up.
+const MAX_RX_ATTEMPTS: usize = 8;
 
-    match cmd {
-        ControllerCommand::NameRequest => {
-            data.push(ControllerCommand::NameRequest as u8);
+// Most frames `poll_bus` reads in one go.
+const MAX_POLL_FRAMES: usize = 64;
+
+// Heartbeats kept for `take_heartbeats`, the oldest are dropped past this.
+const MAX_HEARTBEATS: usize = 64;
+
+// Same for alarms and `take_alarms`.
+const MAX_ALARMS: usize = 64;
+
+// Replies kept for requests we aren't waiting on yet, the oldest are
+// dropped past this.
+const MAX_STASHED: usize = 64;
+
+
+// A request that has been sent but not answered yet.
+struct Outstanding {
+    node: u8,
+    seq: u8,
+    cmd: ControllerCommand,
+}
+
+// A reply that arrived while we were waiting on a different request.
+struct Stashed {
+    node: u8,
+    seq: u8,
+    data: Vec<u8>,
+}
+
+
+// Used by the BUS Master/Controller.
+// Tags every request with a sequence number so that late or out of order
+// replies can't be mistaken for the answer to the current request.
+pub struct Controller {
+    next_seq: u8,
+    outstanding: Vec<Outstanding>,
+    stashed: Vec<Stashed>,
+    crc_modes: Vec<(u8, CrcMode)>,
+    capabilities: Vec<(u8, Capabilities)>,
+    params: Vec<(u8, Vec<ParamInfo>)>,
+    addresses: Vec<(u64, u8)>,
+    announced: Vec<u64>,
+    heartbeats: Vec<(u8, Heartbeat)>,
+    alarms: Vec<(u8, Alarm)>,
+    descriptors: Vec<(u8, Vec<ChannelDescriptor>)>,
+    channels: Vec<(u8, ChannelInfo)>,
+    sub_devices: Vec<(u8, Vec<SubDevice>)>,
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Controller::new()
+    }
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller {
+            next_seq: 0,
+            outstanding: vec![],
+            stashed: vec![],
+            crc_modes: vec![],
+            capabilities: vec![],
+            params: vec![],
+            addresses: vec![],
+            announced: vec![],
+            heartbeats: vec![],
+            alarms: vec![],
+            descriptors: vec![],
+            channels: vec![],
+            sub_devices: vec![],
         }
-        ControllerCommand::StatusRequest => {
-            data.push(ControllerCommand::StatusRequest as u8);
+    }
+
+    // Asks the node what it supports and remembers the answer, requests
+    // to that node are then checked against it before being sent.
+    pub fn query_capabilities(&mut self, bus: &mut dyn Bus, node: u8) -> Result<Capabilities, BusStatus> {
+        let ret = self.send_bus_command(bus, node, &ControllerCommand::CapabilitiesRequest, String::new())?;
+        let caps = match Capabilities::from_bytes(&ret.raw_bytes) {
+            Ok(c) => c,
+            Err(_e) => return Err(BusStatus::DataErr),
+        };
+
+        if !caps.is_compatible() {
+            return Err(BusStatus::Unsupported);
         }
-        ControllerCommand::ResetRequest => {
-            data.push(ControllerCommand::ResetRequest as u8);
+
+        self.capabilities.retain(|(n, _)| *n != node);
+        self.capabilities.push((node, caps));
+        Ok(caps)
+    }
+
+    // What the node reported last time it was asked, if it has been.
+    pub fn capabilities(&self, node: u8) -> Option<&Capabilities> {
+        for (n, caps) in self.capabilities.iter() {
+            if *n == node {
+                return Some(caps);
+            }
         }
-        ControllerCommand::FormattingRequest => {
-            data.push(ControllerCommand::FormattingRequest as u8);
+        None
+    }
+
+    // Fetches every parameter the node has and remembers them so they can
+    // be read/written by name.
+    pub fn list_params(&mut self, bus: &mut dyn Bus, node: u8) -> Result<Vec<ParamInfo>, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::ParamCountRequest, &[])?;
+        if ret.raw_bytes.is_empty() {
+            return Err(BusStatus::DataErr);
         }
-        ControllerCommand::DnamesRequest => {
-            data.push(ControllerCommand::DnamesRequest as u8);
+
+        let mut list: Vec<ParamInfo> = vec![];
+        for idx in 0..ret.raw_bytes[0] {
+            let ret = self.send_command_with_args(bus, node, &ControllerCommand::ParamInfoRequest, &[idx])?;
+            match ParamInfo::decode(&ret.raw_bytes) {
+                Ok(info) => list.push(info),
+                Err(_e) => return Err(BusStatus::DataErr),
+            }
         }
-        ControllerCommand::DataRequest => {
-            data.push(ControllerCommand::DataRequest as u8);
+
+        self.params.retain(|(n, _)| *n != node);
+        self.params.push((node, list.clone()));
+        Ok(list)
+    }
+
+    pub fn read_param(&mut self, bus: &mut dyn Bus, node: u8, name: &str) -> Result<ParamValue, BusStatus> {
+        let info = self.find_param(bus, node, name)?;
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::ParamGetRequest, &[info.index])?;
+        check_param_status(&ret.raw_bytes)?;
+
+        match ParamValue::decode(&ret.raw_bytes[1..]) {
+            Ok(v) if v.param_type() == info.param_type => Ok(v),
+            _ => Err(BusStatus::DataErr),
+        }
+    }
+
+    // The value has to be the parameter's type, the module checks the range.
+    pub fn write_param(&mut self, bus: &mut dyn Bus, node: u8, name: &str, value: ParamValue) -> Result<(), BusStatus> {
+        if let Some(caps) = self.capabilities(node) {
+            if !caps.has_feature(FEATURE_CONFIG_WRITE) {
+                return Err(BusStatus::Unsupported);
+            }
+        }
+
+        let info = self.find_param(bus, node, name)?;
+        if !info.writable {
+            return Err(BusStatus::Unsupported);
+        }
+        if value.param_type() != info.param_type {
+            return Err(BusStatus::Rejected);
+        }
+
+        let mut args: Vec<u8> = vec![info.index];
+        value.encode(&mut args);
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::ParamSetRequest, &args)?;
+        check_param_status(&ret.raw_bytes)
+    }
+
+    // Asks the node to save its current parameters so they survive a reboot.
+    pub fn save_config(&mut self, bus: &mut dyn Bus, node: u8) -> Result<(), BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::ConfigSaveRequest, &[])?;
+        check_store_status(&ret.raw_bytes)
+    }
+
+    // Asks the node to go back to its last saved parameters.
+    pub fn load_config(&mut self, bus: &mut dyn Bus, node: u8) -> Result<(), BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::ConfigLoadRequest, &[])?;
+        check_store_status(&ret.raw_bytes)
+    }
+
+    // Asks the node to restore its default parameters and erase its store.
+    pub fn factory_reset(&mut self, bus: &mut dyn Bus, node: u8) -> Result<(), BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::FactoryResetRequest, &[])?;
+        check_store_status(&ret.raw_bytes)
+    }
+
+    // The node's health flags, detail code and message.
+    pub fn read_health(&mut self, bus: &mut dyn Bus, node: u8) -> Result<SensorHealth, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::StatusRequest, &[])?;
+        ret.health.ok_or(BusStatus::DataErr)
+    }
+
+    // Soft resets the node, returns its health afterwards.
+    pub fn reset_node(&mut self, bus: &mut dyn Bus, node: u8) -> Result<SensorHealth, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::ResetRequest, &[])?;
+        ret.health.ok_or(BusStatus::DataErr)
+    }
+
+    // Brings the node's clock in line with ours, see `timesync`. Worth
+    // repeating every so often since module clocks drift.
+    pub fn sync_time(&mut self, bus: &mut dyn Bus, node: u8) -> Result<SyncResult, BusStatus> {
+        self.sync_time_with(bus, node, &timesync::HostClock)
+    }
+
+    // Same as `sync_time`, to the given clock instead of the host's.
+    pub fn sync_time_with(&mut self, bus: &mut dyn Bus, node: u8, host: &dyn ModuleClock) -> Result<SyncResult, BusStatus> {
+        let t1 = host.now_us();
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::TimeSyncRequest, &[])?;
+        let t4 = host.now_us();
+
+        check_time_status(&ret.raw_bytes)?;
+        let tm = match timesync::decode_time(&ret.raw_bytes[1..]) {
+            Some(t) => t,
+            None => return Err(BusStatus::DataErr),
+        };
+
+        let midpoint = t1 / 2 + t4 / 2;
+        let offset = tm.wrapping_sub(midpoint) as i64;
+        let args = offset.wrapping_neg().to_be_bytes();
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::TimeAdjustRequest, &args)?;
+        check_time_status(&ret.raw_bytes)?;
+
+        Ok(SyncResult {
+            offset_us: offset,
+            round_trip_us: t4.saturating_sub(t1),
+        })
+    }
+
+    // Reads one channel along with when the module took the reading.
+    pub fn read_timed(&mut self, bus: &mut dyn Bus, node: u8, channel: u8) -> Result<TimedSample, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::TimedDataRequest, &[channel])?;
+        check_time_status(&ret.raw_bytes)?;
+
+        let timestamp_us = match timesync::decode_time(&ret.raw_bytes[1..]) {
+            Some(t) => t,
+            None => return Err(BusStatus::DataErr),
+        };
+        Ok(TimedSample {
+            node,
+            channel,
+            timestamp_us,
+            time: timesync::to_host_time(timestamp_us),
+            data: ret.raw_bytes[1 + timesync::TIME_LEN..].to_vec(),
+        })
+    }
+
+    // Unit, scaling and range of one channel, asked once then cached.
+    pub fn channel_info(&mut self, bus: &mut dyn Bus, node: u8, channel: u8) -> Result<ChannelInfo, BusStatus> {
+        for (n, info) in self.channels.iter() {
+            if *n == node && info.index == channel {
+                return Ok(info.clone());
+            }
+        }
+
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::ChannelMetaRequest, &[channel])?;
+        if ret.raw_bytes.is_empty() {
+            return Err(BusStatus::DataErr);
+        }
+        match MetaStatus::from(ret.raw_bytes[0]) {
+            MetaStatus::Ok => {}
+            MetaStatus::BadIndex => return Err(BusStatus::Rejected),
+            MetaStatus::NoMeta => return Err(BusStatus::Unsupported),
+        }
+
+        let info = match ChannelInfo::decode(&ret.raw_bytes) {
+            Ok(i) => i,
+            Err(_e) => return Err(BusStatus::DataErr),
+        };
+        self.channels.push((node, info.clone()));
+        Ok(info)
+    }
+
+    // Reads a channel and scales it into its physical unit.
+    pub fn read_physical(&mut self, bus: &mut dyn Bus, node: u8, channel: u8) -> Result<Measurement, BusStatus> {
+        let info = self.channel_info(bus, node, channel)?;
+        let format = self.channel_format(bus, node, channel)?;
+        let order = match self.capabilities(node) {
+            Some(caps) => caps.endianness,
+            None => Endianness::Big,
+        };
+
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::DataRequest, &[channel])?;
+        let raw = match units::raw_to_f64(&format, &ret.raw_bytes, order) {
+            Some(v) => v,
+            None => return Err(BusStatus::DataErr),
+        };
+        Ok(Measurement {
+            value: info.to_physical(raw),
+            unit: info.unit,
+        })
+    }
+
+    // Every element of a channel as a number, one for plain channels and
+    // `len` for arrays("i16[3]"). Uses LargeDataRequest so channels bigger
+    // than a frame come back in segments, nodes without it are read with
+    // DataRequest.
+    pub fn read_values(&mut self, bus: &mut dyn Bus, node: u8, channel: u8) -> Result<Vec<f64>, BusStatus> {
+        let desc = match self.list_channels(bus, node)?.into_iter().find(|d| d.index == channel) {
+            Some(d) => d,
+            None => return Err(BusStatus::Rejected),
+        };
+        let order = match self.capabilities(node) {
+            Some(caps) => caps.endianness,
+            None => Endianness::Big,
+        };
+
+        let ret = match self.send_command_with_args(bus, node, &ControllerCommand::LargeDataRequest, &[channel]) {
+            Err(BusStatus::Unsupported) => {
+                self.send_command_with_args(bus, node, &ControllerCommand::DataRequest, &[channel])?
+            }
+            ret => ret?,
+        };
+
+        let size = desc.channel_type.size();
+        if size == 0 || ret.raw_bytes.len() < desc.size() {
+            return Err(BusStatus::DataErr);
+        }
+        let format = desc.channel_type.format();
+        let values: Option<Vec<f64>> = ret.raw_bytes.chunks(size)
+            .take(desc.len as usize)
+            .map(|e| units::raw_to_f64(format, e, order))
+            .collect();
+        match values {
+            Some(v) => Ok(v),
+            None => Err(BusStatus::DataErr),
+        }
+    }
+
+    // Type, name and flags of every channel on the node, asked once then
+    // cached. Nodes without ChannelDescRequest are described from their
+    // format and data name strings instead.
+    pub fn list_channels(&mut self, bus: &mut dyn Bus, node: u8) -> Result<Vec<ChannelDescriptor>, BusStatus> {
+        for (n, list) in self.descriptors.iter() {
+            if *n == node {
+                return Ok(list.clone());
+            }
+        }
+
+        let list = match self.channel_descriptor(bus, node, 0) {
+            Ok((first, count)) => {
+                let mut list = vec![first];
+                for idx in 1..count {
+                    list.push(self.channel_descriptor(bus, node, idx)?.0);
+                }
+                list
+            }
+            Err(BusStatus::Unsupported) => self.channels_from_strings(bus, node)?,
+            Err(e) => return Err(e),
+        };
+        self.descriptors.push((node, list.clone()));
+        Ok(list)
+    }
+
+    // One ChannelDescRequest, gives the descriptor and the channel count.
+    fn channel_descriptor(&mut self, bus: &mut dyn Bus, node: u8, idx: u8) -> Result<(ChannelDescriptor, u8), BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::ChannelDescRequest, &[idx])?;
+        decode_descriptor(&ret.raw_bytes)
+    }
+
+    // How many sensors the node has, each is a sub-device.
+    pub fn sub_device_count(&mut self, bus: &mut dyn Bus, node: u8) -> Result<u8, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::SubDeviceCountRequest, &[])?;
+        match ret.raw_bytes.first() {
+            Some(count) => Ok(*count),
+            None => Err(BusStatus::DataErr),
+        }
+    }
+
+    // Sends a command to one sensor of a node, the reply is decoded the
+    // same as when it's sent to the node itself.
+    pub fn send_sub_command(
+        &mut self,
+        bus: &mut dyn Bus,
+        node: u8,
+        sub: u8,
+        cmd: &ControllerCommand,
+        args: &[u8]) -> Result<CmdReturn, BusStatus>
+    {
+        if subdevice::is_node_command(*cmd) {
+            return Err(BusStatus::Rejected);
+        }
+
+        let wrapped = subdevice::wrap(sub, *cmd, args);
+        let raw = if segment::is_segmented(*cmd) {
+            let seq = self.send_request_with_args(bus, node, &ControllerCommand::SubDeviceRequest, &wrapped)?;
+            self.receive_segments(bus, node, seq, &ControllerCommand::SubDeviceRequest, 1)?
+        } else {
+            self.send_command_with_args(bus, node, &ControllerCommand::SubDeviceRequest, &wrapped)?.raw_bytes
+        };
+        if raw.is_empty() {
+            return Err(BusStatus::DataErr);
+        }
+        match SubStatus::from(raw[0]) {
+            SubStatus::Ok => {}
+            SubStatus::BadIndex => return Err(BusStatus::Rejected),
+            SubStatus::BadCommand => return Err(BusStatus::Unsupported),
+        }
+        parse_response(cmd, raw[1..].to_vec())
+    }
+
+    // Every sensor on the node with its name and channels, asked once then
+    // cached. A node without sub-devices is listed as one.
+    pub fn list_sub_devices(&mut self, bus: &mut dyn Bus, node: u8) -> Result<Vec<SubDevice>, BusStatus> {
+        for (n, list) in self.sub_devices.iter() {
+            if *n == node {
+                return Ok(list.clone());
+            }
+        }
+
+        let list = match self.sub_device_count(bus, node) {
+            Ok(count) => {
+                let mut list: Vec<SubDevice> = vec![];
+                for sub in 0..count {
+                    let name = self.send_sub_command(bus, node, sub, &ControllerCommand::NameRequest, &[])?.name;
+                    let channels = self.sub_channels(bus, node, sub)?;
+                    list.push(SubDevice { index: sub, name, channels });
+                }
+                list
+            }
+            Err(BusStatus::Unsupported) => {
+                let name = self.send_bus_command(bus, node, &ControllerCommand::NameRequest, String::new())?.name;
+                let channels = self.list_channels(bus, node)?;
+                vec![SubDevice { index: 0, name, channels }]
+            }
+            Err(e) => return Err(e),
+        };
+        self.sub_devices.push((node, list.clone()));
+        Ok(list)
+    }
+
+    // The channel descriptors of one sub-device.
+    fn sub_channels(&mut self, bus: &mut dyn Bus, node: u8, sub: u8) -> Result<Vec<ChannelDescriptor>, BusStatus> {
+        let ret = self.send_sub_command(bus, node, sub, &ControllerCommand::ChannelDescRequest, &[0])?;
+        let (first, count) = decode_descriptor(&ret.raw_bytes)?;
+
+        let mut list = vec![first];
+        for idx in 1..count {
+            let ret = self.send_sub_command(bus, node, sub, &ControllerCommand::ChannelDescRequest, &[idx])?;
+            list.push(decode_descriptor(&ret.raw_bytes)?.0);
+        }
+        Ok(list)
+    }
+
+    // The old way, splitting FormattingRequest/DnamesRequest on spaces.
+    fn channels_from_strings(&mut self, bus: &mut dyn Bus, node: u8) -> Result<Vec<ChannelDescriptor>, BusStatus> {
+        let formats = self.send_bus_command(bus, node, &ControllerCommand::FormattingRequest, String::new())?;
+        let names = self.send_bus_command(bus, node, &ControllerCommand::DnamesRequest, String::new())?;
+        let formats = formats.format.join(" ");
+        let names = names.data_names.join(" ");
+
+        let mut list: Vec<ChannelDescriptor> = vec![];
+        while let Some(desc) = channels::from_strings(&formats, &names, list.len() as u8) {
+            list.push(desc);
+        }
+        Ok(list)
+    }
+
+    // The type of one channel, from the cached descriptors.
+    fn channel_format(&mut self, bus: &mut dyn Bus, node: u8, channel: u8) -> Result<String, BusStatus> {
+        let list = self.list_channels(bus, node)?;
+        match list.get(channel as usize) {
+            Some(desc) => Ok(String::from(desc.channel_type.format())),
+            None => Err(BusStatus::Rejected),
+        }
+    }
+
+    // The type a parameter's value has to be, for values typed in as text.
+    pub fn param_type(&mut self, bus: &mut dyn Bus, node: u8, name: &str) -> Result<ParamType, BusStatus> {
+        Ok(self.find_param(bus, node, name)?.param_type)
+    }
+
+    // Looks the name up in the cached list, fetching the list if needed.
+    fn find_param(&mut self, bus: &mut dyn Bus, node: u8, name: &str) -> Result<ParamInfo, BusStatus> {
+        if !self.params.iter().any(|(n, _)| *n == node) {
+            self.list_params(bus, node)?;
+        }
+
+        for (n, list) in self.params.iter() {
+            if *n != node {
+                continue;
+            }
+            for info in list.iter() {
+                if info.name == name {
+                    return Ok(info.clone());
+                }
+            }
+        }
+        Err(BusStatus::Unsupported)
+    }
+
+    // Reads everything waiting on the bus, keeping the frames modules send
+    // on their own(announcements, heartbeats). Those that show up while
+    // waiting on a reply are kept too.
+    pub fn poll_bus(&mut self, bus: &mut dyn Bus) {
+        for _ in 0..MAX_POLL_FRAMES {
+            let (rx_id, data) = match bus.receive_message() {
+                Ok(msg) => msg,
+                Err(_e) => break,
+            };
+            if let Ok(id) = CanId::from_raw(rx_id) {
+                self.note_unsolicited(&id, &data);
+            }
+        }
+    }
+
+    // Polls the bus and returns the serials of the modules that have asked
+    // for an address.
+    pub fn collect_announcements(&mut self, bus: &mut dyn Bus) -> Vec<u64> {
+        self.poll_bus(bus);
+        self.announced.clone()
+    }
+
+    // The (node, heartbeat) pairs received since the last call, oldest first.
+    pub fn take_heartbeats(&mut self) -> Vec<(u8, Heartbeat)> {
+        core::mem::take(&mut self.heartbeats)
+    }
+
+    // The (node, alarm) pairs received since the last call, oldest first.
+    pub fn take_alarms(&mut self) -> Vec<(u8, Alarm)> {
+        core::mem::take(&mut self.alarms)
+    }
+
+    // Gives every module that has announced itself an address. A serial we
+    // have seen before gets its old address back, so a module that lost
+    // its stored address doesn't move. Returns the (serial, node) pairs.
+    pub fn assign_addresses(&mut self, bus: &mut dyn Bus) -> Result<Vec<(u64, u8)>, BusStatus> {
+        self.collect_announcements(bus);
+
+        let mut assigned: Vec<(u64, u8)> = vec![];
+        while let Some(serial) = self.announced.first().copied() {
+            let node = match self.node_for_serial(serial) {
+                Some(n) => n,
+                None => self.free_address(bus)?,
+            };
+            self.assign(bus, UNASSIGNED_NODE, serial, node)?;
+            self.announced.retain(|s| *s != serial);
+            assigned.push((serial, node));
+        }
+        Ok(assigned)
+    }
+
+    // Serials of every module answering on the node. More than one means
+    // two modules ended up with the same address. Modules without a serial
+    // show up as 0.
+    pub fn identify(&mut self, bus: &mut dyn Bus, node: u8) -> Result<Vec<u64>, BusStatus> {
+        let seq = self.send_request_with_args(bus, node, &ControllerCommand::IdentifyRequest, &[])?;
+        self.outstanding.retain(|o| !(o.node == node && o.seq == seq));
+
+        /* Unlike `receive_response` we keep going after the first reply,
+         * every module on the address answers. */
+        let mut serials: Vec<u64> = vec![];
+        for _ in 0..MAX_RX_ATTEMPTS {
+            let (rx_id, mut data) = match bus.receive_message() {
+                Ok(msg) => msg,
+                Err(_e) => break,
+            };
+            let rx_id = match CanId::from_raw(rx_id) {
+                Ok(id) => id,
+                Err(_e) => continue,
+            };
+            if self.note_unsolicited(&rx_id, &data) {
+                continue;
+            }
+            if rx_id.msg_type != MessageType::Response || rx_id.source != node {
+                continue;
+            }
+            if rx_id.crc.strip(&mut data).is_err() || data.len() < 2 || data[0] != seq {
+                continue;
+            }
+
+            let serial = match AddrStatus::from(data[1]) {
+                AddrStatus::Ok => match addressing::decode_serial(&data[2..]) {
+                    Ok(s) => s,
+                    Err(_e) => return Err(BusStatus::DataErr),
+                },
+                _ => 0,
+            };
+            if serial != 0 {
+                self.remember_address(serial, node);
+            }
+            serials.push(serial);
+        }
+        Ok(serials)
+    }
+
+    // Moves every module but one off an address they are sharing. Returns
+    // the (serial, node) pairs of the ones that moved.
+    pub fn resolve_conflicts(&mut self, bus: &mut dyn Bus, node: u8) -> Result<Vec<(u64, u8)>, BusStatus> {
+        let serials = self.identify(bus, node)?;
+
+        let mut moved: Vec<(u64, u8)> = vec![];
+        for serial in serials.iter().skip(1) {
+            if *serial == 0 {
+                // No serial, nothing we can address it by.
+                return Err(BusStatus::Unsupported);
+            }
+            let new_node = self.free_address(bus)?;
+            self.assign(bus, node, *serial, new_node)?;
+            moved.push((*serial, new_node));
+        }
+        Ok(moved)
+    }
+
+    // The address handed to a serial, if we know it.
+    pub fn node_for_serial(&self, serial: u64) -> Option<u8> {
+        for (s, node) in self.addresses.iter() {
+            if *s == serial {
+                return Some(*node);
+            }
+        }
+        None
+    }
+
+    // The lowest address nobody has been given and nobody answers on.
+    fn free_address(&mut self, bus: &mut dyn Bus) -> Result<u8, BusStatus> {
+        for node in 1..UNASSIGNED_NODE {
+            if self.addresses.iter().any(|(_, n)| *n == node) {
+                continue;
+            }
+            if self.identify(bus, node)?.is_empty() {
+                return Ok(node);
+            }
+        }
+        Err(BusStatus::Busy)
+    }
+
+    // Sends the module with the serial(listening on `at`) its new address.
+    fn assign(&mut self, bus: &mut dyn Bus, at: u8, serial: u64, node: u8) -> Result<(), BusStatus> {
+        let mut args: Vec<u8> = serial.to_be_bytes().to_vec();
+        args.push(node);
+        let ret = self.send_command_with_args(bus, at, &ControllerCommand::AddressAssignRequest, &args)?;
+        if ret.raw_bytes.is_empty() {
+            return Err(BusStatus::DataErr);
+        }
+
+        match AddrStatus::from(ret.raw_bytes[0]) {
+            AddrStatus::Ok | AddrStatus::NotSaved => {
+                // Anything we knew about the old address is stale now.
+                self.forget_node(node);
+                self.remember_address(serial, node);
+                Ok(())
+            }
+            AddrStatus::NoSerial => Err(BusStatus::Unsupported),
+            AddrStatus::BadAddress => Err(BusStatus::Rejected),
+        }
+    }
+
+    fn remember_address(&mut self, serial: u64, node: u8) {
+        self.addresses.retain(|(s, _)| *s != serial);
+        self.addresses.push((serial, node));
+    }
+
+    fn forget_node(&mut self, node: u8) {
+        self.capabilities.retain(|(n, _)| *n != node);
+        self.params.retain(|(n, _)| *n != node);
+        self.descriptors.retain(|(n, _)| *n != node);
+        self.channels.retain(|(n, _)| *n != node);
+        self.sub_devices.retain(|(n, _)| *n != node);
+    }
+
+    // Keeps frames that aren't replies to anything, returns false for
+    // anything else.
+    fn note_unsolicited(&mut self, id: &CanId, data: &[u8]) -> bool {
+        match id.msg_type {
+            MessageType::Announce => {
+                if let Ok(serial) = addressing::decode_serial(data) {
+                    if !self.announced.contains(&serial) {
+                        self.announced.push(serial);
+                    }
+                }
+            }
+            MessageType::Heartbeat => {
+                if let Ok(hb) = Heartbeat::from_bytes(data) {
+                    if self.heartbeats.len() >= MAX_HEARTBEATS {
+                        self.heartbeats.remove(0);
+                    }
+                    self.heartbeats.push((id.source, hb));
+                }
+            }
+            MessageType::Alarm => {
+                if let Ok(alarm) = Alarm::from_bytes(data) {
+                    if self.alarms.len() >= MAX_ALARMS {
+                        self.alarms.remove(0);
+                    }
+                    self.alarms.push((id.source, alarm));
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Sets the crc trailer used when talking to a node. Plain CAN already
+    // has a crc so links default to `CrcMode::None`.
+    pub fn set_crc_mode(&mut self, node: u8, mode: CrcMode) -> Result<(), BusStatus> {
+        if mode == CrcMode::Unknown {
+            return Err(BusStatus::Error);
+        }
+        if let Some(caps) = self.capabilities(node) {
+            if mode != CrcMode::None && !caps.has_feature(FEATURE_CRC) {
+                return Err(BusStatus::Unsupported);
+            }
+        }
+
+        self.crc_modes.retain(|(n, _)| *n != node);
+        if mode != CrcMode::None {
+            self.crc_modes.push((node, mode));
+        }
+        Ok(())
+    }
+
+    pub fn crc_mode(&self, node: u8) -> CrcMode {
+        for (n, mode) in self.crc_modes.iter() {
+            if *n == node {
+                return *mode;
+            }
+        }
+        CrcMode::None
+    }
+
+    // Sends the request and waits for the matching reply.
+    pub fn send_bus_command(
+        &mut self,
+        bus: &mut dyn Bus,
+        node: u8,
+        cmd: &ControllerCommand,
+        dname: String) -> Result<CmdReturn,BusStatus>
+    {
+        let seq = self.send_request(bus, node, cmd, dname)?;
+        self.receive_response(bus, node, seq)
+    }
+
+    // Sends the request without waiting, returns the sequence number to
+    // hand to `receive_response`. Several requests can be in flight at once.
+    pub fn send_request(
+        &mut self,
+        bus: &mut dyn Bus,
+        node: u8,
+        cmd: &ControllerCommand,
+        dname: String) -> Result<u8,BusStatus>
+    {
+        let mut args: Vec<u8> = vec![];
+        if *cmd == ControllerCommand::DataRequest {
             for byte in dname.into_bytes().iter() {
-                data.push(*byte);
+                args.push(*byte);
             }
         }
+        self.send_request_with_args(bus, node, cmd, &args)
     }
-    
-    let result = bus.send_message(CRONTROLLER_ID, &data);
-    if result.is_err() {
-        return Err(BusStatus::Error);
+
+    // Same as `send_bus_command` but with the raw argument bytes that go
+    // after the header.
+    pub fn send_command_with_args(
+        &mut self,
+        bus: &mut dyn Bus,
+        node: u8,
+        cmd: &ControllerCommand,
+        args: &[u8]) -> Result<CmdReturn,BusStatus>
+    {
+        let seq = self.send_request_with_args(bus, node, cmd, args)?;
+        if segment::is_segmented(*cmd) {
+            let data = self.receive_segments(bus, node, seq, cmd, 0)?;
+            return parse_response(cmd, data);
+        }
+        self.receive_response(bus, node, seq)
     }
 
-    
-    /* Now we try to get the response from the bus */
-    let result = bus.receive_message();
-    if result.is_err() {
+    // Collects every segment of a reply and puts the data back together.
+    // `prefix` is how many bytes come before the segment header, those are
+    // kept from the first segment. A reply with nothing past the prefix is
+    // an error status and is passed on as it is.
+    fn receive_segments(
+        &mut self,
+        bus: &mut dyn Bus,
+        node: u8,
+        seq: u8,
+        cmd: &ControllerCommand,
+        prefix: usize) -> Result<Vec<u8>,BusStatus>
+    {
+        let mut data: Vec<u8> = vec![];
+        let mut next: u8 = 0;
+        loop {
+            let ret = self.receive_response(bus, node, seq)?;
+            if ret.raw_bytes.len() == prefix && prefix > 0 && next == 0 {
+                return Ok(ret.raw_bytes);
+            }
+            if ret.raw_bytes.len() < prefix {
+                return Err(BusStatus::DataErr);
+            }
+            //the module couldn't fit the reply into segments.
+            if ret.raw_bytes[prefix..] == segment::SEGMENTS_FAILED {
+                return Err(BusStatus::Error);
+            }
+
+            let (index, count, chunk) = match segment::parse_segment(&ret.raw_bytes[prefix..]) {
+                Ok(s) => s,
+                Err(_e) => return Err(BusStatus::DataErr),
+            };
+            if index != next {
+                return Err(BusStatus::DataErr);
+            }
+            if next == 0 {
+                data.extend_from_slice(&ret.raw_bytes[..prefix]);
+            }
+            data.extend_from_slice(chunk);
+
+            next += 1;
+            if next == count {
+                return Ok(data);
+            }
+            //the rest come with the same seq.
+            self.outstanding.push(Outstanding { node, seq, cmd: *cmd });
+        }
+    }
+
+    // Same as `send_request` but with the raw argument bytes that go
+    // after the header.
+    pub fn send_request_with_args(
+        &mut self,
+        bus: &mut dyn Bus,
+        node: u8,
+        cmd: &ControllerCommand,
+        args: &[u8]) -> Result<u8,BusStatus>
+    {
+        self.send_code(bus, node, cmd, *cmd as u8, args)
+    }
+
+    // Runs the node's self tests and gives back what each one found.
+    pub fn run_self_test(&mut self, bus: &mut dyn Bus, node: u8) -> Result<SelfTestReport, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::SelfTestRequest, &[])?;
+        decode_self_test(&ret.raw_bytes)
+    }
+
+    // Same for one sensor of a multi-sensor node.
+    pub fn run_sub_self_test(&mut self, bus: &mut dyn Bus, node: u8, sub: u8) -> Result<SelfTestReport, BusStatus> {
+        let ret = self.send_sub_command(bus, node, sub, &ControllerCommand::SelfTestRequest, &[])?;
+        decode_self_test(&ret.raw_bytes)
+    }
+
+    // Sends a vendor command, `code` is VENDOR_COMMAND_BASE or above. The
+    // reply is whatever the module's handler gave back.
+    pub fn send_vendor_command(&mut self, bus: &mut dyn Bus, node: u8, code: u8, args: &[u8]) -> Result<Vec<u8>, BusStatus> {
+        if !vendor::is_vendor(code) {
+            return Err(BusStatus::Rejected);
+        }
+
+        let seq = self.send_code(bus, node, &ControllerCommand::VendorRequest, code, args)?;
+        let ret = self.receive_response(bus, node, seq)?;
+        if ret.raw_bytes.is_empty() {
+            return Err(BusStatus::DataErr);
+        }
+        match VendorStatus::from(ret.raw_bytes[0]) {
+            VendorStatus::Ok => Ok(ret.raw_bytes[1..].to_vec()),
+            VendorStatus::Unknown => Err(BusStatus::Unsupported),
+            VendorStatus::BadArgs => Err(BusStatus::Rejected),
+            VendorStatus::Failed => Err(BusStatus::Error),
+        }
+    }
+
+    // Sets the alarm rule on one of the node's channels, replacing the one
+    // it had. The limits are in raw units.
+    pub fn set_alarm(&mut self, bus: &mut dyn Bus, node: u8, rule: &AlarmRule) -> Result<(), BusStatus> {
+        let mut args: Vec<u8> = vec![];
+        rule.encode(&mut args);
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::AlarmSetRequest, &args)?;
+        check_alarm_status(&ret.raw_bytes)
+    }
+
+    // The rule on a channel and the level the module has it at.
+    pub fn get_alarm(&mut self, bus: &mut dyn Bus, node: u8, channel: u8) -> Result<(AlarmRule, AlarmLevel), BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::AlarmGetRequest, &[channel])?;
+        check_alarm_status(&ret.raw_bytes)?;
+        let b = &ret.raw_bytes[1..];
+        let rule = match AlarmRule::decode(b) {
+            Ok(r) => r,
+            Err(_e) => return Err(BusStatus::DataErr),
+        };
+        match b.get(alarm::RULE_LEN) {
+            Some(level) => Ok((rule, AlarmLevel::from(*level))),
+            None => Err(BusStatus::DataErr),
+        }
+    }
+
+    pub fn clear_alarm(&mut self, bus: &mut dyn Bus, node: u8, channel: u8) -> Result<(), BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::AlarmClearRequest, &[channel])?;
+        check_alarm_status(&ret.raw_bytes)
+    }
+
+    // Gets the node ready to start sampling when `trigger` goes out.
+    pub fn arm_acquisition(&mut self, bus: &mut dyn Bus, node: u8, trigger: u8) -> Result<AcqState, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::AcqArmRequest, &[trigger])?;
+        decode_acq_state(&ret.raw_bytes)
+    }
+
+    // Starts every node armed with `trigger` at once with one broadcast
+    // frame. The modules don't answer it, check them with
+    // `acquisition_state` afterwards if need be.
+    pub fn trigger_acquisition(&mut self, bus: &mut dyn Bus, trigger: u8) -> Result<(), BusStatus> {
+        let mut data: Vec<u8> = vec![ControllerCommand::AcqTriggerRequest as u8, self.next_seq, trigger];
+        let crc = self.crc_mode(BROADCAST_NODE);
+        crc.append(&mut data);
+
+        let tx_id = match CanId::new(DEFAULT_PRIORITY, MessageType::Broadcast, BROADCAST_NODE, CONTROLLER_NODE)
+            .with_crc(crc)
+            .to_raw() {
+            Ok(id) => id,
+            Err(_e) => return Err(BusStatus::Error),
+        };
+        if bus.send_message(tx_id, &data).is_err() {
+            return Err(BusStatus::Error);
+        }
+        self.next_seq = self.next_seq.wrapping_add(1);
+        Ok(())
+    }
+
+    // Triggers one node on its own, answered unlike the broadcast.
+    pub fn trigger_node(&mut self, bus: &mut dyn Bus, node: u8, trigger: u8) -> Result<AcqState, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::AcqTriggerRequest, &[trigger])?;
+        decode_acq_state(&ret.raw_bytes)
+    }
+
+    // Stops sampling, or disarms a node that hasn't been triggered.
+    pub fn stop_acquisition(&mut self, bus: &mut dyn Bus, node: u8) -> Result<AcqState, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::AcqStopRequest, &[])?;
+        decode_acq_state(&ret.raw_bytes)
+    }
+
+    pub fn acquisition_state(&mut self, bus: &mut dyn Bus, node: u8) -> Result<AcqState, BusStatus> {
+        let ret = self.send_command_with_args(bus, node, &ControllerCommand::AcqStateRequest, &[])?;
+        decode_acq_state(&ret.raw_bytes)
+    }
+
+    // `code` is the command byte that goes out, the same as `cmd` apart from
+    // vendor commands.
+    fn send_code(
+        &mut self,
+        bus: &mut dyn Bus,
+        node: u8,
+        cmd: &ControllerCommand,
+        code: u8,
+        args: &[u8]) -> Result<u8,BusStatus>
+    {
+        let seq = self.next_seq;
+        let mut data: Vec<u8> = vec![]; // Vec::with_capacity(SEND_BUFFER_BYTES);
+
+        data.push(code);
+        data.push(seq);
+        data.extend_from_slice(args);
+
+        let crc = self.crc_mode(node);
+        crc.append(&mut data);
+
+        /* Don't bother sending what we already know the node can't handle.
+         * Nodes we haven't asked yet get the benefit of the doubt. */
+        if let Some(caps) = self.capabilities(node) {
+            let vendor = *cmd == ControllerCommand::VendorRequest;
+            if !vendor && !caps.supports(*cmd) {
+                return Err(BusStatus::Unsupported);
+            }
+            if data.len() > caps.max_payload as usize {
+                return Err(BusStatus::Unsupported);
+            }
+        }
+
+        let tx_id = match CanId::request(node).with_crc(crc).to_raw() {
+            Ok(id) => id,
+            Err(_e) => return Err(BusStatus::Error),
+        };
+
+        let result = bus.send_message(tx_id, &data);
+        if result.is_err() {
+            return Err(BusStatus::Error);
+        }
+
+        self.next_seq = self.next_seq.wrapping_add(1);
+        //the seq has wrapped, anything left from its last use isn't ours.
+        self.outstanding.retain(|o| !(o.node == node && o.seq == seq));
+        self.stashed.retain(|s| !(s.node == node && s.seq == seq));
+        self.outstanding.push(Outstanding { node, seq, cmd: *cmd });
+        Ok(seq)
+    }
+
+    // Waits for the reply to an earlier `send_request`. Replies to other
+    // outstanding requests are kept for later, anything else is dropped.
+    #[allow(clippy::needless_return)]
+    pub fn receive_response(
+        &mut self,
+        bus: &mut dyn Bus,
+        node: u8,
+        seq: u8) -> Result<CmdReturn,BusStatus>
+    {
+        let pos = self.outstanding.iter().position(|o| o.node == node && o.seq == seq);
+        let cmd = match pos {
+            Some(i) => self.outstanding.remove(i).cmd,
+            None => return Err(BusStatus::Error),
+        };
+
+        /* It may have shown up while we were waiting on something else. */
+        if let Some(i) = self.stashed.iter().position(|s| s.node == node && s.seq == seq) {
+            let stashed = self.stashed.remove(i);
+            return parse_response(&cmd, stashed.data);
+        }
+
+        /* Now we try to get the response from the bus */
+        for _ in 0..MAX_RX_ATTEMPTS {
+            let result = bus.receive_message();
+            if result.is_err() {
+                return Err(BusStatus::Error);
+            }
+
+            let rx_id: u32;
+            let mut data: Vec<u8>;
+            (rx_id, data) = result.ok().unwrap();
+
+            let rx_id = match CanId::from_raw(rx_id) {
+                Ok(id) if id.msg_type == MessageType::Response => id,
+                Ok(id) => {
+                    self.note_unsolicited(&id, &data);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            /* A reply from the node we're waiting on has to use the link's
+             * crc, and a bad crc means we can't trust the seq either. */
+            let ours = rx_id.source == node;
+            if ours && rx_id.crc != self.crc_mode(node) {
+                return Err(BusStatus::DataErr);
+            }
+            if rx_id.crc.strip(&mut data).is_err() {
+                if ours {
+                    return Err(BusStatus::DataErr);
+                }
+                continue;
+            }
+
+            if data.is_empty() {
+                continue;
+            }
+            let rx_seq = data.remove(0);
+
+            if ours && rx_seq == seq {
+                return parse_response(&cmd, data);
+            }
+
+            let expected = self.outstanding.iter()
+                .any(|o| o.node == rx_id.source && o.seq == rx_seq);
+            if expected {
+                //replies to requests nobody will wait on now are let go.
+                let outstanding = &self.outstanding;
+                self.stashed.retain(|s| outstanding.iter().any(|o| o.node == s.node && o.seq == s.seq));
+                if self.stashed.len() >= MAX_STASHED {
+                    self.stashed.remove(0);
+                }
+                self.stashed.push(Stashed { node: rx_id.source, seq: rx_seq, data });
+            }
+        }
+
         return Err(BusStatus::Error);
     }
-    
-    let _id: u32;
-    let data: Vec<u8>;
-    (_id, data) = result.ok().unwrap();
+}
+
+
+// Turns the payload of a reply(sequence number already removed) into
+// a `CmdReturn` based on what was asked for.
+#[allow(clippy::needless_return)]
+fn parse_response(cmd: &ControllerCommand, data: Vec<u8>) -> Result<CmdReturn,BusStatus> {
+    let mut ret = CmdReturn::new();
 
     match cmd {
         ControllerCommand::NameRequest => {
@@ -71,27 +1125,28 @@ pub fn send_bus_command(
                 Err(_e) => return Err(BusStatus::DataErr),
             };
         }
-        ControllerCommand::StatusRequest => {
-            ret.data_names.push(String::from("Status"));
-            ret.format.push(String::from("u8"));
-            ret.raw_bytes.push(data[0]);
-        }
+        ControllerCommand::StatusRequest |
         ControllerCommand::ResetRequest => {
+            let health = match SensorHealth::decode(&data) {
+                Some(h) => h,
+                None => return Err(BusStatus::DataErr),
+            };
             ret.data_names.push(String::from("Status"));
             ret.format.push(String::from("u8"));
             ret.raw_bytes.push(data[0]);
+            ret.health = Some(health);
         }
         ControllerCommand::FormattingRequest => {
             ret.raw_bytes = data;
             let res = ret.parse_raw_to_format();
-            if ! res.is_ok() {
+            if res.is_err() {
                 return Err(BusStatus::DataErr);
             }
         }
         ControllerCommand::DnamesRequest => {
             ret.raw_bytes = data;
             let res = ret.parse_raw_to_dnames();
-            if ! res.is_ok() {
+            if res.is_err() {
                 return Err(BusStatus::DataErr);
             }
         }
@@ -99,9 +1154,155 @@ pub fn send_bus_command(
             //just copy the raw_data over in this case.
             ret.raw_bytes = data;
         }
+        ControllerCommand::VersionRequest => {
+            if data.len() < 2 {
+                return Err(BusStatus::DataErr);
+            }
+            ret.data_names.push(String::from("Major"));
+            ret.data_names.push(String::from("Minor"));
+            ret.format.push(String::from("u8"));
+            ret.format.push(String::from("u8"));
+            ret.raw_bytes = data;
+        }
+        ControllerCommand::CapabilitiesRequest |
+        ControllerCommand::ParamCountRequest |
+        ControllerCommand::ParamInfoRequest |
+        ControllerCommand::ParamGetRequest |
+        ControllerCommand::ParamSetRequest |
+        ControllerCommand::ConfigSaveRequest |
+        ControllerCommand::ConfigLoadRequest |
+        ControllerCommand::FactoryResetRequest |
+        ControllerCommand::FwBeginRequest |
+        ControllerCommand::FwWriteRequest |
+        ControllerCommand::FwVerifyRequest |
+        ControllerCommand::FwCommitRequest |
+        ControllerCommand::BlockPrepareRequest |
+        ControllerCommand::BlockInfoRequest |
+        ControllerCommand::BlockReadRequest |
+        ControllerCommand::BlockAckRequest |
+        ControllerCommand::AcqArmRequest |
+        ControllerCommand::AcqTriggerRequest |
+        ControllerCommand::AcqStopRequest |
+        ControllerCommand::AcqStateRequest |
+        ControllerCommand::AlarmSetRequest |
+        ControllerCommand::AlarmGetRequest |
+        ControllerCommand::AlarmClearRequest |
+        ControllerCommand::IdentifyRequest |
+        ControllerCommand::AddressAssignRequest |
+        ControllerCommand::TimeSyncRequest |
+        ControllerCommand::TimeAdjustRequest |
+        ControllerCommand::TimedDataRequest |
+        ControllerCommand::ChannelMetaRequest |
+        ControllerCommand::ChannelDescRequest |
+        ControllerCommand::SubDeviceCountRequest |
+        ControllerCommand::SubDeviceRequest |
+        ControllerCommand::SelfTestRequest |
+        ControllerCommand::LargeDataRequest |
+        ControllerCommand::VendorRequest => {
+            //decoded by the helper that sent it.
+            ret.raw_bytes = data;
+        }
+        ControllerCommand::UnknownCommand => return Err(BusStatus::Unsupported),
+    }
+    //println!("ret: {:?}", ret);
+    return Ok(ret);
+}
+
+
+fn decode_self_test(b: &[u8]) -> Result<SelfTestReport, BusStatus> {
+    match b.first().map(|s| SelfTestStatus::from(*s)) {
+        Some(SelfTestStatus::Ok) => {}
+        Some(SelfTestStatus::NotSupported) => return Err(BusStatus::Unsupported),
+        None => return Err(BusStatus::DataErr),
+    }
+    match SelfTestReport::decode(b) {
+        Ok(r) => Ok(r),
+        Err(_e) => Err(BusStatus::DataErr),
+    }
+}
+
+
+fn check_alarm_status(b: &[u8]) -> Result<(), BusStatus> {
+    match b.first().map(|s| AlarmStatus::from(*s)) {
+        Some(AlarmStatus::Ok) => Ok(()),
+        Some(AlarmStatus::NotSupported) => Err(BusStatus::Unsupported),
+        Some(_) => Err(BusStatus::Rejected),
+        None => Err(BusStatus::DataErr),
+    }
+}
+
+
+fn decode_acq_state(b: &[u8]) -> Result<AcqState, BusStatus> {
+    match b.first().map(|s| AcqStatus::from(*s)) {
+        Some(AcqStatus::Ok) => {}
+        Some(AcqStatus::NotSupported) => return Err(BusStatus::Unsupported),
+        Some(AcqStatus::BadState) => return Err(BusStatus::Rejected),
+        Some(AcqStatus::Failed) => return Err(BusStatus::Error),
+        None => return Err(BusStatus::DataErr),
+    }
+    match AcqState::decode(b) {
+        Ok(state) => Ok(state),
+        Err(_e) => Err(BusStatus::DataErr),
+    }
+}
+
+
+// A ChannelDescRequest reply, gives the descriptor and the channel count.
+fn decode_descriptor(b: &[u8]) -> Result<(ChannelDescriptor, u8), BusStatus> {
+    if b.is_empty() {
+        return Err(BusStatus::DataErr);
+    }
+    if channels::DescStatus::from(b[0]) != channels::DescStatus::Ok {
+        return Err(BusStatus::Rejected);
+    }
+    match ChannelDescriptor::decode(b) {
+        Ok(d) => Ok(d),
+        Err(_e) => Err(BusStatus::DataErr),
+    }
+}
+
+
+// First byte of the time replies.
+fn check_time_status(data: &[u8]) -> Result<(), BusStatus> {
+    if data.is_empty() {
+        return Err(BusStatus::DataErr);
+    }
+
+    match TimeStatus::from(data[0]) {
+        TimeStatus::Ok => Ok(()),
+        TimeStatus::NoClock => Err(BusStatus::Unsupported),
+        TimeStatus::BadIndex => Err(BusStatus::Rejected),
+        TimeStatus::BadArgs => Err(BusStatus::Rejected),
+    }
+}
+
+
+// First byte of every parameter reply.
+fn check_param_status(data: &[u8]) -> Result<(), BusStatus> {
+    if data.is_empty() {
+        return Err(BusStatus::DataErr);
+    }
+
+    match ParamStatus::from(data[0]) {
+        ParamStatus::Ok => Ok(()),
+        ParamStatus::BadIndex | ParamStatus::ReadOnly => Err(BusStatus::Unsupported),
+        _ => Err(BusStatus::Rejected),
+    }
+}
+
+
+// First byte of every save/load/factory reset reply.
+fn check_store_status(data: &[u8]) -> Result<(), BusStatus> {
+    if data.is_empty() {
+        return Err(BusStatus::DataErr);
+    }
+
+    match StoreStatus::from(data[0]) {
+        StoreStatus::Ok => Ok(()),
+        StoreStatus::NoStore => Err(BusStatus::Unsupported),
+        StoreStatus::Empty | StoreStatus::TooBig => Err(BusStatus::Rejected),
+        StoreStatus::Failed => Err(BusStatus::Error),
     }
-    //println!("ret: {:?}", ret);
-    return Ok(ret);
 }
 
 
@@ -110,15 +1311,33 @@ mod controller_tests {
     use super::*;
     use crate::fake_sensor::ExampleSensor;
     use crate::fake_bus::FakeBus;
+    use crate::fake_bus::LoopbackBus;
+    use crate::fake_bus::SharedBus;
+    use crate::fake_bus::MultiBus;
+    use crate::params::ParamType;
     use crate::SensorData;
     use crate::fake_sensor::SENSOR_NAME;
+    use crate::fake_sensor::DEFAULT_PARAMS;
     use crate::SensorStatus;
+    use crate::UNKNOWN_COMMAND_REPLY;
+
+    const NODE: u8 = 0x01;
+
+    // A fresh controller always starts counting from zero.
+    const FIRST_SEQ: u8 = 0;
+
+    fn reply(d: &[u8]) -> Vec<u8> {
+        let mut r: Vec<u8> = vec![FIRST_SEQ];
+        r.extend_from_slice(d);
+        r
+    }
 
 
     #[allow(dead_code)]
     struct TestData{
         sens: ExampleSensor,
         bus: FakeBus,
+        ctrl: Controller,
     }
 
     #[allow(dead_code)]
@@ -133,6 +1352,12 @@ mod controller_tests {
                 data_types: ["u8", "u16", "u16"],
                 data_names: ["Status", "Temp", "Humid"],
                 data: sd,
+                params: DEFAULT_PARAMS,
+                store: None,
+                flash: None,
+                serial: None,
+                addr_store: None,
+                clock: None,
         };
 
         let fake_bus = FakeBus::new();
@@ -140,12 +1365,15 @@ mod controller_tests {
         let mut td = TestData{
             sens: fake_sensor,
             bus: fake_bus,
+            ctrl: Controller::new(),
         };
         td.bus.auto_response = true;
+        td.bus.set_rmsg_id(CanId::request(NODE).response_to().to_raw().unwrap());
         td
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn check_self() {
         assert!(true);
     }
@@ -156,16 +1384,16 @@ mod controller_tests {
 
         // preload the response into the msg buffer.
         let name_data: Vec<u8> = SENSOR_NAME.as_bytes().to_vec();
-        let set_res = td.bus.set_rmsg_data(&name_data);
+        let set_res = td.bus.set_rmsg_data(&reply(&name_data));
         assert!(set_res.is_ok());
 
         // send the controller command
         let dname: String = String::new(); 
-        let cmd_result = send_bus_command(&mut td.bus, &ControllerCommand::NameRequest, dname);
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::NameRequest, dname);
         assert!(cmd_result.is_ok());
 
         // now check the send data.
-        assert!(td.bus.spy_id() == 0);
+        assert!(td.bus.spy_id() == CanId::request(NODE).to_raw().unwrap());
         assert!(td.bus.spy_data()[0] == ControllerCommand::NameRequest as u8);
 
         // chcek the actual returned value.
@@ -179,15 +1407,15 @@ mod controller_tests {
 
         // Preload the response
         let status_data: Vec<u8> = vec![SensorStatus::Ready as u8]; 
-        assert!(td.bus.set_rmsg_data(&status_data).is_ok());
+        assert!(td.bus.set_rmsg_data(&reply(&status_data)).is_ok());
        
         // send the controller command
         let dname: String = String::new(); 
-        let cmd_result = send_bus_command(&mut td.bus, &ControllerCommand::StatusRequest, dname);
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::StatusRequest, dname);
         assert!(cmd_result.is_ok());
 
         // now check the send data.
-        assert!(td.bus.spy_id() == 0);
+        assert!(td.bus.spy_id() == CanId::request(NODE).to_raw().unwrap());
         assert!(td.bus.spy_data()[0] == ControllerCommand::StatusRequest as u8);
         
         // Check returned data.
@@ -203,15 +1431,15 @@ mod controller_tests {
 
         // Preload the response
         let reset_data: Vec<u8> = vec![SensorStatus::Busy as u8]; 
-        assert!(td.bus.set_rmsg_data(&reset_data).is_ok());
+        assert!(td.bus.set_rmsg_data(&reply(&reset_data)).is_ok());
 
         // Send the controller cmd
         let dname: String = String::new(); 
-        let cmd_result = send_bus_command(&mut td.bus, &ControllerCommand::ResetRequest, dname);
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::ResetRequest, dname);
         assert!(cmd_result.is_ok());
 
         // now check the send data.
-        assert!(td.bus.spy_id() == 0);
+        assert!(td.bus.spy_id() == CanId::request(NODE).to_raw().unwrap());
         assert!(td.bus.spy_data()[0] == ControllerCommand::ResetRequest as u8);
 
         // check returned data.
@@ -221,6 +1449,50 @@ mod controller_tests {
         assert_eq!(SensorStatus::Busy as u8, cmd_data.raw_bytes[0]); 
     }
 
+    #[test]
+    fn unknown_command_reply() {
+        let mut td = setup();
+
+        assert!(td.bus.set_rmsg_data(&reply(&[UNKNOWN_COMMAND_REPLY])).is_ok());
+
+        let res = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::UnknownCommand, String::new());
+        assert!(matches!(res, Err(BusStatus::Unsupported)));
+    }
+
+    #[test]
+    fn health_report() {
+        let mut td = setup();
+
+        // Temperature and voltage warnings together, with a vendor code.
+        let flags = crate::status::HEALTH_TEMPERATURE_WARNING | crate::status::HEALTH_VOLTAGE_WARNING;
+        let mut data: Vec<u8> = vec![SensorStatus::TempertureWarning as u8];
+        data.extend_from_slice(&flags.to_be_bytes());
+        data.extend_from_slice(&0x0042u16.to_be_bytes());
+        data.extend_from_slice(b"hot");
+        assert!(td.bus.set_rmsg_data(&reply(&data)).is_ok());
+
+        let health = td.ctrl.read_health(&mut td.bus, NODE).unwrap();
+        assert_eq!(health.status, SensorStatus::TempertureWarning);
+        assert_eq!(health.flags, flags);
+        assert_eq!(health.detail, 0x42);
+        assert_eq!(health.message, "hot");
+    }
+
+    #[test]
+    fn health_end_to_end() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        let health = ctrl.read_health(&mut bus, NODE).unwrap();
+        assert_eq!(health.status, SensorStatus::Ready);
+        assert!(health.is_healthy());
+
+        // The fake sensor is busy after a reset.
+        let health = ctrl.reset_node(&mut bus, NODE).unwrap();
+        assert!(health.has(crate::status::HEALTH_BUSY));
+    }
+
     #[test]
     fn formatting_request() {
         
@@ -228,15 +1500,15 @@ mod controller_tests {
 
         // Preload the response
         let format_data: Vec<u8> = String::from("u8 u16 u16").into_bytes(); 
-        assert!(td.bus.set_rmsg_data(&format_data).is_ok());
+        assert!(td.bus.set_rmsg_data(&reply(&format_data)).is_ok());
 
         // Send the controller cmd
         let dname: String = String::new(); 
-        let cmd_result = send_bus_command(&mut td.bus, &ControllerCommand::FormattingRequest, dname);
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::FormattingRequest, dname);
         assert!(cmd_result.is_ok());
 
         // now check the send data.
-        assert!(td.bus.spy_id() == 0);
+        assert!(td.bus.spy_id() == CanId::request(NODE).to_raw().unwrap());
         assert!(td.bus.spy_data()[0] == ControllerCommand::FormattingRequest as u8);
 
         // check returned data.
@@ -252,15 +1524,15 @@ mod controller_tests {
 
         // Preload the response.
         let data_names: Vec<u8> = String::from("Status Temp Humid").into_bytes();
-        assert!(td.bus.set_rmsg_data(&data_names).is_ok());
+        assert!(td.bus.set_rmsg_data(&reply(&data_names)).is_ok());
 
         // Send the controller cmd
         let dname: String = String::new(); 
-        let cmd_result = send_bus_command(&mut td.bus, &ControllerCommand::DnamesRequest, dname);
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::DnamesRequest, dname);
         assert!(cmd_result.is_ok());
 
         // Now check the sent data.
-        assert!(td.bus.spy_id() == 0);
+        assert!(td.bus.spy_id() == CanId::request(NODE).to_raw().unwrap());
         assert!(td.bus.spy_data()[0] == ControllerCommand::DnamesRequest as u8);
 
         // Check the returned data.
@@ -278,15 +1550,15 @@ mod controller_tests {
         // Preload the response.
         //let sensor_data: Vec<u8> = vec![0, 0, 255, 0, 255];
         let sensor_data: Vec<u8> = vec![0, 255];
-        assert!(td.bus.set_rmsg_data(&sensor_data).is_ok());
+        assert!(td.bus.set_rmsg_data(&reply(&sensor_data)).is_ok());
 
         // Send the controller cmd
         let dname: String = String::from("Temp");
-        let cmd_result = send_bus_command(&mut td.bus, &ControllerCommand::DataRequest, dname);
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::DataRequest, dname);
         assert!(cmd_result.is_ok());
 
         // Now check the sent data.
-        assert!(td.bus.spy_id() == 0);
+        assert!(td.bus.spy_id() == CanId::request(NODE).to_raw().unwrap());
         assert!(td.bus.spy_data()[0] == ControllerCommand::DataRequest as u8);
 
         // Check the returned data.
@@ -294,4 +1566,620 @@ mod controller_tests {
         assert_eq!(cmd_data.raw_bytes[0], sensor_data[0]);
         assert_eq!(cmd_data.raw_bytes[1], sensor_data[1]);
     }
+
+    #[test]
+    fn wrong_node_response() {
+        let mut td = setup();
+
+        // Preload a response that comes from some other module.
+        td.bus.set_rmsg_id(CanId::request(NODE + 1).response_to().to_raw().unwrap());
+        let name_data: Vec<u8> = SENSOR_NAME.as_bytes().to_vec();
+        assert!(td.bus.set_rmsg_data(&reply(&name_data)).is_ok());
+
+        let dname: String = String::new(); 
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::NameRequest, dname);
+        assert!(matches!(cmd_result, Err(BusStatus::Error)));
+    }
+
+    #[test]
+    fn sequence_in_request() {
+        let mut td = setup();
+        assert!(td.bus.set_rmsg_data(&reply(&[0])).is_ok());
+
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new());
+        assert!(cmd_result.is_ok());
+        assert_eq!(td.bus.spy_data()[1], FIRST_SEQ);
+
+        // The next request should use the next sequence number.
+        assert!(td.ctrl.send_request(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new()).is_ok());
+        assert_eq!(td.bus.spy_data()[1], FIRST_SEQ + 1);
+    }
+
+    #[test]
+    fn late_reply_discarded() {
+        let mut td = setup();
+        let resp_id = CanId::request(NODE).response_to().to_raw().unwrap();
+
+        // First request never gets answered in time.
+        let first = td.ctrl.send_request(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new()).unwrap();
+        assert!(td.ctrl.receive_response(&mut td.bus, NODE, first).is_err());
+
+        // Its reply then shows up ahead of the reply to the second request.
+        let second = td.ctrl.send_request(&mut td.bus, NODE, &ControllerCommand::NameRequest, String::new()).unwrap();
+        td.bus.queue_rmsg(resp_id, &[first, SensorStatus::Busy as u8]);
+        let mut name_reply: Vec<u8> = vec![second];
+        name_reply.extend_from_slice(SENSOR_NAME.as_bytes());
+        td.bus.queue_rmsg(resp_id, &name_reply);
+
+        let cmd_data = td.ctrl.receive_response(&mut td.bus, NODE, second).unwrap();
+        assert_eq!(cmd_data.name, SENSOR_NAME);
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let mut td = setup();
+        let resp_id = CanId::request(NODE).response_to().to_raw().unwrap();
+        let other_id = CanId::request(NODE + 1).response_to().to_raw().unwrap();
+
+        // Two requests in flight, to two different nodes.
+        let first = td.ctrl.send_request(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new()).unwrap();
+        let second = td.ctrl.send_request(&mut td.bus, NODE + 1, &ControllerCommand::ResetRequest, String::new()).unwrap();
+
+        // Replies come back in the opposite order.
+        td.bus.queue_rmsg(other_id, &[second, SensorStatus::Busy as u8]);
+        td.bus.queue_rmsg(resp_id, &[first, SensorStatus::Ready as u8]);
+
+        let first_data = td.ctrl.receive_response(&mut td.bus, NODE, first).unwrap();
+        assert_eq!(first_data.raw_bytes[0], SensorStatus::Ready as u8);
+
+        // The second reply was kept while waiting on the first.
+        let second_data = td.ctrl.receive_response(&mut td.bus, NODE + 1, second).unwrap();
+        assert_eq!(second_data.raw_bytes[0], SensorStatus::Busy as u8);
+    }
+
+    #[test]
+    fn stale_stash_dropped() {
+        let mut td = setup();
+
+        // A reply left over from the last time this seq was used.
+        td.ctrl.stashed.push(Stashed { node: NODE, seq: FIRST_SEQ, data: vec![SensorStatus::Busy as u8] });
+
+        assert!(td.bus.set_rmsg_data(&reply(&[SensorStatus::Ready as u8])).is_ok());
+        let seq = td.ctrl.send_request(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new()).unwrap();
+        assert_eq!(seq, FIRST_SEQ);
+        let data = td.ctrl.receive_response(&mut td.bus, NODE, seq).unwrap();
+        assert_eq!(data.raw_bytes[0], SensorStatus::Ready as u8);
+        assert!(td.ctrl.stashed.is_empty());
+    }
+
+    #[test]
+    fn stash_bounded() {
+        let mut td = setup();
+        let resp_id = CanId::request(NODE).response_to().to_raw().unwrap();
+        let other_id = CanId::request(NODE + 1).response_to().to_raw().unwrap();
+
+        // Already full of replies to requests still in flight, plus one
+        // nobody is waiting on any more.
+        td.ctrl.stashed.push(Stashed { node: NODE + 2, seq: 0, data: vec![] });
+        for seq in 0..MAX_STASHED as u8 {
+            td.ctrl.outstanding.push(Outstanding { node: NODE + 3, seq, cmd: ControllerCommand::StatusRequest });
+            td.ctrl.stashed.push(Stashed { node: NODE + 3, seq, data: vec![] });
+        }
+
+        let other = td.ctrl.send_request(&mut td.bus, NODE + 1, &ControllerCommand::StatusRequest, String::new()).unwrap();
+        let ours = td.ctrl.send_request(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new()).unwrap();
+        td.bus.queue_rmsg(other_id, &[other, SensorStatus::Busy as u8]);
+        td.bus.queue_rmsg(resp_id, &[ours, SensorStatus::Ready as u8]);
+        assert!(td.ctrl.receive_response(&mut td.bus, NODE, ours).is_ok());
+
+        // The orphan and then the oldest went, the new reply was kept.
+        assert_eq!(td.ctrl.stashed.len(), MAX_STASHED);
+        assert!(td.ctrl.stashed.iter().all(|s| s.node != NODE + 2));
+        assert!(td.ctrl.stashed.iter().all(|s| !(s.node == NODE + 3 && s.seq == 0)));
+        let data = td.ctrl.receive_response(&mut td.bus, NODE + 1, other).unwrap();
+        assert_eq!(data.raw_bytes[0], SensorStatus::Busy as u8);
+    }
+
+    #[test]
+    fn crc_link() {
+        let mut td = setup();
+        assert!(td.ctrl.set_crc_mode(NODE, CrcMode::Crc16).is_ok());
+        assert_eq!(td.ctrl.crc_mode(NODE), CrcMode::Crc16);
+        assert_eq!(td.ctrl.crc_mode(NODE + 1), CrcMode::None);
+
+        // Preload a reply with a good trailer.
+        let resp_id = CanId::request(NODE).response_to().with_crc(CrcMode::Crc16);
+        td.bus.set_rmsg_id(resp_id.to_raw().unwrap());
+        let mut status_data: Vec<u8> = reply(&[SensorStatus::Ready as u8]);
+        CrcMode::Crc16.append(&mut status_data);
+        assert!(td.bus.set_rmsg_data(&status_data).is_ok());
+
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new());
+        assert_eq!(cmd_result.unwrap().raw_bytes[0], SensorStatus::Ready as u8);
+
+        // The request went out with a trailer too.
+        assert_eq!(CanId::from_raw(td.bus.spy_id()).unwrap().crc, CrcMode::Crc16);
+        let mut sent = td.bus.spy_data();
+        assert!(CrcMode::Crc16.strip(&mut sent).is_ok());
+        assert_eq!(sent, vec![ControllerCommand::StatusRequest as u8, FIRST_SEQ]);
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let mut td = setup();
+        assert!(td.ctrl.set_crc_mode(NODE, CrcMode::Crc32).is_ok());
+
+        // Corrupt the reply after the trailer is added.
+        let resp_id = CanId::request(NODE).response_to().with_crc(CrcMode::Crc32);
+        td.bus.set_rmsg_id(resp_id.to_raw().unwrap());
+        let mut status_data: Vec<u8> = reply(&[SensorStatus::Ready as u8]);
+        CrcMode::Crc32.append(&mut status_data);
+        status_data[1] = SensorStatus::Busy as u8;
+        assert!(td.bus.set_rmsg_data(&status_data).is_ok());
+
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new());
+        assert!(matches!(cmd_result, Err(BusStatus::DataErr)));
+    }
+
+    #[test]
+    fn crc_missing() {
+        let mut td = setup();
+        assert!(td.ctrl.set_crc_mode(NODE, CrcMode::Crc16).is_ok());
+
+        // The module answered without a trailer.
+        assert!(td.bus.set_rmsg_data(&reply(&[SensorStatus::Ready as u8])).is_ok());
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::StatusRequest, String::new());
+        assert!(matches!(cmd_result, Err(BusStatus::DataErr)));
+    }
+
+    #[test]
+    fn version_request() {
+        let mut td = setup();
+        assert!(td.bus.set_rmsg_data(&reply(&[1, 4])).is_ok());
+
+        let cmd_result = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::VersionRequest, String::new());
+        let cmd_data = cmd_result.unwrap();
+        assert_eq!(td.bus.spy_data()[0], ControllerCommand::VersionRequest as u8);
+        assert_eq!(cmd_data.data_names, vec!["Major", "Minor"]);
+        assert_eq!(cmd_data.raw_bytes, vec![1, 4]);
+    }
+
+    #[test]
+    fn capabilities_request() {
+        let mut td = setup();
+        let mut caps = Capabilities::default();
+        caps.commands &= !(1 << ControllerCommand::ResetRequest as u8);
+        caps.features = 0;
+        caps.max_payload = 8;
+        assert!(td.bus.set_rmsg_data(&reply(&caps.to_bytes())).is_ok());
+
+        assert!(td.ctrl.capabilities(NODE).is_none());
+        assert_eq!(td.ctrl.query_capabilities(&mut td.bus, NODE).unwrap(), caps);
+        assert_eq!(td.ctrl.capabilities(NODE), Some(&caps));
+
+        // The controller now refuses what the node said it can't do.
+        let res = td.ctrl.send_bus_command(&mut td.bus, NODE, &ControllerCommand::ResetRequest, String::new());
+        assert!(matches!(res, Err(BusStatus::Unsupported)));
+        assert!(matches!(td.ctrl.set_crc_mode(NODE, CrcMode::Crc16), Err(BusStatus::Unsupported)));
+        let res = td.ctrl.send_request(&mut td.bus, NODE, &ControllerCommand::DataRequest, String::from("TooLongForEight"));
+        assert!(matches!(res, Err(BusStatus::Unsupported)));
+
+        // Other nodes are left alone.
+        assert!(td.ctrl.set_crc_mode(NODE + 1, CrcMode::Crc16).is_ok());
+    }
+
+    #[test]
+    fn incompatible_version() {
+        let mut td = setup();
+        let mut caps = Capabilities::default();
+        caps.version_major += 1;
+        assert!(td.bus.set_rmsg_data(&reply(&caps.to_bytes())).is_ok());
+
+        let res = td.ctrl.query_capabilities(&mut td.bus, NODE);
+        assert!(matches!(res, Err(BusStatus::Unsupported)));
+        assert!(td.ctrl.capabilities(NODE).is_none());
+    }
+
+    #[test]
+    fn list_params() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        let list = ctrl.list_params(&mut bus, NODE).unwrap();
+        assert_eq!(list.len(), crate::fake_sensor::NUM_PARAMS);
+        assert_eq!(list[0].name, "SampleRate");
+        assert_eq!(list[0].param_type, ParamType::U16);
+        assert_eq!(list[0].max, ParamValue::U16(1000));
+        assert_eq!(list[2].param_type, ParamType::F32);
+        assert!(!list[3].writable);
+    }
+
+    #[test]
+    fn read_write_params() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        assert_eq!(ctrl.read_param(&mut bus, NODE, "Averaging").unwrap(), DEFAULT_PARAMS[1]);
+        assert!(ctrl.write_param(&mut bus, NODE, "Averaging", ParamValue::U8(16)).is_ok());
+        assert_eq!(ctrl.read_param(&mut bus, NODE, "Averaging").unwrap(), ParamValue::U8(16));
+        assert_eq!(bus.sens.params[1], ParamValue::U8(16));
+
+        assert!(ctrl.write_param(&mut bus, NODE, "TempLimit", ParamValue::F32(60.5)).is_ok());
+        assert_eq!(bus.sens.params[2], ParamValue::F32(60.5));
+    }
+
+    #[test]
+    fn rejected_params() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        // Out of range, wrong type, read only and unknown.
+        let res = ctrl.write_param(&mut bus, NODE, "Averaging", ParamValue::U8(65));
+        assert!(matches!(res, Err(BusStatus::Rejected)));
+        let res = ctrl.write_param(&mut bus, NODE, "Averaging", ParamValue::U16(8));
+        assert!(matches!(res, Err(BusStatus::Rejected)));
+        let res = ctrl.write_param(&mut bus, NODE, "Build", ParamValue::U32(1));
+        assert!(matches!(res, Err(BusStatus::Unsupported)));
+        let res = ctrl.read_param(&mut bus, NODE, "Nope");
+        assert!(matches!(res, Err(BusStatus::Unsupported)));
+
+        // Nothing changed on the module.
+        assert_eq!(bus.sens.params, DEFAULT_PARAMS);
+    }
+
+    #[test]
+    fn save_load_config() {
+        let path = crate::file_store::file_store_tests::temp_path();
+        let mut td = setup();
+        td.sens.store = Some(crate::file_store::FileStore::open(&path, 256).unwrap());
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        // Nothing saved yet.
+        assert!(matches!(ctrl.load_config(&mut bus, NODE), Err(BusStatus::Rejected)));
+
+        assert!(ctrl.write_param(&mut bus, NODE, "SampleRate", ParamValue::U16(250)).is_ok());
+        assert!(ctrl.save_config(&mut bus, NODE).is_ok());
+
+        // Factory reset puts the default back, and forgets the saved value.
+        assert!(ctrl.factory_reset(&mut bus, NODE).is_ok());
+        assert_eq!(bus.sens.params, DEFAULT_PARAMS);
+        assert!(matches!(ctrl.load_config(&mut bus, NODE), Err(BusStatus::Rejected)));
+
+        // Save again, change it, then load it back.
+        assert!(ctrl.write_param(&mut bus, NODE, "SampleRate", ParamValue::U16(250)).is_ok());
+        assert!(ctrl.save_config(&mut bus, NODE).is_ok());
+        assert!(ctrl.write_param(&mut bus, NODE, "SampleRate", ParamValue::U16(5)).is_ok());
+        assert!(ctrl.load_config(&mut bus, NODE).is_ok());
+        assert_eq!(bus.sens.params[0], ParamValue::U16(250));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn no_config_store() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        assert!(matches!(ctrl.save_config(&mut bus, NODE), Err(BusStatus::Unsupported)));
+        assert!(matches!(ctrl.load_config(&mut bus, NODE), Err(BusStatus::Unsupported)));
+        // Still puts the defaults back.
+        assert!(ctrl.factory_reset(&mut bus, NODE).is_ok());
+    }
+
+    // A module with the given serial, using dynamic addressing.
+    fn with_serial(serial: Option<u64>) -> ExampleSensor {
+        let mut sens = setup().sens;
+        sens.serial = serial;
+        sens
+    }
+
+    #[test]
+    fn assign_addresses() {
+        let mut ctrl = Controller::new();
+        let mut bus: SharedBus<ExampleSensor> = SharedBus::new();
+        // Node 1 has a fixed address, the other two need one.
+        bus.add(NODE, with_serial(None));
+        bus.add(UNASSIGNED_NODE, with_serial(Some(0xA)));
+        bus.add(UNASSIGNED_NODE, with_serial(Some(0xB)));
+
+        bus.announce();
+        assert_eq!(ctrl.collect_announcements(&mut bus), vec![0xA, 0xB]);
+
+        bus.announce();
+        let assigned = ctrl.assign_addresses(&mut bus).unwrap();
+        assert_eq!(assigned, vec![(0xA, 2), (0xB, 3)]);
+        assert_eq!(bus.modules[1].0, 2);
+        assert_eq!(bus.modules[2].0, 3);
+
+        // They answer on the new address.
+        let ret = ctrl.send_bus_command(&mut bus, 3, &ControllerCommand::NameRequest, String::new());
+        assert_eq!(ret.unwrap().name, SENSOR_NAME);
+
+        // Lost its address(no store), gets the same one back.
+        bus.modules[1].0 = UNASSIGNED_NODE;
+        bus.announce();
+        assert_eq!(ctrl.assign_addresses(&mut bus).unwrap(), vec![(0xA, 2)]);
+        assert_eq!(ctrl.node_for_serial(0xB), Some(3));
+    }
+
+    #[test]
+    fn address_conflict() {
+        let mut ctrl = Controller::new();
+        let mut bus: SharedBus<ExampleSensor> = SharedBus::new();
+        bus.add(5, with_serial(Some(0xA)));
+        bus.add(5, with_serial(Some(0xB)));
+
+        assert_eq!(ctrl.identify(&mut bus, 5).unwrap(), vec![0xA, 0xB]);
+
+        let moved = ctrl.resolve_conflicts(&mut bus, 5).unwrap();
+        assert_eq!(moved, vec![(0xB, 1)]);
+        assert_eq!(ctrl.identify(&mut bus, 5).unwrap(), vec![0xA]);
+        assert_eq!(ctrl.identify(&mut bus, 1).unwrap(), vec![0xB]);
+
+        // Nothing to fix.
+        assert!(ctrl.resolve_conflicts(&mut bus, 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn conflict_without_serial() {
+        let mut ctrl = Controller::new();
+        let mut bus: SharedBus<ExampleSensor> = SharedBus::new();
+        bus.add(5, with_serial(Some(0xA)));
+        bus.add(5, with_serial(None));
+
+        assert_eq!(ctrl.identify(&mut bus, 5).unwrap(), vec![0xA, 0]);
+        assert!(matches!(ctrl.resolve_conflicts(&mut bus, 5), Err(BusStatus::Unsupported)));
+    }
+
+    #[test]
+    fn time_sync() {
+        use crate::fake_clock::FakeClock;
+        const HOST_US: u64 = 1_709_296_205_250_000;
+        let mut td = setup();
+        // Booted 5s ago, knows nothing about the real time.
+        td.sens.clock = Some(FakeClock::new(5_000_000));
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+        let host = FakeClock::new(HOST_US);
+
+        let sync = ctrl.sync_time_with(&mut bus, NODE, &host).unwrap();
+        assert_eq!(sync.offset_us, 5_000_000 - HOST_US as i64);
+        assert_eq!(sync.round_trip_us, 0);
+
+        // Now on our time, the round trip took no time at all.
+        let clock = bus.sens.clock.as_ref().unwrap();
+        assert_eq!(clock.now_us(), HOST_US);
+
+        // Both clocks move on together.
+        bus.sens.clock.as_mut().unwrap().local += 250;
+        let sample = ctrl.read_timed(&mut bus, NODE, 1).unwrap();
+        assert_eq!(sample.channel, 1);
+        assert_eq!(sample.data.len(), 2);
+        assert_eq!(sample.timestamp_us, HOST_US + 250);
+        assert_eq!(sample.time, timesync::to_host_time(HOST_US + 250));
+
+        assert!(matches!(ctrl.read_timed(&mut bus, NODE, 3), Err(BusStatus::Rejected)));
+        let res = ctrl.send_command_with_args(&mut bus, NODE, &ControllerCommand::TimeAdjustRequest, &[0, 1]);
+        assert_eq!(res.unwrap().raw_bytes, vec![TimeStatus::BadArgs as u8]);
+    }
+
+    #[test]
+    fn no_clock() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+        assert!(matches!(ctrl.sync_time(&mut bus, NODE), Err(BusStatus::Unsupported)));
+        assert!(matches!(ctrl.read_timed(&mut bus, NODE, 0), Err(BusStatus::Unsupported)));
+    }
+
+    #[test]
+    fn physical_values() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        let info = ctrl.channel_info(&mut bus, NODE, 1).unwrap();
+        assert_eq!(info.unit, "Cel");
+        assert_eq!(info.min, -40.0);
+
+        // 0x0FAA is 4010 centi-degrees.
+        let temp = ctrl.read_physical(&mut bus, NODE, 1).unwrap();
+        assert_eq!(temp.unit, "Cel");
+        assert!((temp.value - 40.10).abs() < 1e-3);
+
+        let status = ctrl.read_physical(&mut bus, NODE, 0).unwrap();
+        assert_eq!(status.unit, "1");
+        assert_eq!(status.value, 15.0);
+
+        assert!(matches!(ctrl.channel_info(&mut bus, NODE, 3), Err(BusStatus::Rejected)));
+    }
+
+    #[test]
+    fn channel_descriptors() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        let list = ctrl.list_channels(&mut bus, NODE).unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[2].name, "Relative humidity");
+        assert_eq!(list[2].channel_type, channels::ChannelType::U16);
+        assert!(list[0].has_flag(channels::CHANNEL_DIAGNOSTIC));
+        assert!(list[1].has_flag(channels::CHANNEL_HAS_META));
+
+        // Cached, nothing more goes out.
+        let sent = bus.sent;
+        assert_eq!(ctrl.list_channels(&mut bus, NODE).unwrap(), list);
+        assert_eq!(bus.sent, sent);
+    }
+
+    #[test]
+    fn channel_descriptors_old_node() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        // A node from before ChannelDescRequest.
+        let mut caps = Capabilities::default();
+        caps.commands &= !(1 << ControllerCommand::ChannelDescRequest as u8);
+        ctrl.capabilities.push((NODE, caps));
+
+        let list = ctrl.list_channels(&mut bus, NODE).unwrap();
+        let names: Vec<&str> = list.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["Status", "Temp", "Humid"]);
+        assert_eq!(list[0].channel_type, channels::ChannelType::U8);
+        assert_eq!(list[2].index, 2);
+    }
+
+    #[test]
+    fn sub_devices() {
+        let mut ctrl = Controller::new();
+        let mut second = with_serial(None);
+        second.sensor_name = "Second";
+        second.data = SensorData { data: [0x01, 0x02, 0, 0], size: 4 };
+        let mut bus = MultiBus::new(NODE, vec![Box::new(with_serial(None)), Box::new(second)]);
+
+        assert_eq!(ctrl.sub_device_count(&mut bus, NODE).unwrap(), 2);
+        let subs = ctrl.list_sub_devices(&mut bus, NODE).unwrap();
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs[0].name, SENSOR_NAME);
+        assert_eq!(subs[1].name, "Second");
+        assert_eq!(subs[1].index, 1);
+        assert_eq!(subs[1].format(), vec!["u8", "u16", "u16"]);
+        assert_eq!(subs[1].channels[2].name, "Relative humidity");
+
+        let ret = ctrl.send_sub_command(&mut bus, NODE, 1, &ControllerCommand::DataRequest, &[1]).unwrap();
+        assert_eq!(ret.raw_bytes, vec![0x01, 0x02]);
+
+        // Unwrapped requests go to the first sensor.
+        let ret = ctrl.send_bus_command(&mut bus, NODE, &ControllerCommand::NameRequest, String::new()).unwrap();
+        assert_eq!(ret.name, SENSOR_NAME);
+
+        let res = ctrl.send_sub_command(&mut bus, NODE, 2, &ControllerCommand::NameRequest, &[]);
+        assert!(matches!(res, Err(BusStatus::Rejected)));
+        let res = ctrl.send_sub_command(&mut bus, NODE, 0, &ControllerCommand::AddressAssignRequest, &[]);
+        assert!(matches!(res, Err(BusStatus::Rejected)));
+    }
+
+    #[test]
+    fn sub_device_time() {
+        use crate::fake_clock::FakeClock;
+        const HOST_US: u64 = 1_709_296_205_250_000;
+        let mut ctrl = Controller::new();
+        // Only the node keeps time, the second sensor has no clock.
+        let mut node = with_serial(None);
+        node.clock = Some(FakeClock::new(5_000_000));
+        let mut bus = MultiBus::new(NODE, vec![Box::new(node), Box::new(with_serial(None))]);
+
+        ctrl.sync_time_with(&mut bus, NODE, &FakeClock::new(HOST_US)).unwrap();
+
+        let ret = ctrl.send_sub_command(&mut bus, NODE, 1, &ControllerCommand::TimedDataRequest, &[1]).unwrap();
+        assert!(check_time_status(&ret.raw_bytes).is_ok());
+        assert_eq!(timesync::decode_time(&ret.raw_bytes[1..]), Some(HOST_US));
+    }
+
+    #[test]
+    fn sub_device_node_commands() {
+        let mut ctrl = Controller::new();
+        let mut bus = MultiBus::new(NODE, vec![Box::new(with_serial(None)), Box::new(with_serial(None))]);
+
+        for cmd in [ControllerCommand::FwBeginRequest, ControllerCommand::TimeSyncRequest,
+                    ControllerCommand::IdentifyRequest, ControllerCommand::VersionRequest] {
+            let res = ctrl.send_sub_command(&mut bus, NODE, 1, &cmd, &[]);
+            assert!(matches!(res, Err(BusStatus::Rejected)));
+        }
+
+        // A controller that wraps one anyway is turned away by the module.
+        let wrapped = subdevice::wrap(1, ControllerCommand::FwBeginRequest, &[0, 0, 0, 4]);
+        let ret = ctrl.send_command_with_args(&mut bus, NODE, &ControllerCommand::SubDeviceRequest, &wrapped).unwrap();
+        assert_eq!(ret.raw_bytes, vec![SubStatus::BadCommand as u8]);
+    }
+
+    #[test]
+    fn single_sensor_node() {
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        let subs = ctrl.list_sub_devices(&mut bus, NODE).unwrap();
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].name, SENSOR_NAME);
+        assert_eq!(subs[0].channels.len(), 3);
+    }
+
+    #[test]
+    fn vendor_commands() {
+        use crate::fake_sensor::{VENDOR_PEEK, VENDOR_POKE};
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+        ctrl.query_capabilities(&mut bus, NODE).unwrap();
+
+        assert_eq!(ctrl.send_vendor_command(&mut bus, NODE, VENDOR_POKE, &[0x12, 0x34]).unwrap(), vec![]);
+        let ret = ctrl.send_command_with_args(&mut bus, NODE, &ControllerCommand::DataRequest, &[1]).unwrap();
+        assert_eq!(ret.raw_bytes, vec![0x12, 0x34]);
+        assert_eq!(ctrl.send_vendor_command(&mut bus, NODE, VENDOR_PEEK, &[]).unwrap(), vec![0x12, 0x34, 0x00, 0x55]);
+
+        let res = ctrl.send_vendor_command(&mut bus, NODE, VENDOR_POKE, &[]);
+        assert!(matches!(res, Err(BusStatus::Rejected)));
+        let res = ctrl.send_vendor_command(&mut bus, NODE, 0xFE, &[]);
+        assert!(matches!(res, Err(BusStatus::Unsupported)));
+        // Not in the vendor range.
+        let res = ctrl.send_vendor_command(&mut bus, NODE, ControllerCommand::ResetRequest as u8, &[]);
+        assert!(matches!(res, Err(BusStatus::Rejected)));
+    }
+
+    #[test]
+    fn self_test() {
+        use crate::selftest::TestOutcome;
+        let td = setup();
+        let mut ctrl = td.ctrl;
+        let mut bus = LoopbackBus::new(NODE, td.sens);
+
+        let report = ctrl.run_self_test(&mut bus, NODE).unwrap();
+        // One request, the reply is too long for one frame so it came back
+        // in segments.
+        assert_eq!(bus.sent, 1);
+        assert_eq!(report.results.len(), 3);
+        assert!(!report.passed());
+        assert_eq!(report.failures()[0].name, "Configuration store present");
+
+        let temp = report.get("Temperature within the rated range").unwrap();
+        assert_eq!(temp.outcome, TestOutcome::Pass);
+        assert!((temp.value.unwrap() - 40.10).abs() < 1e-3);
+    }
+
+    #[test]
+    fn segments_failed() {
+        let mut td = setup();
+        let resp_id = CanId::request(NODE).response_to().to_raw().unwrap();
+        // The module's payload had no room for any segment data.
+        td.bus.queue_rmsg(resp_id, &[FIRST_SEQ, 0, 0]);
+
+        let res = td.ctrl.run_self_test(&mut td.bus, NODE);
+        assert!(matches!(res, Err(BusStatus::Error)));
+    }
+
+    #[test]
+    fn sub_device_self_test() {
+        let mut ctrl = Controller::new();
+        let mut bad = with_serial(None);
+        bad.data = SensorData { data: [0xFF, 0xFF, 0, 0], size: 4 };
+        let mut bus = MultiBus::new(NODE, vec![Box::new(with_serial(None)), Box::new(bad)]);
+
+        let report = ctrl.run_sub_self_test(&mut bus, NODE, 1).unwrap();
+        assert_eq!(report.failures().len(), 2);
+        assert!(matches!(ctrl.run_sub_self_test(&mut bus, NODE, 2), Err(BusStatus::Rejected)));
+    }
+
+    #[test]
+    fn unknown_sequence() {
+        let mut td = setup();
+        assert!(matches!(td.ctrl.receive_response(&mut td.bus, NODE, 9), Err(BusStatus::Error)));
+    }
 }
@@ -0,0 +1,189 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: crc.rs
+ * Desc: Optional CRC trailer for links that don't have CAN's built in CRC,
+ *       like serial or bridged transports.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::BusError;
+
+// CRC-16/CCITT-FALSE
+const CRC16_POLY: u16 = 0x1021;
+const CRC16_INIT: u16 = 0xFFFF;
+
+// CRC-32/ISO-HDLC(the zip/ethernet one), reflected form.
+const CRC32_POLY: u32 = 0xEDB8_8320;
+const CRC32_INIT: u32 = 0xFFFF_FFFF;
+
+
+// Which trailer(if any) is on the end of a payload. Carried in the
+// can id so the receiver knows what to check without any extra state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CrcMode {
+    None = 0,
+    Crc16,
+    Crc32,
+    Unknown = 0xFF,
+}
+
+impl From<u8> for CrcMode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => CrcMode::None,
+            1 => CrcMode::Crc16,
+            2 => CrcMode::Crc32,
+            _ => CrcMode::Unknown,
+        }
+    }
+}
+
+impl CrcMode {
+    pub fn trailer_len(&self) -> usize {
+        match self {
+            CrcMode::Crc16 => 2,
+            CrcMode::Crc32 => 4,
+            _ => 0,
+        }
+    }
+
+    // Computes the crc over the payload and appends it(big endian).
+    pub fn append(&self, payload: &mut Vec<u8>) {
+        match self {
+            CrcMode::Crc16 => payload.extend_from_slice(&crc16(payload).to_be_bytes()),
+            CrcMode::Crc32 => payload.extend_from_slice(&crc32(payload).to_be_bytes()),
+            _ => {}
+        }
+    }
+
+    // Checks and removes the trailer, leaving just the payload.
+    pub fn strip(&self, payload: &mut Vec<u8>) -> Result<(), BusError> {
+        if *self == CrcMode::Unknown {
+            return Err(BusError::BadParameter);
+        }
+
+        let len = self.trailer_len();
+        if payload.len() < len {
+            return Err(BusError::CrcMismatch);
+        }
+
+        let split = payload.len() - len;
+        let (body, trailer) = payload.split_at(split);
+        let matches = match self {
+            CrcMode::Crc16 => crc16(body).to_be_bytes() == trailer,
+            CrcMode::Crc32 => crc32(body).to_be_bytes() == trailer,
+            _ => true,
+        };
+
+        if !matches {
+            return Err(BusError::CrcMismatch);
+        }
+
+        payload.truncate(split);
+        Ok(())
+    }
+}
+
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = CRC16_INIT;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ CRC16_POLY;
+            }
+            else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_finish(crc32_update(crc32_begin(), data))
+}
+
+
+// For data too big to hold at once(firmware images), feed it through
+// `crc32_update` a piece at a time.
+pub fn crc32_begin() -> u32 {
+    CRC32_INIT
+}
+
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ CRC32_POLY;
+            }
+            else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub fn crc32_finish(crc: u32) -> u32 {
+    !crc
+}
+
+
+#[cfg(test)]
+mod crc_tests {
+    use super::*;
+
+    const CHECK: &[u8] = b"123456789";
+
+    #[test]
+    fn check_values() {
+        // The standard check values for each algorithm.
+        assert_eq!(crc16(CHECK), 0x29B1);
+        assert_eq!(crc32(CHECK), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn crc32_in_pieces() {
+        let mut crc = crc32_begin();
+        for piece in CHECK.chunks(4) {
+            crc = crc32_update(crc, piece);
+        }
+        assert_eq!(crc32_finish(crc), crc32(CHECK));
+    }
+
+    #[test]
+    fn append_then_strip() {
+        for mode in [CrcMode::None, CrcMode::Crc16, CrcMode::Crc32] {
+            let mut payload: Vec<u8> = CHECK.to_vec();
+            mode.append(&mut payload);
+            assert_eq!(payload.len(), CHECK.len() + mode.trailer_len());
+
+            assert!(mode.strip(&mut payload).is_ok());
+            assert_eq!(payload, CHECK);
+        }
+    }
+
+    #[test]
+    fn corruption_detected() {
+        for mode in [CrcMode::Crc16, CrcMode::Crc32] {
+            let mut payload: Vec<u8> = CHECK.to_vec();
+            mode.append(&mut payload);
+            payload[3] ^= 0x01;
+            assert!(matches!(mode.strip(&mut payload), Err(BusError::CrcMismatch)));
+        }
+    }
+
+    #[test]
+    fn too_short() {
+        let mut payload: Vec<u8> = vec![0x12];
+        assert!(CrcMode::Crc32.strip(&mut payload).is_err());
+    }
+}
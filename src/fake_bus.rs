@@ -3,12 +3,22 @@
  * Filename: fake_bus.rs
  * Description: A fake implimentation of a bus for testing.
  */
+use std::collections::VecDeque;
+
 use crate::Bus;
 use crate::BusError;
+use crate::SensorInterface;
+use crate::handler::handle_bus_command;
+use crate::handler::handle_bus_command_dynamic;
+use crate::handler::handle_bus_command_multi;
+use crate::handler::check_alarms;
+use crate::handler::check_alarms_multi;
+use crate::addressing;
+use crate::heartbeat;
+use crate::can_id::EXT_ID_MASK;
 
 const BUFFER_SIZE: usize = 32;
-const MIN_ID: u32 =  0;
-const MAX_ID: u32 = 128;
+const MAX_ID: u32 = EXT_ID_MASK;
 const LITTLE_ENDIAN: bool = true;
 const BYTES_IN_U32: usize = 4;
 
@@ -20,9 +30,11 @@ pub struct FakeBus {
     rmsg_buffer: [u8; BUFFER_SIZE],
     msg_size: usize,
     rmsg_size: usize,
+    rmsg_queue: VecDeque<(u32, Vec<u8>)>,
     pub auto_response: bool
 }
 
+#[allow(clippy::needless_return)]
 impl FakeBus {
     
     pub fn new() -> FakeBus {
@@ -33,6 +45,7 @@ impl FakeBus {
             rmsg_buffer: [0; BUFFER_SIZE],
             msg_size: 0,
             rmsg_size: 0,
+            rmsg_queue: VecDeque::new(),
             auto_response: false,
         };
         return fb;
@@ -53,23 +66,16 @@ impl FakeBus {
 
     //Returns the id of the message in the buffer.
     pub fn spy_id(&self) -> u32 {
-        let id: u32;
-        id = ((self.msg_buffer[0] as u32) | 
-              ((self.msg_buffer[1] as u32)>>8) | 
-              ((self.msg_buffer[2] as u32)>>16) | 
-              ((self.msg_buffer[3] as u32)>>24)) as u32;
-        return id;
+        buffer_to_id(&self.msg_buffer)
     }
 
 
-    pub fn set_rmsg_data(&mut self, d: &Vec<u8>) -> Result<(), &'static str> {
-        if d.len() > BUFFER_SIZE {
+    pub fn set_rmsg_data(&mut self, d: &[u8]) -> Result<(), &'static str> {
+        if d.len() > BUFFER_SIZE - BYTES_IN_U32 {
             return Err("Passed vector too big!");
         }
 
-        for i in 0..d.len(){
-            self.rmsg_buffer[i + 4] = d[i];
-        }
+        self.rmsg_buffer[BYTES_IN_U32..(d.len() + BYTES_IN_U32)].copy_from_slice(d);
         
         self.rmsg_size = d.len();
 
@@ -77,23 +83,24 @@ impl FakeBus {
     }
 
 
+    //Sets the id the auto response will appear to come from.
+    pub fn set_rmsg_id(&mut self, id: u32) {
+        self.rmsg_buffer[0..BYTES_IN_U32].copy_from_slice(&id_to_buffer(id));
+    }
+
+
+    //Queues up a response, queued responses are handed out in order
+    //before falling back to the preloaded rmsg buffer.
+    pub fn queue_rmsg(&mut self, id: u32, d: &[u8]) {
+        self.rmsg_queue.push_back((id, d.to_vec()));
+    }
+
+
     pub fn regular_receive(&mut self) -> Result<(u32, Vec<u8>), BusError> {
-        let id: u32;
         let mut data: Vec<u8> = vec![];
         
         //Read the id from the message.
-        if LITTLE_ENDIAN {
-            id = ((self.msg_buffer[0] as u32) | 
-                  ((self.msg_buffer[1] as u32)>>8) | 
-                  ((self.msg_buffer[2] as u32)>>16) | 
-                  ((self.msg_buffer[3] as u32)>>24)) as u32; 
-        }
-        else {
-            id = ((self.msg_buffer[3] as u32) | 
-                  ((self.msg_buffer[2] as u32)>>8) | 
-                  ((self.msg_buffer[1] as u32)>>16) | 
-                  ((self.msg_buffer[0] as u32)>>24)) as u32; 
-        }
+        let id: u32 = buffer_to_id(&self.msg_buffer);
 
 
         //copy the message into the data array.
@@ -106,22 +113,14 @@ impl FakeBus {
 
 
     pub fn auto_receive(&mut self) -> Result<(u32, Vec<u8>), BusError> {
-        let id: u32;
+        if let Some(queued) = self.rmsg_queue.pop_front() {
+            return Ok(queued);
+        }
+
         let mut data: Vec<u8> = vec![];
         
         //Read the id from the message.
-        if LITTLE_ENDIAN {
-            id = ((self.rmsg_buffer[0] as u32) | 
-                  ((self.rmsg_buffer[1] as u32)>>8) | 
-                  ((self.rmsg_buffer[2] as u32)>>16) | 
-                  ((self.rmsg_buffer[3] as u32)>>24)) as u32; 
-        }
-        else {
-            id = ((self.rmsg_buffer[3] as u32) | 
-                  ((self.rmsg_buffer[2] as u32)>>8) | 
-                  ((self.rmsg_buffer[1] as u32)>>16) | 
-                  ((self.rmsg_buffer[0] as u32)>>24)) as u32; 
-        }
+        let id: u32 = buffer_to_id(&self.rmsg_buffer);
 
         //copy the message into the data array.
         for i in BYTES_IN_U32..(self.rmsg_size + BYTES_IN_U32) {
@@ -138,21 +137,12 @@ impl Bus for FakeBus {
         //save needed state variables
         self.msg_size = data.len();
 
-        if id > MAX_ID || id < MIN_ID { 
+        if id > MAX_ID { 
             return Err(BusError::BadParameter);
         }
 
-        //copy the id + data into the message_buffer, we do some bit shifting.
-        let id_buf: [u8; BYTES_IN_U32];
-
-        if LITTLE_ENDIAN {
-            id_buf = id.to_le_bytes();  
-        }
-        else {
-            id_buf = id.to_be_bytes();
-        }
-
-        self.msg_buffer[0..BYTES_IN_U32].copy_from_slice(&id_buf);
+        //copy the id + data into the message_buffer.
+        self.msg_buffer[0..BYTES_IN_U32].copy_from_slice(&id_to_buffer(id));
                 
         //Now copy the data into the msg_buffer as well.
         self.msg_buffer[BYTES_IN_U32..(data.len()+ BYTES_IN_U32)].copy_from_slice(&data[0..data.len()]);
@@ -172,6 +162,232 @@ impl Bus for FakeBus {
 }
 
 
+//A bus with a module on the other end, every message the controller sends
+//is run through `handle_bus_command` straight away and the reply queued up.
+//Used for the tests that need several round trips.
+pub struct LoopbackBus<S: SensorInterface> {
+    pub node: u8,
+    pub sens: S,
+    pub sent: usize,                    //messages the controller has sent.
+    pub drop_reply_at: Option<usize>,   //loses the reply to that message.
+    to_module: VecDeque<(u32, Vec<u8>)>,
+    to_controller: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl<S: SensorInterface> LoopbackBus<S> {
+    pub fn new(node: u8, sens: S) -> LoopbackBus<S> {
+        LoopbackBus {
+            node,
+            sens,
+            sent: 0,
+            drop_reply_at: None,
+            to_module: VecDeque::new(),
+            to_controller: VecDeque::new(),
+        }
+    }
+
+    //The module sends its heartbeat.
+    pub fn heartbeat(&mut self, uptime: u32) {
+        let mut port = ModulePort {
+            rx: &mut self.to_module,
+            tx: &mut self.to_controller,
+        };
+        let _ = heartbeat::send_heartbeat(&mut port, self.node, &self.sens, uptime);
+    }
+
+    //The module checks its alarm rules, returns how many alarms it sent.
+    pub fn check_alarms(&mut self) -> usize {
+        let mut port = ModulePort {
+            rx: &mut self.to_module,
+            tx: &mut self.to_controller,
+        };
+        check_alarms(self.node, &mut port, &mut self.sens).unwrap_or(0)
+    }
+}
+
+impl<S: SensorInterface> Bus for LoopbackBus<S> {
+    fn send_message(&mut self, id: u32, data: &Vec<u8>) -> Result<(), BusError> {
+        if id > MAX_ID {
+            return Err(BusError::BadParameter);
+        }
+        self.to_module.push_back((id, data.to_vec()));
+
+        let mut port = ModulePort {
+            rx: &mut self.to_module,
+            tx: &mut self.to_controller,
+        };
+        //the module's errors are its own business, the controller just
+        //won't get a reply.
+        let _ = handle_bus_command(self.node, &mut port, &mut self.sens);
+
+        self.sent += 1;
+        if self.drop_reply_at == Some(self.sent) {
+            self.to_controller.clear();
+        }
+        Ok(())
+    }
+
+    fn receive_message(&mut self) -> Result<(u32, Vec<u8>), BusError> {
+        match self.to_controller.pop_front() {
+            Some(msg) => Ok(msg),
+            None => Err(BusError::BusError),
+        }
+    }
+}
+
+//Several modules on one bus, each with its own address that the
+//controller can change(see `addressing`). Every frame reaches every module.
+pub struct SharedBus<S: SensorInterface> {
+    pub modules: Vec<(u8, S)>,
+    to_controller: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl<S: SensorInterface> SharedBus<S> {
+    pub fn new() -> SharedBus<S> {
+        SharedBus {
+            modules: vec![],
+            to_controller: VecDeque::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: u8, sens: S) {
+        self.modules.push((node, sens));
+    }
+
+    //Every module still without an address announces itself.
+    pub fn announce(&mut self) {
+        for (node, sens) in self.modules.iter() {
+            if *node == crate::can_id::UNASSIGNED_NODE {
+                let mut port = ModulePort {
+                    rx: &mut VecDeque::new(),
+                    tx: &mut self.to_controller,
+                };
+                let _ = addressing::announce(&mut port, sens);
+            }
+        }
+    }
+}
+
+impl<S: SensorInterface> Bus for SharedBus<S> {
+    fn send_message(&mut self, id: u32, data: &Vec<u8>) -> Result<(), BusError> {
+        if id > MAX_ID {
+            return Err(BusError::BadParameter);
+        }
+
+        for (node, sens) in self.modules.iter_mut() {
+            let mut rx: VecDeque<(u32, Vec<u8>)> = VecDeque::new();
+            rx.push_back((id, data.to_vec()));
+            let mut port = ModulePort {
+                rx: &mut rx,
+                tx: &mut self.to_controller,
+            };
+            let _ = handle_bus_command_dynamic(node, &mut port, sens);
+        }
+        Ok(())
+    }
+
+    fn receive_message(&mut self) -> Result<(u32, Vec<u8>), BusError> {
+        match self.to_controller.pop_front() {
+            Some(msg) => Ok(msg),
+            None => Err(BusError::BusError),
+        }
+    }
+}
+
+//A module with several sensors behind one node, each a sub-device.
+pub struct MultiBus {
+    pub node: u8,
+    pub sensors: Vec<Box<dyn SensorInterface>>,
+    to_controller: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl MultiBus {
+    pub fn new(node: u8, sensors: Vec<Box<dyn SensorInterface>>) -> MultiBus {
+        MultiBus {
+            node,
+            sensors,
+            to_controller: VecDeque::new(),
+        }
+    }
+
+    //The module checks every sensor's alarm rules, returns how many alarms
+    //it sent.
+    pub fn check_alarms(&mut self) -> usize {
+        let mut rx: VecDeque<(u32, Vec<u8>)> = VecDeque::new();
+        let mut port = ModulePort {
+            rx: &mut rx,
+            tx: &mut self.to_controller,
+        };
+        let mut sensors: Vec<&mut dyn SensorInterface> = self.sensors.iter_mut().map(|s| s.as_mut() as &mut dyn SensorInterface).collect();
+        check_alarms_multi(self.node, &mut port, &mut sensors).unwrap_or(0)
+    }
+}
+
+impl Bus for MultiBus {
+    fn send_message(&mut self, id: u32, data: &Vec<u8>) -> Result<(), BusError> {
+        if id > MAX_ID {
+            return Err(BusError::BadParameter);
+        }
+
+        let mut rx: VecDeque<(u32, Vec<u8>)> = VecDeque::new();
+        rx.push_back((id, data.to_vec()));
+        let mut port = ModulePort {
+            rx: &mut rx,
+            tx: &mut self.to_controller,
+        };
+        let mut sensors: Vec<&mut dyn SensorInterface> = self.sensors.iter_mut().map(|s| s.as_mut() as &mut dyn SensorInterface).collect();
+        let _ = handle_bus_command_multi(self.node, &mut port, &mut sensors);
+        Ok(())
+    }
+
+    fn receive_message(&mut self) -> Result<(u32, Vec<u8>), BusError> {
+        match self.to_controller.pop_front() {
+            Some(msg) => Ok(msg),
+            None => Err(BusError::BusError),
+        }
+    }
+}
+
+//The module's end of a `LoopbackBus`.
+struct ModulePort<'a> {
+    rx: &'a mut VecDeque<(u32, Vec<u8>)>,
+    tx: &'a mut VecDeque<(u32, Vec<u8>)>,
+}
+
+impl Bus for ModulePort<'_> {
+    fn send_message(&mut self, id: u32, data: &Vec<u8>) -> Result<(), BusError> {
+        self.tx.push_back((id, data.to_vec()));
+        Ok(())
+    }
+
+    fn receive_message(&mut self) -> Result<(u32, Vec<u8>), BusError> {
+        match self.rx.pop_front() {
+            Some(msg) => Ok(msg),
+            None => Err(BusError::BusError),
+        }
+    }
+}
+
+
+fn id_to_buffer(id: u32) -> [u8; BYTES_IN_U32] {
+    if LITTLE_ENDIAN {
+        return id.to_le_bytes();
+    }
+    id.to_be_bytes()
+}
+
+
+fn buffer_to_id(buf: &[u8]) -> u32 {
+    let mut id_buf: [u8; BYTES_IN_U32] = [0; BYTES_IN_U32];
+    id_buf.copy_from_slice(&buf[0..BYTES_IN_U32]);
+
+    if LITTLE_ENDIAN {
+        return u32::from_le_bytes(id_buf);
+    }
+    u32::from_be_bytes(id_buf)
+}
+
+
 #[cfg(test)]
 mod fake_bus_tests {
     #[allow(unused_imports)]
@@ -189,6 +405,7 @@ mod fake_bus_tests {
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn check_self() {
         assert!(true);
     }
@@ -248,7 +465,7 @@ mod fake_bus_tests {
 
     #[test]
     fn send_bad_msg_id() {
-        const INVALID_ID: u32 = 129;
+        const INVALID_ID: u32 = EXT_ID_MASK + 1;
         let mut fb = FakeBus::new();
         let mut msg_data: Vec<u8> = vec!(0, 0, 0, 0, 0, 0, 0, 0);
         
@@ -257,7 +474,28 @@ mod fake_bus_tests {
         msg_data[1] = 6;
 
         //indicate we only want to read 1 byte
-        assert!(fb.send_message(INVALID_ID, &msg_data).is_ok() == false);
+        assert!(fb.send_message(INVALID_ID, &msg_data).is_err());
+    }
+
+    #[test]
+    fn send_extended_id() {
+        let mut fb = FakeBus::new();
+        let msg_data: Vec<u8> = vec!(1, 2);
+
+        assert!(fb.send_message(EXT_ID_MASK, &msg_data).is_ok());
+        assert_eq!(fb.spy_id(), EXT_ID_MASK);
+    }
+
+    #[test]
+    fn set_rmsg_id() {
+        let mut fb = FakeBus::new();
+        fb.auto_response = true;
+        fb.set_rmsg_id(0x0401_0203);
+        assert!(fb.set_rmsg_data(&[9]).is_ok());
+
+        let (rx_id, data) = fb.receive_message().unwrap();
+        assert_eq!(rx_id, 0x0401_0203);
+        assert_eq!(data, vec![9]);
     }
 
     #[test]
@@ -295,10 +533,23 @@ mod fake_bus_tests {
         let res = fb.set_rmsg_data(&data);
         assert!(res.is_ok());
     
-        for i in 0..data.len() {
-            assert_eq!(fb.rmsg_buffer[i+4], data[i]);
+        for (i, byte) in data.iter().enumerate() {
+            assert_eq!(fb.rmsg_buffer[i+4], *byte);
         }
 
         assert_eq!(fb.rmsg_size, data.len());
     }
+
+    #[test]
+    fn queue_rmsg() {
+        let mut fb = FakeBus::new();
+        fb.auto_response = true;
+        assert!(fb.set_rmsg_data(&[7]).is_ok());
+        fb.queue_rmsg(1, &[1]);
+        fb.queue_rmsg(2, &[2, 2]);
+
+        assert_eq!(fb.receive_message().unwrap(), (1, vec![1]));
+        assert_eq!(fb.receive_message().unwrap(), (2, vec![2, 2]));
+        assert_eq!(fb.receive_message().unwrap().1, vec![7]);
+    }
 }
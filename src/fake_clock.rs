@@ -0,0 +1,37 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: fake_clock.rs
+ * Desc: A module clock for testing, the tests move `local` by hand.
+ */
+
+use crate::timesync::ModuleClock;
+
+
+pub struct FakeClock {
+    pub local: u64,
+    offset: i64,
+}
+
+impl FakeClock {
+    pub fn new(local: u64) -> FakeClock {
+        FakeClock {
+            local,
+            offset: 0,
+        }
+    }
+}
+
+impl ModuleClock for FakeClock {
+    fn local_us(&self) -> u64 {
+        self.local
+    }
+
+    fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    fn set_offset(&mut self, offset: i64) {
+        self.offset = offset;
+    }
+}
@@ -0,0 +1,77 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: fake_flash.rs
+ * Desc: An in memory stand in for a module's firmware staging flash.
+ */
+
+use crate::BusError;
+use crate::firmware::FirmwareTarget;
+use crate::firmware::UpdateState;
+
+const ERASED: u8 = 0xFF;
+
+
+#[allow(dead_code)]
+pub struct RamFlash {
+    pub mem: Vec<u8>,
+    pub state: UpdateState,
+    pub committed: bool,
+    pub erases: usize,
+    pub fail_from: Option<u32>,   //writes at or past this offset fail.
+    pub lost_size: bool,          //forgets the image size, every write is a BadOffset.
+}
+
+impl RamFlash {
+    pub fn new(capacity: usize) -> RamFlash {
+        RamFlash {
+            mem: vec![ERASED; capacity],
+            state: UpdateState::default(),
+            committed: false,
+            erases: 0,
+            fail_from: None,
+            lost_size: false,
+        }
+    }
+}
+
+impl FirmwareTarget for RamFlash {
+    fn capacity(&self) -> u32 {
+        self.mem.len() as u32
+    }
+
+    fn erase(&mut self, size: u32) -> Result<(), BusError> {
+        self.erases += 1;
+        for byte in self.mem[..size as usize].iter_mut() {
+            *byte = ERASED;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), BusError> {
+        if self.fail_from.is_some_and(|from| offset >= from) {
+            return Err(BusError::BusError);
+        }
+        let start = offset as usize;
+        self.mem[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), BusError> {
+        let start = offset as usize;
+        buf.copy_from_slice(&self.mem[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), BusError> {
+        self.committed = true;
+        Ok(())
+    }
+
+    fn update_state(&mut self) -> &mut UpdateState {
+        if self.lost_size && self.state.active {
+            self.state.size = 0;
+        }
+        &mut self.state
+    }
+}
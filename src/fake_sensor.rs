@@ -8,8 +8,26 @@
 use crate::SensorData;
 use crate::SensorInterface;
 use crate::SensorStatus;
+use crate::params::ParamDescriptor;
+use crate::params::ParamStatus;
+use crate::params::ParamValue;
+use crate::config_store::ConfigStore;
+use crate::file_store::FileStore;
+use crate::fake_flash::RamFlash;
+use crate::firmware::FirmwareTarget;
+use crate::fake_clock::FakeClock;
+use crate::timesync::ModuleClock;
+use crate::units::ChannelMeta;
+use crate::channels;
+use crate::channels::ChannelDescriptor;
+use crate::vendor;
+use crate::selftest::TestResult;
+use crate::vendor::VendorCommand;
+use crate::vendor::VendorHandler;
+use crate::vendor::VendorStatus;
 
 pub const NUM_TYPES: usize = 3;
+pub const NUM_PARAMS: usize = 4;
 
 /*
  * This section shows how you should impliment
@@ -18,6 +36,76 @@ pub const NUM_TYPES: usize = 3;
 pub const SENSOR_NAME: &str = "Fakesensor";
 pub const READING_NAMES: &str = "Status Temp Humid";
 pub const READING_TYPES: &str = "u8 u16 u16";
+// What ChannelDescRequest calls them, with spaces the old strings can't carry.
+pub const CHANNEL_NAMES: [&str; NUM_TYPES] = ["Status", "Temperature", "Relative humidity"];
+
+// Temp is in centi-degrees and Humid in centi-percent.
+pub const CHANNELS: [ChannelMeta; NUM_TYPES] = [
+    ChannelMeta::plain("1"),
+    ChannelMeta {
+        unit: "Cel",
+        scale: 0.01,
+        offset: 0.0,
+        min: -40.0,
+        max: 125.0,
+        resolution: 0.01,
+    },
+    ChannelMeta {
+        unit: "%",
+        scale: 0.01,
+        offset: 0.0,
+        min: 0.0,
+        max: 100.0,
+        resolution: 0.01,
+    },
+];
+
+// Vendor commands, overwrite and read back the raw reading.
+pub const VENDOR_POKE: u8 = vendor::VENDOR_COMMAND_BASE;
+pub const VENDOR_PEEK: u8 = vendor::VENDOR_COMMAND_BASE + 1;
+
+const VENDOR_COMMANDS: [VendorCommand<ExampleSensor>; 2] = [
+    VendorCommand { code: VENDOR_POKE, run: ExampleSensor::poke },
+    VendorCommand { code: VENDOR_PEEK, run: ExampleSensor::peek },
+];
+
+pub const PARAMS: [ParamDescriptor; NUM_PARAMS] = [
+    ParamDescriptor {
+        name: "SampleRate",
+        min: ParamValue::U16(1),
+        max: ParamValue::U16(1000),
+        default: ParamValue::U16(10),
+        writable: true,
+    },
+    ParamDescriptor {
+        name: "Averaging",
+        min: ParamValue::U8(1),
+        max: ParamValue::U8(64),
+        default: ParamValue::U8(4),
+        writable: true,
+    },
+    ParamDescriptor {
+        name: "TempLimit",
+        min: ParamValue::F32(-40.0),
+        max: ParamValue::F32(125.0),
+        default: ParamValue::F32(85.0),
+        writable: true,
+    },
+    ParamDescriptor {
+        name: "Build",
+        min: ParamValue::U32(0),
+        max: ParamValue::U32(u32::MAX),
+        default: ParamValue::U32(42),
+        writable: false,
+    },
+];
+
+pub const DEFAULT_PARAMS: [ParamValue; NUM_PARAMS] = [
+    PARAMS[0].default,
+    PARAMS[1].default,
+    PARAMS[2].default,
+    PARAMS[3].default,
+];
 
 
 //This is a structure just used to show how it works,
@@ -29,8 +117,15 @@ pub struct ExampleSensor{
     pub data_types: [&'static str; NUM_TYPES],
     pub data_names: [&'static str; NUM_TYPES],
     pub data: SensorData,
+    pub params: [ParamValue; NUM_PARAMS],
+    pub store: Option<FileStore>,
+    pub flash: Option<RamFlash>,
+    pub serial: Option<u64>,
+    pub addr_store: Option<FileStore>,
+    pub clock: Option<FakeClock>,
 }
 
+#[allow(clippy::needless_return)]
 impl SensorInterface for ExampleSensor {
 
     fn get_name(&self) -> &'static str {
@@ -68,6 +163,129 @@ impl SensorInterface for ExampleSensor {
         return &self.data;
     }
 
+    fn channel_meta(&self, idx: u8) -> Option<ChannelMeta> {
+        CHANNELS.get(idx as usize).copied()
+    }
+
+    fn channel_descriptor(&self, idx: u8) -> Option<ChannelDescriptor> {
+        let mut desc = channels::from_strings(READING_TYPES, READING_NAMES, idx)?;
+        desc.name = CHANNEL_NAMES[idx as usize].into();
+        desc.flags = channels::CHANNEL_HAS_META;
+        if idx == 0 {
+            desc.flags |= channels::CHANNEL_DIAGNOSTIC;
+        }
+        Some(desc)
+    }
+
+    fn param_count(&self) -> u8 {
+        NUM_PARAMS as u8
+    }
+
+    fn param_descriptor(&self, idx: u8) -> Option<ParamDescriptor> {
+        PARAMS.get(idx as usize).copied()
+    }
+
+    fn get_param(&self, idx: u8) -> Option<ParamValue> {
+        self.params.get(idx as usize).copied()
+    }
+
+    fn set_param(&mut self, idx: u8, value: ParamValue) -> ParamStatus {
+        match self.params.get_mut(idx as usize) {
+            Some(p) => {
+                *p = value;
+                ParamStatus::Ok
+            }
+            None => ParamStatus::BadIndex,
+        }
+    }
+
+    fn config_store(&mut self) -> Option<&mut dyn ConfigStore> {
+        match self.store.as_mut() {
+            Some(store) => Some(store),
+            None => None,
+        }
+    }
+
+    fn firmware_target(&mut self) -> Option<&mut dyn FirmwareTarget> {
+        match self.flash.as_mut() {
+            Some(flash) => Some(flash),
+            None => None,
+        }
+    }
+
+    fn serial_number(&self) -> Option<u64> {
+        self.serial
+    }
+
+    fn address_store(&mut self) -> Option<&mut dyn ConfigStore> {
+        match self.addr_store.as_mut() {
+            Some(store) => Some(store),
+            None => None,
+        }
+    }
+
+    fn clock(&mut self) -> Option<&mut dyn ModuleClock> {
+        match self.clock.as_mut() {
+            Some(clock) => Some(clock),
+            None => None,
+        }
+    }
+
+    fn self_test(&mut self) -> Option<Vec<TestResult>> {
+        // Temp is read from the start of the reading, in centi-degrees.
+        let temp = u16::from_be_bytes([self.data.data[0], self.data.data[1]]) as f32 * CHANNELS[1].scale;
+        let temp_ok = temp >= CHANNELS[1].min && temp <= CHANNELS[1].max;
+        let format_ok = self.data_types.join(" ") == READING_TYPES;
+
+        Some(vec![
+            TestResult::new("Reading format matches the channel table", format_ok),
+            TestResult::new("Temperature within the rated range", temp_ok).with_value(temp),
+            TestResult::new("Configuration store present", self.store.is_some()),
+        ])
+    }
+
+    fn vendor_handler(&mut self) -> Option<&mut dyn VendorHandler> {
+        Some(self)
+    }
+
+}
+
+impl ExampleSensor {
+    // The sensor the tests start from, reading `data` with default params
+    // and nothing else attached. Set the optional parts on the result.
+    pub(crate) fn new(data: [u8; 4]) -> ExampleSensor {
+        ExampleSensor {
+            sensor_name: SENSOR_NAME,
+            data_types: ["u8", "u16", "u16"],
+            data_names: ["Status", "Temp", "Humid"],
+            data: SensorData { data, size: 4 },
+            params: DEFAULT_PARAMS,
+            store: None,
+            flash: None,
+            serial: None,
+            addr_store: None,
+            clock: None,
+        }
+    }
+
+    fn poke(&mut self, args: &[u8], _reply: &mut Vec<u8>) -> VendorStatus {
+        if args.is_empty() || args.len() > self.data.data.len() {
+            return VendorStatus::BadArgs;
+        }
+        self.data.data[..args.len()].copy_from_slice(args);
+        VendorStatus::Ok
+    }
+
+    fn peek(&mut self, _args: &[u8], reply: &mut Vec<u8>) -> VendorStatus {
+        reply.extend_from_slice(&self.data.data);
+        VendorStatus::Ok
+    }
+}
+
+impl VendorHandler for ExampleSensor {
+    fn handle_vendor(&mut self, code: u8, args: &[u8], reply: &mut Vec<u8>) -> VendorStatus {
+        vendor::dispatch(&VENDOR_COMMANDS, self, code, args, reply)
+    }
 }
 
 
@@ -84,6 +302,7 @@ mod fake_sensor_test {
     }
 
     #[allow(dead_code)]
+    #[allow(clippy::let_and_return)]
     fn setup() -> TestData {
         let sd = SensorData {
             data: [0x0F, 0xAA, 0x00, 0x55],
@@ -95,6 +314,12 @@ mod fake_sensor_test {
                 data_types: ["u8", "u16", "u16"],
                 data_names: ["Status", "Temp", "Humid"],
                 data: sd,
+                params: DEFAULT_PARAMS,
+                store: None,
+                flash: None,
+                serial: None,
+                addr_store: None,
+                clock: None,
         };
 
         let fake_bus = FakeBus::new();
@@ -108,6 +333,7 @@ mod fake_sensor_test {
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn check_self() {
         assert!(true);
     }
@@ -125,4 +351,19 @@ mod fake_sensor_test {
         td.sens.read_sensor(2);
         assert!(td.sens.data.size == 2);
     }
+
+    #[test]
+    fn test_params() {
+        let mut td = setup();
+
+        assert_eq!(td.sens.param_count() as usize, PARAMS.len());
+        assert_eq!(td.sens.get_param(0), Some(DEFAULT_PARAMS[0]));
+        assert_eq!(td.sens.set_param(0, ParamValue::U16(100)), ParamStatus::Ok);
+        assert_eq!(td.sens.get_param(0), Some(ParamValue::U16(100)));
+        assert!(td.sens.get_param(NUM_PARAMS as u8).is_none());
+
+        // Having parameters advertises config writes.
+        let caps = td.sens.get_capabilities();
+        assert!(caps.has_feature(crate::capabilities::FEATURE_CONFIG_WRITE));
+    }
 }
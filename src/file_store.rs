@@ -0,0 +1,144 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: file_store.rs
+ * Desc: A `ConfigStore` backed by a file, for running module code on a
+ *       host(tests, simulators).
+ */
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::BusError;
+use crate::config_store::ConfigStore;
+use crate::config_store::ERASED;
+use crate::config_store::bank_size;
+
+
+pub struct FileStore {
+    path: PathBuf,
+    capacity: usize,
+}
+
+impl FileStore {
+    // Opens the file, creating it(erased) if it doesn't exist yet.
+    pub fn open(path: &Path, capacity: usize) -> Result<FileStore, BusError> {
+        let mut store = FileStore {
+            path: path.to_path_buf(),
+            capacity,
+        };
+
+        let len = match std::fs::metadata(path) {
+            Ok(m) => m.len() as usize,
+            Err(_e) => 0,
+        };
+        if len != capacity {
+            store.reset()?;
+        }
+        Ok(store)
+    }
+
+    // The whole file back to erased.
+    fn reset(&mut self) -> Result<(), BusError> {
+        match std::fs::write(&self.path, vec![ERASED; self.capacity]) {
+            Ok(()) => Ok(()),
+            Err(_e) => Err(BusError::BusError),
+        }
+    }
+
+    fn file(&self) -> Result<File, BusError> {
+        match OpenOptions::new().read(true).write(true).open(&self.path) {
+            Ok(f) => Ok(f),
+            Err(_e) => Err(BusError::BusError),
+        }
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), BusError> {
+        if offset + len > self.capacity {
+            return Err(BusError::BadParameter);
+        }
+        Ok(())
+    }
+}
+
+impl ConfigStore for FileStore {
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), BusError> {
+        self.check_range(offset, buf.len())?;
+        let mut f = self.file()?;
+        if f.seek(SeekFrom::Start(offset as u64)).is_err() || f.read_exact(buf).is_err() {
+            return Err(BusError::BusError);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), BusError> {
+        self.check_range(offset, data.len())?;
+        let mut f = self.file()?;
+        if f.seek(SeekFrom::Start(offset as u64)).is_err() || f.write_all(data).is_err() {
+            return Err(BusError::BusError);
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, bank: usize) -> Result<(), BusError> {
+        let size = bank_size(self);
+        self.write(bank * size, &vec![ERASED; size])
+    }
+}
+
+
+#[cfg(test)]
+pub mod file_store_tests {
+    use super::*;
+    use crate::config_store::*;
+    use crate::params::ParamValue;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    // A fresh path in the temp dir, unique per test. Used for files and
+    // directories by the other modules' tests too.
+    pub fn temp_path() -> PathBuf {
+        let n = NEXT_FILE.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("bus_interface_{}_{}", std::process::id(), n))
+    }
+
+    #[test]
+    fn starts_erased() {
+        let path = temp_path();
+        let mut store = FileStore::open(&path, 32).unwrap();
+
+        let mut buf: [u8; 32] = [0; 32];
+        assert!(store.read(0, &mut buf).is_ok());
+        assert!(buf.iter().all(|b| *b == ERASED));
+        assert!(store.read(30, &mut buf[0..4]).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let path = temp_path();
+        let entries = vec![ConfigEntry { key: param_key("Averaging"), value: ParamValue::U8(8) }];
+
+        {
+            let mut store = FileStore::open(&path, 64).unwrap();
+            assert_eq!(save_record(&mut store, &entries), StoreStatus::Ok);
+        }
+
+        // Like a reboot, a new handle on the same file.
+        let mut store = FileStore::open(&path, 64).unwrap();
+        assert_eq!(load_record(&mut store).unwrap(), entries);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: firmware.rs
+ * Desc: Firmware update over the bus, the module(bootloader) side.
+ *
+ *       begin(size, crc32) -> write(offset, chunk).. -> verify -> commit
+ *
+ *       Chunks have to arrive in order. Every reply carries how many
+ *       bytes the module has, so an interrupted upload can pick up where
+ *       it left off by sending the same begin again.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::BusError;
+use crate::crc::crc32_begin;
+use crate::crc::crc32_finish;
+use crate::crc::crc32_update;
+
+// size(4) + crc32 of the image(4)
+pub const BEGIN_ARGS_LEN: usize = 8;
+// offset(4) then the chunk.
+pub const WRITE_HEADER_LEN: usize = 4;
+
+// How much is read back at a time when checking the image.
+const VERIFY_BLOCK: usize = 64;
+
+
+// First byte of every firmware reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FwStatus {
+    Ok = 0,
+    NoTarget,       //The module can't be updated over the bus.
+    TooBig,         //Image doesn't fit in the staging area.
+    BadState,       //Command out of order(write before begin etc).
+    BadOffset,      //Chunk isn't the next one, the reply says which is.
+    HashMismatch,   //The image didn't check out, start over.
+    Failed,         //The flash itself reported an error.
+}
+
+impl From<u8> for FwStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => FwStatus::Ok,
+            1 => FwStatus::NoTarget,
+            2 => FwStatus::TooBig,
+            3 => FwStatus::BadState,
+            4 => FwStatus::BadOffset,
+            5 => FwStatus::HashMismatch,
+            _ => FwStatus::Failed,
+        }
+    }
+}
+
+
+// Where the update is at, kept by the `FirmwareTarget` so it lives as long
+// as the staging area does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UpdateState {
+    pub active: bool,
+    pub size: u32,
+    pub crc: u32,
+    pub written: u32,
+    pub verified: bool,
+}
+
+
+// The flash writes for the update, given by the module.
+pub trait FirmwareTarget {
+
+    // Biggest image the staging area can take.
+    fn capacity(&self) -> u32;
+
+    // Gets the staging area ready for an image of `size` bytes.
+    fn erase(&mut self, size: u32) -> Result<(), BusError>;
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), BusError>;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), BusError>;
+
+    // Marks the staged image to boot. The reboot itself should happen
+    // after the handler has sent the reply.
+    fn commit(&mut self) -> Result<(), BusError>;
+
+    fn update_state(&mut self) -> &mut UpdateState;
+}
+
+
+// [status, written(4)]
+fn reply(status: FwStatus, written: u32, buf: &mut Vec<u8>) {
+    buf.push(status as u8);
+    buf.extend_from_slice(&written.to_be_bytes());
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+
+pub fn handle_begin(target: &mut dyn FirmwareTarget, args: &[u8], buf: &mut Vec<u8>) {
+    if args.len() < BEGIN_ARGS_LEN {
+        return reply(FwStatus::BadState, 0, buf);
+    }
+    let size = be_u32(&args[0..4]);
+    let crc = be_u32(&args[4..8]);
+
+    if size > target.capacity() {
+        return reply(FwStatus::TooBig, 0, buf);
+    }
+
+    // Same image as the one in progress, carry on from where it stopped.
+    let state = *target.update_state();
+    if state.active && state.size == size && state.crc == crc {
+        return reply(FwStatus::Ok, state.written, buf);
+    }
+
+    if target.erase(size).is_err() {
+        *target.update_state() = UpdateState::default();
+        return reply(FwStatus::Failed, 0, buf);
+    }
+
+    *target.update_state() = UpdateState {
+        active: true,
+        size,
+        crc,
+        written: 0,
+        verified: false,
+    };
+    reply(FwStatus::Ok, 0, buf);
+}
+
+
+pub fn handle_write(target: &mut dyn FirmwareTarget, args: &[u8], buf: &mut Vec<u8>) {
+    let state = *target.update_state();
+    if !state.active || args.len() < WRITE_HEADER_LEN {
+        return reply(FwStatus::BadState, state.written, buf);
+    }
+
+    let offset = be_u32(&args[0..4]);
+    let chunk = &args[WRITE_HEADER_LEN..];
+    if offset != state.written || offset as usize + chunk.len() > state.size as usize {
+        return reply(FwStatus::BadOffset, state.written, buf);
+    }
+
+    if target.write(offset, chunk).is_err() {
+        return reply(FwStatus::Failed, state.written, buf);
+    }
+
+    let state = target.update_state();
+    state.written += chunk.len() as u32;
+    state.verified = false;
+    reply(FwStatus::Ok, state.written, buf);
+}
+
+
+// Reads the whole image back and checks it against the crc from begin.
+pub fn handle_verify(target: &mut dyn FirmwareTarget, buf: &mut Vec<u8>) {
+    let state = *target.update_state();
+    if !state.active || state.written != state.size {
+        return reply(FwStatus::BadState, state.written, buf);
+    }
+
+    let mut crc = crc32_begin();
+    let mut block: [u8; VERIFY_BLOCK] = [0; VERIFY_BLOCK];
+    let mut offset: u32 = 0;
+    while offset < state.size {
+        let len = VERIFY_BLOCK.min((state.size - offset) as usize);
+        if target.read(offset, &mut block[..len]).is_err() {
+            return reply(FwStatus::Failed, state.written, buf);
+        }
+        crc = crc32_update(crc, &block[..len]);
+        offset += len as u32;
+    }
+
+    if crc32_finish(crc) != state.crc {
+        // Whatever is staged is no good, the next begin starts fresh.
+        *target.update_state() = UpdateState::default();
+        return reply(FwStatus::HashMismatch, 0, buf);
+    }
+
+    target.update_state().verified = true;
+    reply(FwStatus::Ok, state.written, buf);
+}
+
+
+pub fn handle_commit(target: &mut dyn FirmwareTarget, buf: &mut Vec<u8>) {
+    let state = *target.update_state();
+    if !state.active || !state.verified {
+        return reply(FwStatus::BadState, state.written, buf);
+    }
+
+    if target.commit().is_err() {
+        return reply(FwStatus::Failed, state.written, buf);
+    }
+
+    *target.update_state() = UpdateState::default();
+    reply(FwStatus::Ok, state.written, buf);
+}
+
+
+#[cfg(test)]
+mod firmware_tests {
+    use super::*;
+    use crate::crc::crc32;
+    use crate::fake_flash::RamFlash;
+
+    fn begin_args(image: &[u8]) -> Vec<u8> {
+        let mut args: Vec<u8> = vec![];
+        args.extend_from_slice(&(image.len() as u32).to_be_bytes());
+        args.extend_from_slice(&crc32(image).to_be_bytes());
+        args
+    }
+
+    fn write_args(offset: u32, chunk: &[u8]) -> Vec<u8> {
+        let mut args: Vec<u8> = offset.to_be_bytes().to_vec();
+        args.extend_from_slice(chunk);
+        args
+    }
+
+    fn status(buf: &[u8]) -> (FwStatus, u32) {
+        (buf[0].into(), be_u32(&buf[1..5]))
+    }
+
+    #[test]
+    fn full_update() {
+        let image: Vec<u8> = (0..200u32).map(|i| i as u8).collect();
+        let mut flash = RamFlash::new(256);
+
+        let mut buf: Vec<u8> = vec![];
+        handle_begin(&mut flash, &begin_args(&image), &mut buf);
+        assert_eq!(status(&buf), (FwStatus::Ok, 0));
+
+        for (i, chunk) in image.chunks(64).enumerate() {
+            buf.clear();
+            handle_write(&mut flash, &write_args(i as u32 * 64, chunk), &mut buf);
+            assert_eq!(buf[0], FwStatus::Ok as u8);
+        }
+        assert_eq!(status(&buf), (FwStatus::Ok, 200));
+
+        buf.clear();
+        handle_verify(&mut flash, &mut buf);
+        assert_eq!(buf[0], FwStatus::Ok as u8);
+
+        buf.clear();
+        handle_commit(&mut flash, &mut buf);
+        assert_eq!(buf[0], FwStatus::Ok as u8);
+        assert!(flash.committed);
+        assert_eq!(flash.mem[..200], image[..]);
+        assert!(!flash.state.active);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let image: Vec<u8> = vec![1; 32];
+        let mut flash = RamFlash::new(64);
+        let mut buf: Vec<u8> = vec![];
+
+        // Write and commit before begin.
+        handle_write(&mut flash, &write_args(0, &image), &mut buf);
+        assert_eq!(buf[0], FwStatus::BadState as u8);
+        buf.clear();
+        handle_commit(&mut flash, &mut buf);
+        assert_eq!(buf[0], FwStatus::BadState as u8);
+
+        // Skipping ahead tells us where it's at.
+        buf.clear();
+        handle_begin(&mut flash, &begin_args(&image), &mut buf);
+        buf.clear();
+        handle_write(&mut flash, &write_args(0, &image[..16]), &mut buf);
+        buf.clear();
+        handle_write(&mut flash, &write_args(20, &image[..4]), &mut buf);
+        assert_eq!(status(&buf), (FwStatus::BadOffset, 16));
+
+        // Verify before it's all there.
+        buf.clear();
+        handle_verify(&mut flash, &mut buf);
+        assert_eq!(buf[0], FwStatus::BadState as u8);
+    }
+
+    #[test]
+    fn resume() {
+        let image: Vec<u8> = vec![7; 48];
+        let mut flash = RamFlash::new(64);
+        let mut buf: Vec<u8> = vec![];
+
+        handle_begin(&mut flash, &begin_args(&image), &mut buf);
+        buf.clear();
+        handle_write(&mut flash, &write_args(0, &image[..16]), &mut buf);
+
+        // Same begin again picks up at 16 without erasing.
+        buf.clear();
+        handle_begin(&mut flash, &begin_args(&image), &mut buf);
+        assert_eq!(status(&buf), (FwStatus::Ok, 16));
+        assert_eq!(flash.erases, 1);
+
+        // A different image starts over.
+        let other: Vec<u8> = vec![8; 48];
+        buf.clear();
+        handle_begin(&mut flash, &begin_args(&other), &mut buf);
+        assert_eq!(status(&buf), (FwStatus::Ok, 0));
+        assert_eq!(flash.erases, 2);
+    }
+
+    #[test]
+    fn bad_image() {
+        let image: Vec<u8> = vec![3; 16];
+        let mut flash = RamFlash::new(64);
+        let mut buf: Vec<u8> = vec![];
+
+        handle_begin(&mut flash, &begin_args(&image), &mut buf);
+        buf.clear();
+        let mut corrupt = image.clone();
+        corrupt[5] = 0;
+        handle_write(&mut flash, &write_args(0, &corrupt), &mut buf);
+
+        buf.clear();
+        handle_verify(&mut flash, &mut buf);
+        assert_eq!(buf[0], FwStatus::HashMismatch as u8);
+        assert!(!flash.state.active);
+    }
+
+    #[test]
+    fn too_big() {
+        let image: Vec<u8> = vec![0; 65];
+        let mut flash = RamFlash::new(64);
+        let mut buf: Vec<u8> = vec![];
+        handle_begin(&mut flash, &begin_args(&image), &mut buf);
+        assert_eq!(buf[0], FwStatus::TooBig as u8);
+    }
+}
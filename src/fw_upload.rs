@@ -0,0 +1,300 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: fw_upload.rs
+ * Desc: Controller side of the firmware update, see firmware.rs for the
+ *       module side.
+ */
+
+use crate::Bus;
+use crate::BusStatus;
+use crate::ControllerCommand;
+use crate::REQUEST_HEADER_LEN;
+use crate::controller::Controller;
+use crate::crc::crc32;
+use crate::firmware::FwStatus;
+use crate::firmware::WRITE_HEADER_LEN;
+
+// Fits a write in the default 64 byte payload with a crc32 trailer.
+pub const DEFAULT_CHUNK: usize = 48;
+
+// How many lost/failed writes in a row, or replies without progress,
+// before giving up.
+const MAX_RETRIES: usize = 3;
+
+
+// Sends a firmware image to a module. If the upload gets interrupted just
+// call `upload` again, the module remembers how far it got.
+pub struct FirmwareUploader<'a> {
+    image: &'a [u8],
+    crc: u32,
+    chunk_size: Option<usize>,
+    progress: Option<Box<dyn FnMut(usize, usize) + 'a>>,
+}
+
+impl<'a> FirmwareUploader<'a> {
+    pub fn new(image: &'a [u8]) -> FirmwareUploader<'a> {
+        FirmwareUploader {
+            image,
+            crc: crc32(image),
+            chunk_size: None,
+            progress: None,
+        }
+    }
+
+    // Overrides the chunk size worked out from the node's capabilities.
+    pub fn chunk_size(mut self, size: usize) -> FirmwareUploader<'a> {
+        self.chunk_size = Some(size);
+        self
+    }
+
+    // Called with (bytes the module has, image size) after every chunk.
+    pub fn on_progress(mut self, f: impl FnMut(usize, usize) + 'a) -> FirmwareUploader<'a> {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    pub fn upload(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus, node: u8) -> Result<(), BusStatus> {
+        let chunk_size = self.pick_chunk_size(ctrl, node);
+        if chunk_size == 0 {
+            return Err(BusStatus::Unsupported);
+        }
+
+        let mut offset = self.begin(ctrl, bus, node)?;
+        let mut furthest = offset;
+        let mut retries: usize = 0;
+
+        while offset < self.image.len() {
+            let end = (offset + chunk_size).min(self.image.len());
+            let mut args: Vec<u8> = (offset as u32).to_be_bytes().to_vec();
+            args.extend_from_slice(&self.image[offset..end]);
+
+            match fw_command(ctrl, bus, node, ControllerCommand::FwWriteRequest, &args) {
+                Ok((FwStatus::Ok, written)) | Ok((FwStatus::BadOffset, written)) => {
+                    // The module says where it's at, go from there. Only
+                    // getting further than before counts as progress, a
+                    // module stuck on the same offset runs out of retries.
+                    offset = written as usize;
+                    if offset > furthest {
+                        furthest = offset;
+                        retries = 0;
+                    } else if retries < MAX_RETRIES {
+                        retries += 1;
+                    } else {
+                        return Err(BusStatus::Error);
+                    }
+                }
+                Ok((status, _)) => return Err(status_to_bus(status)),
+                Err(BusStatus::Error) if retries < MAX_RETRIES => {
+                    // Lost the request or the reply, ask where it got to.
+                    retries += 1;
+                    offset = self.begin(ctrl, bus, node)?;
+                }
+                Err(e) => return Err(e),
+            }
+
+            if let Some(f) = self.progress.as_mut() {
+                f(offset, self.image.len());
+            }
+        }
+
+        check(fw_command(ctrl, bus, node, ControllerCommand::FwVerifyRequest, &[]))?;
+        check(fw_command(ctrl, bus, node, ControllerCommand::FwCommitRequest, &[]))?;
+        Ok(())
+    }
+
+    // Starts the update, or picks up an interrupted one. Returns the offset
+    // to carry on from.
+    fn begin(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus, node: u8) -> Result<usize, BusStatus> {
+        let mut args: Vec<u8> = (self.image.len() as u32).to_be_bytes().to_vec();
+        args.extend_from_slice(&self.crc.to_be_bytes());
+
+        let written = check(fw_command(ctrl, bus, node, ControllerCommand::FwBeginRequest, &args))?;
+        if written as usize > self.image.len() {
+            return Err(BusStatus::DataErr);
+        }
+        Ok(written as usize)
+    }
+
+    fn pick_chunk_size(&self, ctrl: &Controller, node: u8) -> usize {
+        if let Some(size) = self.chunk_size {
+            return size;
+        }
+
+        match ctrl.capabilities(node) {
+            Some(caps) => {
+                let overhead = REQUEST_HEADER_LEN + WRITE_HEADER_LEN + ctrl.crc_mode(node).trailer_len();
+                (caps.max_payload as usize).saturating_sub(overhead)
+            }
+            None => DEFAULT_CHUNK,
+        }
+    }
+}
+
+
+// Sends one firmware command, returns the status and the module's count.
+fn fw_command(
+    ctrl: &mut Controller,
+    bus: &mut dyn Bus,
+    node: u8,
+    cmd: ControllerCommand,
+    args: &[u8]) -> Result<(FwStatus, u32), BusStatus>
+{
+    let ret = ctrl.send_command_with_args(bus, node, &cmd, args)?;
+    let b = &ret.raw_bytes;
+    if b.len() < 5 {
+        return Err(BusStatus::DataErr);
+    }
+    Ok((b[0].into(), u32::from_be_bytes([b[1], b[2], b[3], b[4]])))
+}
+
+fn check(res: Result<(FwStatus, u32), BusStatus>) -> Result<u32, BusStatus> {
+    match res? {
+        (FwStatus::Ok, written) => Ok(written),
+        (status, _) => Err(status_to_bus(status)),
+    }
+}
+
+fn status_to_bus(status: FwStatus) -> BusStatus {
+    match status {
+        FwStatus::Ok => BusStatus::Good,
+        FwStatus::NoTarget => BusStatus::Unsupported,
+        FwStatus::TooBig => BusStatus::Rejected,
+        FwStatus::HashMismatch => BusStatus::DataErr,
+        FwStatus::BadState | FwStatus::BadOffset | FwStatus::Failed => BusStatus::Error,
+    }
+}
+
+
+#[cfg(test)]
+mod fw_upload_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::fake_bus::LoopbackBus;
+    use crate::fake_flash::RamFlash;
+    use crate::fake_sensor::*;
+
+    const NODE: u8 = 0x01;
+
+    fn sensor(flash: Option<RamFlash>) -> ExampleSensor {
+        ExampleSensor {
+            flash,
+            ..ExampleSensor::new([0; 4])
+        }
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn upload() {
+        let img = image(300);
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor(Some(RamFlash::new(512))));
+
+        let seen: RefCell<Vec<usize>> = RefCell::new(vec![]);
+        let mut uploader = FirmwareUploader::new(&img)
+            .on_progress(|done, total| {
+                assert_eq!(total, 300);
+                seen.borrow_mut().push(done);
+            });
+        assert!(uploader.upload(&mut ctrl, &mut bus, NODE).is_ok());
+        drop(uploader);
+
+        let flash = bus.sens.flash.as_ref().unwrap();
+        assert!(flash.committed);
+        assert_eq!(flash.mem[..300], img[..]);
+        assert_eq!(*seen.borrow(), vec![48, 96, 144, 192, 240, 288, 300]);
+    }
+
+    #[test]
+    fn chunk_from_capabilities() {
+        let img = image(100);
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor(Some(RamFlash::new(128))));
+        assert!(ctrl.query_capabilities(&mut bus, NODE).is_ok());
+
+        // 64 byte payload - header(2) - offset(4) = 58 byte chunks.
+        let sent_before = bus.sent;
+        assert!(FirmwareUploader::new(&img).upload(&mut ctrl, &mut bus, NODE).is_ok());
+        // begin, 2 writes, verify, commit.
+        assert_eq!(bus.sent - sent_before, 5);
+    }
+
+    #[test]
+    fn lost_reply_resumes() {
+        let img = image(200);
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor(Some(RamFlash::new(256))));
+
+        // Lose the reply to the second write, the chunk did land though.
+        bus.drop_reply_at = Some(3);
+        assert!(FirmwareUploader::new(&img).upload(&mut ctrl, &mut bus, NODE).is_ok());
+
+        let flash = bus.sens.flash.as_ref().unwrap();
+        assert!(flash.committed);
+        assert_eq!(flash.erases, 1);
+        assert_eq!(flash.mem[..200], img[..]);
+    }
+
+    #[test]
+    fn resume_next_call() {
+        let img = image(200);
+        let mut ctrl = Controller::new();
+        let mut flash = RamFlash::new(256);
+        // The flash gives out after the first two chunks.
+        flash.fail_from = Some(100);
+        let mut bus = LoopbackBus::new(NODE, sensor(Some(flash)));
+
+        let mut uploader = FirmwareUploader::new(&img).chunk_size(50);
+        assert!(matches!(uploader.upload(&mut ctrl, &mut bus, NODE), Err(BusStatus::Error)));
+        assert_eq!(bus.sens.flash.as_ref().unwrap().state.written, 100);
+
+        // Fixed, the next upload carries on from 100 without erasing.
+        bus.sens.flash.as_mut().unwrap().fail_from = None;
+        let sent_before = bus.sent;
+        assert!(uploader.upload(&mut ctrl, &mut bus, NODE).is_ok());
+        // begin, 2 writes, verify, commit.
+        assert_eq!(bus.sent - sent_before, 5);
+
+        let flash = bus.sens.flash.as_ref().unwrap();
+        assert_eq!(flash.erases, 1);
+        assert!(flash.committed);
+        assert_eq!(flash.mem[..200], img[..]);
+    }
+
+    #[test]
+    fn stuck_offset() {
+        let img = image(200);
+        let mut ctrl = Controller::new();
+        let mut flash = RamFlash::new(256);
+        flash.lost_size = true;
+        let mut bus = LoopbackBus::new(NODE, sensor(Some(flash)));
+
+        // Every write comes back BadOffset at 0, gives up instead of looping.
+        let res = FirmwareUploader::new(&img).upload(&mut ctrl, &mut bus, NODE);
+        assert!(matches!(res, Err(BusStatus::Error)));
+        // begin, then the first write and its retries.
+        assert_eq!(bus.sent, 1 + 1 + MAX_RETRIES);
+        assert!(!bus.sens.flash.as_ref().unwrap().committed);
+    }
+
+    #[test]
+    fn no_target() {
+        let img = image(10);
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor(None));
+        let res = FirmwareUploader::new(&img).upload(&mut ctrl, &mut bus, NODE);
+        assert!(matches!(res, Err(BusStatus::Unsupported)));
+    }
+
+    #[test]
+    fn too_big() {
+        let img = image(100);
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor(Some(RamFlash::new(64))));
+        let res = FirmwareUploader::new(&img).upload(&mut ctrl, &mut bus, NODE);
+        assert!(matches!(res, Err(BusStatus::Rejected)));
+    }
+}
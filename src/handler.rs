@@ -6,84 +6,531 @@
  */
 
 use super::*;
+use crate::can_id::CanId;
+use crate::can_id::MessageType;
 
 #[allow(dead_code)]
-pub fn handle_bus_command(slv_id: u32, bus: &mut dyn Bus, sens: &mut dyn SensorInterface) -> Result<(), BusError>{
+pub fn handle_bus_command(slv_id: u8, bus: &mut dyn Bus, sens: &mut dyn SensorInterface) -> Result<(), BusError>{
+    handle(slv_id, bus, &mut [sens])?;
+    Ok(())
+}
+
+
+// For modules with several sensors, each one is a sub-device numbered by
+// its place in `sensors`. Requests that aren't wrapped in SubDeviceRequest
+// go to the first one, except a broadcast AcqTriggerRequest which goes to
+// all of them.
+pub fn handle_bus_command_multi(slv_id: u8, bus: &mut dyn Bus, sensors: &mut [&mut dyn SensorInterface]) -> Result<(), BusError>{
+    handle(slv_id, bus, sensors)?;
+    Ok(())
+}
+
+
+// For modules using dynamic addressing. `node` starts out as
+// `addressing::stored_address` and is updated when the controller assigns
+// a new one.
+pub fn handle_bus_command_dynamic(node: &mut u8, bus: &mut dyn Bus, sens: &mut dyn SensorInterface) -> Result<(), BusError>{
+    if let Some(assigned) = handle(*node, bus, &mut [sens])? {
+        *node = assigned;
+    }
+    Ok(())
+}
+
+
+// Reads every channel with an alarm rule and sends an Alarm frame for each
+// one whose level changed. Call it from the module's main loop after
+// sampling, returns how many were sent.
+pub fn check_alarms(slv_id: u8, bus: &mut dyn Bus, sens: &mut dyn SensorInterface) -> Result<usize, BusError>{
+    check_alarms_multi(slv_id, bus, &mut [sens])
+}
+
+
+// `check_alarms` for every sensor of a multi-sensor module, each alarm
+// carries the sub-device it came from.
+pub fn check_alarms_multi(slv_id: u8, bus: &mut dyn Bus, sensors: &mut [&mut dyn SensorInterface]) -> Result<usize, BusError>{
+    let mut sent: usize = 0;
+    for (sub, sens) in sensors.iter_mut().enumerate() {
+        let channels = match sens.alarms() {
+            Some(table) => table.channels(),
+            None => continue,
+        };
+        let order = sens.get_capabilities().endianness;
+
+        for channel in channels {
+            let format = match sens.channel_descriptor(channel) {
+                Some(desc) => desc.channel_type.format(),
+                None => continue,
+            };
+            let value = match units::raw_to_f64(format, sens.read_data(channel), order) {
+                Some(v) => v as f32,
+                None => continue,
+            };
+            let mut alarm = match sens.alarms().and_then(|table| table.update(channel, value)) {
+                Some(a) => a,
+                None => continue,
+            };
+            alarm.sub = sub.min(u8::MAX as usize) as u8;
+            alarm::send_alarm(bus, slv_id, &alarm)?;
+            sent += 1;
+        }
+    }
+    Ok(sent)
+}
+
+
+// Handles one request, returns the module's new address if it was given one.
+fn handle(slv_id: u8, bus: &mut dyn Bus, sensors: &mut [&mut dyn SensorInterface]) -> Result<Option<u8>, BusError>{
     
     //get the cmd out of the message.
     let result = bus.receive_message()?;
 
-    let _id;
-    let master_data: Vec<u8>;
-    (_id, master_data) = result;
-    let cmd: ControllerCommand = master_data[0].into();
+    let rx_id: u32;
+    let mut master_data: Vec<u8>;
+    (rx_id, master_data) = result;
+
+    //ignore anything that isn't a request meant for this module.
+    let rx_id = CanId::from_raw(rx_id)?;
+    let is_request = rx_id.msg_type == MessageType::Request ||
+        rx_id.msg_type == MessageType::Broadcast;
+    if !is_request || !rx_id.is_for(slv_id) {
+        return Ok(None);
+    }
+
+    //drop the crc trailer(if the link uses one), a bad one means the
+    //request can't be trusted so we don't answer it.
+    rx_id.crc.strip(&mut master_data)?;
+
+    //the reply goes back to whoever asked, at the same priority and
+    //with the same kind of crc trailer.
+    let tx_id = CanId::new(rx_id.priority, MessageType::Response, rx_id.source, slv_id)
+        .with_crc(rx_id.crc)
+        .to_raw()?;
+
+    if master_data.len() < REQUEST_HEADER_LEN {
+        return Err(BusError::BadParameter);
+    }
+    let mut cmd: ControllerCommand = master_data[0].into();
+    let seq: u8 = master_data[1];
+
+    //every reply echoes the request's sequence number first.
+    let mut write_buf: Vec<u8> = vec![seq];
+    let mut assigned: Option<u8> = None;
+
+    //a wrapped request is unwrapped into [cmd, seq, args..] for the
+    //sub-device it's meant for, the reply gets a status in front.
+    let mut sub: usize = 0;
+    let wrapped = cmd == ControllerCommand::SubDeviceRequest;
+    if wrapped {
+        if master_data.len() < REQUEST_HEADER_LEN + subdevice::SUB_ARGS_LEN {
+            return Err(BusError::BadParameter);
+        }
+        sub = master_data[REQUEST_HEADER_LEN] as usize;
+        let inner = master_data[REQUEST_HEADER_LEN + 1];
+        cmd = inner.into();
+        master_data.drain(..REQUEST_HEADER_LEN);
+        master_data[0] = inner;
+        master_data[1] = seq;
+
+        let status = if sub >= sensors.len() {
+            subdevice::SubStatus::BadIndex
+        } else if subdevice::is_node_command(cmd) {
+            subdevice::SubStatus::BadCommand
+        } else {
+            subdevice::SubStatus::Ok
+        };
+        write_buf.push(status as u8);
+        if status != subdevice::SubStatus::Ok {
+            rx_id.crc.append(&mut write_buf);
+            bus.send_message(tx_id, &write_buf)?;
+            return Ok(None);
+        }
+    }
+    let reply_start = write_buf.len();
+
+    //only the node keeps time(TimeSync and TimeAdjust are node commands),
+    //so every sub-device's timestamps come from its clock. Taken first so
+    //it's as close to the trigger or reading as we can get.
+    let node_now = match cmd {
+        ControllerCommand::AcqArmRequest |
+        ControllerCommand::AcqTriggerRequest |
+        ControllerCommand::AcqStopRequest |
+        ControllerCommand::AcqStateRequest |
+        ControllerCommand::TimedDataRequest => node_time(sensors),
+        _ => None,
+    };
+
+    //a broadcast trigger starts every sensor on the module armed with it,
+    //and isn't answered, every module would at once.
+    if cmd == ControllerCommand::AcqTriggerRequest && !wrapped && rx_id.msg_type == MessageType::Broadcast {
+        let args = &master_data[REQUEST_HEADER_LEN..];
+        let now = node_now.unwrap_or(0);
+        for s in sensors.iter_mut() {
+            if let Some(acq) = s.acquisition() {
+                acquisition::handle_trigger(acq, now, args, &mut Vec::new());
+            }
+        }
+        return Ok(None);
+    }
+
+    let sensors_len = sensors.len().min(u8::MAX as usize) as u8;
+    let sens: &mut dyn SensorInterface = match sensors.get_mut(sub) {
+        Some(s) => &mut **s,
+        None => return Err(BusError::BadParameter),
+    };
 
-    let mut write_buf: Vec<u8> = vec![];
-    
     //match the command so we can call a handler.
     match cmd {
         ControllerCommand::NameRequest => {
             //get the data from the sensor interface.
             let name = sens.get_name().as_bytes();            
             
-            for i in 0..name.len() {
-                write_buf.push(name[i]);
-            }
-
-            //send the data.              
-            bus.send_message(slv_id, &write_buf)?;
-
+            write_buf.extend_from_slice(name);
         }
         ControllerCommand::StatusRequest => {
-            let status = sens.get_status() as u8;
-            write_buf.push(status); 
-            bus.send_message(slv_id, &write_buf)?;
-
+            sens.get_health().encode(&mut write_buf);
         }
         ControllerCommand::ResetRequest => {
-            let status = sens.soft_reset() as u8;
-            write_buf.push(status); 
-            bus.send_message(slv_id, &write_buf)?;
-
+            sens.reset_health().encode(&mut write_buf);
         }
         ControllerCommand::FormattingRequest => {
             let formatting = sens.get_format().as_bytes(); 
             
-            for i in 0..formatting.len() {
-                write_buf.push(formatting[i]);
-            }
-
-            bus.send_message(slv_id, &write_buf)?;
-
+            write_buf.extend_from_slice(formatting);
         }
         ControllerCommand::DnamesRequest => {
 
             let data_names = sens.get_data_names().as_bytes(); 
             
-            for i in 0..data_names.len() {
-                write_buf.push(data_names[i]);
-            }
-
-            bus.send_message(slv_id, &write_buf)?;
-
+            write_buf.extend_from_slice(data_names);
         }
         ControllerCommand::DataRequest => {
-            // The '1' index of the sent data indicates the sensor info 
+            // The byte after the header indicates the sensor info 
             // that is being requested.
-            let data_index = master_data[1];
+            if master_data.len() <= REQUEST_HEADER_LEN {
+                return Err(BusError::BadParameter);
+            }
+            let data_index = master_data[REQUEST_HEADER_LEN];
 
             // The sensor info returned is based off the index.
-            let sensor_info = sens.read_sensor(data_index);
-            for i in 0..sensor_info.size {
-                write_buf.push(sensor_info.data[i]);
+            let sensor_info = sens.read_data(data_index);
+            write_buf.extend_from_slice(sensor_info);
+        }
+        ControllerCommand::LargeDataRequest => {
+            //same as DataRequest, the reply is segmented below.
+            let idx = param_index(&master_data)?;
+            let sensor_info = sens.read_data(idx);
+            write_buf.extend_from_slice(sensor_info);
+        }
+        ControllerCommand::VersionRequest => {
+            write_buf.push(capabilities::PROTOCOL_VERSION_MAJOR);
+            write_buf.push(capabilities::PROTOCOL_VERSION_MINOR);
+        }
+        ControllerCommand::CapabilitiesRequest => {
+            let caps = sens.get_capabilities().to_bytes();
+            write_buf.extend_from_slice(&caps);
+        }
+        ControllerCommand::ParamCountRequest => {
+            write_buf.push(sens.param_count());
+        }
+        ControllerCommand::ParamInfoRequest => {
+            let idx = param_index(&master_data)?;
+            match sens.param_descriptor(idx) {
+                Some(desc) if idx < sens.param_count() => desc.encode(idx, &mut write_buf),
+                _ => write_buf.push(params::ParamStatus::BadIndex as u8),
+            }
+        }
+        ControllerCommand::ParamGetRequest => {
+            let idx = param_index(&master_data)?;
+            match sens.get_param(idx) {
+                Some(value) if idx < sens.param_count() => {
+                    write_buf.push(params::ParamStatus::Ok as u8);
+                    value.encode(&mut write_buf);
+                }
+                _ => write_buf.push(params::ParamStatus::BadIndex as u8),
+            }
+        }
+        ControllerCommand::ParamSetRequest => {
+            let idx = param_index(&master_data)?;
+            let status = match params::ParamValue::decode(&master_data[REQUEST_HEADER_LEN + 1..]) {
+                Ok(value) => apply_param(sens, idx, value),
+                Err(_e) => params::ParamStatus::BadType,
+            };
+            write_buf.push(status as u8);
+        }
+        ControllerCommand::ConfigSaveRequest => {
+            write_buf.push(save_config(sens) as u8);
+        }
+        ControllerCommand::ConfigLoadRequest => {
+            write_buf.push(load_config(sens) as u8);
+        }
+        ControllerCommand::FactoryResetRequest => {
+            write_buf.push(factory_reset(sens) as u8);
+        }
+        ControllerCommand::FwBeginRequest |
+        ControllerCommand::FwWriteRequest |
+        ControllerCommand::FwVerifyRequest |
+        ControllerCommand::FwCommitRequest => {
+            let args = &master_data[REQUEST_HEADER_LEN..];
+            match sens.firmware_target() {
+                Some(target) => match cmd {
+                    ControllerCommand::FwBeginRequest => firmware::handle_begin(target, args, &mut write_buf),
+                    ControllerCommand::FwWriteRequest => firmware::handle_write(target, args, &mut write_buf),
+                    ControllerCommand::FwVerifyRequest => firmware::handle_verify(target, &mut write_buf),
+                    _ => firmware::handle_commit(target, &mut write_buf),
+                },
+                None => {
+                    write_buf.push(firmware::FwStatus::NoTarget as u8);
+                    write_buf.extend_from_slice(&0u32.to_be_bytes());
+                }
+            }
+        }
+        ControllerCommand::BlockPrepareRequest |
+        ControllerCommand::BlockInfoRequest |
+        ControllerCommand::BlockReadRequest |
+        ControllerCommand::BlockAckRequest => {
+            //reads get whatever room the frame has left.
+            let room = (sens.get_capabilities().max_payload as usize)
+                .saturating_sub(reply_start + block::READ_HEADER_LEN + rx_id.crc.trailer_len());
+            let args = &master_data[REQUEST_HEADER_LEN..];
+            match sens.block_source() {
+                Some(source) => match cmd {
+                    ControllerCommand::BlockPrepareRequest => block::handle_prepare(source, args, &mut write_buf),
+                    ControllerCommand::BlockInfoRequest => block::handle_info(source, &mut write_buf),
+                    ControllerCommand::BlockReadRequest => block::handle_read(source, args, room, &mut write_buf),
+                    _ => block::handle_ack(source, &mut write_buf),
+                },
+                None => write_buf.push(block::BlockStatus::NoSource as u8),
+            }
+        }
+        ControllerCommand::AcqArmRequest |
+        ControllerCommand::AcqTriggerRequest |
+        ControllerCommand::AcqStopRequest |
+        ControllerCommand::AcqStateRequest => {
+            let now = node_now.unwrap_or(0);
+            let args = &master_data[REQUEST_HEADER_LEN..];
+            match sens.acquisition() {
+                Some(acq) => match cmd {
+                    ControllerCommand::AcqArmRequest => acquisition::handle_arm(acq, args, &mut write_buf),
+                    ControllerCommand::AcqTriggerRequest => acquisition::handle_trigger(acq, now, args, &mut write_buf),
+                    ControllerCommand::AcqStopRequest => acquisition::handle_stop(acq, &mut write_buf),
+                    _ => acquisition::handle_state(acq, &mut write_buf),
+                },
+                None => write_buf.push(acquisition::AcqStatus::NotSupported as u8),
+            }
+            //not even a broadcast one for a single sub-device.
+            if cmd == ControllerCommand::AcqTriggerRequest && rx_id.msg_type == MessageType::Broadcast {
+                return Ok(None);
+            }
+        }
+        ControllerCommand::AlarmSetRequest |
+        ControllerCommand::AlarmGetRequest |
+        ControllerCommand::AlarmClearRequest => {
+            let channels = sens.channel_count();
+            let args = &master_data[REQUEST_HEADER_LEN..];
+            match sens.alarms() {
+                Some(table) => match cmd {
+                    ControllerCommand::AlarmSetRequest => alarm::handle_set(table, channels, args, &mut write_buf),
+                    ControllerCommand::AlarmGetRequest => alarm::handle_get(table, args, &mut write_buf),
+                    _ => alarm::handle_clear(table, args, &mut write_buf),
+                },
+                None => write_buf.push(alarm::AlarmStatus::NotSupported as u8),
+            }
+        }
+        ControllerCommand::IdentifyRequest => {
+            addressing::handle_identify(sens, &mut write_buf);
+        }
+        ControllerCommand::TimeSyncRequest => {
+            timesync::handle_sync(sens.clock(), &mut write_buf);
+        }
+        ControllerCommand::TimeAdjustRequest => {
+            let args = &master_data[REQUEST_HEADER_LEN..];
+            timesync::handle_adjust(sens.clock(), args, &mut write_buf);
+        }
+        ControllerCommand::TimedDataRequest => {
+            let idx = param_index(&master_data)?;
+            let channels = sens.channel_count();
+            match node_now {
+                None => write_buf.push(timesync::TimeStatus::NoClock as u8),
+                Some(_) if idx >= channels => {
+                    write_buf.push(timesync::TimeStatus::BadIndex as u8);
+                }
+                Some(now) => {
+                    write_buf.push(timesync::TimeStatus::Ok as u8);
+                    write_buf.extend_from_slice(&now.to_be_bytes());
+                    let sensor_info = sens.read_data(idx);
+                    write_buf.extend_from_slice(sensor_info);
+                }
+            }
+        }
+        ControllerCommand::ChannelMetaRequest => {
+            let idx = param_index(&master_data)?;
+            let channels = sens.channel_count();
+            match sens.channel_meta(idx) {
+                Some(meta) if idx < channels => meta.encode(idx, &mut write_buf),
+                Some(_) => write_buf.push(units::MetaStatus::BadIndex as u8),
+                None if idx < channels => write_buf.push(units::MetaStatus::NoMeta as u8),
+                None => write_buf.push(units::MetaStatus::BadIndex as u8),
+            }
+        }
+        ControllerCommand::ChannelDescRequest => {
+            let idx = param_index(&master_data)?;
+            let count = sens.channel_count();
+            match sens.channel_descriptor(idx) {
+                Some(desc) if idx < count => desc.encode(count, &mut write_buf),
+                _ => write_buf.push(channels::DescStatus::BadIndex as u8),
+            }
+        }
+        ControllerCommand::SubDeviceCountRequest => {
+            write_buf.push(sensors_len);
+        }
+        ControllerCommand::SubDeviceRequest => {
+            //unwrapped above, can't get here.
+            return Err(BusError::BadParameter);
+        }
+        ControllerCommand::SelfTestRequest => {
+            selftest::handle(sens.self_test(), &mut write_buf);
+        }
+        ControllerCommand::VendorRequest => {
+            //the module's own command, the code is the raw command byte.
+            let code = master_data[0];
+            let args = &master_data[REQUEST_HEADER_LEN..];
+            vendor::handle(sens.vendor_handler(), code, args, &mut write_buf);
+        }
+        ControllerCommand::AddressAssignRequest => {
+            let args = &master_data[REQUEST_HEADER_LEN..];
+            assigned = addressing::handle_assign(sens, args, &mut write_buf);
+            if write_buf.len() == reply_start {
+                //someone else's serial.
+                return Ok(None);
+            }
+        }
+        ControllerCommand::UnknownCommand => {
+            //answered so the controller isn't left waiting, nothing is run.
+            write_buf.push(UNKNOWN_COMMAND_REPLY);
+        }
+    }
+
+    //long replies go out in segments, each with the seq(and sub-device
+    //status) in front.
+    if segment::is_segmented(cmd) {
+        let chunk = segment::chunk_size(sens.get_capabilities().max_payload, reply_start, rx_id.crc);
+        let (prefix, data) = write_buf.split_at(reply_start);
+        segment::send_segments(bus, tx_id, rx_id.crc, prefix, data, chunk)?;
+        return Ok(assigned);
+    }
+
+    //send the data, an assignment is answered from the old address
+    //since that's where the controller sent it.
+    rx_id.crc.append(&mut write_buf);
+    bus.send_message(tx_id, &write_buf)?;
+
+    Ok(assigned) 
+}
+
+
+// Now on the node's clock, the first sensor's.
+fn node_time(sensors: &mut [&mut dyn SensorInterface]) -> Option<u64> {
+    sensors.first_mut().and_then(|s| s.clock()).map(|clock| clock.now_us())
+}
+
+
+// The parameter index is the first byte after the header.
+fn param_index(master_data: &[u8]) -> Result<u8, BusError> {
+    if master_data.len() <= REQUEST_HEADER_LEN {
+        return Err(BusError::BadParameter);
+    }
+    Ok(master_data[REQUEST_HEADER_LEN])
+}
+
+
+// Checks the new value against the descriptor before handing it over.
+fn apply_param(sens: &mut dyn SensorInterface, idx: u8, value: params::ParamValue) -> params::ParamStatus {
+    let desc = match sens.param_descriptor(idx) {
+        Some(d) if idx < sens.param_count() => d,
+        _ => return params::ParamStatus::BadIndex,
+    };
+
+    if !desc.writable {
+        return params::ParamStatus::ReadOnly;
+    }
+
+    if value.param_type() != desc.param_type() {
+        return params::ParamStatus::BadType;
+    }
+
+    if !value.in_range(&desc.min, &desc.max) {
+        return params::ParamStatus::OutOfRange;
+    }
+
+    sens.set_param(idx, value)
+}
+
+
+// Saves every writable parameter, keyed by name.
+fn save_config(sens: &mut dyn SensorInterface) -> config_store::StoreStatus {
+    let mut entries: Vec<config_store::ConfigEntry> = vec![];
+    for idx in 0..sens.param_count() {
+        let desc = sens.param_descriptor(idx);
+        let value = sens.get_param(idx);
+        if let (Some(desc), Some(value)) = (desc, value) {
+            if desc.writable {
+                entries.push(config_store::ConfigEntry {
+                    key: config_store::param_key(desc.name),
+                    value,
+                });
             }
+        }
+    }
+
+    match sens.config_store() {
+        Some(store) => config_store::save_record(store, &entries),
+        None => config_store::StoreStatus::NoStore,
+    }
+}
+
 
-            bus.send_message(slv_id, &write_buf)?;
+// Puts the saved values back, anything that no longer matches a parameter
+// (renamed, retyped, out of range) is left at its current value.
+fn load_config(sens: &mut dyn SensorInterface) -> config_store::StoreStatus {
+    let entries = match sens.config_store() {
+        Some(store) => config_store::load_record(store),
+        None => return config_store::StoreStatus::NoStore,
+    };
+    let entries = match entries {
+        Ok(e) => e,
+        Err(status) => return status,
+    };
+
+    for idx in 0..sens.param_count() {
+        let key = match sens.param_descriptor(idx) {
+            Some(desc) => config_store::param_key(desc.name),
+            None => continue,
+        };
+        for entry in entries.iter() {
+            if entry.key == key {
+                apply_param(sens, idx, entry.value);
+            }
         }
     }
+    config_store::StoreStatus::Ok
+}
 
 
-    Ok(()) 
+// Back to the descriptor defaults, and forget whatever was saved.
+fn factory_reset(sens: &mut dyn SensorInterface) -> config_store::StoreStatus {
+    for idx in 0..sens.param_count() {
+        if let Some(desc) = sens.param_descriptor(idx) {
+            apply_param(sens, idx, desc.default);
+        }
+    }
+
+    match sens.config_store() {
+        Some(store) => match config_store::erase_all(store) {
+            Ok(()) => config_store::StoreStatus::Ok,
+            Err(_e) => config_store::StoreStatus::Failed,
+        },
+        None => config_store::StoreStatus::Ok,
+    }
 }
 
 
@@ -92,6 +539,9 @@ mod handler_tests {
     use super::*;
     use crate::fake_bus::FakeBus;
     use crate::fake_sensor::*;
+    use crate::crc::CrcMode;
+
+    const SEQ: u8 = 0x2A;
 
     #[allow(dead_code)]
     struct TestData{
@@ -111,6 +561,12 @@ mod handler_tests {
                 data_types: ["u8", "u16", "u16"],
                 data_names: ["Status", "Temp", "Humid"],
                 data: sd,
+                params: DEFAULT_PARAMS,
+                store: None,
+                flash: None,
+                serial: None,
+                addr_store: None,
+                clock: None,
         };
 
         let fake_bus = FakeBus::new();
@@ -124,6 +580,7 @@ mod handler_tests {
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn check_self() {
         assert!(true);
     }
@@ -131,60 +588,117 @@ mod handler_tests {
     #[test]
     fn name_handler() {
         let mut td = setup();
-        let slv_id: u32 = 0x01;
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
 
         // Preload the needed test data.
-        let data: Vec<u8> = vec![ControllerCommand::NameRequest as u8];
+        let data: Vec<u8> = vec![ControllerCommand::NameRequest as u8, SEQ];
         assert!(td.bus.set_rmsg_data(&data).is_ok());
 
         // Call the code under test.
         assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
         
         // Check that the response is correct.
-        assert_eq!(td.bus.spy_data(), td.sens.sensor_name.as_bytes());
+        assert_eq!(td.bus.spy_data()[0], SEQ);
+        assert_eq!(td.bus.spy_data()[1..], *td.sens.sensor_name.as_bytes());
+        let tx_id = CanId::from_raw(td.bus.spy_id()).unwrap();
+        assert_eq!(tx_id.msg_type, MessageType::Response);
+        assert_eq!(tx_id.source, slv_id);
+        assert_eq!(tx_id.destination, crate::can_id::CONTROLLER_NODE);
+    }
+
+    #[test]
+    fn ignores_other_nodes() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(0x02).to_raw().unwrap());
+
+        // Preload the needed test data.
+        let data: Vec<u8> = vec![ControllerCommand::NameRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+
+        // Call the code under test, nothing should be sent.
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data().len(), 0);
+    }
+
+    #[test]
+    fn answers_broadcast() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        let bcast = CanId::new(0, MessageType::Broadcast, crate::can_id::BROADCAST_NODE, 0);
+        td.bus.set_rmsg_id(bcast.to_raw().unwrap());
+
+        // Preload the needed test data.
+        let data: Vec<u8> = vec![ControllerCommand::StatusRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+
+        // Call the code under test.
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data()[1], td.sens.get_status() as u8);
+        assert_eq!(CanId::from_raw(td.bus.spy_id()).unwrap().source, slv_id);
     }
 
 
     #[test]
     fn status_handler() {
         let mut td = setup();
-        let slv_id: u32 = 0x01;
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
 
         // Preload the needed test data.
-        let data: Vec<u8> = vec![ControllerCommand::StatusRequest as u8];
+        let data: Vec<u8> = vec![ControllerCommand::StatusRequest as u8, SEQ];
         assert!(td.bus.set_rmsg_data(&data).is_ok());
 
         // Call the code under test.
         assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
         
         // Check that the response is correct.
-        assert_eq!(td.bus.spy_data()[0], td.sens.get_status() as u8);
+        assert_eq!(td.bus.spy_data()[1], td.sens.get_status() as u8);
     }
 
     #[test]
     fn reset_handler() {
         let mut td = setup();
-        let slv_id: u32 = 0x01;
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
 
         // Preload the needed test data.
-        let data: Vec<u8> = vec![ControllerCommand::ResetRequest as u8];
+        let data: Vec<u8> = vec![ControllerCommand::ResetRequest as u8, SEQ];
         assert!(td.bus.set_rmsg_data(&data).is_ok());
 
         // Call the code under test.
         assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
         
         // Check that the response is correct.
-        assert_eq!(td.bus.spy_data()[0], td.sens.soft_reset() as u8);
+        assert_eq!(td.bus.spy_data()[1], td.sens.soft_reset() as u8);
+    }
+
+    #[test]
+    fn unknown_command_handler() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        // The first byte after the last command, not a reset.
+        let data: Vec<u8> = vec![COMMAND_COUNT, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+
+        assert!(ControllerCommand::from(COMMAND_COUNT) == ControllerCommand::UnknownCommand);
+        assert_eq!(td.bus.spy_data(), vec![SEQ, UNKNOWN_COMMAND_REPLY]);
     }
 
     #[test]
     fn formatting_handler() {
         
         let mut td = setup();
-        let slv_id: u32 = 0x01;
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
 
         // Preload the needed test data.
-        let data: Vec<u8> = vec![ControllerCommand::FormattingRequest as u8];
+        let data: Vec<u8> = vec![ControllerCommand::FormattingRequest as u8, SEQ];
         assert!(td.bus.set_rmsg_data(&data).is_ok());
 
         // Call the code under test.
@@ -192,19 +706,20 @@ mod handler_tests {
         
         // Check that the response is correct.
         let mut tmps: String = String::new();
-        tmps.push_str(td.sens.data_types[0]); tmps.push_str(" ");
-        tmps.push_str(td.sens.data_types[1]); tmps.push_str(" ");
+        tmps.push_str(td.sens.data_types[0]); tmps.push(' ');
+        tmps.push_str(td.sens.data_types[1]); tmps.push(' ');
         tmps.push_str(td.sens.data_types[2]); 
-        assert_eq!(td.bus.spy_data(), tmps.into_bytes());
+        assert_eq!(td.bus.spy_data()[1..], tmps.into_bytes());
     }
 
     #[test]
     fn dnames_handler() {
         let mut td = setup();
-        let slv_id: u32 = 0x01;
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
 
         // Preload the needed test data.
-        let data: Vec<u8> = vec![ControllerCommand::DnamesRequest as u8];
+        let data: Vec<u8> = vec![ControllerCommand::DnamesRequest as u8, SEQ];
         assert!(td.bus.set_rmsg_data(&data).is_ok());
 
         // Call the code under test.
@@ -212,20 +727,21 @@ mod handler_tests {
         
         // Check that the response is correct.
         let mut tmps: String = String::new();
-        tmps.push_str(td.sens.data_names[0]); tmps.push_str(" ");
-        tmps.push_str(td.sens.data_names[1]); tmps.push_str(" ");
+        tmps.push_str(td.sens.data_names[0]); tmps.push(' ');
+        tmps.push_str(td.sens.data_names[1]); tmps.push(' ');
         tmps.push_str(td.sens.data_names[2]);
-        assert_eq!(td.bus.spy_data(), tmps.into_bytes());
+        assert_eq!(td.bus.spy_data()[1..], tmps.into_bytes());
     }
 
     #[test]
     fn data_handler() {
         let mut td = setup();
-        let slv_id: u32 = 0x01;
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
 
         // Preload the needed test data.
         let data_index: u8 = 1; //Should equate to "temp" 
-        let mut data: Vec<u8> = vec![ControllerCommand::DataRequest as u8];
+        let mut data: Vec<u8> = vec![ControllerCommand::DataRequest as u8, SEQ];
         data.push(data_index);
         assert!(td.bus.set_rmsg_data(&data).is_ok());
 
@@ -236,8 +752,334 @@ mod handler_tests {
         println!("spy_data {:?}", td.bus.spy_data());
         println!("sensor data size: {}", td.sens.data.size);
         println!("sensor_data: {:?}", td.sens.data.data);
-        assert_eq!(td.bus.spy_data()[0], td.sens.data.data[0]);
-        assert_eq!(td.bus.spy_data()[1], td.sens.data.data[1]);
+        assert_eq!(td.bus.spy_data()[0], SEQ);
+        assert_eq!(td.bus.spy_data()[1], td.sens.data.data[0]);
+        assert_eq!(td.bus.spy_data()[2], td.sens.data.data[1]);
+    }
+
+    #[test]
+    fn version_handler() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        let data: Vec<u8> = vec![ControllerCommand::VersionRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ,
+            capabilities::PROTOCOL_VERSION_MAJOR,
+            capabilities::PROTOCOL_VERSION_MINOR]);
+    }
+
+    #[test]
+    fn capabilities_handler() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        let data: Vec<u8> = vec![ControllerCommand::CapabilitiesRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        let caps = capabilities::Capabilities::from_bytes(&td.bus.spy_data()[1..]).unwrap();
+        assert_eq!(caps, td.sens.get_capabilities());
+    }
+
+    #[test]
+    fn param_handlers() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        // Count.
+        let data: Vec<u8> = vec![ControllerCommand::ParamCountRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, NUM_PARAMS as u8]);
+
+        // Info.
+        let data: Vec<u8> = vec![ControllerCommand::ParamInfoRequest as u8, SEQ, 1];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        let mut expected: Vec<u8> = vec![SEQ];
+        PARAMS[1].encode(1, &mut expected);
+        assert_eq!(td.bus.spy_data(), expected);
+
+        // Get.
+        let data: Vec<u8> = vec![ControllerCommand::ParamGetRequest as u8, SEQ, 0];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        let mut expected: Vec<u8> = vec![SEQ, params::ParamStatus::Ok as u8];
+        DEFAULT_PARAMS[0].encode(&mut expected);
+        assert_eq!(td.bus.spy_data(), expected);
+
+        // Set.
+        let mut data: Vec<u8> = vec![ControllerCommand::ParamSetRequest as u8, SEQ, 0];
+        params::ParamValue::U16(500).encode(&mut data);
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, params::ParamStatus::Ok as u8]);
+        assert_eq!(td.sens.params[0], params::ParamValue::U16(500));
+    }
+
+    #[test]
+    fn param_set_checks() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        let checks = [
+            (0, params::ParamValue::U16(1001), params::ParamStatus::OutOfRange),
+            (0, params::ParamValue::U8(5), params::ParamStatus::BadType),
+            (3, params::ParamValue::U32(1), params::ParamStatus::ReadOnly),
+            (9, params::ParamValue::U8(1), params::ParamStatus::BadIndex),
+        ];
+
+        for (idx, value, status) in checks.iter() {
+            let mut data: Vec<u8> = vec![ControllerCommand::ParamSetRequest as u8, SEQ, *idx];
+            value.encode(&mut data);
+            assert!(td.bus.set_rmsg_data(&data).is_ok());
+            assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+            assert_eq!(td.bus.spy_data(), vec![SEQ, *status as u8]);
+        }
+        assert_eq!(td.sens.params, DEFAULT_PARAMS);
+    }
+
+    #[test]
+    fn config_handlers() {
+        let path = crate::file_store::file_store_tests::temp_path();
+        let mut td = setup();
+        td.sens.store = Some(crate::file_store::FileStore::open(&path, 128).unwrap());
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        // Save with a changed value.
+        td.sens.params[1] = params::ParamValue::U8(32);
+        let data: Vec<u8> = vec![ControllerCommand::ConfigSaveRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, config_store::StoreStatus::Ok as u8]);
+
+        // Factory reset.
+        let data: Vec<u8> = vec![ControllerCommand::FactoryResetRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, config_store::StoreStatus::Ok as u8]);
+        assert_eq!(td.sens.params, DEFAULT_PARAMS);
+
+        // The store was erased too.
+        let data: Vec<u8> = vec![ControllerCommand::ConfigLoadRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, config_store::StoreStatus::Empty as u8]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn firmware_handlers() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        // No staging flash, nothing to update.
+        let data: Vec<u8> = vec![ControllerCommand::FwCommitRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, firmware::FwStatus::NoTarget as u8, 0, 0, 0, 0]);
+
+        // Begin a 4 byte image, then write it.
+        td.sens.flash = Some(crate::fake_flash::RamFlash::new(16));
+        let image: [u8; 4] = [1, 2, 3, 4];
+        let mut data: Vec<u8> = vec![ControllerCommand::FwBeginRequest as u8, SEQ, 0, 0, 0, 4];
+        data.extend_from_slice(&crate::crc::crc32(&image).to_be_bytes());
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, firmware::FwStatus::Ok as u8, 0, 0, 0, 0]);
+
+        let mut data: Vec<u8> = vec![ControllerCommand::FwWriteRequest as u8, SEQ, 0, 0, 0, 0];
+        data.extend_from_slice(&image);
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, firmware::FwStatus::Ok as u8, 0, 0, 0, 4]);
+
+        let data: Vec<u8> = vec![ControllerCommand::FwVerifyRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data()[1], firmware::FwStatus::Ok as u8);
+        assert!(td.sens.flash.as_ref().unwrap().state.verified);
+    }
+
+    #[test]
+    fn address_assign() {
+        let mut td = setup();
+        td.sens.serial = Some(0x1234);
+        let mut node = crate::can_id::UNASSIGNED_NODE;
+        td.bus.set_rmsg_id(CanId::request(node).to_raw().unwrap());
+
+        // Someone else's serial, no reply and the address stays.
+        let mut data: Vec<u8> = vec![ControllerCommand::AddressAssignRequest as u8, SEQ];
+        data.extend_from_slice(&0x9999u64.to_be_bytes());
+        data.push(7);
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command_dynamic(&mut node, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data().len(), 0);
+        assert_eq!(node, crate::can_id::UNASSIGNED_NODE);
+
+        // Ours, answered from the old address then moved.
+        let mut data: Vec<u8> = vec![ControllerCommand::AddressAssignRequest as u8, SEQ];
+        data.extend_from_slice(&0x1234u64.to_be_bytes());
+        data.push(7);
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command_dynamic(&mut node, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, addressing::AddrStatus::NotSaved as u8]);
+        assert_eq!(CanId::from_raw(td.bus.spy_id()).unwrap().source, crate::can_id::UNASSIGNED_NODE);
+        assert_eq!(node, 7);
+
+        // Identify gives the serial.
+        td.bus.set_rmsg_id(CanId::request(node).to_raw().unwrap());
+        let data: Vec<u8> = vec![ControllerCommand::IdentifyRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command_dynamic(&mut node, &mut td.bus, &mut td.sens).is_ok());
+        let mut expected: Vec<u8> = vec![SEQ, addressing::AddrStatus::Ok as u8];
+        expected.extend_from_slice(&0x1234u64.to_be_bytes());
+        assert_eq!(td.bus.spy_data(), expected);
+    }
+
+    #[test]
+    fn timed_data_handler() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+        td.sens.clock = Some(crate::fake_clock::FakeClock::new(0x0102));
+
+        let data: Vec<u8> = vec![ControllerCommand::TimedDataRequest as u8, SEQ, 0];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+
+        // status, time, then the u8 reading.
+        let mut expected: Vec<u8> = vec![SEQ, timesync::TimeStatus::Ok as u8];
+        expected.extend_from_slice(&0x0102u64.to_be_bytes());
+        expected.push(td.sens.data.data[0]);
+        assert_eq!(td.bus.spy_data(), expected);
+    }
+
+    #[test]
+    fn crc_request() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).with_crc(CrcMode::Crc16).to_raw().unwrap());
+
+        // Preload a request with a good crc trailer.
+        let mut data: Vec<u8> = vec![ControllerCommand::StatusRequest as u8, SEQ];
+        CrcMode::Crc16.append(&mut data);
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+
+        // The reply uses the same trailer as the request.
+        let tx_id = CanId::from_raw(td.bus.spy_id()).unwrap();
+        assert_eq!(tx_id.crc, CrcMode::Crc16);
+        let mut reply = td.bus.spy_data();
+        assert!(CrcMode::Crc16.strip(&mut reply).is_ok());
+        assert_eq!(reply, vec![SEQ, td.sens.get_status() as u8, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn bad_crc_request() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).with_crc(CrcMode::Crc32).to_raw().unwrap());
+
+        // Corrupt the request after the trailer is added.
+        let mut data: Vec<u8> = vec![ControllerCommand::ResetRequest as u8, SEQ];
+        CrcMode::Crc32.append(&mut data);
+        data[0] = ControllerCommand::NameRequest as u8;
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+
+        // Nothing should be sent back.
+        let res = handle_bus_command(slv_id, &mut td.bus, &mut td.sens);
+        assert!(matches!(res, Err(BusError::CrcMismatch)));
+        assert_eq!(td.bus.spy_data().len(), 0);
     }
 
+    #[test]
+    fn channel_desc_request() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        let data: Vec<u8> = vec![ControllerCommand::ChannelDescRequest as u8, SEQ, 1];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+
+        let reply = td.bus.spy_data();
+        assert_eq!(reply[0], SEQ);
+        let (desc, count) = channels::ChannelDescriptor::decode(&reply[1..]).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(desc.index, 1);
+        assert_eq!(desc.name, "Temperature");
+
+        let data: Vec<u8> = vec![ControllerCommand::ChannelDescRequest as u8, SEQ, 3];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, channels::DescStatus::BadIndex as u8]);
+    }
+
+    #[test]
+    fn sub_device_request() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+        let mut other = setup().sens;
+        other.sensor_name = "Other";
+
+        let data: Vec<u8> = vec![ControllerCommand::SubDeviceRequest as u8, SEQ, 1, ControllerCommand::NameRequest as u8];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command_multi(slv_id, &mut td.bus, &mut [&mut td.sens, &mut other]).is_ok());
+        let mut expected = vec![SEQ, subdevice::SubStatus::Ok as u8];
+        expected.extend_from_slice(b"Other");
+        assert_eq!(td.bus.spy_data(), expected);
+
+        let data: Vec<u8> = vec![ControllerCommand::SubDeviceRequest as u8, SEQ, 2, ControllerCommand::NameRequest as u8];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command_multi(slv_id, &mut td.bus, &mut [&mut td.sens, &mut other]).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, subdevice::SubStatus::BadIndex as u8]);
+
+        let data: Vec<u8> = vec![ControllerCommand::SubDeviceCountRequest as u8, SEQ];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command_multi(slv_id, &mut td.bus, &mut [&mut td.sens, &mut other]).is_ok());
+        assert_eq!(td.bus.spy_data(), vec![SEQ, 2]);
+    }
+
+    #[test]
+    fn wrapped_vendor_request() {
+        use crate::fake_sensor::VENDOR_PEEK;
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+        let mut other = setup().sens;
+        other.data.data = [9, 8, 7, 6];
+
+        // Vendor commands can go to a sub-device too.
+        let data: Vec<u8> = vec![ControllerCommand::SubDeviceRequest as u8, SEQ, 1, VENDOR_PEEK];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+        assert!(handle_bus_command_multi(slv_id, &mut td.bus, &mut [&mut td.sens, &mut other]).is_ok());
+        let expected = vec![SEQ, subdevice::SubStatus::Ok as u8, vendor::VendorStatus::Ok as u8, 9, 8, 7, 6];
+        assert_eq!(td.bus.spy_data(), expected);
+    }
+
+    #[test]
+    fn short_request_rejected() {
+        let mut td = setup();
+        let slv_id: u8 = 0x01;
+        td.bus.set_rmsg_id(CanId::request(slv_id).to_raw().unwrap());
+
+        // Missing the sequence number.
+        let data: Vec<u8> = vec![ControllerCommand::NameRequest as u8];
+        assert!(td.bus.set_rmsg_data(&data).is_ok());
+
+        assert!(handle_bus_command(slv_id, &mut td.bus, &mut td.sens).is_err());
+    }
 }
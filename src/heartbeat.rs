@@ -0,0 +1,86 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: heartbeat.rs
+ * Desc: Optional periodic frame a module sends so the controller knows it's
+ *       still alive between requests, see `monitor` for the controller side.
+ */
+
+use crate::Bus;
+use crate::BusError;
+use crate::SensorInterface;
+use crate::SensorStatus;
+use crate::can_id::CanId;
+use crate::can_id::MessageType;
+use crate::can_id::CONTROLLER_NODE;
+use crate::can_id::MAX_PRIORITY;
+
+// status(1) + uptime(4)
+pub const HEARTBEAT_LEN: usize = 5;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub status: SensorStatus,
+    pub uptime: u32,    //Seconds since the module started.
+}
+
+impl Heartbeat {
+    // [status, uptime(4, BE)]
+    pub fn to_bytes(&self) -> [u8; HEARTBEAT_LEN] {
+        let up = self.uptime.to_be_bytes();
+        [self.status as u8, up[0], up[1], up[2], up[3]]
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Result<Heartbeat, BusError> {
+        if b.len() < HEARTBEAT_LEN {
+            return Err(BusError::BadParameter);
+        }
+        Ok(Heartbeat {
+            status: b[0].into(),
+            uptime: u32::from_be_bytes([b[1], b[2], b[3], b[4]]),
+        })
+    }
+}
+
+
+// Sends the module's heartbeat. Call it every period from the module's
+// main loop or a timer, `uptime` comes from whatever clock it has.
+// Heartbeats go at the lowest priority so they never hold up a request.
+pub fn send_heartbeat(bus: &mut dyn Bus, node: u8, sens: &dyn SensorInterface, uptime: u32) -> Result<(), BusError> {
+    let hb = Heartbeat {
+        status: sens.get_status(),
+        uptime,
+    };
+    let id = CanId::new(MAX_PRIORITY, MessageType::Heartbeat, CONTROLLER_NODE, node).to_raw()?;
+    bus.send_message(id, &hb.to_bytes().to_vec())
+}
+
+
+#[cfg(test)]
+mod heartbeat_tests {
+    use super::*;
+    use crate::fake_bus::FakeBus;
+    use crate::fake_sensor::*;
+
+    #[test]
+    fn round_trip() {
+        let hb = Heartbeat { status: SensorStatus::VoltageWarning, uptime: 0x0102_0304 };
+        assert_eq!(Heartbeat::from_bytes(&hb.to_bytes()).unwrap(), hb);
+        assert!(Heartbeat::from_bytes(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn heartbeat_frame() {
+        let sens = ExampleSensor::new([0; 4]);
+        let mut bus = FakeBus::new();
+        assert!(send_heartbeat(&mut bus, 3, &sens, 60).is_ok());
+
+        let id = CanId::from_raw(bus.spy_id()).unwrap();
+        assert_eq!(id.msg_type, MessageType::Heartbeat);
+        assert_eq!(id.source, 3);
+        assert_eq!(id.priority, MAX_PRIORITY);
+        let hb = Heartbeat::from_bytes(&bus.spy_data()).unwrap();
+        assert_eq!(hb, Heartbeat { status: SensorStatus::Ready, uptime: 60 });
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: influx.rs
+ * Desc: Samples as InfluxDB line protocol. The sensor name is the
+ *       measurement, node/channel/unit are tags and the value the one
+ *       field, timestamped in nanoseconds:
+ *
+ *           Aht20,node=3,channel=Temperature,unit=Cel value=21.5 1709296205250000000
+ */
+
+use std::io;
+use std::io::Write;
+use std::time::UNIX_EPOCH;
+
+use crate::sink::SampleRecord;
+use crate::sink::SampleSink;
+
+
+// One line, without the newline. None for NaN and inf, Influx won't take
+// them.
+pub fn encode(record: &SampleRecord) -> Option<String> {
+    if !record.value.is_finite() {
+        return None;
+    }
+    let nanos = record.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    Some(format!("{},node={},channel={},unit={} value={:?} {}",
+        escape(&record.sensor, false),
+        record.node,
+        escape(&record.channel_name, true),
+        escape(&record.unit, true),
+        record.value,
+        nanos))
+}
+
+// Commas and spaces in measurements, and '=' as well in tags. Line breaks
+// can't be escaped so they're dropped. Empty names/tags aren't allowed so
+// they get a placeholder.
+fn escape(s: &str, tag: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' | '\r' => continue,
+            ',' | ' ' => out.push('\\'),
+            '=' if tag => out.push('\\'),
+            '\\' if tag => out.push('\\'),
+            _ => {}
+        }
+        out.push(c);
+    }
+    if out.is_empty() {
+        return String::from("_");
+    }
+    out
+}
+
+
+// Writes line protocol to anything, a file, a socket to Telegraf, a
+// buffer for an HTTP write..
+pub struct InfluxSink<W: Write> {
+    out: W,
+    skipped: u64,
+}
+
+impl<W: Write> InfluxSink<W> {
+    pub fn new(out: W) -> InfluxSink<W> {
+        InfluxSink {
+            out,
+            skipped: 0,
+        }
+    }
+
+    // Records left out for not having a finite value.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> SampleSink for InfluxSink<W> {
+    fn write(&mut self, record: &SampleRecord) -> io::Result<()> {
+        match encode(record) {
+            Some(line) => writeln!(self.out, "{}", line),
+            None => {
+                self.skipped += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod influx_tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn record() -> SampleRecord {
+        SampleRecord {
+            time: UNIX_EPOCH + Duration::from_millis(1_709_296_205_250),
+            node: 3,
+            sensor: String::from("Aht20"),
+            channel: 1,
+            channel_name: String::from("Temperature"),
+            element: None,
+            unit: String::from("Cel"),
+            value: 21.5,
+        }
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(encode(&record()).unwrap(), "Aht20,node=3,channel=Temperature,unit=Cel value=21.5 1709296205250000000");
+        // Whole numbers still go as floats.
+        assert!(encode(&SampleRecord { value: 40.0, ..record() }).unwrap().contains(" value=40.0 "));
+
+        let awkward = SampleRecord {
+            sensor: String::from("Lab sensor,2"),
+            channel_name: String::from("Relative humidity=x"),
+            unit: String::new(),
+            ..record()
+        };
+        assert_eq!(encode(&awkward).unwrap(),
+            "Lab\\ sensor\\,2,node=3,channel=Relative\\ humidity\\=x,unit=_ value=21.5 1709296205250000000");
+
+        let broken = SampleRecord {
+            sensor: String::from("Aht\n20"),
+            channel_name: String::from("Temp\r\nerature"),
+            unit: String::from("\n"),
+            ..record()
+        };
+        assert_eq!(encode(&broken).unwrap(), "Aht20,node=3,channel=Temperature,unit=_ value=21.5 1709296205250000000");
+    }
+
+    #[test]
+    fn sink() {
+        let mut sink = InfluxSink::new(Vec::new());
+        sink.write(&record()).unwrap();
+        sink.write(&SampleRecord { value: f64::NAN, ..record() }).unwrap();
+        sink.write(&SampleRecord { node: 4, value: -0.25, ..record() }).unwrap();
+        assert_eq!(sink.skipped(), 1);
+
+        let text = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("Aht20,node=4,") && lines[1].contains(" value=-0.25 "));
+    }
+}
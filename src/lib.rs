@@ -15,6 +15,12 @@ extern crate alloc;
 #[cfg(all(not(test), feature = "sensor_module"))]
 use alloc::{vec::Vec, vec, string};
 
+/* So `#[derive(Sensor)]`'s `::bus_interface::` paths work in here too. */
+extern crate self as bus_interface;
+
+#[cfg(any(test, feature = "derive"))]
+pub use bus_interface_derive::Sensor;
+
 /* Need to have the debug for embedded systems */
 #[cfg(all(not(test), feature = "sensor_module"))]
 use defmt::debug;
@@ -26,11 +32,14 @@ const _MAX_NAME_BYTES_LEN: usize = 64;
 const _MAX_WAIT_MS: u32 = 500;
 const _SEND_BUFFER_BYTES: usize = 8;
 const _READ_BUFFER_BYTES: usize = 8;
-#[allow(dead_code)]
-const CRONTROLLER_ID: u32 = 0; /*Not actually dead code, called by controller*/
 const _CONTROLLER_BUFFER: usize = 256;
 const MAX_DATA: usize = 4;
 
+// Every request starts with [cmd, seq], every response with [seq].
+// The module echoes the sequence number back so replies can be matched.
+#[allow(dead_code)]
+const REQUEST_HEADER_LEN: usize = 2;
+
 
 // The Errors that we allow as result's
 #[derive(Debug)]
@@ -38,18 +47,21 @@ pub enum BusError {
     Unknown,
     BadParameter,
     BusError,
+    CrcMismatch,
 }
 
 //A simplified bus setup. Will define wrappers for a variety of busses 
-//elsewhere.
+//elsewhere. The id is the raw extended(29-bit) id, see `can_id::CanId`.
 pub trait Bus{
+    //Kept as &Vec, every bus implementation out there is written against it.
+    #[allow(clippy::ptr_arg)]
     fn send_message(&mut self, id: u32, data: &Vec<u8>) -> Result<(), BusError>;
     fn receive_message(&mut self) -> Result<(u32, Vec<u8>), BusError>;
 }
 
 
 //#[derive(Debug, PartialEq, Eq)]
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum ControllerCommand {
     NameRequest = 0,   //Indicates the sensor's name.
@@ -58,8 +70,55 @@ pub enum ControllerCommand {
     FormattingRequest,  //Gives the format of sensor's readings.
     DnamesRequest,     //Gives the data's names, (volts/temp/humidity etc)
     DataRequest,       //For requests of the sensor's data for individual type.
+    VersionRequest,    //Gives the protocol version the module speaks.
+    CapabilitiesRequest, //Gives the commands/features the module supports.
+    ParamCountRequest, //Gives how many config parameters the module has.
+    ParamInfoRequest,  //Gives the name/type/range of one parameter.
+    ParamGetRequest,   //Reads the current value of one parameter.
+    ParamSetRequest,   //Writes a new value to one parameter.
+    ConfigSaveRequest, //Saves the parameters to the module's config store.
+    ConfigLoadRequest, //Loads the saved parameters back.
+    FactoryResetRequest, //Erases the store and restores the defaults.
+    FwBeginRequest,    //Starts(or resumes) a firmware update.
+    FwWriteRequest,    //Writes the next chunk of the firmware image.
+    FwVerifyRequest,   //Checks the whole image against its crc.
+    FwCommitRequest,   //Marks the new image to boot and reboots.
+    IdentifyRequest,   //Gives the module's hardware serial number.
+    AddressAssignRequest, //Gives the module with a serial a new node address.
+    TimeSyncRequest,   //Gives the module's synchronized time.
+    TimeAdjustRequest, //Moves the module's clock by the given amount.
+    TimedDataRequest,  //Like DataRequest, with the time the reading was taken.
+    ChannelMetaRequest, //Gives a channel's unit, scaling and range.
+    ChannelDescRequest, //Gives a channel's type, name and flags as TLVs.
+    SubDeviceCountRequest, //Gives how many sensors the module has.
+    SubDeviceRequest,  //Sends the wrapped command to one of the module's sensors.
+    SelfTestRequest,   //Runs the module's self tests, the results are segmented.
+    LargeDataRequest,  //DataRequest for channels too big for a frame, segmented.
+    BlockPrepareRequest, //Captures a block of samples to be read out.
+    BlockInfoRequest,  //Channel, sample type, size and crc of the block.
+    BlockReadRequest,  //Reads part of the block from an offset.
+    BlockAckRequest,   //The controller has the block, the module can drop it.
+    AcqArmRequest,     //Gets ready to start sampling on a trigger.
+    AcqTriggerRequest, //Starts the modules armed with the trigger, usually broadcast.
+    AcqStopRequest,    //Stops sampling(or disarms).
+    AcqStateRequest,   //Idle, armed or running, and when it started.
+    AlarmSetRequest,   //Sets the threshold alarm on a channel.
+    AlarmGetRequest,   //A channel's alarm rule and level.
+    AlarmClearRequest, //Removes a channel's alarm.
+    // A byte below the vendor range that isn't one of the above, the module
+    // answers it with UNKNOWN_COMMAND_REPLY and does nothing else.
+    UnknownCommand = vendor::VENDOR_COMMAND_BASE - 1,
+    // Any byte from VENDOR_COMMAND_BASE up, the module decides what it does.
+    VendorRequest = vendor::VENDOR_COMMAND_BASE,
 }
 
+// Number of commands above, they are numbered from 0 with no gaps(not
+// counting the vendor range).
+pub const COMMAND_COUNT: u8 = 41;
+
+// The whole reply(after the seq) to a command the module doesn't know.
+pub const UNKNOWN_COMMAND_REPLY: u8 = 0xFF;
+
 impl From<u8> for ControllerCommand {
     fn from(value: u8) -> Self {
         match value {
@@ -69,14 +128,50 @@ impl From<u8> for ControllerCommand {
             3 => ControllerCommand::FormattingRequest,
             4 => ControllerCommand::DnamesRequest,
             5 => ControllerCommand::DataRequest,
-            _ => ControllerCommand::ResetRequest
+            6 => ControllerCommand::VersionRequest,
+            7 => ControllerCommand::CapabilitiesRequest,
+            8 => ControllerCommand::ParamCountRequest,
+            9 => ControllerCommand::ParamInfoRequest,
+            10 => ControllerCommand::ParamGetRequest,
+            11 => ControllerCommand::ParamSetRequest,
+            12 => ControllerCommand::ConfigSaveRequest,
+            13 => ControllerCommand::ConfigLoadRequest,
+            14 => ControllerCommand::FactoryResetRequest,
+            15 => ControllerCommand::FwBeginRequest,
+            16 => ControllerCommand::FwWriteRequest,
+            17 => ControllerCommand::FwVerifyRequest,
+            18 => ControllerCommand::FwCommitRequest,
+            19 => ControllerCommand::IdentifyRequest,
+            20 => ControllerCommand::AddressAssignRequest,
+            21 => ControllerCommand::TimeSyncRequest,
+            22 => ControllerCommand::TimeAdjustRequest,
+            23 => ControllerCommand::TimedDataRequest,
+            24 => ControllerCommand::ChannelMetaRequest,
+            25 => ControllerCommand::ChannelDescRequest,
+            26 => ControllerCommand::SubDeviceCountRequest,
+            27 => ControllerCommand::SubDeviceRequest,
+            28 => ControllerCommand::SelfTestRequest,
+            29 => ControllerCommand::LargeDataRequest,
+            30 => ControllerCommand::BlockPrepareRequest,
+            31 => ControllerCommand::BlockInfoRequest,
+            32 => ControllerCommand::BlockReadRequest,
+            33 => ControllerCommand::BlockAckRequest,
+            34 => ControllerCommand::AcqArmRequest,
+            35 => ControllerCommand::AcqTriggerRequest,
+            36 => ControllerCommand::AcqStopRequest,
+            37 => ControllerCommand::AcqStateRequest,
+            38 => ControllerCommand::AlarmSetRequest,
+            39 => ControllerCommand::AlarmGetRequest,
+            40 => ControllerCommand::AlarmClearRequest,
+            vendor::VENDOR_COMMAND_BASE..=u8::MAX => ControllerCommand::VendorRequest,
+            _ => ControllerCommand::UnknownCommand
         }
     }
 }
 
 
 // Used to indicate the various kinds of sensor module statuses/states.
-//#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum SensorStatus {
     Ready = 0,
@@ -88,6 +183,20 @@ pub enum SensorStatus {
     VoltageWarning,
 }
 
+impl From<u8> for SensorStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SensorStatus::Ready,
+            1 => SensorStatus::Busy,
+            3 => SensorStatus::PowerFailure,
+            4 => SensorStatus::BusFailure,
+            5 => SensorStatus::TempertureWarning,
+            6 => SensorStatus::VoltageWarning,
+            _ => SensorStatus::SensorFailure,
+        }
+    }
+}
+
 
 //This gives the methods that must be implimented for any sensor that
 //impliments the SensorInterface trait.
@@ -103,30 +212,226 @@ pub trait SensorInterface {
 
     fn get_data_names(&self) -> &'static str;
 
+    // Reads a channel, at most MAX_DATA bytes.
     fn read_sensor(&mut self, idx: u8) -> &SensorData;
 
+    // Reads a channel, this is what the handler sends back. Override it for
+    // channels bigger than MAX_DATA.
+    fn read_data(&mut self, idx: u8) -> &[u8] {
+        self.read_sensor(idx).as_bytes()
+    }
+
+    // Number of channels, one per entry in `get_format`.
+    fn channel_count(&self) -> u8 {
+        self.get_format().split(' ').count() as u8
+    }
+
+    // Describes a channel for ChannelDescRequest. By default it's built from
+    // `get_format`/`get_data_names`, override for names with spaces or to
+    // set flags.
+    fn channel_descriptor(&self, idx: u8) -> Option<channels::ChannelDescriptor> {
+        let mut desc = channels::from_strings(self.get_format(), self.get_data_names(), idx)?;
+        if self.channel_meta(idx).is_some() {
+            desc.flags |= channels::CHANNEL_HAS_META;
+        }
+        Some(desc)
+    }
+
+    // Unit and scaling of each channel(same order as `get_format`).
+    fn channel_meta(&self, _idx: u8) -> Option<units::ChannelMeta> {
+        None
+    }
+
+    // Full health for StatusRequest, override to report several flags at
+    // once or a vendor detail code/message.
+    fn get_health(&self) -> status::HealthReport {
+        self.get_status().into()
+    }
+
+    // Same for ResetRequest, overriding this replaces `soft_reset` there.
+    fn reset_health(&mut self) -> status::HealthReport {
+        self.soft_reset().into()
+    }
+
+    // What the module supports, override to advertise optional features
+    // or a different payload size/byte order.
+    fn get_capabilities(&self) -> capabilities::Capabilities {
+        let mut caps = capabilities::Capabilities::default();
+        if self.param_count() > 0 {
+            caps.features |= capabilities::FEATURE_CONFIG_WRITE;
+        }
+        caps
+    }
+
+    // Configuration parameters, sensors without any can leave these be.
+    // The handler checks the index, type and range before `set_param`.
+    fn param_count(&self) -> u8 {
+        0
+    }
+
+    fn param_descriptor(&self, _idx: u8) -> Option<params::ParamDescriptor> {
+        None
+    }
+
+    fn get_param(&self, _idx: u8) -> Option<params::ParamValue> {
+        None
+    }
+
+    fn set_param(&mut self, _idx: u8, _value: params::ParamValue) -> params::ParamStatus {
+        params::ParamStatus::ReadOnly
+    }
+
+    // Where the parameters are saved across reboots, if anywhere.
+    fn config_store(&mut self) -> Option<&mut dyn config_store::ConfigStore> {
+        None
+    }
+
+    // The staging flash for firmware updates over the bus, if supported.
+    fn firmware_target(&mut self) -> Option<&mut dyn firmware::FirmwareTarget> {
+        None
+    }
+
+    // Captured sample blocks for block transfers, if supported.
+    fn block_source(&mut self) -> Option<&mut dyn block::BlockSource> {
+        None
+    }
+
+    // Arm/trigger/stop hooks for synchronized sampling, if supported.
+    fn acquisition(&mut self) -> Option<&mut dyn acquisition::Acquisition> {
+        None
+    }
+
+    // The channel alarm rules, for modules that check them.
+    fn alarms(&mut self) -> Option<&mut alarm::AlarmTable> {
+        None
+    }
+
+    // Unique hardware serial number, needed for dynamic addressing.
+    fn serial_number(&self) -> Option<u64> {
+        None
+    }
+
+    // Where an assigned node address is kept across reboots. Kept apart
+    // from `config_store` so a factory reset doesn't lose the address.
+    fn address_store(&mut self) -> Option<&mut dyn config_store::ConfigStore> {
+        None
+    }
+
+    // The module's clock, needed for time sync and timed data.
+    fn clock(&mut self) -> Option<&mut dyn timesync::ModuleClock> {
+        None
+    }
+
+    // Runs the module's diagnostics, None if it doesn't have any.
+    fn self_test(&mut self) -> Option<Vec<selftest::TestResult>> {
+        None
+    }
+
+    // Handles the module's own commands(see `vendor`), if it has any.
+    fn vendor_handler(&mut self) -> Option<&mut dyn vendor::VendorHandler> {
+        None
+    }
+
 }
 
 
-//#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum BusStatus {
     Good = 0,
     Busy,
     Error,
     DataErr,
+    Unsupported,
+    Rejected,       //The module refused the value(out of range etc).
 }
 
+// A channel's reading. The capacity defaults to MAX_DATA bytes, sensors
+// with bigger channels(arrays, f64..) keep a `SensorData<N>` of their own
+// and hand it out from `read_data`.
 #[allow(dead_code)]
-pub struct SensorData {
-    data: [u8; MAX_DATA],
+pub struct SensorData<const N: usize = MAX_DATA> {
+    data: [u8; N],
     size: usize,
 }
 
+impl<const N: usize> Default for SensorData<N> {
+    fn default() -> Self {
+        SensorData::new()
+    }
+}
+
+impl<const N: usize> SensorData<N> {
+    pub const CAPACITY: usize = N;
+
+    pub const fn new() -> SensorData<N> {
+        SensorData {
+            data: [0; N],
+            size: 0,
+        }
+    }
+
+    // Copies in a reading, anything past the capacity is dropped.
+    pub fn set(&mut self, bytes: &[u8]) {
+        let size = bytes.len().min(N);
+        self.data[..size].copy_from_slice(&bytes[..size]);
+        self.size = size;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.size]
+    }
+}
+
+// What `read_sensor` gives for a derived sensor whose channels don't fit a
+// plain `SensorData`, `read_data` has the real readings.
+pub static NO_READING: SensorData = SensorData::new();
+
 
 
 /* All the modules we need*/
-mod cmd_return;
+pub mod cmd_return;
+
+pub mod can_id;
+
+pub mod crc;
+
+pub mod capabilities;
+
+pub mod params;
+
+pub mod config_store;
+
+pub mod firmware;
+
+pub mod addressing;
+
+pub mod heartbeat;
+
+pub mod status;
+
+pub mod timesync;
+
+pub mod units;
+
+pub mod channels;
+
+pub mod subdevice;
+
+pub mod vendor;
+
+pub mod segment;
+
+pub mod selftest;
+
+pub mod block;
+
+pub mod acquisition;
+
+pub mod alarm;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod file_store;
 
 #[cfg(test)]
 mod fake_sensor;
@@ -134,8 +439,44 @@ mod fake_sensor;
 #[cfg(test)]
 mod fake_bus;
 
+#[cfg(test)]
+mod fake_flash;
+
+#[cfg(test)]
+mod fake_clock;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod controller;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod fw_upload;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod block_read;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod monitor;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod alarm_monitor;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod sink;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod sample_log;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod influx;
+
+#[cfg(any(test, feature = "metrics_http"))]
+pub mod metrics_http;
+
+#[cfg(any(test, feature = "bus_master"))]
+pub mod mqtt;
+
 #[cfg(any(test, feature = "bus_master"))]
-mod controller;
+pub mod scheduler;
 
 #[cfg(any(test, feature = "sensor_module"))]
-mod handler;
+pub mod handler;
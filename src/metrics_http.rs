@@ -0,0 +1,261 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: metrics_http.rs
+ * Desc: The latest value of every channel, served at /metrics in the
+ *       Prometheus text format for scraping. `LatestValues` is the sink the
+ *       scheduler writes to, `MetricsServer` a small HTTP server on its own
+ *       thread sharing it. Behind the `metrics_http` feature.
+ *
+ *           bus_sample{node="3",sensor="Aht20",channel="Temperature",unit="Cel"} 21.5
+ */
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::sink::SampleRecord;
+use crate::sink::SampleSink;
+
+pub const METRIC_NAME: &str = "bus_sample";
+
+// Biggest request we'll read, anything longer is cut off.
+const MAX_REQUEST: usize = 4096;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+// The last record from each node's channels. Clones share the same values.
+#[derive(Debug, Clone, Default)]
+pub struct LatestValues {
+    records: Arc<Mutex<Vec<SampleRecord>>>,
+}
+
+impl LatestValues {
+    pub fn new() -> LatestValues {
+        LatestValues::default()
+    }
+
+    // Sorted by node then channel.
+    pub fn snapshot(&self) -> Vec<SampleRecord> {
+        let mut records = match self.records.lock() {
+            Ok(r) => r.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+        records.sort_by(|a, b| (a.node, a.channel, &a.channel_name).cmp(&(b.node, b.channel, &b.channel_name)));
+        records
+    }
+
+    pub fn exposition(&self) -> String {
+        exposition(&self.snapshot())
+    }
+}
+
+impl SampleSink for LatestValues {
+    fn write(&mut self, record: &SampleRecord) -> io::Result<()> {
+        let mut records = match self.records.lock() {
+            Ok(r) => r,
+            Err(e) => e.into_inner(),
+        };
+        // Array elements share the channel, the name tells them apart.
+        match records.iter_mut().find(|r| r.node == record.node && r.channel_name == record.channel_name) {
+            Some(last) => *last = record.clone(),
+            None => records.push(record.clone()),
+        }
+        Ok(())
+    }
+}
+
+
+// The records as one gauge, labelled by node, sensor, channel and unit.
+pub fn exposition(records: &[SampleRecord]) -> String {
+    let mut out = format!("# HELP {} Latest value read from each bus channel.\n# TYPE {} gauge\n", METRIC_NAME, METRIC_NAME);
+    for r in records.iter() {
+        out += &format!("{}{{node=\"{}\",sensor=\"{}\",channel=\"{}\",unit=\"{}\"}} {}\n",
+            METRIC_NAME,
+            r.node,
+            escape_label(&r.sensor),
+            escape_label(&r.channel_name),
+            escape_label(&r.unit),
+            metric_value(r.value));
+    }
+    out
+}
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn metric_value(v: f64) -> String {
+    if v.is_nan() {
+        return String::from("NaN");
+    }
+    if v.is_infinite() {
+        return String::from(if v > 0.0 { "+Inf" } else { "-Inf" });
+    }
+    v.to_string()
+}
+
+
+// Answers GET /metrics until stopped(or dropped). One connection at a
+// time, a scrape is small.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    // Port 0 picks a free one, see `addr`.
+    pub fn start(addr: impl ToSocketAddrs, values: LatestValues) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let stopping = stop.clone();
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                // A client that goes away is its own problem.
+                if let Ok(s) = stream {
+                    let _ = serve(s, &values);
+                }
+            }
+        });
+
+        Ok(MetricsServer {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stop(&mut self) {
+        let thread = match self.thread.take() {
+            Some(t) => t,
+            None => return,
+        };
+        self.stop.store(true, Ordering::SeqCst);
+        // Wakes up the accept so it sees the flag.
+        let _ = TcpStream::connect(self.addr);
+        let _ = thread.join();
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+
+fn serve(mut stream: TcpStream, values: &LatestValues) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    // Only the request line matters, read up to the end of the headers.
+    let mut request: Vec<u8> = vec![];
+    let mut buf = [0u8; 512];
+    while request.len() < MAX_REQUEST && !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, body) = match (method, path.split('?').next().unwrap_or("")) {
+        ("GET", "/metrics") => ("200 OK", values.exposition()),
+        ("GET", _) => ("404 Not Found", String::from("Not found, try /metrics\n")),
+        _ => ("405 Method Not Allowed", String::from("Only GET\n")),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)?;
+    stream.flush()
+}
+
+
+#[cfg(test)]
+mod metrics_http_tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn record(node: u8, channel_name: &str, value: f64) -> SampleRecord {
+        SampleRecord {
+            time: UNIX_EPOCH,
+            node,
+            sensor: String::from("Aht20"),
+            channel: 1,
+            channel_name: String::from(channel_name),
+            element: None,
+            unit: String::from("Cel"),
+            value,
+        }
+    }
+
+    // A bare HTTP/1.1 client, gives the status line and the body.
+    fn get(addr: SocketAddr, method: &str, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nAccept: text/plain\r\n\r\n", method, path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    #[test]
+    fn latest_only() {
+        let mut values = LatestValues::new();
+        values.write(&record(3, "Temperature", 21.5)).unwrap();
+        values.write(&record(2, "Temp \"in\"", f64::NAN)).unwrap();
+        values.write(&record(3, "Temperature", 22.0)).unwrap();
+
+        assert_eq!(values.exposition(), String::from("# HELP bus_sample Latest value read from each bus channel.\n")
+            + "# TYPE bus_sample gauge\n"
+            + "bus_sample{node=\"2\",sensor=\"Aht20\",channel=\"Temp \\\"in\\\"\",unit=\"Cel\"} NaN\n"
+            + "bus_sample{node=\"3\",sensor=\"Aht20\",channel=\"Temperature\",unit=\"Cel\"} 22\n");
+    }
+
+    #[test]
+    fn scrape() {
+        let values = LatestValues::new();
+        let mut server = MetricsServer::start("127.0.0.1:0", values.clone()).unwrap();
+
+        let (status, body) = get(server.addr(), "GET", "/metrics");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(!body.contains("bus_sample{"));
+
+        // The scheduler's side of the same values.
+        let mut sink = values.clone();
+        sink.write(&record(3, "Accel[0]", 0.5)).unwrap();
+        sink.write(&record(3, "Accel[1]", -9.81)).unwrap();
+        let (_, body) = get(server.addr(), "GET", "/metrics");
+        assert!(body.contains("channel=\"Accel[0]\",unit=\"Cel\"} 0.5\n"));
+        assert!(body.contains("channel=\"Accel[1]\",unit=\"Cel\"} -9.81\n"));
+
+        assert_eq!(get(server.addr(), "GET", "/").0, "HTTP/1.1 404 Not Found");
+        assert_eq!(get(server.addr(), "POST", "/metrics").0, "HTTP/1.1 405 Method Not Allowed");
+
+        server.stop();
+        assert!(TcpStream::connect(server.addr()).is_err());
+    }
+}
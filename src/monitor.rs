@@ -0,0 +1,216 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: monitor.rs
+ * Desc: Keeps track of which modules are alive from their heartbeats and
+ *       tells whoever subscribed when one comes, goes or changes status.
+ */
+
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::Bus;
+use crate::SensorStatus;
+use crate::controller::Controller;
+use crate::heartbeat::Heartbeat;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeEvent {
+    Online { node: u8, status: SensorStatus },
+    Offline { node: u8 },
+    StatusChanged { node: u8, old: SensorStatus, new: SensorStatus },
+}
+
+
+// What the monitor knows about one node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeHealth {
+    pub online: bool,
+    pub status: SensorStatus,
+    pub uptime: u32,
+    pub last_seen: Instant,
+}
+
+
+pub type Subscriber<'a> = Box<dyn FnMut(&NodeEvent) + 'a>;
+
+
+// A node is offline once nothing has been heard from it for `timeout`,
+// a few heartbeat periods is about right. Time is passed in so the caller
+// decides where it comes from.
+pub struct NodeMonitor<'a> {
+    timeout: Duration,
+    nodes: Vec<(u8, NodeHealth)>,
+    subscribers: Vec<Subscriber<'a>>,
+}
+
+impl<'a> NodeMonitor<'a> {
+    pub fn new(timeout: Duration) -> NodeMonitor<'a> {
+        NodeMonitor {
+            timeout,
+            nodes: vec![],
+            subscribers: vec![],
+        }
+    }
+
+    // Every event goes to every subscriber, in the order they subscribed.
+    pub fn subscribe(&mut self, f: impl FnMut(&NodeEvent) + 'a) {
+        self.subscribers.push(Box::new(f));
+    }
+
+    // Picks up the heartbeats waiting on the bus(or already read by the
+    // controller) then looks for nodes that have gone quiet.
+    pub fn poll(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus, now: Instant) {
+        ctrl.poll_bus(bus);
+        for (node, hb) in ctrl.take_heartbeats() {
+            self.heartbeat(node, hb, now);
+        }
+        self.check(now);
+    }
+
+    pub fn heartbeat(&mut self, node: u8, hb: Heartbeat, now: Instant) {
+        let pos = self.nodes.iter().position(|(n, _)| *n == node);
+        let i = match pos {
+            Some(i) => i,
+            None => {
+                self.nodes.push((node, NodeHealth {
+                    online: false,
+                    status: hb.status,
+                    uptime: hb.uptime,
+                    last_seen: now,
+                }));
+                self.nodes.len() - 1
+            }
+        };
+
+        let old = self.nodes[i].1;
+        self.nodes[i].1 = NodeHealth {
+            online: true,
+            status: hb.status,
+            uptime: hb.uptime,
+            last_seen: now,
+        };
+
+        if !old.online {
+            self.raise(NodeEvent::Online { node, status: hb.status });
+        }
+        else if old.status != hb.status {
+            self.raise(NodeEvent::StatusChanged { node, old: old.status, new: hb.status });
+        }
+    }
+
+    // Marks the nodes that haven't been heard from in time as offline.
+    pub fn check(&mut self, now: Instant) {
+        let mut gone: Vec<u8> = vec![];
+        for (node, health) in self.nodes.iter_mut() {
+            if health.online && now.duration_since(health.last_seen) > self.timeout {
+                health.online = false;
+                gone.push(*node);
+            }
+        }
+        for node in gone {
+            self.raise(NodeEvent::Offline { node });
+        }
+    }
+
+    pub fn health(&self, node: u8) -> Option<&NodeHealth> {
+        for (n, health) in self.nodes.iter() {
+            if *n == node {
+                return Some(health);
+            }
+        }
+        None
+    }
+
+    pub fn online_nodes(&self) -> Vec<u8> {
+        self.nodes.iter().filter(|(_, h)| h.online).map(|(n, _)| *n).collect()
+    }
+
+    fn raise(&mut self, event: NodeEvent) {
+        for f in self.subscribers.iter_mut() {
+            f(&event);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod monitor_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::fake_bus::LoopbackBus;
+    use crate::fake_sensor::*;
+
+    const NODE: u8 = 0x01;
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    fn hb(status: SensorStatus, uptime: u32) -> Heartbeat {
+        Heartbeat { status, uptime }
+    }
+
+    #[test]
+    fn online_status_offline() {
+        let events: RefCell<Vec<NodeEvent>> = RefCell::new(vec![]);
+        let mut mon = NodeMonitor::new(TIMEOUT);
+        mon.subscribe(|e| events.borrow_mut().push(*e));
+        let start = Instant::now();
+
+        mon.heartbeat(NODE, hb(SensorStatus::Ready, 1), start);
+        mon.heartbeat(NODE, hb(SensorStatus::Ready, 2), start + Duration::from_secs(1));
+        mon.heartbeat(NODE, hb(SensorStatus::Busy, 3), start + Duration::from_secs(2));
+
+        // Still within the timeout of the last one.
+        mon.check(start + Duration::from_secs(5));
+        assert_eq!(mon.online_nodes(), vec![NODE]);
+
+        mon.check(start + Duration::from_secs(6));
+        assert!(!mon.health(NODE).unwrap().online);
+
+        // Back again.
+        mon.heartbeat(NODE, hb(SensorStatus::Busy, 1), start + Duration::from_secs(10));
+        drop(mon);
+
+        assert_eq!(*events.borrow(), vec![
+            NodeEvent::Online { node: NODE, status: SensorStatus::Ready },
+            NodeEvent::StatusChanged { node: NODE, old: SensorStatus::Ready, new: SensorStatus::Busy },
+            NodeEvent::Offline { node: NODE },
+            NodeEvent::Online { node: NODE, status: SensorStatus::Busy },
+        ]);
+    }
+
+    #[test]
+    fn every_subscriber_told() {
+        let count: RefCell<usize> = RefCell::new(0);
+        let mut mon = NodeMonitor::new(TIMEOUT);
+        mon.subscribe(|_| *count.borrow_mut() += 1);
+        mon.subscribe(|_| *count.borrow_mut() += 10);
+
+        mon.heartbeat(NODE, hb(SensorStatus::Ready, 1), Instant::now());
+        drop(mon);
+        assert_eq!(*count.borrow(), 11);
+    }
+
+    #[test]
+    fn heartbeats_from_bus() {
+        let sens = ExampleSensor::new([0; 4]);
+        let mut bus = LoopbackBus::new(NODE, sens);
+        let mut ctrl = Controller::new();
+        let mut mon = NodeMonitor::new(TIMEOUT);
+        let now = Instant::now();
+
+        bus.heartbeat(42);
+        mon.poll(&mut ctrl, &mut bus, now);
+        let health = mon.health(NODE).unwrap();
+        assert!(health.online);
+        assert_eq!(health.uptime, 42);
+        assert_eq!(health.status, SensorStatus::Ready);
+
+        // One that arrives while waiting on a reply isn't lost.
+        bus.heartbeat(43);
+        let ret = ctrl.send_bus_command(&mut bus, NODE, &crate::ControllerCommand::NameRequest, String::new());
+        assert!(ret.is_ok());
+        mon.poll(&mut ctrl, &mut bus, now);
+        assert_eq!(mon.health(NODE).unwrap().uptime, 43);
+    }
+}
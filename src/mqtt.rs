@@ -0,0 +1,359 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: mqtt.rs
+ * Desc: Bridges the bus to MQTT. Samples go out on `bus/<node>/<channel>`
+ *       (`bus/<node>/<channel>/<i>` for array elements), each node's name,
+ *       format and data names as a retained `bus/<node>/discovery`, and
+ *       commands come in on `bus/<node>/cmd/<command>`:
+ *
+ *           reset           soft resets the node
+ *           config          "Name=value", writes a parameter
+ *
+ *       Each command's outcome goes back on `.../cmd/<command>/result`,
+ *       "ok" or "error: ..". The MQTT connection itself is whatever
+ *       implements `MqttClient`, so any client library can be put under it.
+ */
+
+use std::io;
+
+use crate::Bus;
+use crate::BusStatus;
+use crate::ControllerCommand;
+use crate::controller::Controller;
+use crate::params::ParamValue;
+use crate::sample_log::json_string;
+use crate::sink::SampleRecord;
+use crate::sink::SampleSink;
+
+pub const DEFAULT_PREFIX: &str = "bus";
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+
+// The little the gateway needs from an MQTT connection.
+pub trait MqttClient {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()>;
+
+    // Filters can use the `+` and `#` wildcards.
+    fn subscribe(&mut self, filter: &str) -> io::Result<()>;
+
+    // The next message from the subscriptions, None if nothing's waiting.
+    fn poll_message(&mut self) -> io::Result<Option<MqttMessage>>;
+}
+
+
+// What a node says about itself, published retained for whoever joins
+// later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovery {
+    pub name: String,
+    pub format: Vec<String>,
+    pub data_names: Vec<String>,
+}
+
+impl Discovery {
+    pub fn fetch(ctrl: &mut Controller, bus: &mut dyn Bus, node: u8) -> Result<Discovery, BusStatus> {
+        let name = ctrl.send_bus_command(bus, node, &ControllerCommand::NameRequest, String::new())?.name;
+        let format = ctrl.send_bus_command(bus, node, &ControllerCommand::FormattingRequest, String::new())?.format;
+        let data_names = ctrl.send_bus_command(bus, node, &ControllerCommand::DnamesRequest, String::new())?.data_names;
+        Ok(Discovery { name, format, data_names })
+    }
+
+    // {"name":"..","format":["u8",..],"data_names":["Status",..]}
+    pub fn to_json(&self) -> String {
+        let list = |v: &Vec<String>| v.iter().map(|s| json_string(s)).collect::<Vec<String>>().join(",");
+        format!("{{\"name\":{},\"format\":[{}],\"data_names\":[{}]}}",
+            json_string(&self.name), list(&self.format), list(&self.data_names))
+    }
+}
+
+
+pub struct MqttGateway<C: MqttClient> {
+    client: C,
+    prefix: String,
+}
+
+impl<C: MqttClient> MqttGateway<C> {
+    pub fn new(client: C) -> MqttGateway<C> {
+        MqttGateway {
+            client,
+            prefix: String::from(DEFAULT_PREFIX),
+        }
+    }
+
+    // In place of "bus", for more than one bus on a broker.
+    pub fn prefix(mut self, prefix: &str) -> MqttGateway<C> {
+        self.prefix = String::from(prefix.trim_end_matches('/'));
+        self
+    }
+
+    pub fn client(&mut self) -> &mut C {
+        &mut self.client
+    }
+
+    // Subscribes to the command topics.
+    pub fn start(&mut self) -> io::Result<()> {
+        self.client.subscribe(&format!("{}/+/cmd/+", self.prefix))
+    }
+
+    pub fn announce(&mut self, node: u8, discovery: &Discovery) -> io::Result<()> {
+        let topic = format!("{}/{}/discovery", self.prefix, node);
+        self.client.publish(&topic, discovery.to_json().as_bytes(), true)
+    }
+
+    // Runs the commands that came in. Returns how many were for us.
+    pub fn poll_commands(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus) -> io::Result<usize> {
+        let mut handled = 0;
+        while let Some(msg) = self.client.poll_message()? {
+            let (node, command) = match self.parse_command_topic(&msg.topic) {
+                Some(c) => c,
+                None => continue,
+            };
+            let reply = match run_command(ctrl, bus, node, &command, &msg.payload) {
+                Ok(()) => String::from("ok"),
+                Err(e) => format!("error: {}", e),
+            };
+            self.client.publish(&format!("{}/result", msg.topic), reply.as_bytes(), false)?;
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    // "bus/3/cmd/reset" -> (3, "reset")
+    fn parse_command_topic(&self, topic: &str) -> Option<(u8, String)> {
+        let rest = topic.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        let parts: Vec<&str> = rest.split('/').collect();
+        match parts.as_slice() {
+            [node, "cmd", command] => Some((node.parse().ok()?, String::from(*command))),
+            _ => None,
+        }
+    }
+}
+
+// Every record is published as it's written, the value as text.
+impl<C: MqttClient> SampleSink for MqttGateway<C> {
+    fn write(&mut self, record: &SampleRecord) -> io::Result<()> {
+        let mut topic = format!("{}/{}/{}", self.prefix, record.node, record.channel);
+        if let Some(i) = record.element {
+            topic += &format!("/{}", i);
+        }
+        self.client.publish(&topic, record.value.to_string().as_bytes(), false)
+    }
+}
+
+
+fn run_command(ctrl: &mut Controller, bus: &mut dyn Bus, node: u8, command: &str, payload: &[u8]) -> Result<(), String> {
+    match command {
+        "reset" => {
+            ctrl.reset_node(bus, node).map_err(|e| format!("{:?}", e))?;
+        }
+        "config" => {
+            let text = String::from_utf8_lossy(payload);
+            let (name, value) = match text.split_once('=') {
+                Some((n, v)) => (n.trim(), v.trim()),
+                None => return Err(String::from("expected Name=value")),
+            };
+            let param_type = ctrl.param_type(bus, node, name).map_err(|e| format!("{:?}", e))?;
+            let value = match ParamValue::parse(param_type, value) {
+                Ok(v) => v,
+                Err(_e) => return Err(format!("bad value for {}", name)),
+            };
+            ctrl.write_param(bus, node, name, value).map_err(|e| format!("{:?}", e))?;
+        }
+        _ => return Err(format!("unknown command {}", command)),
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod mqtt_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::time::UNIX_EPOCH;
+    use crate::fake_bus::LoopbackBus;
+    use crate::fake_sensor::*;
+
+    const NODE: u8 = 0x01;
+
+    // A broker in the same process: subscriptions, wildcards and retained
+    // messages, nothing over the network.
+    #[derive(Default)]
+    struct BrokerState {
+        retained: Vec<MqttMessage>,
+        clients: Vec<(Vec<String>, VecDeque<MqttMessage>)>,
+    }
+
+    #[derive(Clone, Default)]
+    struct Broker(Rc<RefCell<BrokerState>>);
+
+    impl Broker {
+        fn client(&self) -> BrokerClient {
+            let mut state = self.0.borrow_mut();
+            state.clients.push((vec![], VecDeque::new()));
+            BrokerClient { broker: self.clone(), id: state.clients.len() - 1 }
+        }
+
+        fn retained(&self, topic: &str) -> Option<String> {
+            let state = self.0.borrow();
+            let msg = state.retained.iter().find(|m| m.topic == topic)?;
+            Some(String::from_utf8(msg.payload.clone()).unwrap())
+        }
+    }
+
+    struct BrokerClient {
+        broker: Broker,
+        id: usize,
+    }
+
+    impl BrokerClient {
+        // Everything waiting, as (topic, payload).
+        fn drain(&mut self) -> Vec<(String, String)> {
+            let mut out = vec![];
+            while let Some(m) = self.poll_message().unwrap() {
+                out.push((m.topic, String::from_utf8(m.payload).unwrap()));
+            }
+            out
+        }
+    }
+
+    impl MqttClient for BrokerClient {
+        fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+            let msg = MqttMessage { topic: String::from(topic), payload: payload.to_vec(), retain };
+            let mut state = self.broker.0.borrow_mut();
+            if retain {
+                state.retained.retain(|m| m.topic != topic);
+                state.retained.push(msg.clone());
+            }
+            for (filters, inbox) in state.clients.iter_mut() {
+                if filters.iter().any(|f| matches(f, topic)) {
+                    inbox.push_back(MqttMessage { retain: false, ..msg.clone() });
+                }
+            }
+            Ok(())
+        }
+
+        fn subscribe(&mut self, filter: &str) -> io::Result<()> {
+            let mut state = self.broker.0.borrow_mut();
+            let retained: Vec<MqttMessage> = state.retained.iter().filter(|m| matches(filter, &m.topic)).cloned().collect();
+            let (filters, inbox) = &mut state.clients[self.id];
+            filters.push(String::from(filter));
+            inbox.extend(retained);
+            Ok(())
+        }
+
+        fn poll_message(&mut self) -> io::Result<Option<MqttMessage>> {
+            Ok(self.broker.0.borrow_mut().clients[self.id].1.pop_front())
+        }
+    }
+
+    fn matches(filter: &str, topic: &str) -> bool {
+        let mut levels = topic.split('/');
+        for f in filter.split('/') {
+            match (f, levels.next()) {
+                ("#", _) => return true,
+                ("+", Some(_)) => {}
+                (f, Some(t)) if f == t => {}
+                _ => return false,
+            }
+        }
+        levels.next().is_none()
+    }
+
+    fn sensor() -> ExampleSensor {
+        ExampleSensor::new([0x0F, 0x09, 0xC4, 0x00])
+    }
+
+    fn record(channel: u8, channel_name: &str, value: f64) -> SampleRecord {
+        SampleRecord {
+            time: UNIX_EPOCH,
+            node: NODE,
+            sensor: String::from(SENSOR_NAME),
+            channel,
+            channel_name: String::from(channel_name),
+            element: None,
+            unit: String::from("1"),
+            value,
+        }
+    }
+
+    #[test]
+    fn topic_filters() {
+        assert!(matches("bus/+/cmd/+", "bus/3/cmd/reset"));
+        assert!(!matches("bus/+/cmd/+", "bus/3/cmd/reset/result"));
+        assert!(matches("bus/#", "bus/3/1/0"));
+        assert!(!matches("bus/3/+", "bus/4/1"));
+    }
+
+    #[test]
+    fn samples_and_discovery() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor());
+        let broker = Broker::default();
+        let mut gateway = MqttGateway::new(broker.client());
+
+        let discovery = Discovery::fetch(&mut ctrl, &mut bus, NODE).unwrap();
+        assert_eq!(discovery.format, vec!["u8", "u16", "u16"]);
+        gateway.announce(NODE, &discovery).unwrap();
+
+        gateway.write(&record(1, "Temperature", 38.49)).unwrap();
+        gateway.write(&SampleRecord { element: Some(2), ..record(4, "Accel[2]", -9.81) }).unwrap();
+
+        // Subscribing late still gets the discovery, but not old samples.
+        let mut watcher = broker.client();
+        watcher.subscribe("bus/#").unwrap();
+        gateway.write(&record(1, "Temperature", 38.5)).unwrap();
+        gateway.write(&SampleRecord { element: Some(2), ..record(4, "Accel[2]", -9.8) }).unwrap();
+        // Brackets in a plain channel's name aren't an element.
+        gateway.write(&record(2, "Humid[raw]", 40.0)).unwrap();
+
+        let json = "{\"name\":\"Fakesensor\",\"format\":[\"u8\",\"u16\",\"u16\"],\"data_names\":[\"Status\",\"Temp\",\"Humid\"]}";
+        assert_eq!(broker.retained("bus/1/discovery").unwrap(), json);
+        assert_eq!(watcher.drain(), vec![
+            (String::from("bus/1/discovery"), String::from(json)),
+            (String::from("bus/1/1"), String::from("38.5")),
+            (String::from("bus/1/4/2"), String::from("-9.8")),
+            (String::from("bus/1/2"), String::from("40")),
+        ]);
+    }
+
+    #[test]
+    fn commands() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor());
+        let broker = Broker::default();
+        let mut gateway = MqttGateway::new(broker.client()).prefix("lab/");
+        gateway.start().unwrap();
+
+        let mut app = broker.client();
+        app.subscribe("lab/+/cmd/+/result").unwrap();
+        app.publish("lab/1/cmd/config", b"SampleRate=250", false).unwrap();
+        app.publish("lab/1/cmd/config", b"Averaging=500", false).unwrap();
+        app.publish("lab/1/cmd/config", b"Build=7", false).unwrap();
+        app.publish("lab/1/cmd/reset", b"", false).unwrap();
+        app.publish("lab/1/cmd/explode", b"", false).unwrap();
+        // Not a command topic, ignored.
+        app.publish("lab/x/cmd/reset", b"", false).unwrap();
+
+        assert_eq!(gateway.poll_commands(&mut ctrl, &mut bus).unwrap(), 5);
+        assert_eq!(ctrl.read_param(&mut bus, NODE, "SampleRate").unwrap(), ParamValue::U16(250));
+
+        let results: Vec<String> = app.drain().into_iter().map(|(_, r)| r).collect();
+        assert_eq!(results, vec![
+            "ok",
+            "error: bad value for Averaging",
+            "error: Unsupported",
+            "ok",
+            "error: unknown command explode",
+        ]);
+    }
+}
@@ -0,0 +1,317 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: params.rs
+ * Desc: Configuration parameters(sample rate, averaging, range, thresholds..)
+ *       that the controller can list, read and write on a module.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::BusError;
+
+// type + 4 byte value
+pub const PARAM_VALUE_LEN: usize = 5;
+
+// status, index, type, min(4), max(4), then the name.
+const DESCRIPTOR_HEADER_LEN: usize = 11;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ParamType {
+    U8 = 0,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    Unknown = 0xFF,
+}
+
+impl From<u8> for ParamType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => ParamType::U8,
+            1 => ParamType::I8,
+            2 => ParamType::U16,
+            3 => ParamType::I16,
+            4 => ParamType::U32,
+            5 => ParamType::I32,
+            6 => ParamType::F32,
+            _ => ParamType::Unknown,
+        }
+    }
+}
+
+
+// Result of a parameter command, first byte of every reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ParamStatus {
+    Ok = 0,
+    BadIndex,
+    BadType,
+    OutOfRange,
+    ReadOnly,
+    Failed,
+}
+
+impl From<u8> for ParamStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => ParamStatus::Ok,
+            1 => ParamStatus::BadIndex,
+            2 => ParamStatus::BadType,
+            3 => ParamStatus::OutOfRange,
+            4 => ParamStatus::ReadOnly,
+            _ => ParamStatus::Failed,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+}
+
+impl ParamValue {
+    pub fn param_type(&self) -> ParamType {
+        match self {
+            ParamValue::U8(_) => ParamType::U8,
+            ParamValue::I8(_) => ParamType::I8,
+            ParamValue::U16(_) => ParamType::U16,
+            ParamValue::I16(_) => ParamType::I16,
+            ParamValue::U32(_) => ParamType::U32,
+            ParamValue::I32(_) => ParamType::I32,
+            ParamValue::F32(_) => ParamType::F32,
+        }
+    }
+
+    // Every value goes over the bus as 4 big endian bytes regardless of
+    // type, keeps the framing the same for all of them.
+    pub fn to_be_bytes(&self) -> [u8; 4] {
+        match self {
+            ParamValue::U8(v) => (*v as u32).to_be_bytes(),
+            ParamValue::I8(v) => (*v as i32).to_be_bytes(),
+            ParamValue::U16(v) => (*v as u32).to_be_bytes(),
+            ParamValue::I16(v) => (*v as i32).to_be_bytes(),
+            ParamValue::U32(v) => v.to_be_bytes(),
+            ParamValue::I32(v) => v.to_be_bytes(),
+            ParamValue::F32(v) => v.to_be_bytes(),
+        }
+    }
+
+    pub fn from_be_bytes(t: ParamType, b: [u8; 4]) -> Result<ParamValue, BusError> {
+        let raw = u32::from_be_bytes(b);
+        let signed = i32::from_be_bytes(b);
+        match t {
+            ParamType::U8 => Ok(ParamValue::U8(raw as u8)),
+            ParamType::I8 => Ok(ParamValue::I8(signed as i8)),
+            ParamType::U16 => Ok(ParamValue::U16(raw as u16)),
+            ParamType::I16 => Ok(ParamValue::I16(signed as i16)),
+            ParamType::U32 => Ok(ParamValue::U32(raw)),
+            ParamType::I32 => Ok(ParamValue::I32(signed)),
+            ParamType::F32 => Ok(ParamValue::F32(f32::from_be_bytes(b))),
+            ParamType::Unknown => Err(BusError::BadParameter),
+        }
+    }
+
+    // A value typed in as text("100", "-2.5"), as the given type.
+    pub fn parse(t: ParamType, s: &str) -> Result<ParamValue, BusError> {
+        let s = s.trim();
+        let value = match t {
+            ParamType::U8 => s.parse().map(ParamValue::U8).ok(),
+            ParamType::I8 => s.parse().map(ParamValue::I8).ok(),
+            ParamType::U16 => s.parse().map(ParamValue::U16).ok(),
+            ParamType::I16 => s.parse().map(ParamValue::I16).ok(),
+            ParamType::U32 => s.parse().map(ParamValue::U32).ok(),
+            ParamType::I32 => s.parse().map(ParamValue::I32).ok(),
+            ParamType::F32 => s.parse().map(ParamValue::F32).ok(),
+            ParamType::Unknown => None,
+        };
+        value.ok_or(BusError::BadParameter)
+    }
+
+    // [type, value(4)]
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.param_type() as u8);
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+
+    pub fn decode(b: &[u8]) -> Result<ParamValue, BusError> {
+        if b.len() < PARAM_VALUE_LEN {
+            return Err(BusError::BadParameter);
+        }
+        ParamValue::from_be_bytes(b[0].into(), [b[1], b[2], b[3], b[4]])
+    }
+
+    // Only values of the same type compare, anything else is out of range.
+    pub fn in_range(&self, min: &ParamValue, max: &ParamValue) -> bool {
+        match (self, min, max) {
+            (ParamValue::U8(v), ParamValue::U8(lo), ParamValue::U8(hi)) => lo <= v && v <= hi,
+            (ParamValue::I8(v), ParamValue::I8(lo), ParamValue::I8(hi)) => lo <= v && v <= hi,
+            (ParamValue::U16(v), ParamValue::U16(lo), ParamValue::U16(hi)) => lo <= v && v <= hi,
+            (ParamValue::I16(v), ParamValue::I16(lo), ParamValue::I16(hi)) => lo <= v && v <= hi,
+            (ParamValue::U32(v), ParamValue::U32(lo), ParamValue::U32(hi)) => lo <= v && v <= hi,
+            (ParamValue::I32(v), ParamValue::I32(lo), ParamValue::I32(hi)) => lo <= v && v <= hi,
+            (ParamValue::F32(v), ParamValue::F32(lo), ParamValue::F32(hi)) => lo <= v && v <= hi,
+            _ => false,
+        }
+    }
+}
+
+
+// Describes one parameter, given by the sensor. The type is taken from
+// `min`/`max` which must be the same type. `default` is what a factory
+// reset puts back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParamDescriptor {
+    pub name: &'static str,
+    pub min: ParamValue,
+    pub max: ParamValue,
+    pub default: ParamValue,
+    pub writable: bool,
+}
+
+impl ParamDescriptor {
+    pub fn param_type(&self) -> ParamType {
+        self.min.param_type()
+    }
+
+    // [status, index, type, min(4), max(4), name..], writable rides in the
+    // top bit of the type byte.
+    pub fn encode(&self, idx: u8, buf: &mut Vec<u8>) {
+        buf.push(ParamStatus::Ok as u8);
+        buf.push(idx);
+        let mut t = self.param_type() as u8;
+        if self.writable {
+            t |= WRITABLE_FLAG;
+        }
+        buf.push(t);
+        buf.extend_from_slice(&self.min.to_be_bytes());
+        buf.extend_from_slice(&self.max.to_be_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+    }
+}
+
+const WRITABLE_FLAG: u8 = 0x80;
+
+
+// The controller side copy of a `ParamDescriptor`.
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamInfo {
+    pub index: u8,
+    pub name: String,
+    pub param_type: ParamType,
+    pub min: ParamValue,
+    pub max: ParamValue,
+    pub writable: bool,
+}
+
+#[cfg(any(test, feature = "bus_master"))]
+impl ParamInfo {
+    pub fn decode(b: &[u8]) -> Result<ParamInfo, BusError> {
+        if b.len() < DESCRIPTOR_HEADER_LEN || ParamStatus::from(b[0]) != ParamStatus::Ok {
+            return Err(BusError::BadParameter);
+        }
+
+        let param_type: ParamType = (b[2] & !WRITABLE_FLAG).into();
+        let min = ParamValue::from_be_bytes(param_type, [b[3], b[4], b[5], b[6]])?;
+        let max = ParamValue::from_be_bytes(param_type, [b[7], b[8], b[9], b[10]])?;
+        let name = match String::from_utf8(b[DESCRIPTOR_HEADER_LEN..].to_vec()) {
+            Ok(n) => n,
+            Err(_e) => return Err(BusError::BadParameter),
+        };
+
+        Ok(ParamInfo {
+            index: b[1],
+            name,
+            param_type,
+            min,
+            max,
+            writable: b[2] & WRITABLE_FLAG != 0,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod params_tests {
+    use super::*;
+
+    #[test]
+    fn value_round_trip() {
+        let values = [
+            ParamValue::U8(200),
+            ParamValue::I8(-100),
+            ParamValue::U16(60000),
+            ParamValue::I16(-30000),
+            ParamValue::U32(0xDEAD_BEEF),
+            ParamValue::I32(-123456),
+            ParamValue::F32(2.5),
+        ];
+
+        for v in values.iter() {
+            let mut buf: Vec<u8> = vec![];
+            v.encode(&mut buf);
+            assert_eq!(buf.len(), PARAM_VALUE_LEN);
+            assert_eq!(ParamValue::decode(&buf).unwrap(), *v);
+        }
+    }
+
+    #[test]
+    fn parse_text() {
+        assert_eq!(ParamValue::parse(ParamType::U16, " 100").unwrap(), ParamValue::U16(100));
+        assert_eq!(ParamValue::parse(ParamType::F32, "-2.5").unwrap(), ParamValue::F32(-2.5));
+        assert!(ParamValue::parse(ParamType::U8, "300").is_err());
+        assert!(ParamValue::parse(ParamType::I32, "fast").is_err());
+    }
+
+    #[test]
+    fn range() {
+        let min = ParamValue::I16(-10);
+        let max = ParamValue::I16(10);
+        assert!(ParamValue::I16(0).in_range(&min, &max));
+        assert!(ParamValue::I16(10).in_range(&min, &max));
+        assert!(!ParamValue::I16(11).in_range(&min, &max));
+        assert!(!ParamValue::U16(0).in_range(&min, &max));
+    }
+
+    #[test]
+    fn descriptor_round_trip() {
+        let desc = ParamDescriptor {
+            name: "Sample rate",
+            min: ParamValue::U16(1),
+            max: ParamValue::U16(1000),
+            default: ParamValue::U16(10),
+            writable: true,
+        };
+
+        let mut buf: Vec<u8> = vec![];
+        desc.encode(3, &mut buf);
+        let info = ParamInfo::decode(&buf).unwrap();
+        assert_eq!(info.index, 3);
+        assert_eq!(info.name, "Sample rate");
+        assert_eq!(info.param_type, ParamType::U16);
+        assert_eq!(info.min, desc.min);
+        assert_eq!(info.max, desc.max);
+        assert!(info.writable);
+    }
+
+    #[test]
+    fn bad_descriptor() {
+        assert!(ParamInfo::decode(&[ParamStatus::BadIndex as u8]).is_err());
+        assert!(ParamValue::decode(&[0xFF, 0, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: sample_log.rs
+ * Desc: `SampleSink`s that log to files, CSV or JSON Lines, one record per
+ *       line. For long runs the file can be rotated by size or by time:
+ *       the full file is renamed `name.1.ext`, `name.2.ext`.. and a fresh
+ *       one started under the original name.
+ */
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::sink::SampleRecord;
+use crate::sink::SampleSink;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    Never,
+    Size(u64),          //Bytes, a file goes over only for a single long line.
+    Every(Duration),    //By the records' times, from the first in the file.
+}
+
+
+// The file being written and when to move on from it.
+struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    out: BufWriter<File>,
+    written: u64,
+    started: Option<SystemTime>,
+    rotated: u32,
+}
+
+impl LogFile {
+    // Appends if the file is already there, so a restart doesn't lose it.
+    fn open(path: &Path, rotation: Rotation) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            rotation,
+            out: BufWriter::new(file),
+            written,
+            started: None,
+            rotated: 0,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.written == 0
+    }
+
+    fn needs_rotating(&self, time: SystemTime, len: usize) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max) => self.written + len as u64 > max,
+            Rotation::Every(period) => match self.started {
+                Some(start) => time.duration_since(start).is_ok_and(|d| d >= period),
+                None => false,
+            },
+        }
+    }
+
+    // Moves the current file aside, the next free number after any left by
+    // an earlier run.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        let mut n = self.rotated + 1;
+        while rotated_path(&self.path, n).exists() {
+            n += 1;
+        }
+        std::fs::rename(&self.path, rotated_path(&self.path, n))?;
+        self.rotated = n;
+
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.out = BufWriter::new(file);
+        self.written = 0;
+        self.started = None;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.out.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+
+// "samples.csv" -> "samples.3.csv"
+pub fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+
+pub const CSV_HEADER: &str = "time,node,sensor,channel,channel_name,unit,value\n";
+
+// Each file starts with `CSV_HEADER`.
+pub struct CsvSink {
+    file: LogFile,
+}
+
+impl CsvSink {
+    pub fn create(path: &Path, rotation: Rotation) -> io::Result<CsvSink> {
+        let mut file = LogFile::open(path, rotation)?;
+        if file.is_empty() {
+            file.write_line(CSV_HEADER)?;
+        }
+        Ok(CsvSink { file })
+    }
+}
+
+impl SampleSink for CsvSink {
+    fn write(&mut self, record: &SampleRecord) -> io::Result<()> {
+        let line = format!("{},{},{},{},{},{},{}\n",
+            timestamp(record.time),
+            record.node,
+            csv_field(&record.sensor),
+            record.channel,
+            csv_field(&record.channel_name),
+            csv_field(&record.unit),
+            record.value);
+        if self.file.needs_rotating(record.time, line.len()) {
+            self.file.rotate()?;
+            self.file.write_line(CSV_HEADER)?;
+        }
+        if self.file.started.is_none() {
+            self.file.started = Some(record.time);
+        }
+        self.file.write_line(&line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.out.flush()
+    }
+}
+
+// Quoted only when it has to be.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", s.replace('"', "\"\""));
+    }
+    s.to_string()
+}
+
+
+// {"time":"..","node":1,"sensor":"..","channel":1,"channel_name":"..","unit":"..","value":1.5}
+pub struct JsonLinesSink {
+    file: LogFile,
+}
+
+impl JsonLinesSink {
+    pub fn create(path: &Path, rotation: Rotation) -> io::Result<JsonLinesSink> {
+        Ok(JsonLinesSink {
+            file: LogFile::open(path, rotation)?,
+        })
+    }
+}
+
+impl SampleSink for JsonLinesSink {
+    fn write(&mut self, record: &SampleRecord) -> io::Result<()> {
+        // JSON has no NaN or inf.
+        let value = if record.value.is_finite() { record.value.to_string() } else { String::from("null") };
+        let line = format!("{{\"time\":\"{}\",\"node\":{},\"sensor\":{},\"channel\":{},\"channel_name\":{},\"unit\":{},\"value\":{}}}\n",
+            timestamp(record.time),
+            record.node,
+            json_string(&record.sensor),
+            record.channel,
+            json_string(&record.channel_name),
+            json_string(&record.unit),
+            value);
+        if self.file.needs_rotating(record.time, line.len()) {
+            self.file.rotate()?;
+        }
+        if self.file.started.is_none() {
+            self.file.started = Some(record.time);
+        }
+        self.file.write_line(&line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.out.flush()
+    }
+}
+
+// Quoted and escaped.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+
+// RFC 3339 in UTC with milliseconds, "2024-03-01T12:30:05.250Z".
+pub fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil_date((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, (rem / 60) % 60, rem % 60, since.subsec_millis())
+}
+
+// Days since 1970-01-01 to (year, month, day), Howard Hinnant's
+// civil_from_days.
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+
+#[cfg(test)]
+mod sample_log_tests {
+    use super::*;
+    use crate::file_store::file_store_tests::temp_path;
+
+    // A fresh directory per test, rotation leaves files next to the log.
+    fn temp_dir() -> PathBuf {
+        let dir = temp_path();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(secs: u64, value: f64) -> SampleRecord {
+        SampleRecord {
+            time: UNIX_EPOCH + Duration::from_secs(secs),
+            node: 3,
+            sensor: String::from("Aht20"),
+            channel: 1,
+            channel_name: String::from("Temperature"),
+            element: None,
+            unit: String::from("Cel"),
+            value,
+        }
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_millis(1_709_296_205_250)), "2024-03-01T12:30:05.250Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn csv() {
+        let dir = temp_dir();
+        let path = dir.join("samples.csv");
+        let mut sink = CsvSink::create(&path, Rotation::Never).unwrap();
+        sink.write(&record(0, 21.5)).unwrap();
+        sink.write(&SampleRecord { channel_name: String::from("Temp, \"inside\""), ..record(1, -3.0) }).unwrap();
+        sink.flush().unwrap();
+
+        assert_eq!(read(&path), String::from(CSV_HEADER)
+            + "1970-01-01T00:00:00.000Z,3,Aht20,1,Temperature,Cel,21.5\n"
+            + "1970-01-01T00:00:01.000Z,3,Aht20,1,\"Temp, \"\"inside\"\"\",Cel,-3\n");
+
+        // Opening it again carries on without a second header.
+        drop(sink);
+        let mut sink = CsvSink::create(&path, Rotation::Never).unwrap();
+        sink.write(&record(2, 22.0)).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(read(&path).matches("time,").count(), 1);
+        assert_eq!(read(&path).lines().count(), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn json_lines() {
+        let dir = temp_dir();
+        let path = dir.join("samples.jsonl");
+        let mut sink = JsonLinesSink::create(&path, Rotation::Never).unwrap();
+        sink.write(&record(60, 21.25)).unwrap();
+        sink.write(&SampleRecord { sensor: String::from("Lab \"A\"\n"), ..record(61, f64::NAN) }).unwrap();
+        sink.flush().unwrap();
+
+        let text = read(&path);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "{\"time\":\"1970-01-01T00:01:00.000Z\",\"node\":3,\"sensor\":\"Aht20\",\"channel\":1,\"channel_name\":\"Temperature\",\"unit\":\"Cel\",\"value\":21.25}");
+        assert!(lines[1].contains("\"sensor\":\"Lab \\\"A\\\"\\n\""));
+        assert!(lines[1].ends_with("\"value\":null}"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = temp_dir();
+        let path = dir.join("samples.csv");
+        // Room for the header and two records.
+        let line_len = "1970-01-01T00:00:00.000Z,3,Aht20,1,Temperature,Cel,20\n".len();
+        let mut sink = CsvSink::create(&path, Rotation::Size((CSV_HEADER.len() + 2 * line_len) as u64)).unwrap();
+        for i in 0..5 {
+            sink.write(&record(i, 20.0)).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let first = read(&rotated_path(&path, 1));
+        assert!(first.starts_with(CSV_HEADER));
+        assert_eq!(first.lines().count(), 3);
+        assert_eq!(first.len(), CSV_HEADER.len() + 2 * line_len);
+        assert_eq!(read(&rotated_path(&path, 2)).lines().count(), 3);
+        // The fifth, with its own header.
+        assert_eq!(read(&path).lines().count(), 2);
+        assert!(!rotated_path(&path, 3).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_time() {
+        let dir = temp_dir();
+        let path = dir.join("samples.jsonl");
+        let mut sink = JsonLinesSink::create(&path, Rotation::Every(Duration::from_secs(60))).unwrap();
+        for secs in [0, 30, 59, 60, 119, 200] {
+            sink.write(&record(secs, 1.0)).unwrap();
+        }
+        sink.flush().unwrap();
+
+        assert_eq!(read(&rotated_path(&path, 1)).lines().count(), 3);
+        assert_eq!(read(&rotated_path(&path, 2)).lines().count(), 2);
+        assert_eq!(read(&path).lines().count(), 1);
+        drop(sink);
+
+        // A restart doesn't overwrite the old ones.
+        let mut sink = JsonLinesSink::create(&path, Rotation::Every(Duration::from_secs(60))).unwrap();
+        sink.write(&record(300, 1.0)).unwrap();
+        sink.write(&record(400, 1.0)).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(read(&rotated_path(&path, 3)).lines().count(), 2);
+        assert_eq!(read(&path).lines().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,348 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: scheduler.rs
+ * Desc: Polls channels at their own rates. Each job is a (node, channel,
+ *       period), due times are kept on a fixed grid(start + n * period) so
+ *       a late run doesn't push the ones after it back. When several jobs
+ *       are due the most overdue goes first, so a fast job can't starve a
+ *       slow one. How late each run was is kept as the job's jitter.
+ */
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::Bus;
+use crate::BusStatus;
+use crate::ControllerCommand;
+use crate::controller::Controller;
+use crate::sink::SampleRecord;
+use crate::sink::SampleSink;
+use crate::units::ChannelInfo;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollJob {
+    pub node: u8,
+    pub channel: u8,
+    pub period: Duration,
+}
+
+
+// How a job has been doing. Jitter is how long after its due time a run
+// started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JobStats {
+    pub runs: u64,
+    pub errors: u64,        //Reads that failed, nothing went to the sink.
+    pub skipped: u64,       //Due times missed altogether, running too late.
+    pub min_jitter: Duration,
+    pub max_jitter: Duration,
+    pub total_jitter: Duration,
+}
+
+impl JobStats {
+    pub fn mean_jitter(&self) -> Duration {
+        if self.runs == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.total_jitter.as_nanos() / self.runs as u128) as u64)
+    }
+
+    fn note_jitter(&mut self, jitter: Duration) {
+        if self.runs == 0 || jitter < self.min_jitter {
+            self.min_jitter = jitter;
+        }
+        self.max_jitter = self.max_jitter.max(jitter);
+        self.total_jitter += jitter;
+        self.runs += 1;
+    }
+}
+
+
+// Names and scaling for a job's records, asked for on its first run.
+struct JobMeta {
+    sensor: String,
+    channel_name: String,
+    info: Option<ChannelInfo>,
+}
+
+struct JobState {
+    job: PollJob,
+    due: Instant,
+    stats: JobStats,
+    meta: Option<JobMeta>,
+}
+
+
+pub struct Scheduler {
+    jobs: Vec<JobState>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            jobs: vec![],
+        }
+    }
+
+    // Adds a job, first due at `start`. Returns its index for `stats`.
+    pub fn add(&mut self, job: PollJob, start: Instant) -> Result<usize, BusStatus> {
+        if job.period.is_zero() {
+            return Err(BusStatus::Rejected);
+        }
+        self.jobs.push(JobState {
+            job,
+            due: start,
+            stats: JobStats::default(),
+            meta: None,
+        });
+        Ok(self.jobs.len() - 1)
+    }
+
+    pub fn jobs(&self) -> Vec<PollJob> {
+        self.jobs.iter().map(|j| j.job).collect()
+    }
+
+    pub fn stats(&self, idx: usize) -> Option<&JobStats> {
+        self.jobs.get(idx).map(|j| &j.stats)
+    }
+
+    // When the next job is due, None with no jobs.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.jobs.iter().map(|j| j.due).min()
+    }
+
+    // Runs every job due by `now`, most overdue first. Returns how many
+    // ran. A failed read is counted against the job, a failed write to the
+    // sink stops the run and is passed on.
+    pub fn run_due(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus, sink: &mut dyn SampleSink, now: Instant) -> io::Result<usize> {
+        let mut due: Vec<usize> = (0..self.jobs.len()).filter(|i| self.jobs[*i].due <= now).collect();
+        due.sort_by_key(|i| self.jobs[*i].due);
+
+        for i in due.iter() {
+            let state = &mut self.jobs[*i];
+            state.stats.note_jitter(now - state.due);
+
+            // Next slot on the grid that's still ahead, anything in between
+            // was missed. So far behind the grid can't be stepped along, it
+            // starts again from now.
+            let period = state.job.period;
+            let behind = (now - state.due).as_nanos() / period.as_nanos();
+            state.stats.skipped = state.stats.skipped.saturating_add(u64::try_from(behind).unwrap_or(u64::MAX));
+            state.due = u32::try_from(behind + 1).ok()
+                .and_then(|steps| period.checked_mul(steps))
+                .and_then(|step| state.due.checked_add(step))
+                .unwrap_or(now + period);
+
+            match read_job(ctrl, bus, state) {
+                Ok(records) => {
+                    for record in records.iter() {
+                        sink.write(record)?;
+                    }
+                }
+                Err(_e) => state.stats.errors += 1,
+            }
+        }
+        if !due.is_empty() {
+            sink.flush()?;
+        }
+        Ok(due.len())
+    }
+
+    // Runs the jobs in real time until `until`, sleeping in between.
+    pub fn run_until(&mut self, ctrl: &mut Controller, bus: &mut dyn Bus, sink: &mut dyn SampleSink, until: Instant) -> io::Result<()> {
+        loop {
+            let next = match self.next_due() {
+                Some(n) if n < until => n,
+                _ => return Ok(()),
+            };
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            }
+            self.run_due(ctrl, bus, sink, Instant::now())?;
+        }
+    }
+}
+
+
+// Reads the job's channel, one record per element.
+fn read_job(ctrl: &mut Controller, bus: &mut dyn Bus, state: &mut JobState) -> Result<Vec<SampleRecord>, BusStatus> {
+    let (node, channel) = (state.job.node, state.job.channel);
+    if state.meta.is_none() {
+        state.meta = Some(job_meta(ctrl, bus, node, channel)?);
+    }
+
+    let values = ctrl.read_values(bus, node, channel)?;
+    let meta = match state.meta.as_ref() {
+        Some(m) => m,
+        None => return Err(BusStatus::Error),
+    };
+    let time = SystemTime::now();
+    let array = values.len() > 1;
+
+    let mut records: Vec<SampleRecord> = Vec::with_capacity(values.len());
+    for (i, raw) in values.into_iter().enumerate() {
+        let (value, unit) = match meta.info.as_ref() {
+            Some(info) => (info.to_physical(raw), info.unit.clone()),
+            None => (raw, String::from("1")),
+        };
+        records.push(SampleRecord {
+            time,
+            node,
+            sensor: meta.sensor.clone(),
+            channel,
+            channel_name: if array { format!("{}[{}]", meta.channel_name, i) } else { meta.channel_name.clone() },
+            element: if array { Some(i) } else { None },
+            unit,
+            value,
+        });
+    }
+    Ok(records)
+}
+
+fn job_meta(ctrl: &mut Controller, bus: &mut dyn Bus, node: u8, channel: u8) -> Result<JobMeta, BusStatus> {
+    let sensor = ctrl.send_bus_command(bus, node, &ControllerCommand::NameRequest, String::new())?.name;
+    let channel_name = match ctrl.list_channels(bus, node)?.into_iter().find(|d| d.index == channel) {
+        Some(d) => d.name,
+        None => return Err(BusStatus::Rejected),
+    };
+    // Channels without metadata are passed on raw.
+    let info = match ctrl.channel_info(bus, node, channel) {
+        Ok(info) => Some(info),
+        Err(BusStatus::Unsupported) => None,
+        Err(e) => return Err(e),
+    };
+    Ok(JobMeta { sensor, channel_name, info })
+}
+
+
+#[cfg(test)]
+mod scheduler_tests {
+    use super::*;
+    use crate::fake_bus::LoopbackBus;
+    use crate::fake_sensor::*;
+
+    const NODE: u8 = 0x01;
+
+    fn sensor() -> ExampleSensor {
+        ExampleSensor::new([0x0F, 0x09, 0xC4, 0x00])
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn records() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor());
+        let mut sink: Vec<SampleRecord> = vec![];
+        let mut sched = Scheduler::new();
+        let start = Instant::now();
+        sched.add(PollJob { node: NODE, channel: 1, period: ms(100) }, start).unwrap();
+
+        assert_eq!(sched.run_due(&mut ctrl, &mut bus, &mut sink, start).unwrap(), 1);
+        assert_eq!(sink.len(), 1);
+        let record = &sink[0];
+        assert_eq!(record.sensor, SENSOR_NAME);
+        assert_eq!(record.channel_name, "Temperature");
+        assert_eq!(record.element, None);
+        assert_eq!(record.unit, "Cel");
+        // 0x0F09 centi-degrees.
+        assert!((record.value - 38.49).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rates_and_jitter() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor());
+        let mut sink: Vec<SampleRecord> = vec![];
+        let mut sched = Scheduler::new();
+        let start = Instant::now();
+        let fast = sched.add(PollJob { node: NODE, channel: 1, period: ms(10) }, start).unwrap();
+        let slow = sched.add(PollJob { node: NODE, channel: 2, period: ms(50) }, start).unwrap();
+
+        // Ticks 2ms late every 10ms for 100ms.
+        for tick in 0..10 {
+            sched.run_due(&mut ctrl, &mut bus, &mut sink, start + ms(tick * 10 + 2)).unwrap();
+        }
+
+        let fast = sched.stats(fast).unwrap();
+        assert_eq!(fast.runs, 10);
+        assert_eq!((fast.min_jitter, fast.max_jitter, fast.mean_jitter()), (ms(2), ms(2), ms(2)));
+        let slow = sched.stats(slow).unwrap();
+        assert_eq!(slow.runs, 2);
+        assert_eq!(slow.skipped, 0);
+        assert_eq!(sink.len(), 12);
+        // Due on the grid, not from when it last ran.
+        assert_eq!(sched.next_due(), Some(start + ms(100)));
+    }
+
+    #[test]
+    fn most_overdue_first() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor());
+        let mut sink: Vec<SampleRecord> = vec![];
+        let mut sched = Scheduler::new();
+        let start = Instant::now();
+        sched.add(PollJob { node: NODE, channel: 1, period: ms(10) }, start + ms(5)).unwrap();
+        sched.add(PollJob { node: NODE, channel: 2, period: ms(10) }, start).unwrap();
+
+        // Way behind, each runs once and the missed slots are counted.
+        sched.run_due(&mut ctrl, &mut bus, &mut sink, start + ms(35)).unwrap();
+        assert_eq!(sink.iter().map(|r| r.channel).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(sched.stats(0).unwrap().skipped, 3);
+        assert_eq!(sched.stats(1).unwrap().skipped, 3);
+        assert_eq!(sched.next_due(), Some(start + ms(40)));
+    }
+
+    #[test]
+    fn far_behind() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor());
+        let mut sink: Vec<SampleRecord> = vec![];
+        let mut sched = Scheduler::new();
+        let start = Instant::now();
+        // More missed slots than a u32 holds.
+        sched.add(PollJob { node: NODE, channel: 1, period: Duration::from_nanos(1) }, start).unwrap();
+
+        sched.run_due(&mut ctrl, &mut bus, &mut sink, start + Duration::from_secs(5)).unwrap();
+        assert_eq!(sched.stats(0).unwrap().skipped, 5_000_000_000);
+        assert!(sched.next_due().unwrap() > start + Duration::from_secs(5));
+
+        // The mean doesn't wrap or panic with more runs than a u32 holds.
+        let stats = JobStats {
+            runs: 1 << 33,
+            total_jitter: Duration::from_nanos(3 << 33),
+            ..JobStats::default()
+        };
+        assert_eq!(stats.mean_jitter(), Duration::from_nanos(3));
+    }
+
+    #[test]
+    fn failed_reads_counted() {
+        let mut ctrl = Controller::new();
+        let mut bus = LoopbackBus::new(NODE, sensor());
+        let mut sink: Vec<SampleRecord> = vec![];
+        let mut sched = Scheduler::new();
+        let start = Instant::now();
+        // No channel 7 on the sensor.
+        sched.add(PollJob { node: NODE, channel: 7, period: ms(10) }, start).unwrap();
+        assert!(sched.add(PollJob { node: NODE, channel: 1, period: ms(0) }, start).is_err());
+
+        sched.run_due(&mut ctrl, &mut bus, &mut sink, start).unwrap();
+        assert_eq!(sched.stats(0).unwrap().errors, 1);
+        assert!(sink.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: segment.rs
+ * Desc: Replies too long for one frame. The reply is cut into segments that
+ *       all carry the request's sequence number:
+ *
+ *           [seq, prefix.., index, count, data..]
+ *
+ *       `prefix` is whatever comes before the data in that reply(the
+ *       sub-device status), it's repeated in each segment. The controller
+ *       puts the data back together in index order.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::Bus;
+use crate::BusError;
+use crate::ControllerCommand;
+use crate::crc::CrcMode;
+
+// index, count
+pub const SEGMENT_HEADER_LEN: usize = 2;
+
+// A reply can be at most this many segments.
+pub const MAX_SEGMENTS: usize = u8::MAX as usize;
+
+// Index and count of a reply that couldn't be cut up, the payload has no
+// room for data or it would take more than MAX_SEGMENTS.
+pub const SEGMENTS_FAILED: [u8; SEGMENT_HEADER_LEN] = [0, 0];
+
+
+// The commands whose replies are always segmented, even if they'd fit in
+// one frame.
+pub fn is_segmented(cmd: ControllerCommand) -> bool {
+    matches!(cmd, ControllerCommand::SelfTestRequest | ControllerCommand::LargeDataRequest)
+}
+
+
+// Room for data in each segment. `prefix_len` counts the sequence number.
+pub fn chunk_size(max_payload: u16, prefix_len: usize, crc: CrcMode) -> usize {
+    let overhead = prefix_len + SEGMENT_HEADER_LEN + crc.trailer_len();
+    (max_payload as usize).saturating_sub(overhead)
+}
+
+
+// Sends `data` cut into segments, an empty reply is still one segment.
+// When it can't be cut up the controller still gets a reply, a single
+// SEGMENTS_FAILED frame, so it isn't left waiting.
+pub fn send_segments(bus: &mut dyn Bus, tx_id: u32, crc: CrcMode, prefix: &[u8], data: &[u8], chunk: usize) -> Result<(), BusError> {
+    if chunk == 0 || data.len().div_ceil(chunk) > MAX_SEGMENTS {
+        let mut frame: Vec<u8> = Vec::with_capacity(prefix.len() + SEGMENT_HEADER_LEN);
+        frame.extend_from_slice(prefix);
+        frame.extend_from_slice(&SEGMENTS_FAILED);
+        crc.append(&mut frame);
+        bus.send_message(tx_id, &frame)?;
+        return Err(BusError::BadParameter);
+    }
+    let count = data.len().div_ceil(chunk).max(1);
+
+    for index in 0..count {
+        let start = index * chunk;
+        let end = (start + chunk).min(data.len());
+
+        let mut frame: Vec<u8> = Vec::with_capacity(prefix.len() + SEGMENT_HEADER_LEN + chunk);
+        frame.extend_from_slice(prefix);
+        frame.push(index as u8);
+        frame.push(count as u8);
+        frame.extend_from_slice(&data[start..end]);
+        crc.append(&mut frame);
+        bus.send_message(tx_id, &frame)?;
+    }
+    Ok(())
+}
+
+
+// One segment(without the seq and prefix), gives (index, count, data).
+pub fn parse_segment(b: &[u8]) -> Result<(u8, u8, &[u8]), BusError> {
+    if b.len() < SEGMENT_HEADER_LEN || b[0] >= b[1] {
+        return Err(BusError::BadParameter);
+    }
+    Ok((b[0], b[1], &b[SEGMENT_HEADER_LEN..]))
+}
+
+
+#[cfg(test)]
+mod segment_tests {
+    use super::*;
+    use crate::fake_bus::FakeBus;
+
+    #[test]
+    fn chunks() {
+        assert_eq!(chunk_size(64, 1, CrcMode::None), 61);
+        assert_eq!(chunk_size(64, 2, CrcMode::Crc32), 56);
+        assert_eq!(chunk_size(2, 1, CrcMode::Crc32), 0);
+    }
+
+    #[test]
+    fn split_reply() {
+        let mut bus = FakeBus::new();
+        let data: Vec<u8> = (0..10).collect();
+        assert!(send_segments(&mut bus, 0x10, CrcMode::None, &[7], &data, 4).is_ok());
+
+        // Only the last frame can be looked at, the third of three.
+        let last = bus.spy_data();
+        assert_eq!(last, vec![7, 2, 3, 8, 9]);
+        assert_eq!(parse_segment(&last[1..]).unwrap(), (2, 3, &[8u8, 9][..]));
+
+        assert!(parse_segment(&[3, 3]).is_err());
+        assert!(parse_segment(&[0]).is_err());
+    }
+
+    #[test]
+    fn no_room() {
+        let mut bus = FakeBus::new();
+        let data: Vec<u8> = (0..10).collect();
+        assert!(send_segments(&mut bus, 0x10, CrcMode::None, &[7], &data, 0).is_err());
+        assert_eq!(bus.spy_data(), vec![7, 0, 0]);
+
+        let long: Vec<u8> = vec![0; MAX_SEGMENTS + 1];
+        assert!(send_segments(&mut bus, 0x10, CrcMode::None, &[7, 1], &long, 1).is_err());
+        assert_eq!(bus.spy_data(), vec![7, 1, 0, 0]);
+    }
+
+    #[test]
+    fn empty_reply() {
+        let mut bus = FakeBus::new();
+        assert!(send_segments(&mut bus, 0x10, CrcMode::None, &[7], &[], 4).is_ok());
+        assert_eq!(bus.spy_data(), vec![7, 0, 1]);
+    }
+}
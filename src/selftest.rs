@@ -0,0 +1,223 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: selftest.rs
+ * Desc: Diagnostics the controller can run on a module. The module runs its
+ *       tests on SelfTestRequest and sends back one record per test:
+ *
+ *           [status, count, (outcome, value(f32), name len, name)..]
+ *
+ *       The value is NaN for tests that don't measure anything. The reply
+ *       is segmented(see `segment`) so a module can have many tests.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+#[cfg(any(test, feature = "bus_master"))]
+use crate::BusError;
+
+// outcome, value(4), name len
+#[cfg(any(test, feature = "bus_master"))]
+const RECORD_HEADER_LEN: usize = 6;
+
+
+// First byte of the SelfTestRequest reply data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SelfTestStatus {
+    Ok = 0,
+    NotSupported,
+}
+
+impl From<u8> for SelfTestStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SelfTestStatus::Ok,
+            _ => SelfTestStatus::NotSupported,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TestOutcome {
+    Pass = 0,
+    Fail,
+    Skipped,    //Couldn't be run, e.g. the part it tests isn't fitted.
+}
+
+impl From<u8> for TestOutcome {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => TestOutcome::Pass,
+            2 => TestOutcome::Skipped,
+            _ => TestOutcome::Fail,
+        }
+    }
+}
+
+
+// One test as the sensor reports it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TestResult {
+    pub name: &'static str,
+    pub outcome: TestOutcome,
+    pub value: Option<f32>,     //What was measured, if anything.
+}
+
+impl TestResult {
+    pub const fn new(name: &'static str, passed: bool) -> TestResult {
+        TestResult {
+            name,
+            outcome: if passed { TestOutcome::Pass } else { TestOutcome::Fail },
+            value: None,
+        }
+    }
+
+    pub const fn with_value(mut self, value: f32) -> TestResult {
+        self.value = Some(value);
+        self
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        // The length is a byte, names are cut to fit between characters.
+        let mut end = self.name.len().min(u8::MAX as usize);
+        while !self.name.is_char_boundary(end) {
+            end -= 1;
+        }
+        let name = &self.name.as_bytes()[..end];
+        buf.push(self.outcome as u8);
+        buf.extend_from_slice(&self.value.unwrap_or(f32::NAN).to_be_bytes());
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(name);
+    }
+}
+
+
+pub fn handle(results: Option<Vec<TestResult>>, buf: &mut Vec<u8>) {
+    let results = match results {
+        Some(r) => r,
+        None => return buf.push(SelfTestStatus::NotSupported as u8),
+    };
+
+    // A count byte, anything past 255 tests isn't sent.
+    let count = results.len().min(u8::MAX as usize);
+    buf.push(SelfTestStatus::Ok as u8);
+    buf.push(count as u8);
+    for result in results.iter().take(count) {
+        result.encode(buf);
+    }
+}
+
+
+// The controller side copy of a `TestResult`.
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+    pub value: Option<f32>,
+}
+
+
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+    pub results: Vec<SelfTestResult>,
+}
+
+#[cfg(any(test, feature = "bus_master"))]
+impl SelfTestReport {
+    pub fn decode(b: &[u8]) -> Result<SelfTestReport, BusError> {
+        if b.len() < 2 || SelfTestStatus::from(b[0]) != SelfTestStatus::Ok {
+            return Err(BusError::BadParameter);
+        }
+
+        let mut results: Vec<SelfTestResult> = vec![];
+        let mut at = 2;
+        for _ in 0..b[1] {
+            let header = match b.get(at..at + RECORD_HEADER_LEN) {
+                Some(h) => h,
+                None => return Err(BusError::BadParameter),
+            };
+            let value = f32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+            let name_len = header[5] as usize;
+            at += RECORD_HEADER_LEN;
+
+            let name = match b.get(at..at + name_len).map(|n| String::from_utf8(n.to_vec())) {
+                Some(Ok(n)) => n,
+                _ => return Err(BusError::BadParameter),
+            };
+            at += name_len;
+
+            results.push(SelfTestResult {
+                name,
+                outcome: header[0].into(),
+                value: if value.is_nan() { None } else { Some(value) },
+            });
+        }
+        Ok(SelfTestReport { results })
+    }
+
+    // True if nothing failed, skipped tests don't count against it.
+    pub fn passed(&self) -> bool {
+        !self.results.iter().any(|r| r.outcome == TestOutcome::Fail)
+    }
+
+    pub fn failures(&self) -> Vec<&SelfTestResult> {
+        self.results.iter().filter(|r| r.outcome == TestOutcome::Fail).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SelfTestResult> {
+        self.results.iter().find(|r| r.name == name)
+    }
+}
+
+
+#[cfg(test)]
+mod selftest_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let results = vec![
+            TestResult::new("Bus", true),
+            TestResult::new("Supply", false).with_value(2.9),
+            TestResult { name: "Heater", outcome: TestOutcome::Skipped, value: None },
+        ];
+        let mut buf: Vec<u8> = vec![];
+        handle(Some(results), &mut buf);
+
+        let report = SelfTestReport::decode(&buf).unwrap();
+        assert_eq!(report.results.len(), 3);
+        assert_eq!(report.get("Supply").unwrap().value, Some(2.9));
+        assert_eq!(report.get("Bus").unwrap().value, None);
+        assert_eq!(report.get("Heater").unwrap().outcome, TestOutcome::Skipped);
+        assert!(!report.passed());
+        assert_eq!(report.failures().len(), 1);
+    }
+
+    #[test]
+    fn long_names() {
+        // 254 bytes then a 2 byte character across the limit, it's left out
+        // rather than split.
+        let name: &'static str = Box::leak(("a".repeat(254) + "°C").into_boxed_str());
+        let mut buf: Vec<u8> = vec![];
+        handle(Some(vec![TestResult::new(name, true)]), &mut buf);
+
+        let report = SelfTestReport::decode(&buf).unwrap();
+        assert_eq!(report.results[0].name, "a".repeat(254));
+    }
+
+    #[test]
+    fn not_supported() {
+        let mut buf: Vec<u8> = vec![];
+        handle(None, &mut buf);
+        assert_eq!(buf, vec![SelfTestStatus::NotSupported as u8]);
+        assert!(SelfTestReport::decode(&buf).is_err());
+        // Cut short.
+        assert!(SelfTestReport::decode(&[0, 1, 0, 0]).is_err());
+    }
+}
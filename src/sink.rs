@@ -0,0 +1,57 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: sink.rs
+ * Desc: Where decoded samples go once the controller has them, a log file,
+ *       a database, a test's Vec..
+ */
+
+use std::io;
+use std::time::SystemTime;
+
+
+// One decoded value, scaled to its unit when the channel has metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRecord {
+    pub time: SystemTime,
+    pub node: u8,
+    pub sensor: String,         //The sensor's name.
+    pub channel: u8,
+    pub channel_name: String,   //"Accel[2]" for an element of an array.
+    pub element: Option<usize>, //Which element, for array channels.
+    pub unit: String,           //UCUM, "1" for raw values.
+    pub value: f64,
+}
+
+
+pub trait SampleSink {
+    fn write(&mut self, record: &SampleRecord) -> io::Result<()>;
+
+    // Called after each batch of writes, for sinks that buffer.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+// Keeps everything in memory, handy for tests and small runs.
+impl SampleSink for Vec<SampleRecord> {
+    fn write(&mut self, record: &SampleRecord) -> io::Result<()> {
+        self.push(record.clone());
+        Ok(())
+    }
+}
+
+
+// Both get every record, e.g. a log file and the metrics endpoint.
+impl<A: SampleSink, B: SampleSink> SampleSink for (A, B) {
+    fn write(&mut self, record: &SampleRecord) -> io::Result<()> {
+        self.0.write(record)?;
+        self.1.write(record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
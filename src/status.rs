@@ -0,0 +1,214 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: status.rs
+ * Desc: Health reporting. `SensorStatus` only holds one state at a time,
+ *       this lets a module report several(a temperature and a voltage
+ *       warning together) plus its own detail code and message.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::SensorStatus;
+
+// Health flags, bits in `HealthReport::flags`.
+pub const HEALTH_BUSY: u16 = 1 << 0;
+pub const HEALTH_SENSOR_FAILURE: u16 = 1 << 1;
+pub const HEALTH_POWER_FAILURE: u16 = 1 << 2;
+pub const HEALTH_BUS_FAILURE: u16 = 1 << 3;
+pub const HEALTH_TEMPERATURE_WARNING: u16 = 1 << 4;
+pub const HEALTH_VOLTAGE_WARNING: u16 = 1 << 5;
+
+// The message is cut to at most this many bytes so the reply fits a CAN FD
+// frame. It's cut between characters, never inside one.
+pub const MAX_MESSAGE_LEN: usize = 48;
+
+/*
+ * Reply to StatusRequest and ResetRequest:
+ *   status(1) flags(2, BE) detail(2, BE) message(utf8, rest of the reply)
+ *
+ * The first byte is still the plain `SensorStatus`, so a controller that
+ * only knows about that keeps working.
+ */
+const REPORT_HEADER_LEN: usize = 5;
+
+
+// What a module says about its health. `detail` and `message` are the
+// vendor's own, 0 and "" when there's nothing to add.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthReport {
+    pub flags: u16,
+    pub detail: u16,
+    pub message: &'static str,
+}
+
+impl From<SensorStatus> for HealthReport {
+    fn from(status: SensorStatus) -> Self {
+        HealthReport {
+            flags: status_flags(status),
+            detail: 0,
+            message: "",
+        }
+    }
+}
+
+impl HealthReport {
+    // The single status that best sums up the flags, failures first.
+    pub fn status(&self) -> SensorStatus {
+        flags_status(self.flags)
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.status() as u8);
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        buf.extend_from_slice(&self.detail.to_be_bytes());
+        let mut end = self.message.len().min(MAX_MESSAGE_LEN);
+        while !self.message.is_char_boundary(end) {
+            end -= 1;
+        }
+        buf.extend_from_slice(&self.message.as_bytes()[..end]);
+    }
+}
+
+
+pub fn status_flags(status: SensorStatus) -> u16 {
+    match status {
+        SensorStatus::Ready => 0,
+        SensorStatus::Busy => HEALTH_BUSY,
+        SensorStatus::SensorFailure => HEALTH_SENSOR_FAILURE,
+        SensorStatus::PowerFailure => HEALTH_POWER_FAILURE,
+        SensorStatus::BusFailure => HEALTH_BUS_FAILURE,
+        SensorStatus::TempertureWarning => HEALTH_TEMPERATURE_WARNING,
+        SensorStatus::VoltageWarning => HEALTH_VOLTAGE_WARNING,
+    }
+}
+
+pub fn flags_status(flags: u16) -> SensorStatus {
+    // Most serious first.
+    let order = [
+        (HEALTH_SENSOR_FAILURE, SensorStatus::SensorFailure),
+        (HEALTH_POWER_FAILURE, SensorStatus::PowerFailure),
+        (HEALTH_BUS_FAILURE, SensorStatus::BusFailure),
+        (HEALTH_TEMPERATURE_WARNING, SensorStatus::TempertureWarning),
+        (HEALTH_VOLTAGE_WARNING, SensorStatus::VoltageWarning),
+        (HEALTH_BUSY, SensorStatus::Busy),
+    ];
+    for (flag, status) in order.iter() {
+        if flags & flag != 0 {
+            return *status;
+        }
+    }
+    SensorStatus::Ready
+}
+
+
+// The controller side copy of a `HealthReport`.
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SensorHealth {
+    pub status: SensorStatus,
+    pub flags: u16,
+    pub detail: u16,
+    pub message: String,
+}
+
+#[cfg(any(test, feature = "bus_master"))]
+impl SensorHealth {
+    // Modules from before health reports only send the status byte, the
+    // flags are worked out from it.
+    pub fn decode(b: &[u8]) -> Option<SensorHealth> {
+        if b.is_empty() {
+            return None;
+        }
+        let status: SensorStatus = b[0].into();
+        if b.len() < REPORT_HEADER_LEN {
+            return Some(SensorHealth {
+                status,
+                flags: status_flags(status),
+                detail: 0,
+                message: String::new(),
+            });
+        }
+
+        Some(SensorHealth {
+            status,
+            flags: u16::from_be_bytes([b[1], b[2]]),
+            detail: u16::from_be_bytes([b[3], b[4]]),
+            message: String::from_utf8_lossy(&b[REPORT_HEADER_LEN..]).into_owned(),
+        })
+    }
+
+    pub fn has(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.flags & !HEALTH_BUSY == 0
+    }
+}
+
+
+#[cfg(test)]
+mod status_tests {
+    use super::*;
+
+    #[test]
+    fn several_flags() {
+        let report = HealthReport {
+            flags: HEALTH_TEMPERATURE_WARNING | HEALTH_VOLTAGE_WARNING,
+            detail: 0x0102,
+            message: "Vin low",
+        };
+        let mut buf: Vec<u8> = vec![];
+        report.encode(&mut buf);
+        assert_eq!(buf[0], SensorStatus::TempertureWarning as u8);
+
+        let health = SensorHealth::decode(&buf).unwrap();
+        assert!(health.has(HEALTH_TEMPERATURE_WARNING));
+        assert!(health.has(HEALTH_VOLTAGE_WARNING));
+        assert!(!health.is_healthy());
+        assert_eq!(health.detail, 0x0102);
+        assert_eq!(health.message, "Vin low");
+    }
+
+    #[test]
+    fn failure_wins() {
+        assert_eq!(flags_status(HEALTH_BUSY | HEALTH_POWER_FAILURE), SensorStatus::PowerFailure);
+        assert_eq!(flags_status(HEALTH_BUSY), SensorStatus::Busy);
+        assert_eq!(flags_status(0), SensorStatus::Ready);
+    }
+
+    #[test]
+    fn old_module_reply() {
+        let health = SensorHealth::decode(&[SensorStatus::Busy as u8]).unwrap();
+        assert_eq!(health.flags, HEALTH_BUSY);
+        assert!(health.is_healthy());
+        assert!(health.message.is_empty());
+        assert!(SensorHealth::decode(&[]).is_none());
+    }
+
+    #[test]
+    fn long_message_cut() {
+        let report = HealthReport {
+            flags: 0,
+            detail: 0,
+            message: "0123456789012345678901234567890123456789012345678901234567890123",
+        };
+        let mut buf: Vec<u8> = vec![];
+        report.encode(&mut buf);
+        assert_eq!(buf.len(), REPORT_HEADER_LEN + MAX_MESSAGE_LEN);
+
+        // 47 bytes then a 2 byte character across the limit, it's left out
+        // rather than split.
+        let report = HealthReport {
+            message: "01234567890123456789012345678901234567890123456°C",
+            ..report
+        };
+        buf.clear();
+        report.encode(&mut buf);
+        assert_eq!(buf.len(), REPORT_HEADER_LEN + MAX_MESSAGE_LEN - 1);
+        let health = SensorHealth::decode(&buf).unwrap();
+        assert_eq!(health.message, "01234567890123456789012345678901234567890123456");
+    }
+}
@@ -0,0 +1,110 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: subdevice.rs
+ * Desc: Several sensors behind one node. Each is a sub-device with its own
+ *       SensorInterface, a request is sent to one of them by wrapping it:
+ *
+ *           [SubDeviceRequest, seq, sub, cmd, args..]
+ *
+ *       and the reply is [seq, SubStatus, reply to cmd..]. Requests that
+ *       aren't wrapped go to sub-device 0, so controllers that don't know
+ *       about sub-devices still see the first sensor.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::ControllerCommand;
+
+#[cfg(any(test, feature = "bus_master"))]
+use crate::channels::ChannelDescriptor;
+
+// sub, cmd
+pub const SUB_ARGS_LEN: usize = 2;
+
+
+// Second byte of the SubDeviceRequest reply, after the sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SubStatus {
+    Ok = 0,
+    BadIndex,   //No such sub-device.
+    BadCommand, //Only the node as a whole answers that one.
+}
+
+impl From<u8> for SubStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => SubStatus::Ok,
+            1 => SubStatus::BadIndex,
+            _ => SubStatus::BadCommand,
+        }
+    }
+}
+
+
+// Commands about the node itself rather than one of its sensors, these
+// can't be wrapped. The firmware, clock, identity and address are the
+// node's, not one sensor's.
+pub fn is_node_command(cmd: ControllerCommand) -> bool {
+    matches!(cmd,
+        ControllerCommand::FwBeginRequest |
+        ControllerCommand::FwWriteRequest |
+        ControllerCommand::FwVerifyRequest |
+        ControllerCommand::FwCommitRequest |
+        ControllerCommand::IdentifyRequest |
+        ControllerCommand::TimeSyncRequest |
+        ControllerCommand::TimeAdjustRequest |
+        ControllerCommand::CapabilitiesRequest |
+        ControllerCommand::VersionRequest |
+        ControllerCommand::AddressAssignRequest |
+        ControllerCommand::SubDeviceCountRequest |
+        ControllerCommand::SubDeviceRequest)
+}
+
+
+// The args of a SubDeviceRequest.
+pub fn wrap(sub: u8, cmd: ControllerCommand, args: &[u8]) -> Vec<u8> {
+    let mut wrapped: Vec<u8> = Vec::with_capacity(SUB_ARGS_LEN + args.len());
+    wrapped.push(sub);
+    wrapped.push(cmd as u8);
+    wrapped.extend_from_slice(args);
+    wrapped
+}
+
+
+// What the controller knows about one sensor on a node.
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubDevice {
+    pub index: u8,
+    pub name: String,
+    pub channels: Vec<ChannelDescriptor>,
+}
+
+#[cfg(any(test, feature = "bus_master"))]
+impl SubDevice {
+    // Same order as the channels, the way `get_format` gives them.
+    pub fn format(&self) -> Vec<String> {
+        self.channels.iter().map(|c| String::from(c.channel_type.format())).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod subdevice_tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_args() {
+        let args = wrap(2, ControllerCommand::DataRequest, &[1]);
+        assert_eq!(args, vec![2, ControllerCommand::DataRequest as u8, 1]);
+        assert!(is_node_command(ControllerCommand::AddressAssignRequest));
+        assert!(is_node_command(ControllerCommand::FwWriteRequest));
+        assert!(is_node_command(ControllerCommand::TimeSyncRequest));
+        assert!(!is_node_command(ControllerCommand::NameRequest));
+        assert!(!is_node_command(ControllerCommand::DataRequest));
+        assert_eq!(SubStatus::from(9), SubStatus::BadCommand);
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: timesync.rs
+ * Desc: Keeps the modules' clocks in line with the controller's so samples
+ *       from different modules can be lined up.
+ *
+ *       The controller notes its time(t1), sends TimeSyncRequest, the module
+ *       answers with its own time(tm) and the controller notes the time the
+ *       reply came in(t4). Taking the module's reading as being half way
+ *       through the round trip:
+ *
+ *           offset = tm - (t1 + t4) / 2
+ *
+ *       The controller sends the module -offset with TimeAdjustRequest and
+ *       from then on the module's time is the controller's(microseconds
+ *       since the unix epoch), give or take half the round trip.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+#[cfg(any(test, feature = "bus_master"))]
+use std::time::Duration;
+#[cfg(any(test, feature = "bus_master"))]
+use std::time::SystemTime;
+#[cfg(any(test, feature = "bus_master"))]
+use std::time::UNIX_EPOCH;
+
+pub const TIME_LEN: usize = 8;
+
+
+// First byte of the time sync, adjust and timed data replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimeStatus {
+    Ok = 0,
+    NoClock,    //The module doesn't keep time.
+    BadIndex,   //No such channel(timed data only).
+    BadArgs,    //The adjustment was short.
+}
+
+impl From<u8> for TimeStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => TimeStatus::Ok,
+            2 => TimeStatus::BadIndex,
+            3 => TimeStatus::BadArgs,
+            _ => TimeStatus::NoClock,
+        }
+    }
+}
+
+
+// The module's clock. `local_us` is whatever free running microsecond
+// counter it has(a timer since boot), the offset is what the controller
+// asked to add to it.
+pub trait ModuleClock {
+    fn local_us(&self) -> u64;
+
+    fn offset(&self) -> i64;
+
+    fn set_offset(&mut self, offset: i64);
+
+    // Synchronized time, microseconds since the unix epoch once synced.
+    fn now_us(&self) -> u64 {
+        self.local_us().wrapping_add_signed(self.offset())
+    }
+}
+
+
+// [status, time(8)]
+pub fn handle_sync(clock: Option<&mut dyn ModuleClock>, buf: &mut Vec<u8>) {
+    match clock {
+        Some(clock) => {
+            buf.push(TimeStatus::Ok as u8);
+            buf.extend_from_slice(&clock.now_us().to_be_bytes());
+        }
+        None => buf.push(TimeStatus::NoClock as u8),
+    }
+}
+
+
+// Args are the adjustment(8, BE, signed), added to the current offset.
+pub fn handle_adjust(clock: Option<&mut dyn ModuleClock>, args: &[u8], buf: &mut Vec<u8>) {
+    let clock = match clock {
+        Some(c) => c,
+        None => return buf.push(TimeStatus::NoClock as u8),
+    };
+    if args.len() < TIME_LEN {
+        return buf.push(TimeStatus::BadArgs as u8);
+    }
+
+    let mut adj: [u8; TIME_LEN] = [0; TIME_LEN];
+    adj.copy_from_slice(&args[..TIME_LEN]);
+    let offset = clock.offset().wrapping_add(i64::from_be_bytes(adj));
+    clock.set_offset(offset);
+    buf.push(TimeStatus::Ok as u8);
+}
+
+
+pub fn decode_time(b: &[u8]) -> Option<u64> {
+    if b.len() < TIME_LEN {
+        return None;
+    }
+    let mut t: [u8; TIME_LEN] = [0; TIME_LEN];
+    t.copy_from_slice(&b[..TIME_LEN]);
+    Some(u64::from_be_bytes(t))
+}
+
+
+// The controller's clock, microseconds since the unix epoch.
+#[cfg(any(test, feature = "bus_master"))]
+pub fn host_now_us() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_micros() as u64,
+        Err(_e) => 0,
+    }
+}
+
+// The controller's side of a sync, the reference everything is set to so
+// it's never adjusted. Tests hand `Controller::sync_time_with` a fake clock
+// instead.
+#[cfg(any(test, feature = "bus_master"))]
+pub struct HostClock;
+
+#[cfg(any(test, feature = "bus_master"))]
+impl ModuleClock for HostClock {
+    fn local_us(&self) -> u64 {
+        host_now_us()
+    }
+
+    fn offset(&self) -> i64 {
+        0
+    }
+
+    fn set_offset(&mut self, _offset: i64) {}
+}
+
+// A synchronized module timestamp as host time.
+#[cfg(any(test, feature = "bus_master"))]
+pub fn to_host_time(us: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(us)
+}
+
+
+// Result of a sync with one module.
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncResult {
+    pub offset_us: i64,     //How far the module was off before the adjust.
+    pub round_trip_us: u64, //Half of this is how far off it can still be.
+}
+
+
+// A reading tagged with the time the module took it.
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedSample {
+    pub node: u8,
+    pub channel: u8,
+    pub timestamp_us: u64,
+    pub time: SystemTime,
+    pub data: Vec<u8>,
+}
+
+
+#[cfg(test)]
+mod timesync_tests {
+    use super::*;
+    use crate::fake_clock::FakeClock;
+
+    #[test]
+    fn adjust_moves_clock() {
+        let mut clock = FakeClock::new(1_000);
+        let mut buf: Vec<u8> = vec![];
+
+        handle_adjust(Some(&mut clock), &500i64.to_be_bytes(), &mut buf);
+        assert_eq!(buf, vec![TimeStatus::Ok as u8]);
+        handle_adjust(Some(&mut clock), &(-200i64).to_be_bytes(), &mut buf);
+        assert_eq!(clock.now_us(), 1_300);
+
+        buf.clear();
+        handle_sync(Some(&mut clock), &mut buf);
+        assert_eq!(buf[0], TimeStatus::Ok as u8);
+        assert_eq!(decode_time(&buf[1..]), Some(1_300));
+    }
+
+    #[test]
+    fn short_adjust() {
+        let mut clock = FakeClock::new(1_000);
+        let mut buf: Vec<u8> = vec![];
+        handle_adjust(Some(&mut clock), &[0, 0, 1], &mut buf);
+        assert_eq!(buf, vec![TimeStatus::BadArgs as u8]);
+        assert_eq!(clock.now_us(), 1_000);
+        assert_eq!(TimeStatus::from(3), TimeStatus::BadArgs);
+    }
+
+    #[test]
+    fn no_clock() {
+        let mut buf: Vec<u8> = vec![];
+        handle_sync(None, &mut buf);
+        assert_eq!(buf, vec![TimeStatus::NoClock as u8]);
+        assert_eq!(decode_time(&buf[1..]), None);
+    }
+
+    #[test]
+    fn host_time() {
+        let t = to_host_time(1_500_000);
+        assert_eq!(t.duration_since(UNIX_EPOCH).unwrap(), Duration::from_micros(1_500_000));
+    }
+}
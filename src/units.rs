@@ -0,0 +1,192 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: units.rs
+ * Desc: What a channel's raw reading means, its unit and how to scale it.
+ *
+ *       physical = raw * scale + offset
+ *
+ *       Units are UCUM codes("Cel", "%", "V", "Pa", "m/s2".. and "1" for
+ *       plain numbers), so the controller doesn't have to guess.
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+use crate::capabilities::Endianness;
+use crate::channels::ChannelType;
+
+#[cfg(any(test, feature = "bus_master"))]
+use crate::BusError;
+
+// status, index, scale(4), offset(4), min(4), max(4), resolution(4), unit..
+const META_HEADER_LEN: usize = 22;
+
+
+// First byte of the ChannelMetaRequest reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MetaStatus {
+    Ok = 0,
+    BadIndex,   //No such channel.
+    NoMeta,     //The module doesn't describe its channels.
+}
+
+impl From<u8> for MetaStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => MetaStatus::Ok,
+            1 => MetaStatus::BadIndex,
+            _ => MetaStatus::NoMeta,
+        }
+    }
+}
+
+
+// Given by the sensor for each channel. `min`, `max` and `resolution` are
+// in the physical unit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelMeta {
+    pub unit: &'static str,
+    pub scale: f32,
+    pub offset: f32,
+    pub min: f32,
+    pub max: f32,
+    pub resolution: f32,
+}
+
+impl ChannelMeta {
+    // Raw value already is the physical one.
+    pub const fn plain(unit: &'static str) -> ChannelMeta {
+        ChannelMeta {
+            unit,
+            scale: 1.0,
+            offset: 0.0,
+            min: f32::MIN,
+            max: f32::MAX,
+            resolution: 1.0,
+        }
+    }
+
+    pub fn encode(&self, idx: u8, buf: &mut Vec<u8>) {
+        buf.push(MetaStatus::Ok as u8);
+        buf.push(idx);
+        for v in [self.scale, self.offset, self.min, self.max, self.resolution] {
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        buf.extend_from_slice(self.unit.as_bytes());
+    }
+}
+
+
+// Turns the raw bytes of a channel into a number, based on its format
+// ("u8", "i16", "f32"..). None for formats we don't know or short data.
+pub fn raw_to_f64(format: &str, b: &[u8], order: Endianness) -> Option<f64> {
+    let channel_type = ChannelType::from_format(format);
+    let size = channel_type.size();
+    if size == 0 || b.len() < size {
+        return None;
+    }
+
+    // `decode` reads big endian.
+    let mut raw: [u8; 8] = [0; 8];
+    raw[..size].copy_from_slice(&b[..size]);
+    if order == Endianness::Little {
+        raw[..size].reverse();
+    }
+    channel_type.decode(&raw[..size])
+}
+
+
+// The controller side copy of a `ChannelMeta`.
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    pub index: u8,
+    pub unit: String,
+    pub scale: f64,
+    pub offset: f64,
+    pub min: f64,
+    pub max: f64,
+    pub resolution: f64,
+}
+
+#[cfg(any(test, feature = "bus_master"))]
+impl ChannelInfo {
+    pub fn decode(b: &[u8]) -> Result<ChannelInfo, BusError> {
+        if b.len() < META_HEADER_LEN || MetaStatus::from(b[0]) != MetaStatus::Ok {
+            return Err(BusError::BadParameter);
+        }
+
+        let f = |at: usize| f32::from_be_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) as f64;
+        let unit = match String::from_utf8(b[META_HEADER_LEN..].to_vec()) {
+            Ok(u) => u,
+            Err(_e) => return Err(BusError::BadParameter),
+        };
+
+        Ok(ChannelInfo {
+            index: b[1],
+            unit,
+            scale: f(2),
+            offset: f(6),
+            min: f(10),
+            max: f(14),
+            resolution: f(18),
+        })
+    }
+
+    pub fn to_physical(&self, raw: f64) -> f64 {
+        raw * self.scale + self.offset
+    }
+}
+
+
+// A reading in its physical unit.
+#[cfg(any(test, feature = "bus_master"))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub value: f64,
+    pub unit: String,
+}
+
+
+#[cfg(test)]
+mod units_tests {
+    use super::*;
+
+    #[test]
+    fn meta_round_trip() {
+        let meta = ChannelMeta {
+            unit: "Cel",
+            scale: 0.01,
+            offset: -40.0,
+            min: -40.0,
+            max: 125.0,
+            resolution: 0.01,
+        };
+        let mut buf: Vec<u8> = vec![];
+        meta.encode(1, &mut buf);
+
+        let info = ChannelInfo::decode(&buf).unwrap();
+        assert_eq!(info.index, 1);
+        assert_eq!(info.unit, "Cel");
+        assert_eq!(info.scale, 0.01f32 as f64);
+        assert_eq!(info.max, 125.0);
+        assert!((info.to_physical(6500.0) - 25.0).abs() < 1e-3);
+
+        assert!(ChannelInfo::decode(&[MetaStatus::BadIndex as u8, 1]).is_err());
+    }
+
+    #[test]
+    fn raw_values() {
+        assert_eq!(raw_to_f64("u16", &[0x01, 0x00], Endianness::Big), Some(256.0));
+        assert_eq!(raw_to_f64("u16", &[0x01, 0x00], Endianness::Little), Some(1.0));
+        assert_eq!(raw_to_f64("i16", &[0xFF, 0xFE], Endianness::Big), Some(-2.0));
+        assert_eq!(raw_to_f64("i8", &[0x80], Endianness::Big), Some(-128.0));
+        assert_eq!(raw_to_f64("f32", &1.5f32.to_le_bytes(), Endianness::Little), Some(1.5));
+        assert_eq!(raw_to_f64("u32", &[0, 1], Endianness::Big), None);
+        assert_eq!(raw_to_f64("q7", &[0], Endianness::Big), None);
+        assert_eq!(raw_to_f64("i64", &(-3i64).to_be_bytes(), Endianness::Big), Some(-3.0));
+        assert_eq!(raw_to_f64("f64", &0.25f64.to_le_bytes(), Endianness::Little), Some(0.25));
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * Authors: Jake G,
+ * Date: 2024
+ * Filename: vendor.rs
+ * Desc: Module specific commands(heater on, calibrate..) without touching
+ *       `ControllerCommand`. Command bytes from VENDOR_COMMAND_BASE up are
+ *       left to the module, the handler passes them on to the sensor's
+ *       `VendorHandler` and the controller sends them with
+ *       `send_vendor_command`.
+ *
+ *       Request [code, seq, args..], reply [seq, VendorStatus, data..].
+ */
+
+#[cfg(all(not(test), feature = "sensor_module"))]
+use alloc::vec::Vec;
+
+// First vendor command byte, everything below belongs to the protocol.
+pub const VENDOR_COMMAND_BASE: u8 = 0xC0;
+
+
+// First byte of a vendor reply, after the sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VendorStatus {
+    Ok = 0,
+    Unknown,    //The module has no such vendor command.
+    BadArgs,
+    Failed,
+}
+
+impl From<u8> for VendorStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => VendorStatus::Ok,
+            1 => VendorStatus::Unknown,
+            2 => VendorStatus::BadArgs,
+            _ => VendorStatus::Failed,
+        }
+    }
+}
+
+
+pub fn is_vendor(code: u8) -> bool {
+    code >= VENDOR_COMMAND_BASE
+}
+
+
+// Handles the module's vendor commands, `reply` is sent back after the
+// status.
+pub trait VendorHandler {
+    fn handle_vendor(&mut self, code: u8, args: &[u8], reply: &mut Vec<u8>) -> VendorStatus;
+}
+
+
+// One entry of a static dispatch table, for sensors that would rather
+// list their commands than write the match themselves:
+//
+//     const VENDOR: [VendorCommand<Aht20>; 1] = [
+//         VendorCommand { code: 0xC0, run: Aht20::heater },
+//     ];
+//
+//     impl VendorHandler for Aht20 {
+//         fn handle_vendor(&mut self, code: u8, args: &[u8], reply: &mut Vec<u8>) -> VendorStatus {
+//             vendor::dispatch(&VENDOR, self, code, args, reply)
+//         }
+//     }
+pub struct VendorCommand<S> {
+    pub code: u8,
+    pub run: fn(&mut S, &[u8], &mut Vec<u8>) -> VendorStatus,
+}
+
+pub fn dispatch<S>(table: &[VendorCommand<S>], sens: &mut S, code: u8, args: &[u8], reply: &mut Vec<u8>) -> VendorStatus {
+    match table.iter().find(|c| c.code == code) {
+        Some(c) => (c.run)(sens, args, reply),
+        None => VendorStatus::Unknown,
+    }
+}
+
+
+// [status, data..] for the handler.
+pub fn handle(handler: Option<&mut dyn VendorHandler>, code: u8, args: &[u8], buf: &mut Vec<u8>) {
+    let at = buf.len();
+    buf.push(VendorStatus::Unknown as u8);
+    if let Some(handler) = handler {
+        let status = handler.handle_vendor(code, args, buf);
+        buf[at] = status as u8;
+    }
+}
+
+
+#[cfg(test)]
+mod vendor_tests {
+    use super::*;
+
+    struct Heater {
+        on: bool,
+    }
+
+    impl Heater {
+        fn set(&mut self, args: &[u8], _reply: &mut Vec<u8>) -> VendorStatus {
+            match args.first() {
+                Some(v) => self.on = *v != 0,
+                None => return VendorStatus::BadArgs,
+            }
+            VendorStatus::Ok
+        }
+
+        fn get(&mut self, _args: &[u8], reply: &mut Vec<u8>) -> VendorStatus {
+            reply.push(self.on as u8);
+            VendorStatus::Ok
+        }
+    }
+
+    const TABLE: [VendorCommand<Heater>; 2] = [
+        VendorCommand { code: 0xC0, run: Heater::set },
+        VendorCommand { code: 0xC1, run: Heater::get },
+    ];
+
+    impl VendorHandler for Heater {
+        fn handle_vendor(&mut self, code: u8, args: &[u8], reply: &mut Vec<u8>) -> VendorStatus {
+            dispatch(&TABLE, self, code, args, reply)
+        }
+    }
+
+    #[test]
+    fn table_dispatch() {
+        let mut heater = Heater { on: false };
+        let mut buf: Vec<u8> = vec![];
+
+        handle(Some(&mut heater), 0xC0, &[1], &mut buf);
+        assert_eq!(buf, vec![VendorStatus::Ok as u8]);
+        assert!(heater.on);
+
+        buf.clear();
+        handle(Some(&mut heater), 0xC1, &[], &mut buf);
+        assert_eq!(buf, vec![VendorStatus::Ok as u8, 1]);
+
+        buf.clear();
+        handle(Some(&mut heater), 0xC0, &[], &mut buf);
+        assert_eq!(buf, vec![VendorStatus::BadArgs as u8]);
+
+        buf.clear();
+        handle(Some(&mut heater), 0xC7, &[], &mut buf);
+        assert_eq!(buf, vec![VendorStatus::Unknown as u8]);
+    }
+
+    #[test]
+    fn no_handler() {
+        let mut buf: Vec<u8> = vec![];
+        handle(None, 0xC0, &[], &mut buf);
+        assert_eq!(buf, vec![VendorStatus::Unknown as u8]);
+        assert!(is_vendor(0xFF));
+        assert!(!is_vendor(0x10));
+    }
+}